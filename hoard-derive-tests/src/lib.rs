@@ -1,7 +1,7 @@
 #![feature(never_type)]
 
 use leint::Le;
-use hoard_derive::Primitive;
+use hoard_derive::{Coerce, Primitive, Schema};
 
 #[derive(Primitive)]
 #[repr(C)]
@@ -14,6 +14,102 @@ pub struct Outpoint {
 #[repr(C)]
 pub struct Foo(u8,bool);
 
+#[derive(Schema)]
+pub struct Point {
+    x: Le<u32>,
+    y: Le<u32>,
+}
+
+#[derive(Schema)]
+#[hoard(schema_tag = "point-v2")]
+pub struct PointV2 {
+    x: Le<u32>,
+    y: Le<u32>,
+}
+
+#[derive(Schema)]
+pub enum Shape {
+    Dot(Point),
+    Line { a: Point, b: Point },
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use super::*;
+
+    use hoard::fingerprint::Fingerprint;
+
+    #[test]
+    fn schema_tag() {
+        assert_ne!(Fingerprint::of::<Point>(), Fingerprint::of::<PointV2>());
+        assert_ne!(Fingerprint::of::<Point>(), Fingerprint::of::<Shape>());
+    }
+}
+
+#[derive(Coerce)]
+#[repr(C)]
+pub struct Node<P> {
+    left: P,
+    right: P,
+    value: Le<u32>,
+}
+
+#[derive(Coerce)]
+#[repr(transparent)]
+pub struct Snapshot<'p, 'v>(hoard::pile::TryPile<'p, 'v>);
+
+#[cfg(test)]
+mod coerce_tests {
+    use super::*;
+
+    use std::ptr::NonNull;
+
+    use hoard::coerce::{Coerce, TryCoerce, TryCoerceFieldsError};
+    use hoard::offset::{Offset, OffsetMut, TryCoerceDirtyError};
+    use hoard::pile::TryPile;
+
+    #[test]
+    fn clean_node() {
+        let node = Node::<OffsetMut> {
+            left: Offset::new(1).unwrap().into(),
+            right: Offset::new(2).unwrap().into(),
+            value: 42.into(),
+        };
+
+        let node: Node<Offset> = node.try_coerce().unwrap();
+        assert_eq!(node.left, 1);
+        assert_eq!(node.right, 2);
+        assert_eq!(node.value, 42);
+    }
+
+    #[test]
+    fn dirty_node() {
+        let node = Node::<OffsetMut> {
+            left: Offset::new(1).unwrap().into(),
+            right: OffsetMut::from_ptr(NonNull::dangling()).unwrap(),
+            value: 42.into(),
+        };
+
+        assert_eq!(TryCoerce::<Node<Offset>>::try_coerce(node).err(),
+                   Some(TryCoerceFieldsError::Rest(TryCoerceFieldsError::Field {
+                       name: "right",
+                       err: TryCoerceDirtyError,
+                   })));
+    }
+
+    #[test]
+    fn rebrand() {
+        let buf = [1u8, 2, 3];
+
+        fn shorten<'p, 'v1: 'v2, 'v2>(snapshot: Snapshot<'p, 'v1>) -> Snapshot<'p, 'v2> {
+            snapshot.coerce()
+        }
+        TryPile::new(&buf, |pile| {
+            assert_eq!(shorten(Snapshot(pile)).0.as_bytes(), &buf);
+        });
+    }
+}
+
 #[cfg(tests)]
 mod tests {
     #[test]
@@ -1,8 +1,13 @@
-use quote::quote;
+use std::collections::HashMap;
+
+use quote::{quote, format_ident};
 use syn;
+use syn::visit_mut::{self, VisitMut};
 use synstructure::decl_derive;
 
 decl_derive!([Primitive, attributes(foo)] => derive_primitive);
+decl_derive!([Schema, attributes(hoard)] => derive_schema);
+decl_derive!([Coerce] => derive_coerce);
 
 fn derive_primitive(s: synstructure::Structure) -> proc_macro2::TokenStream {
     let mut fields_ty = vec![];
@@ -116,3 +121,291 @@ fn derive_primitive(s: synstructure::Structure) -> proc_macro2::TokenStream {
     t
 }
 
+
+/// Finds the `#[hoard(schema_tag = "...")]` attribute, if present.
+fn schema_tag(attrs: &[syn::Attribute]) -> Option<String> {
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("hoard")) {
+        if let Ok(syn::Meta::List(list)) = attr.parse_meta() {
+            for nested in list.nested.iter() {
+                match nested {
+                    syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("schema_tag") => {
+                        match &nv.lit {
+                            syn::Lit::Str(tag) => return Some(tag.value()),
+                            _ => panic!("schema_tag must be a string"),
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+    None
+}
+
+fn derive_schema(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
+    s.add_bounds(synstructure::AddBounds::Fields);
+
+    let tag = match schema_tag(&s.ast().attrs) {
+        Some(tag) => quote! { __hasher.write_bytes(b"tag").write_str(#tag); },
+        None => quote! {},
+    };
+
+    let describe_fields = |fields: &syn::Fields| {
+        let fields = fields.iter().enumerate().map(|(i, field)| {
+            let ty = &field.ty;
+            let name = field.ident.as_ref()
+                                  .map(|ident| ident.to_string())
+                                  .unwrap_or_else(|| i.to_string());
+            quote! { __hasher.field::<#ty>(#name); }
+        });
+        quote! { #( #fields )* }
+    };
+
+    let body = match &s.ast().data {
+        syn::Data::Struct(data) => {
+            let n = data.fields.len() as u64;
+            let fields = describe_fields(&data.fields);
+            quote! {
+                __hasher.write_str("struct").write_u64(#n);
+                #fields
+            }
+        },
+        syn::Data::Enum(data) => {
+            let n = data.variants.len() as u64;
+            let variants = data.variants.iter().map(|variant| {
+                let name = variant.ident.to_string();
+                let n = variant.fields.len() as u64;
+                let fields = describe_fields(&variant.fields);
+                quote! {
+                    __hasher.write_str(#name).write_u64(#n);
+                    #fields
+                }
+            });
+            quote! {
+                __hasher.write_str("enum").write_u64(#n);
+                #( #variants )*
+            }
+        },
+        syn::Data::Union(_) => {
+            panic!("unions not supported")
+        },
+    };
+
+    s.gen_impl(quote! {
+        extern crate hoard;
+
+        gen impl ::hoard::fingerprint::Schema for @Self {
+            fn describe_schema(__hasher: &mut ::hoard::fingerprint::SchemaHasher) {
+                #tag
+                #body
+            }
+        }
+    })
+}
+
+/// Returns true if the type has a `#[repr(C)]` or `#[repr(transparent)]` attribute.
+fn has_stable_repr(attrs: &[syn::Attribute]) -> bool {
+    attrs.iter()
+         .filter(|attr| attr.path.is_ident("repr"))
+         .filter_map(|attr| attr.parse_meta().ok())
+         .any(|meta| match meta {
+             syn::Meta::List(list) => list.nested.iter().any(|nested| match nested {
+                 syn::NestedMeta::Meta(syn::Meta::Path(path)) => {
+                     path.is_ident("C") || path.is_ident("transparent")
+                 },
+                 _ => false,
+             }),
+             _ => false,
+         })
+}
+
+/// Replaces generic parameters with their counterparts in the coercion target.
+struct Rename {
+    types: HashMap<syn::Ident, syn::Ident>,
+    lifetimes: HashMap<syn::Ident, syn::Ident>,
+}
+
+impl VisitMut for Rename {
+    fn visit_path_mut(&mut self, path: &mut syn::Path) {
+        if path.leading_colon.is_none() {
+            if let Some(first) = path.segments.first_mut() {
+                if let Some(renamed) = self.types.get(&first.ident) {
+                    first.ident = renamed.clone();
+                }
+            }
+        }
+        visit_mut::visit_path_mut(self, path)
+    }
+
+    fn visit_type_param_mut(&mut self, param: &mut syn::TypeParam) {
+        if let Some(renamed) = self.types.get(&param.ident) {
+            param.ident = renamed.clone();
+        }
+        visit_mut::visit_type_param_mut(self, param)
+    }
+
+    fn visit_lifetime_mut(&mut self, lifetime: &mut syn::Lifetime) {
+        if let Some(renamed) = self.lifetimes.get(&lifetime.ident) {
+            lifetime.ident = renamed.clone();
+        }
+    }
+}
+
+/// Derives `TryCoerce` from a struct to the same struct with different generic parameters.
+///
+/// Every type and lifetime parameter gets a counterpart in the target, and each field is required
+/// to implement `TryCoerce` to the corresponding field of the target. Since the target is the
+/// same struct definition, a stable `#[repr]` is required for the field layouts to match; the
+/// layouts are also checked at compile time.
+///
+/// When every field implements `Coerce`, so does the struct. That's checked at compile time too,
+/// with the field bounds on `Coerce`, so a field error type that can't be ruled out is caught by
+/// the derive rather than at the use site.
+fn derive_coerce(s: synstructure::Structure) -> proc_macro2::TokenStream {
+    let ast = s.ast();
+    let name = &ast.ident;
+
+    let fields = match &ast.data {
+        syn::Data::Struct(data) => &data.fields,
+        syn::Data::Enum(_) => panic!("enums not supported"),
+        syn::Data::Union(_) => panic!("unions not supported"),
+    };
+
+    if !has_stable_repr(&ast.attrs) {
+        return quote! {
+            compile_error!("#[derive(Coerce)] requires #[repr(C)] or #[repr(transparent)]");
+        };
+    }
+
+    let mut rename = Rename {
+        types: HashMap::new(),
+        lifetimes: HashMap::new(),
+    };
+    for param in ast.generics.params.iter() {
+        match param {
+            syn::GenericParam::Type(param) => {
+                rename.types.insert(param.ident.clone(), format_ident!("__Coerce{}", param.ident));
+            },
+            syn::GenericParam::Lifetime(param) => {
+                let ident = &param.lifetime.ident;
+                rename.lifetimes.insert(ident.clone(), format_ident!("__coerce_{}", ident));
+            },
+            syn::GenericParam::Const(_) => {},
+        }
+    }
+
+    let mut target_generics = ast.generics.clone();
+    rename.visit_generics_mut(&mut target_generics);
+
+    // The impl is generic over both sets of parameters, with const parameters shared.
+    let mut impl_generics = ast.generics.clone();
+    for param in target_generics.params.iter() {
+        match param {
+            syn::GenericParam::Type(_) | syn::GenericParam::Lifetime(_) => {
+                impl_generics.params.push(param.clone());
+            },
+            syn::GenericParam::Const(_) => {},
+        }
+    }
+    for param in impl_generics.params.iter_mut() {
+        match param {
+            syn::GenericParam::Type(param) => {
+                param.eq_token = None;
+                param.default = None;
+            },
+            syn::GenericParam::Const(param) => {
+                param.eq_token = None;
+                param.default = None;
+            },
+            syn::GenericParam::Lifetime(_) => {},
+        }
+    }
+
+    let where_clause = impl_generics.make_where_clause();
+    if let Some(target_where) = &target_generics.where_clause {
+        where_clause.predicates.extend(target_where.predicates.iter().cloned());
+    }
+
+    let mut coerce_where_clause = where_clause.clone();
+
+    let mut field_tys = vec![];
+    for field in fields.iter() {
+        let ty = &field.ty;
+        let mut target_ty = ty.clone();
+        rename.visit_type_mut(&mut target_ty);
+
+        where_clause.predicates.push(syn::parse_quote! {
+            #ty: ::hoard::coerce::TryCoerce<#target_ty>
+        });
+        coerce_where_clause.predicates.push(syn::parse_quote! {
+            #ty: ::hoard::coerce::Coerce<#target_ty> + ::hoard::coerce::TryCoerce<#target_ty>
+        });
+        coerce_where_clause.predicates.push(syn::parse_quote! {
+            <#ty as ::hoard::coerce::TryCoerce<#target_ty>>::Error: Into<!>
+        });
+        field_tys.push((ty.clone(), target_ty));
+    }
+
+    let error_ty = field_tys.iter().rev().fold(quote! { ! }, |rest, (ty, target_ty)| quote! {
+        ::hoard::coerce::TryCoerceFieldsError<
+            <#ty as ::hoard::coerce::TryCoerce<#target_ty>>::Error,
+            #rest
+        >
+    });
+
+    let members: Vec<syn::Member> = fields.iter().enumerate().map(|(i, field)| match &field.ident {
+        Some(ident) => syn::Member::Named(ident.clone()),
+        None => syn::Member::Unnamed(syn::Index::from(i)),
+    }).collect();
+    let bindings: Vec<syn::Ident> = (0 .. members.len()).map(|i| format_ident!("__field{}", i)).collect();
+
+    let checks = field_tys.iter().zip(members.iter()).zip(bindings.iter()).enumerate()
+                          .map(|(i, (((ty, target_ty), member), binding))| {
+        let field_name = match member {
+            syn::Member::Named(ident) => ident.to_string(),
+            syn::Member::Unnamed(idx) => idx.index.to_string(),
+        };
+        let mut err = quote! {
+            ::hoard::coerce::TryCoerceFieldsError::Field { name: #field_name, err: __err }
+        };
+        for _ in 0 .. i {
+            err = quote! { ::hoard::coerce::TryCoerceFieldsError::Rest(#err) };
+        }
+        quote! {
+            <#ty as ::hoard::coerce::TryCoerce<#target_ty>>::try_coerce_ptr(#binding)
+                .map_err(|__err| #err)?;
+        }
+    });
+
+    let (impl_generics, _, where_clause) = impl_generics.split_for_impl();
+    let (_, ty_generics, _) = ast.generics.split_for_impl();
+    let (_, target_ty_generics, _) = target_generics.split_for_impl();
+
+    quote! {
+        const _: () = {
+            #[allow(dead_code)]
+            fn __fields_coerce #impl_generics ()
+                #coerce_where_clause
+            {
+                fn is_coerce<T: ::hoard::coerce::Coerce<U>, U>() {}
+                is_coerce::<#name #ty_generics, #name #target_ty_generics>();
+            }
+        };
+
+        unsafe impl #impl_generics ::hoard::coerce::TryCoerce<#name #target_ty_generics>
+            for #name #ty_generics
+            #where_clause
+        {
+            type Error = #error_ty;
+
+            fn try_coerce_ptr(this: &Self) -> Result<*const #name #target_ty_generics, Self::Error> {
+                let () = ::hoard::coerce::AssertSameLayout::<Self, #name #target_ty_generics>::SAME_LAYOUT;
+
+                let Self { #( #members: #bindings, )* } = this;
+                #( #checks )*
+
+                Ok(this as *const Self as *const #name #target_ty_generics)
+            }
+        }
+    }
+}
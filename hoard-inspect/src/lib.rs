@@ -0,0 +1,650 @@
+//! Inspection of hoard journals and piles.
+//!
+//! The `hoard-inspect` binary only knows about the built-in scalar types. Downstream crates that
+//! want to validate their own roots write a tiny binary of their own that registers their types,
+//! and then hands off to `run()`:
+//!
+//! ```no_run
+//! use hoard_inspect::Registry;
+//!
+//! let mut registry = Registry::default();
+//! registry.register::<[u8; 32]>("digest");
+//!
+//! std::process::exit(hoard_inspect::run(&registry, std::env::args().skip(1)));
+//! ```
+//!
+//! Validation is deep: everything reachable from a blob is checked with `Fsck`. Types that contain
+//! pointers are generic over the pile's lifetimes, so they can't be registered with `register()`;
+//! implement `InspectType` for them instead, naming the type within each method.
+//!
+//! Checksummed and signed journals are inspected like any other. Encrypted journals can't be
+//! validated without their key, so `blob` and `validate` refuse them.
+
+#![feature(never_type)]
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+use thiserror::Error;
+
+use leint::Le;
+
+use hoard::blob::ValidateBlob;
+use hoard::fingerprint::{Fingerprint, Schema};
+use hoard::journal::Journal;
+use hoard::journal::commit::Commit;
+use hoard::offset::Offset;
+use hoard::pile::TryPile;
+use hoard::pile::fsck::{Fsck, FsckStats};
+use hoard::validate::ValidateChildren;
+
+pub type BoxError = Box<dyn Error + 'static + Send + Sync>;
+
+/// A type that `hoard-inspect` knows how to validate.
+pub trait InspectType {
+    /// The name used to refer to the type on the command line.
+    fn name(&self) -> &str;
+
+    /// The fingerprint of the type.
+    fn fingerprint(&self) -> Fingerprint;
+
+    /// The size of the type's blob.
+    ///
+    /// Unsized types take their metadata - the length of a slice, for instance - from the command
+    /// line, and fail if it's missing.
+    fn blob_size(&self, metadata: Option<&str>) -> Result<usize, BoxError>;
+
+    /// Validates a blob of this type at an offset, and everything reachable from it.
+    fn validate_blob<'p, 'v>(&self, pile: TryPile<'p, 'v>, offset: Offset<'p, 'v>, metadata: Option<&str>)
+        -> Result<FsckStats, BoxError>;
+
+    /// Validates the root of a commit as this type, and everything reachable from it.
+    fn validate_root(&self, commit: &Commit) -> Result<FsckStats, BoxError>;
+}
+
+/// Metadata that can be given on the command line.
+pub trait InspectMetadata : Sized {
+    /// Parses metadata from an optional command line argument.
+    fn parse(arg: Option<&str>) -> Result<Self, MetadataError>;
+}
+
+impl InspectMetadata for () {
+    fn parse(arg: Option<&str>) -> Result<Self, MetadataError> {
+        match arg {
+            None => Ok(()),
+            Some(arg) => Err(MetadataError::Unexpected(arg.to_owned())),
+        }
+    }
+}
+
+/// The length of a slice-like type.
+impl InspectMetadata for Le<u64> {
+    fn parse(arg: Option<&str>) -> Result<Self, MetadataError> {
+        let arg = arg.ok_or(MetadataError::Missing)?;
+        let len = parse_usize(arg).map_err(|_| MetadataError::Invalid(arg.to_owned()))?;
+        Ok(Le::new(len as u64))
+    }
+}
+
+/// Returned when metadata is missing or invalid.
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error("unsized type requires a length")]
+    Missing,
+
+    #[error("sized type doesn't take a length, got {0:?}")]
+    Unexpected(String),
+
+    #[error("invalid length {0:?}")]
+    Invalid(String),
+
+    #[error("commit records don't record the length of unsized roots")]
+    UnsizedRoot,
+}
+
+/// The `InspectType` implementation used by `Registry::register()`.
+pub struct Registered<T: ?Sized> {
+    marker: PhantomData<fn(&T)>,
+    name: String,
+}
+
+impl<T: ?Sized> fmt::Debug for Registered<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registered")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<T> InspectType for Registered<T>
+    where T: ?Sized + Schema + for<'p, 'v> ValidateChildren<Offset<'p, 'v>>,
+          T::Metadata: InspectMetadata,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        T::fingerprint()
+    }
+
+    fn blob_size(&self, metadata: Option<&str>) -> Result<usize, BoxError> {
+        let metadata = T::Metadata::parse(metadata)?;
+        Ok(T::try_blob_layout(metadata)?.size())
+    }
+
+    fn validate_blob<'p, 'v>(&self, pile: TryPile<'p, 'v>, offset: Offset<'p, 'v>, metadata: Option<&str>)
+        -> Result<FsckStats, BoxError>
+    {
+        let metadata = T::Metadata::parse(metadata)?;
+        Ok(Fsck::new(pile).validate_root::<T>(offset, metadata)?)
+    }
+
+    fn validate_root(&self, commit: &Commit) -> Result<FsckStats, BoxError> {
+        let offset = commit.root_offset::<T>()?;
+        let metadata = T::Metadata::parse(None).map_err(|_| MetadataError::UnsizedRoot)?;
+        Ok(Fsck::new(commit.pile()).validate_root::<T>(offset, metadata)?)
+    }
+}
+
+/// A registry of inspectable types.
+#[derive(Default)]
+pub struct Registry {
+    types: Vec<Box<dyn InspectType>>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.types.iter().map(|ty| ty.name()))
+            .finish()
+    }
+}
+
+impl Registry {
+    /// Creates a registry containing the built-in scalar types.
+    pub fn with_builtins() -> Self {
+        let mut this = Self::default();
+        this.register::<()>("()")
+            .register::<bool>("bool")
+            .register::<u8>("u8")
+            .register::<i8>("i8")
+            .register::<Le<u16>>("u16")
+            .register::<Le<u32>>("u32")
+            .register::<Le<u64>>("u64")
+            .register::<Le<u128>>("u128")
+            .register::<Le<i16>>("i16")
+            .register::<Le<i32>>("i32")
+            .register::<Le<i64>>("i64")
+            .register::<Le<i128>>("i128");
+        this
+    }
+
+    /// Registers a type under a name.
+    pub fn register<T>(&mut self, name: &str) -> &mut Self
+        where T: 'static + ?Sized + Schema + for<'p, 'v> ValidateChildren<Offset<'p, 'v>>,
+              T::Metadata: InspectMetadata,
+    {
+        self.register_dyn(Box::new(Registered::<T> {
+            marker: PhantomData,
+            name: name.to_owned(),
+        }))
+    }
+
+    /// Registers a custom `InspectType` implementation.
+    pub fn register_dyn(&mut self, ty: Box<dyn InspectType>) -> &mut Self {
+        self.types.push(ty);
+        self
+    }
+
+    /// Looks up a type by name.
+    pub fn get(&self, name: &str) -> Option<&dyn InspectType> {
+        self.types.iter()
+            .find(|ty| ty.name() == name)
+            .map(|ty| &**ty)
+    }
+
+    /// Looks up a type by fingerprint.
+    pub fn get_by_fingerprint(&self, fingerprint: Fingerprint) -> Option<&dyn InspectType> {
+        self.types.iter()
+            .find(|ty| ty.fingerprint() == fingerprint)
+            .map(|ty| &**ty)
+    }
+}
+
+/// Errors returned by commands.
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("usage: {0}")]
+    Usage(&'static str),
+
+    #[error("unknown type {0:?}")]
+    UnknownType(String),
+
+    #[error("no such commit: {0}")]
+    NoSuchCommit(usize),
+
+    #[error("invalid number {0:?}")]
+    InvalidNumber(String),
+
+    #[error("offset {0} out of range")]
+    OutOfRange(usize),
+
+    #[error("blob at offset {0} hasn't been committed")]
+    Uncommitted(usize),
+
+    #[error("journal is encrypted")]
+    Encrypted,
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Validate(BoxError),
+}
+
+const USAGE: &str = "\
+hoard-inspect commits <journal>
+hoard-inspect dump <journal> <offset> <len>
+hoard-inspect blob <journal> <offset> <type> [<len>]
+hoard-inspect validate <journal> <type> [<commit>]
+hoard-inspect types";
+
+/// Runs the command-line tool, returning the process exit code.
+pub fn run(registry: &Registry, args: impl IntoIterator<Item = String>) -> i32 {
+    let args: Vec<String> = args.into_iter().collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    let stdout = io::stdout();
+    match run_command(registry, &args, &mut stdout.lock()) {
+        Ok(()) => 0,
+        Err(CommandError::Usage(usage)) => {
+            eprintln!("usage:\n{}", usage);
+            2
+        },
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    }
+}
+
+/// Runs a single command, writing its output to `out`.
+pub fn run_command(registry: &Registry, args: &[&str], out: &mut impl Write) -> Result<(), CommandError> {
+    match args {
+        ["commits", path] => {
+            let journal = Journal::<()>::open(path)?;
+            list_commits(registry, &journal, out)
+        },
+        ["dump", path, offset, len] => {
+            let journal = Journal::<()>::open(path)?;
+            let offset = parse_usize(offset)?;
+            let len = parse_usize(len)?;
+
+            let bytes = offset.checked_add(len)
+                              .and_then(|end| journal.as_bytes().get(offset .. end))
+                              .ok_or(CommandError::OutOfRange(offset))?;
+            hexdump(offset, bytes, out)?;
+            Ok(())
+        },
+        ["blob", path, offset, ty, rest @ ..] => {
+            let ty = registry.get(ty).ok_or_else(|| CommandError::UnknownType(ty.to_string()))?;
+            let metadata = match rest {
+                [] => None,
+                [metadata] => Some(*metadata),
+                _ => return Err(CommandError::Usage(USAGE)),
+            };
+            let journal = open_plaintext(path)?;
+            let offset = parse_usize(offset)?;
+
+            let size = ty.blob_size(metadata).map_err(CommandError::Validate)?;
+            let end = offset.checked_add(size).ok_or(CommandError::OutOfRange(offset))?;
+            let bytes = journal.as_bytes().get(offset .. end).ok_or(CommandError::OutOfRange(offset))?;
+            hexdump(offset, bytes, out)?;
+
+            // Validate as of the first commit containing the blob, so pointers to anything
+            // written later are caught.
+            let pile = journal.commits()
+                              .map(|commit| commit.pile())
+                              .find(|pile| pile.as_bytes().len() >= end)
+                              .ok_or(CommandError::Uncommitted(offset))?;
+
+            let offset = Offset::new(offset).ok_or(CommandError::OutOfRange(offset))?;
+            match ty.validate_blob(pile, offset, metadata) {
+                Ok(_) => writeln!(out, "valid {}", ty.name())?,
+                Err(err) => writeln!(out, "invalid {}: {}", ty.name(), err)?,
+            }
+            Ok(())
+        },
+        ["validate", path, ty, rest @ ..] => {
+            let ty = registry.get(ty).ok_or_else(|| CommandError::UnknownType(ty.to_string()))?;
+            let journal = open_plaintext(path)?;
+
+            let commit = match rest {
+                [] => journal.last_commit().ok_or(CommandError::NoSuchCommit(0))?,
+                [idx] => {
+                    let idx = parse_usize(idx)?;
+                    journal.commits().nth(idx).ok_or(CommandError::NoSuchCommit(idx))?
+                },
+                _ => return Err(CommandError::Usage(USAGE)),
+            };
+
+            ty.validate_root(&commit).map_err(CommandError::Validate)?;
+            writeln!(out, "valid {}", ty.name())?;
+            Ok(())
+        },
+        ["types"] => {
+            for ty in registry.types.iter() {
+                match ty.blob_size(None) {
+                    Ok(size) => writeln!(out, "{}\t{}\t{}", ty.name(), ty.fingerprint(), size)?,
+                    Err(_) => writeln!(out, "{}\t{}\t?", ty.name(), ty.fingerprint())?,
+                }
+            }
+            Ok(())
+        },
+        _ => Err(CommandError::Usage(USAGE)),
+    }
+}
+
+fn parse_usize(s: &str) -> Result<usize, CommandError> {
+    let r = if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    r.map_err(|_| CommandError::InvalidNumber(s.to_string()))
+}
+
+/// Opens a journal whose blobs can be validated.
+fn open_plaintext(path: &str) -> Result<Journal<'static>, CommandError> {
+    let journal = Journal::open(path)?;
+    if journal.is_encrypted() {
+        Err(CommandError::Encrypted)
+    } else {
+        Ok(journal)
+    }
+}
+
+/// Lists the commits in a journal, along with their space usage.
+pub fn list_commits(registry: &Registry, journal: &Journal, out: &mut impl Write) -> Result<(), CommandError> {
+    writeln!(out, "idx\tmark\tend\tsize\troot\tfingerprint\ttype")?;
+
+    let mut prev_end = 0;
+    for (idx, commit) in journal.commits().enumerate() {
+        let end = commit.pile().as_bytes().len();
+        let size = end - prev_end;
+        prev_end = end;
+
+        match commit.record() {
+            Ok(record) => {
+                let ty = registry.get_by_fingerprint(record.fingerprint())
+                                 .map_or("?", |ty| ty.name());
+                writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                         idx, commit.mark(), end, size,
+                         record.root().get(), record.fingerprint(), ty)?;
+            },
+            Err(err) => {
+                writeln!(out, "{}\t{}\t{}\t{}\t{}", idx, commit.mark(), end, size, err)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Writes a classic hexdump of `bytes`, labeled as starting at `offset`.
+pub fn hexdump(offset: usize, bytes: &[u8], out: &mut impl Write) -> io::Result<()> {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        write!(out, "{:08x}  ", offset + i * 16)?;
+
+        for j in 0 .. 16 {
+            match line.get(j) {
+                Some(b) => write!(out, "{:02x} ", b)?,
+                None => write!(out, "   ")?,
+            }
+            if j == 7 {
+                write!(out, " ")?;
+            }
+        }
+
+        write!(out, " |")?;
+        for b in line {
+            let c = if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' };
+            write!(out, "{}", c)?;
+        }
+        writeln!(out, "|")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+    use std::ptr;
+
+    use hoard::bag::Bag;
+    use hoard::blob::{Blob, BlobLayout, ValidBlob};
+    use hoard::fingerprint::SchemaHasher;
+    use hoard::journal::JournalMut;
+    use hoard::journal::sign::HmacSigner;
+    use hoard::pile::checksum::Checksum;
+    use hoard::pile::encrypted::Key;
+    use hoard::pointee::Pointee;
+    use hoard::ptr::PersistPtr;
+    use hoard::validate::PtrValidator;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_hexdump() {
+        let mut out = vec![];
+        hexdump(0x10, b"hello world", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "00000010  68 65 6c 6c 6f 20 77 6f  72 6c 64                 |hello world|\n");
+    }
+
+    #[test]
+    fn validate_root() -> Result<(), CommandError> {
+        let file = NamedTempFile::new()?;
+        let mut journal = JournalMut::<()>::create_from_fd(file.reopen()?, ())?;
+        journal.write_root(&Le::new(42u32))?;
+
+        let registry = Registry::with_builtins();
+        let path = file.path().to_str().unwrap();
+
+        let mut out = vec![];
+        run_command(&registry, &["validate", path, "u32"], &mut out)?;
+        assert_eq!(out, b"valid u32\n");
+
+        match run_command(&registry, &["validate", path, "u64"], &mut vec![]) {
+            Err(CommandError::Validate(_)) => {},
+            r => panic!("expected validation failure; got {:?}", r),
+        }
+
+        let mut out = vec![];
+        run_command(&registry, &["commits", path], &mut out)?;
+        assert!(String::from_utf8(out).unwrap().contains("u32"));
+
+        Ok(())
+    }
+
+    /// A nested type, which can't be registered as it contains pointers.
+    struct NestedBool;
+
+    type Nested<'p, 'v> = Bag<Bag<bool, Offset<'p, 'v>>, Offset<'p, 'v>>;
+
+    impl InspectType for NestedBool {
+        fn name(&self) -> &str {
+            "nested"
+        }
+
+        fn fingerprint(&self) -> Fingerprint {
+            Nested::fingerprint()
+        }
+
+        fn blob_size(&self, _: Option<&str>) -> Result<usize, BoxError> {
+            Ok(Nested::blob_layout().size())
+        }
+
+        fn validate_blob<'p, 'v>(&self, pile: TryPile<'p, 'v>, offset: Offset<'p, 'v>, _: Option<&str>)
+            -> Result<FsckStats, BoxError>
+        {
+            Ok(Fsck::new(pile).validate_root::<Nested<'p, 'v>>(offset, ())?)
+        }
+
+        fn validate_root(&self, commit: &Commit) -> Result<FsckStats, BoxError> {
+            let offset = commit.root_offset::<Nested>()?;
+            Ok(Fsck::new(commit.pile()).validate_root::<Nested>(offset, ())?)
+        }
+    }
+
+    #[test]
+    fn validate_is_deep() -> Result<(), CommandError> {
+        let file = NamedTempFile::new()?;
+        let mut journal = JournalMut::<()>::create_from_fd(file.reopen()?, ())?;
+        journal.write_root(&Bag::new(Bag::new(true)))?;
+
+        let mut registry = Registry::default();
+        registry.register_dyn(Box::new(NestedBool));
+        let path = file.path().to_str().unwrap();
+
+        let mut out = vec![];
+        run_command(&registry, &["validate", path, "nested"], &mut out)?;
+        assert_eq!(out, b"valid nested\n");
+
+        // Corrupt the innermost bool, which was written first. The root itself is still valid.
+        let header_len = file.as_file().metadata()?.len() as usize - Journal::<()>::open(path)?.as_bytes().len();
+        let mut fd = OpenOptions::new().write(true).open(path)?;
+        fd.seek(SeekFrom::Start(header_len as u64))?;
+        fd.write_all(&[2])?;
+
+        match run_command(&registry, &["validate", path, "nested"], &mut vec![]) {
+            Err(CommandError::Validate(_)) => {},
+            r => panic!("expected validation failure; got {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn checksummed_and_signed() -> Result<(), CommandError> {
+        let file = NamedTempFile::new()?;
+        let mut journal = JournalMut::<()>::create_from_fd_with(file.reopen()?, (), Checksum::Crc32c)?
+                                       .with_signer(HmacSigner::new("key", b"secret".to_vec()));
+        let root = journal.write_root(&Le::new(42u32))?;
+
+        let registry = Registry::with_builtins();
+        let path = file.path().to_str().unwrap();
+
+        let mut out = vec![];
+        run_command(&registry, &["validate", path, "u32"], &mut out)?;
+        assert_eq!(out, b"valid u32\n");
+
+        let mut out = vec![];
+        run_command(&registry, &["blob", path, &root.get().to_string(), "u32"], &mut out)?;
+        assert!(String::from_utf8(out).unwrap().ends_with("valid u32\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_is_rejected() -> Result<(), CommandError> {
+        let file = NamedTempFile::new()?;
+        let key = Key::new([0x42; 32]);
+        let mut journal = JournalMut::<()>::create_encrypted_from_fd(file.reopen()?, (), &key)?;
+        journal.write_root(&Le::new(42u32))?;
+
+        let registry = Registry::with_builtins();
+        let path = file.path().to_str().unwrap();
+
+        match run_command(&registry, &["validate", path, "u32"], &mut vec![]) {
+            Err(CommandError::Encrypted) => {},
+            r => panic!("expected encrypted journal to be rejected; got {:?}", r),
+        }
+        match run_command(&registry, &["blob", path, "0", "u32"], &mut vec![]) {
+            Err(CommandError::Encrypted) => {},
+            r => panic!("expected encrypted journal to be rejected; got {:?}", r),
+        }
+        Ok(())
+    }
+
+    /// An unsized byte string.
+    #[repr(transparent)]
+    struct Bytes([u8]);
+
+    unsafe impl Pointee for Bytes {
+        type Metadata = Le<u64>;
+        type LayoutError = !;
+
+        fn metadata(this: &Self) -> Le<u64> {
+            Le::new(this.0.len() as u64)
+        }
+
+        fn make_fat_ptr(thin: *const (), len: Le<u64>) -> *const Self {
+            ptr::slice_from_raw_parts(thin as *const u8, len.get() as usize) as *const Self
+        }
+
+        fn make_fat_ptr_mut(thin: *mut (), len: Le<u64>) -> *mut Self {
+            ptr::slice_from_raw_parts_mut(thin as *mut u8, len.get() as usize) as *mut Self
+        }
+    }
+
+    unsafe impl ValidateBlob for Bytes {
+        type BlobError = !;
+
+        fn try_blob_layout(len: Le<u64>) -> Result<BlobLayout, !> {
+            Ok(BlobLayout::new(len.get() as usize))
+        }
+
+        fn validate_blob<'a>(blob: Blob<'a, Self>, _: bool) -> Result<ValidBlob<'a, Self>, !> {
+            unsafe { Ok(blob.assume_valid()) }
+        }
+    }
+
+    impl<Q: PersistPtr> ValidateChildren<Q> for Bytes {
+        fn validate_children<V>(_: ValidBlob<Self>, _: &mut V) -> Result<(), V::Error>
+            where V: PtrValidator<Q>
+        {
+            Ok(())
+        }
+    }
+
+    impl Schema for Bytes {
+        fn describe_schema(hasher: &mut SchemaHasher) {
+            hasher.write_str("bytes");
+        }
+    }
+
+    #[test]
+    fn unsized_blob() -> Result<(), CommandError> {
+        let file = NamedTempFile::new()?;
+        let mut journal = JournalMut::<()>::create_from_fd(file.reopen()?, ())?;
+        let root = journal.write_root(&Le::new(u32::from_le_bytes(*b"abcd")))?;
+
+        let mut registry = Registry::with_builtins();
+        registry.register::<Bytes>("bytes");
+        let path = file.path().to_str().unwrap();
+        let offset = root.get().to_string();
+
+        let mut out = vec![];
+        run_command(&registry, &["blob", path, &offset, "bytes", "3"], &mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("|abc|"));
+        assert!(out.ends_with("valid bytes\n"));
+
+        match run_command(&registry, &["blob", path, &offset, "bytes"], &mut vec![]) {
+            Err(CommandError::Validate(_)) => {},
+            r => panic!("expected missing length to fail; got {:?}", r),
+        }
+        match run_command(&registry, &["validate", path, "bytes"], &mut vec![]) {
+            Err(CommandError::Validate(_)) => {},
+            r => panic!("expected unsized root to fail; got {:?}", r),
+        }
+
+        let mut out = vec![];
+        run_command(&registry, &["types"], &mut out)?;
+        assert!(String::from_utf8(out).unwrap().contains("bytes\t"));
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+use hoard_inspect::Registry;
+
+fn main() {
+    let registry = Registry::with_builtins();
+    std::process::exit(hoard_inspect::run(&registry, std::env::args().skip(1)));
+}
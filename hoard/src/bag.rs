@@ -2,10 +2,11 @@
 
 use std::any::{Any, type_name};
 use std::borrow::{Borrow, BorrowMut};
+use std::cell::RefCell;
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem::{self, ManuallyDrop};
-use std::ptr;
+use std::ptr::{self, NonNull};
 use std::error::Error;
 
 use thiserror::Error;
@@ -18,6 +19,11 @@ use crate::blob::*;
 use crate::load::*;
 use crate::save::*;
 use crate::ptr::*;
+use crate::fingerprint::{Schema, SchemaHasher};
+use crate::validate::{ValidateChildren, PtrValidator};
+use crate::usage::{MeasureUsage, UsageCounter};
+use crate::coerce::TryCoerce;
+use crate::offset::OffsetMut;
 
 /// A `Box` that is generic over the type of `Ptr`.
 ///
@@ -42,7 +48,142 @@ impl<T: ?Sized + Pointee, P: Ptr, M: 'static> Drop for Bag<T, P, M> {
                                              .expect("metadata to be correct type");
 
         // SAFETY: ptr being valid is an invariant we uphold
-        unsafe { self.ptr.dealloc::<T>(*metadata) };
+        unsafe { dealloc::<T, P>(&self.ptr, *metadata) };
+    }
+}
+
+thread_local! {
+    /// Values waiting to be deallocated, if a deallocation is in progress on this thread.
+    static DEALLOC_QUEUE: RefCell<Option<Vec<QueuedDealloc>>> = RefCell::new(None);
+}
+
+/// A value queued for deallocation, with the types of its pointer and metadata erased.
+struct QueuedDealloc {
+    /// A `Box<(P, T::Metadata)>`.
+    data: *mut (),
+    dealloc: unsafe fn(*mut ()),
+}
+
+impl QueuedDealloc {
+    unsafe fn new<T: ?Sized + Pointee, P: Ptr>(ptr: P, metadata: T::Metadata) -> Self {
+        unsafe fn dealloc<T: ?Sized + Pointee, P: Ptr>(data: *mut ()) {
+            let (ptr, metadata) = *Box::from_raw(data as *mut (P, T::Metadata));
+            ptr.dealloc::<T>(metadata)
+        }
+
+        Self {
+            data: Box::into_raw(Box::new((ptr, metadata))) as *mut (),
+            dealloc: dealloc::<T, P>,
+        }
+    }
+}
+
+/// Empties the deallocation queue when the outermost deallocation finishes.
+///
+/// If a deallocation panics, whatever is left in the queue is leaked.
+struct DeallocQueueGuard;
+
+impl Drop for DeallocQueueGuard {
+    fn drop(&mut self) {
+        let _ = DEALLOC_QUEUE.try_with(|queue| queue.borrow_mut().take());
+    }
+}
+
+/// Deallocates the value behind an owning pointer, without recursing through the values it owns.
+///
+/// Deallocating a value drops the `Bag`s within it, which deallocate their own values, and so on:
+/// a long enough chain of `Bag`s would overflow the stack. Instead, deallocations that happen
+/// while another is in progress are queued, and the outermost deallocation works through the
+/// queue.
+///
+/// # Safety
+///
+/// The pointer must own a valid value with the specified metadata, and must not be used again.
+pub(crate) unsafe fn dealloc<T: ?Sized + Pointee, P: Ptr>(ptr: &P, metadata: T::Metadata) {
+    // The queue holds a bitwise copy of the pointer, which is only sound if it has no drop glue.
+    let queued = !mem::needs_drop::<P>() && DEALLOC_QUEUE.try_with(|queue| {
+        match &mut *queue.borrow_mut() {
+            Some(queue) => {
+                queue.push(QueuedDealloc::new::<T, P>(ptr::read(ptr), metadata));
+                true
+            },
+            None => false,
+        }
+    }).unwrap_or(false);
+
+    if !queued {
+        let outermost = DEALLOC_QUEUE.try_with(|queue| {
+            let mut queue = queue.borrow_mut();
+            if queue.is_none() {
+                *queue = Some(vec![]);
+                true
+            } else {
+                false
+            }
+        }).unwrap_or(false);
+
+        let _guard = if outermost { Some(DeallocQueueGuard) } else { None };
+        ptr.dealloc::<T>(metadata);
+
+        if outermost {
+            while let Some(next) = DEALLOC_QUEUE.with(|queue| queue.borrow_mut().as_mut().and_then(Vec::pop)) {
+                (next.dealloc)(next.data);
+            }
+        }
+    }
+}
+
+impl<T: ?Sized + Pointee, P: Ptr> Bag<T, P> {
+    /// Creates a `Bag` from a pointer and metadata.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a valid value with the specified metadata, and ownership of that
+    /// value is transferred to the `Bag`.
+    pub unsafe fn from_raw_parts(ptr: P, metadata: T::Metadata) -> Self {
+        Self {
+            marker: PhantomData,
+            ptr,
+            metadata,
+        }
+    }
+
+    /// Gets the pointer.
+    pub fn ptr(&self) -> &P {
+        &self.ptr
+    }
+
+    /// Gets the metadata.
+    pub fn metadata(&self) -> T::Metadata {
+        self.metadata
+    }
+
+    /// Replaces the pointer, returning the old one.
+    ///
+    /// # Safety
+    ///
+    /// The new pointer must point to a valid value with the same metadata. The value the old
+    /// pointer points to is no longer owned by the `Bag`, so the caller is responsible for
+    /// deallocating it.
+    pub unsafe fn replace_ptr(&mut self, ptr: P) -> P {
+        mem::replace(&mut self.ptr, ptr)
+    }
+
+    /// Gets the value, loading it from a zone if it's clean.
+    pub fn try_get_in<'a, Z>(&'a self, zone: &'a Z) -> Result<Ref<'a, T>, Z::Error>
+        where Z: TryGetPtr<P>,
+              T: LoadPtr<P>,
+    {
+        // SAFETY: ptr being valid is an invariant we uphold
+        unsafe { zone.try_get_ptr_unchecked::<T>(&self.ptr, self.metadata) }
+    }
+}
+
+impl<'p, 'v, T> Bag<T, OffsetMut<'p, 'v>> {
+    /// Moves a value to the heap, creating a dirty `Bag`.
+    pub fn new(value: T) -> Self {
+        // SAFETY: the value was just allocated, and is owned by nothing else
+        unsafe { Self::from_raw_parts(OffsetMut::alloc(value), ()) }
     }
 }
 
@@ -76,6 +217,9 @@ unsafe impl<T: ?Sized + ValidateBlob, P: Ptr> ValidateBlob for Bag<T, P> {
     }
 }
 
+/// A `Bag` whose pointer is `Persist`, such as a `RelOffset`, can be used in place.
+unsafe impl<T: ?Sized + Pointee, P: Ptr + Persist> Persist for Bag<T, P> {}
+
 impl<T: ?Sized + ValidateBlob, P: Ptr> Load for Bag<T, P> {
     type Ptr = P;
 
@@ -92,6 +236,81 @@ impl<T: ?Sized + ValidateBlob, P: Ptr> Load for Bag<T, P> {
         fields.finish();
         r
     }
+
+    /// Dereferences the blob in place if the pointer can be.
+    ///
+    /// Metadata is always `Persist`, so the bytes of the blob are a valid `Bag` exactly when the
+    /// bytes of the pointer are a valid `P`. This is what lets a `Bag<T, RelOffset>` be resolved
+    /// relative to where it is.
+    fn try_deref_blob<'a>(blob: ValidBlob<'a, Self>, zone: &<Self::Ptr as Ptr>::BlobZone)
+        -> Result<&'a Self, ValidBlob<'a, Self>>
+    {
+        let mut fields = blob.valid_fields();
+
+        // SAFETY: validated by Bag::validate_blob()
+        let ptr = unsafe { fields.field_unchecked::<P>() };
+        unsafe { fields.field_unchecked::<T::Metadata>() };
+        let blob = fields.finish();
+
+        match <P as LoadPtr<P>>::try_deref_blob(ptr, zone) {
+            // SAFETY: Bag is #[repr(C)], and both fields can be used in place
+            Ok(_) => Ok(unsafe { &*(blob.as_bytes().as_ptr() as *const Self) }),
+            Err(_) => Err(blob),
+        }
+    }
+}
+
+impl<Q: PersistPtr, T: ?Sized + ValidateChildren<Q>, P: Ptr<Persist = Q> + Persist> ValidateChildren<Q> for Bag<T, P> {
+    fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>
+    {
+        let mut fields = blob.valid_fields();
+
+        // SAFETY: validated by Bag::validate_blob()
+        let ptr = unsafe { fields.field_unchecked::<P>() };
+        let metadata = unsafe { fields.field_unchecked::<T::Metadata>() };
+        let metadata = *metadata.as_value();
+        fields.finish();
+
+        // SAFETY: persisted pointers are always clean
+        match unsafe { ptr.as_value().try_get_dirty_unchecked::<T>(metadata) } {
+            Err(persist_ptr) => validator.validate_ptr::<T>(&persist_ptr, metadata),
+            Ok(_) => unreachable!("dirty pointer in valid blob"),
+        }
+    }
+}
+
+impl<T: ?Sized + ValidateBlob + MeasureUsage, P: Ptr> MeasureUsage for Bag<T, P> {
+    fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>) {
+        // SAFETY: ptr being valid is an invariant we uphold
+        match unsafe { self.ptr.try_get_dirty_unchecked::<T>(self.metadata) } {
+            Ok(value) => counter.dirty(value),
+            Err(_) => counter.clean_ptr::<T>(self.metadata),
+        }
+    }
+}
+
+impl<T: ?Sized + Pointee + Schema, P: Ptr + Schema> Schema for Bag<T, P>
+where T::Metadata: Schema,
+{
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("bag")
+              .field::<P>("ptr")
+              .field::<T::Metadata>("metadata")
+              .field::<T>("value");
+    }
+}
+
+/// A `Bag` can be coerced to a different pointer type if its pointer can be.
+///
+/// For example, a clean `Bag<T, OffsetMut>` can be viewed as a `Bag<T, Offset>`.
+unsafe impl<T: ?Sized + Pointee, P: Ptr + TryCoerce<Q>, Q: Ptr> TryCoerce<Bag<T, Q>> for Bag<T, P> {
+    type Error = P::Error;
+
+    fn try_coerce_ptr(this: &Self) -> Result<*const Bag<T, Q>, P::Error> {
+        P::try_coerce_ptr(&this.ptr)?;
+        Ok(this as *const Self as *const _)
+    }
 }
 
 impl<Q: Ptr, T: ?Sized + Saved<Q>, P: Ptr> Saved<Q> for Bag<T, P> {
@@ -106,10 +325,129 @@ pub struct BagSavePoll<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> {
 
 enum State<QPersist, TSavePoll, PPersist> {
     Clean(PPersist),
-    Dirty(TSavePoll),
+
+    /// Boxed, as the poller of a recursive type contains pollers of itself.
+    Dirty(PollerSlot<TSavePoll>),
     Done(QPersist),
 }
 
+/// A heap-allocated poller, which may be filled in after it's created.
+///
+/// Like a `Box<Option<T>>`, except that moving it doesn't assert unique access to the poller, as
+/// a queued `init_save_poller()` writes to it through a pointer taken before it was moved.
+struct PollerSlot<T>(NonNull<Option<T>>);
+
+unsafe impl<T: Send> Send for PollerSlot<T> {}
+
+impl<T> PollerSlot<T> {
+    fn new(poller: Option<T>) -> Self {
+        // SAFETY: Box::into_raw() never returns null
+        Self(unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(poller))) })
+    }
+
+    fn get_mut(&mut self) -> Option<&mut T> {
+        // SAFETY: we own the allocation
+        unsafe { (*self.0.as_ptr()).as_mut() }
+    }
+}
+
+impl<T> Drop for PollerSlot<T> {
+    fn drop(&mut self) {
+        // SAFETY: allocated by PollerSlot::new(), and only freed here
+        unsafe { drop(Box::from_raw(self.0.as_ptr())) }
+    }
+}
+
+thread_local! {
+    /// Pollers waiting to be created, if a `Bag::init_save()` is in progress on this thread.
+    static INIT_SAVE_QUEUE: RefCell<Option<Vec<QueuedInitSave>>> = RefCell::new(None);
+}
+
+/// A poller queued to be created, with its types erased.
+struct QueuedInitSave {
+    /// A `Box<(*const T, NonNull<Option<T::SavePtrPoll>>)>`.
+    data: *mut (),
+    init: unsafe fn(*mut ()),
+}
+
+impl QueuedInitSave {
+    unsafe fn new<P: Ptr, Q: Ptr, T>(value: &T, slot: &PollerSlot<T::SavePtrPoll>) -> Self
+        where T: ?Sized + SavePtr<P, Q>
+    {
+        unsafe fn init<P: Ptr, Q: Ptr, T>(data: *mut ())
+            where T: ?Sized + SavePtr<P, Q>
+        {
+            let (value, slot) = *Box::from_raw(data as *mut (*const T, NonNull<Option<T::SavePtrPoll>>));
+            *slot.as_ptr() = Some((*value).init_save_ptr());
+        }
+
+        Self {
+            data: Box::into_raw(Box::new((value as *const T, slot.0))) as *mut (),
+            init: init::<P, Q, T>,
+        }
+    }
+}
+
+/// Empties the poller queue when the outermost `init_save_poller()` finishes.
+///
+/// If creating a poller panics, whatever is left in the queue is leaked.
+struct InitSaveQueueGuard;
+
+impl Drop for InitSaveQueueGuard {
+    fn drop(&mut self) {
+        let _ = INIT_SAVE_QUEUE.try_with(|queue| queue.borrow_mut().take());
+    }
+}
+
+/// Creates the poller of a dirty value, without recursing through the values it owns.
+///
+/// Creating a value's poller creates the pollers of the `Bag`s within it, and so on: a long enough
+/// chain of `Bag`s would overflow the stack. Instead, pollers created while another is being
+/// created are queued, and the outermost call creates them before it returns. The values they're
+/// created from are all within the value the outermost call was given, so they outlive the queue,
+/// and the poller returned is complete.
+fn init_save_poller<P: Ptr, Q: Ptr, T>(value: &T) -> PollerSlot<T::SavePtrPoll>
+    where T: ?Sized + SavePtr<P, Q>
+{
+    let slot = PollerSlot::new(None);
+
+    let queued = INIT_SAVE_QUEUE.try_with(|queue| {
+        match &mut *queue.borrow_mut() {
+            Some(queue) => {
+                // SAFETY: the outermost call runs the job before returning, while both the value
+                // and the poller being created, which owns the slot, are still alive
+                queue.push(unsafe { QueuedInitSave::new::<P, Q, T>(value, &slot) });
+                true
+            },
+            None => false,
+        }
+    }).unwrap_or(false);
+
+    if !queued {
+        let outermost = INIT_SAVE_QUEUE.try_with(|queue| {
+            let mut queue = queue.borrow_mut();
+            if queue.is_none() {
+                *queue = Some(vec![]);
+                true
+            } else {
+                false
+            }
+        }).unwrap_or(false);
+
+        let _guard = if outermost { Some(InitSaveQueueGuard) } else { None };
+
+        // SAFETY: nothing else refers to the slot yet
+        unsafe { *slot.0.as_ptr() = Some(value.init_save_ptr()) };
+
+        if outermost {
+            while let Some(next) = INIT_SAVE_QUEUE.with(|queue| queue.borrow_mut().as_mut().and_then(Vec::pop)) {
+                // SAFETY: see above
+                unsafe { (next.init)(next.data) };
+            }
+        }
+    }
+    slot
+}
 
 impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> Save<Q> for Bag<T, P>
 {
@@ -121,7 +459,7 @@ impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> Save<Q> for Bag<T, P>
 
             // SAFETY: self.ptr being valid is an invariant we uphold
             state: match unsafe { self.ptr.try_get_dirty_unchecked::<T>(self.metadata) } {
-                       Ok(value) => State::Dirty(value.init_save_ptr()),
+                       Ok(value) => State::Dirty(init_save_poller::<P, Q, T>(value)),
                        Err(persist_ptr) => State::Clean(persist_ptr),
                    },
         }
@@ -132,15 +470,18 @@ impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> EncodeBlob for BagSavePoll<Q, T,
     type Target = Bag<T::Saved, Q>;
 
     fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
-        if let State::Done(q_persist) = self.state {
-            todo!()
+        if let State::Done(q_persist) = &self.state {
+            dst.write_scalar(q_persist)?
+               .write_scalar(&self.metadata)?
+               .finish()
         } else {
             panic!("polling incomplete")
         }
     }
 }
 
-impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> SavePoll for BagSavePoll<Q, T, P> {
+// SAFETY: the value's poller is boxed, and only dropped once it's done
+unsafe impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> SavePoll for BagSavePoll<Q, T, P> {
     type SrcPtr = P;
     type DstPtr = Q;
 
@@ -148,22 +489,33 @@ impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> SavePoll for BagSavePoll<Q, T, P
         where S: Saver<SrcPtr = Self::SrcPtr, DstPtr = Self::DstPtr>,
     {
         loop {
-            self.state = match &mut self.state {
+            match &mut self.state {
                 State::Clean(persist_ptr) => {
-                    match saver.try_save::<T>(persist_ptr, self.metadata)? {
+                    self.state = match saver.try_save::<T>(persist_ptr, self.metadata)? {
                         Ok(dst_persist) => State::Done(dst_persist),
-                        Err(value_poller) => State::Dirty(value_poller),
-                    }
-                },
-                State::Dirty(value_poller) => {
-                    value_poller.save_poll(saver)?;
-
-                    let persist_ptr = saver.finish_save(value_poller)?;
-                    State::Done(persist_ptr)
+                        Err(value_poller) => State::Dirty(PollerSlot::new(Some(value_poller))),
+                    };
                 },
+                // SAFETY: SavePoll's contract keeps us in place until we're done
+                State::Dirty(_) => unsafe { saver.save_dirty(self)? },
                 State::Done(_) => break Ok(()),
-            };
+            }
+        }
+    }
+}
+
+impl<S, Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> PollDirty<S> for BagSavePoll<Q, T, P>
+    where S: Saver<SrcPtr = P, DstPtr = Q>
+{
+    fn poll_dirty(&mut self, saver: &mut S) -> Result<(), S::Error> {
+        if let State::Dirty(value_poller) = &mut self.state {
+            let value_poller = value_poller.get_mut().expect("poller to be created");
+            value_poller.save_poll(saver)?;
+
+            let persist_ptr = saver.finish_save(&*value_poller)?;
+            self.state = State::Done(persist_ptr);
         }
+        Ok(())
     }
 }
 
@@ -9,6 +9,7 @@ use std::fmt;
 use std::slice;
 
 use owned::IntoOwned;
+use thiserror::Error;
 
 use crate::pointee::Pointee;
 use crate::ptr::*;
@@ -22,6 +23,12 @@ pub mod layout;
 pub use self::layout::BlobLayout;
 
 /// Blob validation for `?Sized` types.
+///
+/// Validation is *strict* unless `ignore_padding` is set: padding must be zero, enum-like tags
+/// must be canonical, and bytes that are unused by a niche-optimized value must be zero. Thus for
+/// every blob that passes strict validation, decoding and then re-encoding the value will
+/// reproduce the original bytes exactly, a property content-addressed and consensus-critical
+/// uses rely on.
 pub unsafe trait ValidateBlob : Pointee {
     /// Error returned when a load fails (for whatever reason).
     type BlobError : std::error::Error + 'static + Send + Sync;
@@ -157,6 +164,13 @@ impl<'a, T: ?Sized + Pointee> ValidBlob<'a, T> {
             zone,
         }
     }
+
+    pub fn valid_fields(self) -> ValidFields<'a, T> {
+        ValidFields {
+            blob: self,
+            idx: 0,
+        }
+    }
 }
 
 /// `Blob` field validator.
@@ -176,7 +190,19 @@ impl<'a, T: ?Sized + ValidateBlob> fmt::Debug for ValidateFields<'a, T> {
     }
 }
 
+/// Returned when padding bytes are non-zero in strict mode.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("non-zero padding")]
+#[non_exhaustive]
+pub struct PaddingError;
+
 impl<'a, T: ?Sized + ValidateBlob> ValidateFields<'a, T> {
+    /// Returns true if padding is being ignored.
+    #[inline(always)]
+    pub fn ignore_padding(&self) -> bool {
+        self.ignore_padding
+    }
+
     #[inline(always)]
     pub fn validate_blob<F: ValidateBlob>(&mut self) -> Result<ValidBlob<'a, F>, F::BlobError> {
         F::validate_blob(self.field_blob::<F>(), self.ignore_padding)
@@ -203,6 +229,17 @@ impl<'a, T: ?Sized + ValidateBlob> ValidateFields<'a, T> {
         r
     }
 
+    /// Validates padding bytes, which must be zero unless padding is being ignored.
+    #[inline(always)]
+    pub fn validate_padding(&mut self, size: usize) -> Result<(), PaddingError> {
+        let padding = self.field_bytes(size);
+        if self.ignore_padding || padding.iter().all(|b| *b == 0) {
+            Ok(())
+        } else {
+            Err(PaddingError)
+        }
+    }
+
     #[inline(always)]
     pub unsafe fn finish(self) -> ValidBlob<'a, T> {
         assert_eq!(self.idx, self.blob.as_bytes().len());
@@ -264,3 +301,52 @@ impl<'a, 'z, P: Ptr, T: ?Sized + ValidateBlob> DecodeFields<'a, 'z, T, P> {
         self.blob
     }
 }
+
+/// `ValidBlob` field cursor, for fields that don't need to be decoded.
+pub struct ValidFields<'a, T: ?Sized + Pointee> {
+    blob: ValidBlob<'a, T>,
+    idx: usize,
+}
+
+impl<'a, T: ?Sized + ValidateBlob> fmt::Debug for ValidFields<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(type_name::<Self>())
+            .field("blob", &self.blob)
+            .field("idx", &self.idx)
+            .finish()
+    }
+}
+
+impl<'a, T: ?Sized + ValidateBlob> ValidFields<'a, T> {
+    /// Gets the next field, assuming that it's valid.
+    ///
+    /// # Safety
+    ///
+    /// `F` must be the type of the next field, as validated by `T::validate_blob()`.
+    #[inline(always)]
+    pub unsafe fn field_unchecked<F: ValidateBlob>(&mut self) -> ValidBlob<'a, F> {
+        Blob {
+            marker: PhantomData,
+            ptr: self.field_bytes(F::blob_layout().size()).as_ptr(),
+            metadata: F::make_sized_metadata(),
+        }.assume_valid()
+    }
+
+    #[inline(always)]
+    pub fn field_bytes(&mut self, size: usize) -> &'a [u8] {
+        let blob_bytes = self.blob.as_bytes();
+        let new_idx = self.idx + size;
+
+        assert!(new_idx <= blob_bytes.len(), "out of range");
+
+        let r = &blob_bytes[self.idx .. new_idx];
+        self.idx = new_idx;
+        r
+    }
+
+    #[inline(always)]
+    pub fn finish(self) -> ValidBlob<'a, T> {
+        assert_eq!(self.idx, self.blob.as_bytes().len());
+        self.blob
+    }
+}
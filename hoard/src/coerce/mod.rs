@@ -2,9 +2,12 @@
 
 use static_assertions::assert_impl_all;
 
+use leint::Le;
+
 use core::alloc::Layout;
 use core::any::type_name;
-use core::mem::ManuallyDrop;
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop};
 
 mod array;
 pub use self::array::*;
@@ -31,7 +34,8 @@ pub unsafe trait TryCoerce<T> : Sized {
 
         unsafe {
             let r = r_ptr.read();
-            drop(self);
+            // ownership was moved into r
+            mem::forget(self);
             Ok(r)
         }
     }
@@ -76,12 +80,49 @@ where T::Error: Into<!>
 
         unsafe {
             let r = r_ptr.read();
-            drop(self);
+            // ownership was moved into r
+            mem::forget(self);
             r
         }
     }
 }
 
+/// Error returned by `TryCoerce` implementations generated by `#[derive(Coerce)]`.
+///
+/// Each field gets one level of nesting: the error for the first field is `Field`, the error for
+/// the second is `Rest(Field)`, and so on. Implements `Into<!>` when every field's error does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TryCoerceFieldsError<E, Rest> {
+    Field {
+        name: &'static str,
+        err: E,
+    },
+    Rest(Rest),
+}
+
+impl<E: Into<!>, Rest: Into<!>> From<TryCoerceFieldsError<E, Rest>> for ! {
+    fn from(err: TryCoerceFieldsError<E, Rest>) -> ! {
+        match err {
+            TryCoerceFieldsError::Field { err, .. } => err.into(),
+            TryCoerceFieldsError::Rest(rest) => rest.into(),
+        }
+    }
+}
+
+/// Compile-time layout check used by `#[derive(Coerce)]`.
+///
+/// Referencing `SAME_LAYOUT` fails to compile unless `T` and `U` have the same size and alignment.
+/// As the check is an associated constant it also works for generic types, failing when an impl
+/// is instantiated with parameters that change the layout.
+#[doc(hidden)]
+pub struct AssertSameLayout<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> AssertSameLayout<T, U> {
+    pub const SAME_LAYOUT: () = assert!(mem::size_of::<T>() == mem::size_of::<U>()
+                                        && mem::align_of::<T>() == mem::align_of::<U>(),
+                                        "can-not implement TryCoerce: layouts differ");
+}
+
 macro_rules! unsafe_impl_coerce {
     () => {};
     ($t:ty => $u:ty) => {
@@ -105,7 +146,18 @@ macro_rules! unsafe_impl_coerce {
 unsafe_impl_coerce! {
     () => ();
     bool => {bool, u8};
+    u8 => u8;
+    i8 => i8;
+    Le<u16> => Le<u16>;
+    Le<u32> => Le<u32>;
+    Le<u64> => Le<u64>;
+    Le<u128> => Le<u128>;
+    Le<i16> => Le<i16>;
+    Le<i32> => Le<i32>;
+    Le<i64> => Le<i64>;
+    Le<i128> => Le<i128>;
 }
 
 assert_impl_all!(!: TryCoerce<!>, Coerce<!>);
 assert_impl_all!(bool: Coerce<bool>, Coerce<u8>);
+assert_impl_all!(Le<u64>: Coerce<Le<u64>>);
@@ -0,0 +1,678 @@
+//! Transparent compression of values behind pointers.
+//!
+//! Blobs are normally stored verbatim, so they can be used directly from a memory-mapped zone.
+//! That's the right trade-off for the hot parts of a data structure, but a waste of space for
+//! large, rarely accessed leaves. A `Compressed<T, P>` is a pointer to a `T` that is saved LZ4
+//! compressed; loading it decompresses and decodes a fresh copy, returned as `Ref::Owned`.
+//!
+//! Dirty values are kept uncompressed on the heap, exactly like `Bag`. Only saving compresses.
+//! Unsized values are supported too: like a `Bag`, a `Compressed` stores the metadata of its
+//! value, which determines the size the blob has to decompress to.
+
+use std::any::Any;
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ptr;
+
+use thiserror::Error;
+
+use leint::Le;
+
+use crate::pointee::Pointee;
+use crate::refs::Ref;
+use crate::blob::*;
+use crate::load::*;
+use crate::save::*;
+use crate::ptr::*;
+use crate::fingerprint::{Schema, SchemaHasher};
+use crate::validate::{ValidateChildren, PtrValidator, ValidateLimits, LimitError};
+use crate::usage::{MeasureUsage, UsageCounter};
+use crate::offset::{Offset, OffsetMut};
+use crate::pile::{TryPile, GetBlobError};
+
+/// A pointer to a value that is compressed when saved.
+///
+/// The metadata is a type parameter for the same reason as in `Bag`: to keep `Compressed`
+/// covariant over `T`.
+#[repr(C)]
+pub struct Compressed<T: ?Sized + Pointee, P: Ptr, M: 'static = <T as Pointee>::Metadata> {
+    marker: PhantomData<T>,
+    ptr: P,
+    metadata: M,
+
+    /// The length of the compressed blob; meaningless while dirty.
+    len: Le<u64>,
+}
+
+/// The compressed bytes a clean `Compressed` points to.
+#[repr(transparent)]
+pub struct CompressedBytes([u8]);
+
+impl CompressedBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+unsafe impl Pointee for CompressedBytes {
+    type Metadata = Le<u64>;
+    type LayoutError = !;
+
+    fn metadata(this: &Self) -> Le<u64> {
+        Le::new(this.0.len() as u64)
+    }
+
+    #[inline(always)]
+    fn make_fat_ptr(thin: *const (), len: Le<u64>) -> *const Self {
+        ptr::slice_from_raw_parts(thin as *const u8, len.get() as usize) as *const Self
+    }
+
+    #[inline(always)]
+    fn make_fat_ptr_mut(thin: *mut (), len: Le<u64>) -> *mut Self {
+        ptr::slice_from_raw_parts_mut(thin as *mut u8, len.get() as usize) as *mut Self
+    }
+}
+
+/// Any bytes are valid compressed bytes; corruption is detected when they're decompressed.
+unsafe impl ValidateBlob for CompressedBytes {
+    type BlobError = !;
+
+    fn try_blob_layout(len: Le<u64>) -> Result<BlobLayout, !> {
+        Ok(BlobLayout::new(len.get() as usize))
+    }
+
+    fn validate_blob<'a>(blob: Blob<'a, Self>, _: bool) -> Result<ValidBlob<'a, Self>, !> {
+        unsafe { Ok(blob.assume_valid()) }
+    }
+}
+
+/// The compressed value itself is opaque, so its children aren't validated.
+impl<Q: PersistPtr> ValidateChildren<Q> for CompressedBytes {
+    fn validate_children<V>(_: ValidBlob<Self>, _: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>
+    {
+        Ok(())
+    }
+}
+
+/// Writes compressed bytes as a blob.
+struct EncodeCompressedBytes<'a>(&'a [u8]);
+
+impl EncodeBlob for EncodeCompressedBytes<'_> {
+    type Target = CompressedBytes;
+
+    fn target_metadata(&self) -> Le<u64> {
+        Le::new(self.0.len() as u64)
+    }
+
+    fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+        dst.write_bytes(self.0)?
+           .finish()
+    }
+}
+
+/// Returned when a compressed value can't be loaded.
+#[derive(Debug, Error)]
+pub enum DecompressError<E: Error> {
+    #[error("compressed blob at offset {offset} can't be read: {err:?}")]
+    Blob {
+        offset: usize,
+        err: GetBlobError<!>,
+    },
+
+    #[error("compressed blob at offset {offset} has invalid metadata: {err}")]
+    Layout {
+        offset: usize,
+        err: Box<dyn Error + Send + Sync>,
+    },
+
+    #[error("compressed blob at offset {offset} is too large: {err}")]
+    Limit {
+        offset: usize,
+        err: LimitError,
+    },
+
+    #[error("compressed blob at offset {offset} is corrupt: {err}")]
+    Corrupt {
+        offset: usize,
+        err: lz4_flex::block::DecompressError,
+    },
+
+    #[error("compressed blob at offset {offset} decompressed to {found} bytes; expected {expected}")]
+    Size {
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+
+    #[error("decompressed value at offset {offset} is invalid: {err}")]
+    Validate {
+        offset: usize,
+        err: E,
+    },
+}
+
+/// The most an LZ4 block can expand by when decompressed.
+const MAX_RATIO: usize = 255;
+
+fn compress(bytes: &[u8]) -> Vec<u8> {
+    lz4_flex::block::compress(bytes)
+}
+
+/// Decompresses and decodes a value.
+///
+/// The decompressed size comes from untrusted metadata, and the buffer is allocated up front, so
+/// it's checked against the limits and against the size `bytes` could possibly decompress to.
+fn decompress<T, P>(offset: usize, bytes: &[u8], metadata: T::Metadata, zone: &P::BlobZone, limits: ValidateLimits)
+    -> Result<T::Owned, DecompressError<T::BlobError>>
+    where T: ?Sized + LoadPtr<P>,
+          P: Ptr,
+{
+    let expected = T::try_blob_layout(metadata)
+                     .map_err(|err| DecompressError::Layout { offset, err: Box::new(err) })?
+                     .size();
+    limits.check_blob_size(expected)
+          .map_err(|err| DecompressError::Limit { offset, err })?;
+
+    let max = bytes.len().saturating_mul(MAX_RATIO);
+    if expected > max {
+        return Err(DecompressError::Limit { offset, err: LimitError::BlobSize { size: expected, limit: max } });
+    }
+
+    let buf = lz4_flex::block::decompress(bytes, expected)
+                  .map_err(|err| DecompressError::Corrupt { offset, err })?;
+
+    if buf.len() != expected {
+        return Err(DecompressError::Size { offset, expected, found: buf.len() });
+    }
+
+    // SAFETY: the buffer is exactly the size of a T with this metadata
+    let blob = unsafe { Blob::<T>::new_unchecked(&buf, metadata) };
+    let blob = T::validate_blob(blob, false)
+                 .map_err(|err| DecompressError::Validate { offset, err })?;
+
+    Ok(T::decode_blob(blob, zone))
+}
+
+impl<T: ?Sized + Pointee, P: Ptr, M: 'static> Drop for Compressed<T, P, M> {
+    fn drop(&mut self) {
+        // As with Bag, M is always T::Metadata; Drop just can't be implemented for that alone.
+        let metadata: &dyn Any = &self.metadata;
+        let metadata: &T::Metadata = metadata.downcast_ref()
+                                             .expect("metadata to be correct type");
+
+        // SAFETY: ptr being valid is an invariant we uphold
+        unsafe { crate::bag::dealloc::<T, P>(&self.ptr, *metadata) };
+    }
+}
+
+impl<T: ?Sized + Pointee, P: Ptr> Compressed<T, P> {
+    /// Creates a `Compressed` from a pointer, metadata, and the length of the compressed blob.
+    ///
+    /// # Safety
+    ///
+    /// If the pointer is dirty, it must point to a valid value with the specified metadata. If
+    /// it's clean, it must point to a compressed blob `len` bytes long. Ownership of the value is
+    /// transferred to the `Compressed`.
+    pub unsafe fn from_raw_parts(ptr: P, metadata: T::Metadata, len: u64) -> Self {
+        Self {
+            marker: PhantomData,
+            ptr,
+            metadata,
+            len: Le::new(len),
+        }
+    }
+
+    /// Gets the pointer.
+    pub fn ptr(&self) -> &P {
+        &self.ptr
+    }
+
+    /// Gets the metadata.
+    pub fn metadata(&self) -> T::Metadata {
+        self.metadata
+    }
+
+    /// Gets the length of the compressed blob, or `None` if the value is dirty.
+    pub fn compressed_len(&self) -> Option<u64> {
+        // SAFETY: ptr being valid is an invariant we uphold
+        match unsafe { self.ptr.try_get_dirty_unchecked::<T>(self.metadata) } {
+            Ok(_) => None,
+            Err(_) => Some(self.len.get()),
+        }
+    }
+}
+
+impl<'p, 'v, T> Compressed<T, OffsetMut<'p, 'v>> {
+    /// Moves a value to the heap, creating a dirty `Compressed`.
+    pub fn new(value: T) -> Self {
+        // SAFETY: the value was just allocated, and is owned by nothing else
+        unsafe { Self::from_raw_parts(OffsetMut::alloc(value), (), 0) }
+    }
+}
+
+impl<'p, 'v, T, P> Compressed<T, P>
+where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = TryPile<'p, 'v>>,
+      T: ?Sized + LoadPtr<P>,
+{
+    /// Gets the value, decompressing it if it's clean.
+    pub fn try_get<'a>(&'a self, pile: &TryPile<'p, 'v>) -> Result<Ref<'a, T>, DecompressError<T::BlobError>> {
+        // SAFETY: ptr being valid is an invariant we uphold
+        match unsafe { self.ptr.try_get_dirty_unchecked::<T>(self.metadata) } {
+            Ok(value) => Ok(Ref::Ref(value)),
+            Err(offset) => {
+                let blob = pile.get_blob::<CompressedBytes>(offset, self.len)
+                               .map_err(|err| DecompressError::Blob { offset: offset.get(), err })?;
+
+                decompress::<T, P>(offset.get(), blob.as_bytes(), self.metadata, pile, pile.limits())
+                    .map(Ref::Owned)
+            },
+        }
+    }
+}
+
+impl<T: ?Sized + Pointee + fmt::Debug, P: Ptr + fmt::Debug> fmt::Debug for Compressed<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Compressed")
+            .field("ptr", &self.ptr)
+            .field("metadata", &self.metadata)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Error returned when validation of a `Blob<Compressed>` fails.
+#[derive(Debug, Error)]
+pub enum ValidateCompressedBlobError<PtrError: Error, MetadataError: Error, LayoutError: Error> {
+    #[error("invalid compressed pointer: {0}")]
+    Ptr(PtrError),
+
+    #[error("invalid compressed value metadata: {0}")]
+    Metadata(MetadataError),
+
+    #[error("compressed value metadata has no valid layout: {0}")]
+    Layout(LayoutError),
+}
+
+unsafe impl<T: ?Sized + ValidateBlob, P: Ptr> ValidateBlob for Compressed<T, P> {
+    type BlobError = ValidateCompressedBlobError<<P as ValidateBlob>::BlobError, <T::Metadata as ValidateBlob>::BlobError, T::LayoutError>;
+
+    fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+        Ok(P::blob_layout().extend(T::Metadata::blob_layout())
+                           .extend(<Le<u64>>::blob_layout()))
+    }
+
+    fn validate_blob<'a>(blob: Blob<'a, Self>, ignore_padding: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
+        let mut fields = blob.validate_fields(ignore_padding);
+
+        fields.validate_blob::<P>().map_err(ValidateCompressedBlobError::Ptr)?;
+        let metadata_blob = fields.validate_blob::<T::Metadata>().map_err(ValidateCompressedBlobError::Metadata)?;
+        let metadata = metadata_blob.as_value().clone();
+        fields.validate_blob::<Le<u64>>().into_ok();
+
+        T::try_blob_layout(metadata).map_err(ValidateCompressedBlobError::Layout)?;
+
+        unsafe { Ok(fields.finish()) }
+    }
+}
+
+impl<T: ?Sized + ValidateBlob, P: Ptr> Load for Compressed<T, P> {
+    type Ptr = P;
+
+    fn decode_blob(blob: ValidBlob<Self>, zone: &<Self::Ptr as Ptr>::BlobZone) -> Self {
+        let mut fields = blob.decode_fields(zone);
+
+        let r = unsafe {
+            Self {
+                marker: PhantomData,
+                ptr: fields.decode_unchecked(),
+                metadata: fields.decode_unchecked(),
+                len: fields.decode_unchecked(),
+            }
+        };
+        fields.finish();
+        r
+    }
+}
+
+/// Only the compressed bytes are checked: the value within, and anything it points to, is opaque
+/// until it's decompressed. Corruption within it is reported by `try_get()`, not by fsck.
+impl<Q: PersistPtr, T: ?Sized + ValidateBlob, P: Ptr<Persist = Q> + Persist> ValidateChildren<Q> for Compressed<T, P> {
+    fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>
+    {
+        let mut fields = blob.valid_fields();
+
+        // SAFETY: validated by Compressed::validate_blob()
+        let ptr = unsafe { fields.field_unchecked::<P>() };
+        let metadata = unsafe { fields.field_unchecked::<T::Metadata>() };
+        let metadata = *metadata.as_value();
+        let len = unsafe { fields.field_unchecked::<Le<u64>>() };
+        let len = *len.as_value();
+        fields.finish();
+
+        // SAFETY: persisted pointers are always clean
+        match unsafe { ptr.as_value().try_get_dirty_unchecked::<T>(metadata) } {
+            Err(persist_ptr) => validator.validate_ptr::<CompressedBytes>(&persist_ptr, len),
+            Ok(_) => unreachable!("dirty pointer in valid blob"),
+        }
+    }
+}
+
+impl<T: ?Sized + Pointee + MeasureUsage, P: Ptr> MeasureUsage for Compressed<T, P> {
+    fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>) {
+        // SAFETY: ptr being valid is an invariant we uphold
+        match unsafe { self.ptr.try_get_dirty_unchecked::<T>(self.metadata) } {
+            Ok(value) => counter.dirty(value),
+            Err(_) => counter.clean(self.len.get() as usize),
+        }
+    }
+}
+
+impl<T: ?Sized + Pointee + Schema, P: Ptr + Schema> Schema for Compressed<T, P>
+where T::Metadata: Schema,
+{
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("compressed")
+              .field::<P>("ptr")
+              .field::<T::Metadata>("metadata")
+              .field::<Le<u64>>("len")
+              .field::<T>("value");
+    }
+}
+
+impl<Q: Ptr, T: ?Sized + Saved<Q>, P: Ptr> Saved<Q> for Compressed<T, P> {
+    type Saved = Compressed<T::Saved, Q>;
+}
+
+/// The poller used to save a `Compressed`.
+pub struct CompressedSavePoll<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> {
+    metadata: T::Metadata,
+    len: Le<u64>,
+    state: State<Q::Persist, T::SavePtrPoll, P::Persist>,
+}
+
+enum State<QPersist, TSavePoll, PPersist> {
+    Clean(PPersist),
+    Dirty(TSavePoll),
+
+    /// Compressed, but not yet saved.
+    Compressed(Vec<u8>),
+    Done(QPersist),
+}
+
+impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> Save<Q> for Compressed<T, P> {
+    type SavePoll = CompressedSavePoll<Q, T, P>;
+
+    fn init_save(&self) -> Self::SavePoll {
+        CompressedSavePoll {
+            metadata: self.metadata,
+            len: self.len,
+
+            // SAFETY: self.ptr being valid is an invariant we uphold
+            state: match unsafe { self.ptr.try_get_dirty_unchecked::<T>(self.metadata) } {
+                       Ok(value) => State::Dirty(value.init_save_ptr()),
+                       Err(persist_ptr) => State::Clean(persist_ptr),
+                   },
+        }
+    }
+}
+
+impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> EncodeBlob for CompressedSavePoll<Q, T, P> {
+    type Target = Compressed<T::Saved, Q>;
+
+    fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+        if let State::Done(q_persist) = &self.state {
+            dst.write_scalar(q_persist)?
+               .write_scalar(&self.metadata)?
+               .write_scalar(&self.len)?
+               .finish()
+        } else {
+            panic!("polling incomplete")
+        }
+    }
+}
+
+// SAFETY: the value's poller is only dropped once it's done
+unsafe impl<Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> SavePoll for CompressedSavePoll<Q, T, P> {
+    type SrcPtr = P;
+    type DstPtr = Q;
+
+    fn save_poll<S>(&mut self, saver: &mut S) -> Result<(), S::Error>
+        where S: Saver<SrcPtr = Self::SrcPtr, DstPtr = Self::DstPtr>,
+    {
+        loop {
+            match &mut self.state {
+                State::Clean(persist_ptr) => {
+                    // Saving to another zone means the value has to be decompressed, so that its
+                    // children are saved too. If decompression fails the bytes are copied as-is,
+                    // leaving the error to be reported when the copy is loaded.
+                    //
+                    // The decompressed value is dropped as soon as its poller is created. That's
+                    // fine, as pollers don't refer back to the values they're created from.
+                    let metadata = self.metadata;
+                    let r = saver.try_save_raw::<_, CompressedBytes>(persist_ptr, self.len, |blob, zone| {
+                        let bytes = blob.as_bytes();
+                        match decompress::<T, P>(0, bytes, metadata, zone, ValidateLimits::UNLIMITED) {
+                            Ok(value) => State::Dirty(Borrow::<T>::borrow(&value).init_save_ptr()),
+                            Err(_) => State::Compressed(bytes.to_vec()),
+                        }
+                    })?;
+                    self.state = match r {
+                        Ok(dst_persist) => State::Done(dst_persist),
+                        Err(state) => state,
+                    };
+                },
+                // SAFETY: SavePoll's contract keeps us in place until we're done
+                State::Dirty(_) => unsafe { saver.save_dirty(self)? },
+                State::Compressed(bytes) => {
+                    let persist_ptr = saver.finish_save(&EncodeCompressedBytes(bytes))?;
+                    self.state = State::Done(persist_ptr);
+                },
+                State::Done(_) => break Ok(()),
+            }
+        }
+    }
+}
+
+impl<S, Q: Ptr, T: ?Sized + SavePtr<P, Q>, P: Ptr> PollDirty<S> for CompressedSavePoll<Q, T, P>
+    where S: Saver<SrcPtr = P, DstPtr = Q>
+{
+    fn poll_dirty(&mut self, saver: &mut S) -> Result<(), S::Error> {
+        if let State::Dirty(value_poller) = &mut self.state {
+            value_poller.save_poll(saver)?;
+
+            let bytes = compress(&value_poller.encode_blob(vec![]).into_ok());
+            self.len = Le::new(bytes.len() as u64);
+            self.state = State::Compressed(bytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::io;
+    use std::mem::ManuallyDrop;
+
+    use owned::IntoOwned;
+    use tempfile::tempfile;
+
+    use crate::journal::JournalMut;
+
+    #[test]
+    fn compressed_roundtrip() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+
+        let value = Compressed::new([7u8; 1024]);
+        assert_eq!(value.compressed_len(), None);
+        assert_eq!(&value.try_get(&TryPile::default()).unwrap()[..], &[7u8; 1024][..]);
+
+        journal.write_root(&value)?;
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        let pile = commit.pile();
+        let root = commit.try_root::<Compressed<[u8; 1024], Offset>>().unwrap();
+        let root = <Compressed<[u8; 1024], Offset> as Load>::decode_blob(root, &pile);
+
+        let len = root.compressed_len().unwrap();
+        assert!(len < 100, "{} bytes", len);
+
+        match root.try_get(&pile).unwrap() {
+            Ref::Owned(loaded) => assert_eq!(&loaded[..], &[7u8; 1024][..]),
+            r @ Ref::Ref(_) => panic!("{:?}", r),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_errors() {
+        let mut buf = compress(&[1u8; 64]);
+        let len = buf.len() as u64;
+        buf.extend_from_slice(&[0xff; 8]);
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let ok = unsafe { Compressed::<[u8; 64], Offset>::from_raw_parts(Offset::new(0).unwrap(), (), len) };
+        assert_eq!(&ok.try_get(&pile).unwrap()[..], &[1u8; 64][..]);
+
+        let wrong_size = unsafe { Compressed::<[u8; 128], Offset>::from_raw_parts(Offset::new(0).unwrap(), (), len) };
+        match wrong_size.try_get(&pile) {
+            Err(DecompressError::Size { offset: 0, expected: 128, found: 64 }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let garbage = unsafe { Compressed::<[u8; 64], Offset>::from_raw_parts(Offset::new(len as usize).unwrap(), (), 8) };
+        match garbage.try_get(&pile) {
+            Err(DecompressError::Corrupt { .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let out_of_range = unsafe { Compressed::<[u8; 64], Offset>::from_raw_parts(Offset::new(0).unwrap(), (), 1000) };
+        match out_of_range.try_get(&pile) {
+            Err(DecompressError::Blob { offset: 0, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn decompress_limits() {
+        let mut buf = compress(b"hello world");
+        let len = buf.len() as u64;
+        buf.extend_from_slice(&[0; 8]);
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        // metadata claiming more than the compressed bytes could ever expand to is rejected before
+        // anything is allocated
+        let bomb = unsafe { Compressed::<Bytes, Offset>::from_raw_parts(Offset::new(0).unwrap(), Le::new(u64::MAX), len) };
+        match bomb.try_get(&pile) {
+            Err(DecompressError::Limit { offset: 0, err: LimitError::BlobSize { limit, .. } }) => {
+                assert_eq!(limit, len as usize * MAX_RATIO)
+            },
+            r => panic!("{:?}", r),
+        }
+
+        // as is anything over the pile's limits
+        let pile = pile.with_limits(ValidateLimits { max_blob_size: 10, ..ValidateLimits::UNLIMITED });
+        let ok = unsafe { Compressed::<Bytes, Offset>::from_raw_parts(Offset::new(0).unwrap(), Le::new(11), len) };
+        match ok.try_get(&pile) {
+            Err(DecompressError::Limit { offset: 0, err: LimitError::BlobSize { size: 11, limit: 10 } }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    /// An unsized byte string.
+    #[repr(transparent)]
+    #[derive(Debug)]
+    struct Bytes([u8]);
+
+    unsafe impl Pointee for Bytes {
+        type Metadata = Le<u64>;
+        type LayoutError = !;
+
+        fn metadata(this: &Self) -> Le<u64> {
+            Le::new(this.0.len() as u64)
+        }
+
+        fn make_fat_ptr(thin: *const (), len: Le<u64>) -> *const Self {
+            ptr::slice_from_raw_parts(thin as *const u8, len.get() as usize) as *const Self
+        }
+
+        fn make_fat_ptr_mut(thin: *mut (), len: Le<u64>) -> *mut Self {
+            ptr::slice_from_raw_parts_mut(thin as *mut u8, len.get() as usize) as *mut Self
+        }
+    }
+
+    unsafe impl IntoOwned for Bytes {
+        type Owned = Box<Bytes>;
+
+        unsafe fn into_owned_unchecked(this: &mut ManuallyDrop<Self>) -> Box<Bytes> {
+            let bytes: Box<[u8]> = this.0.into();
+            Box::from_raw(Box::into_raw(bytes) as *mut Bytes)
+        }
+    }
+
+    unsafe impl ValidateBlob for Bytes {
+        type BlobError = !;
+
+        fn try_blob_layout(len: Le<u64>) -> Result<BlobLayout, !> {
+            Ok(BlobLayout::new(len.get() as usize))
+        }
+
+        fn validate_blob<'a>(blob: Blob<'a, Self>, _: bool) -> Result<ValidBlob<'a, Self>, !> {
+            unsafe { Ok(blob.assume_valid()) }
+        }
+    }
+
+    impl Load for Bytes {
+        type Ptr = !;
+
+        fn decode_blob(blob: ValidBlob<Self>, _: &()) -> Box<Bytes> {
+            let bytes: Box<[u8]> = blob.as_bytes().into();
+            unsafe { Box::from_raw(Box::into_raw(bytes) as *mut Bytes) }
+        }
+    }
+
+    #[test]
+    fn unsized_value() {
+        let mut buf = compress(b"hello world");
+        let len = buf.len() as u64;
+        buf.extend_from_slice(&[0; 8]);
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let ok = unsafe { Compressed::<Bytes, Offset>::from_raw_parts(Offset::new(0).unwrap(), Le::new(11), len) };
+        assert_eq!(ok.metadata(), Le::new(11));
+        match ok.try_get(&pile).unwrap() {
+            Ref::Owned(loaded) => assert_eq!(&loaded.0, b"hello world"),
+            r @ Ref::Ref(_) => panic!("{:?}", r),
+        }
+
+        let wrong_len = unsafe { Compressed::<Bytes, Offset>::from_raw_parts(Offset::new(0).unwrap(), Le::new(20), len) };
+        match wrong_len.try_get(&pile) {
+            Err(DecompressError::Size { offset: 0, expected: 20, found: 11 }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn validate_metadata() {
+        // The pointer, the metadata of a Bytes, and the compressed length.
+        let mut buf = vec![];
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&11u64.to_le_bytes());
+        buf.extend_from_slice(&5u64.to_le_bytes());
+        assert_eq!(<Compressed<Bytes, Offset> as ValidateBlob>::blob_layout().size(), buf.len());
+
+        let blob = Blob::<Compressed<Bytes, Offset>>::try_from(&buf[..]).unwrap();
+        let blob = Compressed::<Bytes, Offset>::validate_blob(blob, false).unwrap();
+        let compressed = <Compressed<Bytes, Offset> as Load>::decode_blob(blob, &TryPile::default());
+        assert_eq!(compressed.metadata(), Le::new(11));
+        assert_eq!(compressed.compressed_len(), Some(5));
+    }
+}
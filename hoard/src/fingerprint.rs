@@ -0,0 +1,328 @@
+//! Schema fingerprints.
+//!
+//! A `Fingerprint` is a stable, 64-bit summary of a type's persistent structure: the blob layouts
+//! of its scalars, the order and names of its fields, and an optional user-supplied tag. Journals
+//! record the fingerprint of the root type alongside each commit, so that loading a root with a
+//! different type than it was written with fails cleanly rather than producing garbage.
+//!
+//! Fingerprints only depend on the *persistent* form of a type. In particular `Offset` and
+//! `OffsetMut` have the same fingerprint, so a `Root<OffsetMut>` written by a `JournalMut` can be
+//! loaded as a `Root<Offset>` and vice-versa.
+
+use std::any::TypeId;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::num;
+use std::slice;
+
+use leint::Le;
+
+use crate::pointee::Pointee;
+use crate::blob::*;
+use crate::scalar::Scalar;
+use crate::save::WriteBlob;
+
+/// A stable fingerprint of a type's schema.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[repr(transparent)]
+pub struct Fingerprint(Le<u64>);
+
+impl Fingerprint {
+    /// Creates a `Fingerprint` from its raw value.
+    pub fn new(raw: u64) -> Self {
+        Self(raw.into())
+    }
+
+    /// Gets the raw value of the fingerprint.
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+
+    /// Computes the fingerprint of a type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hoard::fingerprint::Fingerprint;
+    ///
+    /// assert_eq!(Fingerprint::of::<u8>(), Fingerprint::of::<u8>());
+    /// assert_ne!(Fingerprint::of::<u8>(), Fingerprint::of::<bool>());
+    /// ```
+    pub fn of<T: ?Sized + Schema>() -> Self {
+        T::fingerprint()
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fingerprint({})", self)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.get())
+    }
+}
+
+unsafe impl Persist for Fingerprint {}
+
+impl Scalar for Fingerprint {
+    const BLOB_LAYOUT: BlobLayout = BlobLayout::new(mem::size_of::<Self>());
+    type ScalarBlobError = !;
+
+    fn validate_blob<'a>(blob: Blob<'a, Self>) -> Result<ValidBlob<'a, Self>, Self::ScalarBlobError> {
+        unsafe { Ok(blob.assume_valid()) }
+    }
+
+    fn decode_blob(blob: ValidBlob<Self>) -> Self {
+        blob.as_value().clone()
+    }
+
+    fn try_deref_blob<'a>(blob: ValidBlob<'a, Self>) -> Result<&'a Self, ValidBlob<'a, Self>> {
+        Ok(blob.as_value())
+    }
+
+    fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+        dst.write_bytes(&self.get().to_le_bytes())?
+           .finish()
+    }
+}
+
+/// A type with a describable persistent schema.
+///
+/// Usually implemented with `#[derive(Schema)]`; the derive describes each field in order, and
+/// accepts an optional `#[hoard(schema_tag = "...")]` attribute to deliberately change the
+/// fingerprint of a type whose structure hasn't changed, but whose meaning has.
+pub trait Schema {
+    /// Writes a description of this type to the hasher.
+    ///
+    /// Implementations must be deterministic, and should describe children with
+    /// `SchemaHasher::write_schema()` or `SchemaHasher::field()` so that recursive types are
+    /// handled correctly.
+    fn describe_schema(hasher: &mut SchemaHasher);
+
+    /// Computes the fingerprint of this type.
+    fn fingerprint() -> Fingerprint {
+        let mut hasher = SchemaHasher::new();
+        hasher.write_schema::<Self>();
+        hasher.finish()
+    }
+}
+
+/// Accumulates a schema description into a `Fingerprint`.
+///
+/// The hash function is 64-bit FNV-1a, which is stable across platforms and compiler versions.
+#[derive(Debug, Clone)]
+pub struct SchemaHasher {
+    state: u64,
+    stack: Vec<TypeId>,
+}
+
+impl Default for SchemaHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchemaHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    /// Creates a new hasher.
+    pub fn new() -> Self {
+        Self {
+            state: Self::OFFSET_BASIS,
+            stack: vec![],
+        }
+    }
+
+    /// Writes raw bytes.
+    pub fn write_bytes(&mut self, buf: &[u8]) -> &mut Self {
+        for b in buf {
+            self.state ^= *b as u64;
+            self.state = self.state.wrapping_mul(Self::PRIME);
+        }
+        self
+    }
+
+    /// Writes a `u64`.
+    pub fn write_u64(&mut self, n: u64) -> &mut Self {
+        self.write_bytes(&n.to_le_bytes())
+    }
+
+    /// Writes a length-prefixed string.
+    pub fn write_str(&mut self, s: &str) -> &mut Self {
+        self.write_u64(s.len() as u64)
+            .write_bytes(s.as_bytes())
+    }
+
+    /// Writes a blob layout.
+    pub fn write_layout(&mut self, layout: BlobLayout) -> &mut Self {
+        self.write_u64(layout.size() as u64);
+        match layout.niche() {
+            Some(niche) => self.write_u64(niche.start as u64).write_u64(niche.end as u64),
+            None => self.write_u64(0).write_u64(0),
+        };
+        self.write_bytes(&[layout.inhabited() as u8])
+    }
+
+    /// Writes the schema of another type.
+    ///
+    /// If the type is already being described - as happens with recursive types such as linked
+    /// lists - a back-reference is written instead. Only the relative depth of the back-reference
+    /// is hashed, so the fingerprint doesn't depend on how the compiler identifies types.
+    pub fn write_schema<T: ?Sized + Schema>(&mut self) -> &mut Self {
+        let id = erased_type_id::<T>();
+
+        if let Some(depth) = self.stack.iter().rposition(|n| *n == id) {
+            self.write_bytes(b"R")
+                .write_u64((self.stack.len() - depth) as u64)
+        } else {
+            self.stack.push(id);
+            self.write_bytes(b"{");
+            T::describe_schema(self);
+            self.write_bytes(b"}");
+            self.stack.pop();
+            self
+        }
+    }
+
+    /// Writes a named field.
+    pub fn field<T: ?Sized + Schema>(&mut self, name: &str) -> &mut Self {
+        self.write_str(name)
+            .write_schema::<T>()
+    }
+
+    /// Finishes hashing, returning the fingerprint.
+    pub fn finish(self) -> Fingerprint {
+        Fingerprint::new(self.state)
+    }
+}
+
+/// Returns the `TypeId` of a possibly non-`'static` type.
+///
+/// Lifetimes are erased, so types that only differ in their lifetimes - such as `Offset<'a, 'b>`
+/// and `Offset<'c, 'd>` - get the same id. That's exactly what we want for schemas, as lifetimes
+/// have no persistent form.
+pub(crate) fn erased_type_id<T: ?Sized>() -> TypeId {
+    trait NonStaticAny {
+        fn type_id(&self) -> TypeId where Self: 'static;
+    }
+
+    impl<T: ?Sized> NonStaticAny for PhantomData<T> {
+        fn type_id(&self) -> TypeId where Self: 'static {
+            TypeId::of::<T>()
+        }
+    }
+
+    let phantom = PhantomData::<T>;
+    let erased: &dyn NonStaticAny = &phantom;
+
+    // SAFETY: type ids don't depend on lifetimes, and nothing else is done with the extended
+    // lifetime.
+    let erased: &(dyn NonStaticAny + 'static) = unsafe { mem::transmute(erased) };
+    erased.type_id()
+}
+
+macro_rules! impl_schema_for_scalars {
+    ($($t:ty,)+) => {$(
+        impl Schema for $t {
+            fn describe_schema(hasher: &mut SchemaHasher) {
+                hasher.write_str(stringify!($t))
+                      .write_layout(<$t as Scalar>::BLOB_LAYOUT);
+            }
+        }
+    )+}
+}
+
+impl_schema_for_scalars! {
+    (), bool, !,
+    u8, Le<u16>, Le<u32>, Le<u64>, Le<u128>,
+    i8, Le<i16>, Le<i32>, Le<i64>, Le<i128>,
+    num::NonZeroU8, Le<num::NonZeroU16>, Le<num::NonZeroU32>, Le<num::NonZeroU64>, Le<num::NonZeroU128>,
+    num::NonZeroI8, Le<num::NonZeroI16>, Le<num::NonZeroI32>, Le<num::NonZeroI64>, Le<num::NonZeroI128>,
+    Fingerprint,
+}
+
+impl<T: Schema, const N: usize> Schema for [T; N] {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("array")
+              .write_u64(N as u64)
+              .write_schema::<T>();
+    }
+}
+
+impl<T: Schema> Schema for [T] {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("slice")
+              .write_schema::<T>();
+    }
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("option")
+              .write_schema::<T>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_is_stable() {
+        assert_eq!(Fingerprint::of::<u8>(), Fingerprint::of::<u8>());
+        assert_ne!(Fingerprint::of::<u8>(), Fingerprint::of::<i8>());
+        assert_ne!(Fingerprint::of::<[u8; 2]>(), Fingerprint::of::<[u8; 3]>());
+        assert_ne!(Fingerprint::of::<Option<u8>>(), Fingerprint::of::<u8>());
+    }
+
+    #[test]
+    fn recursive_schema() {
+        struct List(Option<Box<List>>);
+
+        impl Schema for List {
+            fn describe_schema(hasher: &mut SchemaHasher) {
+                hasher.write_str("List")
+                      .field::<List>("next");
+            }
+        }
+
+        // would overflow the stack if recursion wasn't handled
+        let _ = Fingerprint::of::<List>();
+    }
+
+    #[test]
+    fn erased_type_id_ignores_lifetimes() {
+        fn id_of<'a>(_: &'a u8) -> TypeId {
+            erased_type_id::<&'a u8>()
+        }
+
+        let x = 1u8;
+        assert_eq!(id_of(&x), TypeId::of::<&'static u8>());
+        assert_ne!(erased_type_id::<u8>(), erased_type_id::<i8>());
+    }
+
+    #[test]
+    fn field_names_matter() {
+        struct A;
+        struct B;
+
+        impl Schema for A {
+            fn describe_schema(hasher: &mut SchemaHasher) {
+                hasher.field::<u8>("a");
+            }
+        }
+
+        impl Schema for B {
+            fn describe_schema(hasher: &mut SchemaHasher) {
+                hasher.field::<u8>("b");
+            }
+        }
+
+        assert_ne!(Fingerprint::of::<A>(), Fingerprint::of::<B>());
+    }
+}
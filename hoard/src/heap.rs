@@ -40,20 +40,21 @@ impl Load for HeapPtr {
     }
 }
 
+/// Raises the alignment to at least 2, so that heap pointers always have their low bit clear.
 #[inline]
 fn min_align_layout(layout: Layout) -> Layout {
     unsafe {
         Layout::from_size_align_unchecked(
             layout.size(),
-            cmp::min(layout.align(), 2),
+            cmp::max(layout.align(), 2),
         )
     }
 }
 
 pub(crate) unsafe fn heap_alloc(layout: Layout) -> NonNull<u16> {
-    if layout.size() > 0 {
-        let layout = min_align_layout(layout);
+    let layout = min_align_layout(layout);
 
+    if layout.size() > 0 {
         NonNull::new(std::alloc::alloc(layout))
                 .unwrap_or_else(|| std::alloc::handle_alloc_error(layout))
                 .cast()
@@ -68,17 +69,34 @@ pub(crate) unsafe fn heap_dealloc(ptr: NonNull<u16>, layout: Layout) {
     };
 }
 
+/// Moves a value to the heap.
+pub(crate) fn heap_alloc_value<T>(value: T) -> NonNull<u16> {
+    let value = ManuallyDrop::new(value);
+    let layout = Layout::for_value(&*value);
+
+    unsafe {
+        let dst = heap_alloc(layout);
+        std::ptr::copy_nonoverlapping(&*value as *const T as *const u8, dst.as_ptr().cast(),
+                                      layout.size());
+        dst
+    }
+}
+
 impl Ptr for HeapPtr {
     type Zone = Heap;
     type BlobZone = !;
     type Persist = !;
 
     unsafe fn dealloc<T: ?Sized + Pointee>(&self, metadata: T::Metadata) {
-        todo!()
+        let value = &mut *T::make_fat_ptr_mut(self.0.cast::<()>().as_ptr(), metadata);
+        let layout = Layout::for_value(value);
+
+        std::ptr::drop_in_place(value);
+        heap_dealloc(self.0, layout)
     }
 
     unsafe fn try_get_dirty_unchecked<T: ?Sized + Pointee>(&self, metadata: T::Metadata) -> Result<&T, Self::Persist> {
-        todo!()
+        Ok(&*T::make_fat_ptr(self.0.cast::<()>().as_ptr(), metadata))
     }
 }
 
@@ -197,13 +215,13 @@ impl Ptr for HeapPtr {
     }
 
     unsafe fn try_get_dirty_unchecked<T: ?Sized + Pointee>(&self, metadata: T::Metadata) -> Result<&T, Self::Persist> {
-        Ok(&*T::make_fat_ptr(self.0.cast().as_ptr(), metadata))
+        Ok(&*T::make_fat_ptr(self.0.cast::<()>().as_ptr(), metadata))
     }
 
     unsafe fn try_take_dirty_unchecked<T: ?Sized + Pointee>(self, metadata: T::Metadata) -> Result<T::Owned, Self::Persist>
         where T: IntoOwned
     {
-        let value = &mut *T::make_fat_ptr_mut(self.0.cast().as_ptr(), metadata);
+        let value = &mut *T::make_fat_ptr_mut(self.0.cast::<()>().as_ptr(), metadata);
         let layout = Layout::for_value(value);
 
         let owned = T::into_owned_unchecked(&mut *(value as *mut _ as *mut ManuallyDrop<T>));
@@ -31,12 +31,17 @@ unsafe impl<T: ValidateBlob, const N: usize> ValidateBlob for [T; N] {
 
     #[inline(always)]
     fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
-        Ok(BlobLayout {
-            size: T::blob_layout().size() * N,
-            niche_start: T::blob_layout().niche_start,
-            niche_end: T::blob_layout().niche_end,
-            inhabited: T::blob_layout().inhabited,
-        })
+        if N == 0 {
+            // an empty array has no bytes for a niche to live in, and is always inhabited
+            Ok(BlobLayout::new(0))
+        } else {
+            Ok(BlobLayout {
+                size: T::blob_layout().size() * N,
+                niche_start: T::blob_layout().niche_start,
+                niche_end: T::blob_layout().niche_end,
+                inhabited: T::blob_layout().inhabited,
+            })
+        }
     }
 
     fn validate_blob<'a>(blob: Blob<'a, Self>, ignore_padding: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
@@ -61,6 +66,10 @@ impl<T: Decode, const N: usize> Load for [T; N] {
         items.finish();
         this.done()
     }
+
+    fn heap_size(owned: &Self) -> usize {
+        owned.iter().map(|item| T::heap_size(item)).sum()
+    }
 }
 
 pub struct ArraySavePoll<Q: Ptr, T: Save<Q>, const N: usize> {
@@ -109,12 +118,18 @@ where T::Saved: Sized
 {
     type Target = [T::Saved; N];
 
-    fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
-        todo!()
+    fn encode_blob<W: WriteBlob>(&self, mut dst: W) -> Result<W::Ok, W::Error> {
+        assert_eq!(self.idx, N, "polling incomplete");
+
+        for item in self.state.iter() {
+            dst = dst.write(item)?;
+        }
+        dst.finish()
     }
 }
 
-impl<Q: Ptr, T: Save<Q> + Decode, const N: usize> SavePoll for ArraySavePoll<Q, T, N>
+// SAFETY: the items' pollers are never moved, and only dropped along with the array's
+unsafe impl<Q: Ptr, T: Save<Q> + Decode, const N: usize> SavePoll for ArraySavePoll<Q, T, N>
 where T::Saved: Sized
 {
     type SrcPtr = T::Ptr;
@@ -132,6 +147,42 @@ where T::Saved: Sized
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::num::NonZeroU8;
+
+    use proptest::prelude::*;
+
+    use crate::impls::check_canonical;
+
+    #[test]
+    fn empty_array_layout() {
+        assert_eq!(<[NonZeroU8; 0]>::blob_layout(), BlobLayout::new(0));
+        assert_eq!(<[NonZeroU8; 2]>::blob_layout(), BlobLayout::new_nonzero(1).extend(BlobLayout::new(1)));
+    }
+
+    proptest! {
+        #[test]
+        fn u8_array_canonical(bytes in any::<[u8; 4]>()) {
+            prop_assert!(check_canonical::<[u8; 4]>(&bytes));
+        }
+
+        #[test]
+        fn bool_array_canonical(bytes in any::<[u8; 3]>()) {
+            let valid = bytes.iter().all(|b| *b <= 1);
+            prop_assert_eq!(check_canonical::<[bool; 3]>(&bytes), valid);
+        }
+
+        #[test]
+        fn nonzero_array_canonical(bytes in any::<[u8; 2]>()) {
+            let valid = bytes.iter().all(|b| *b != 0);
+            prop_assert_eq!(check_canonical::<[NonZeroU8; 2]>(&bytes), valid);
+        }
+    }
+}
+
 /*
 /*
 impl<Y, Q, T: SavePoll<Y, Q>, const N: usize> SavePoll<Y, Q> for ArraySavePoll<T, N>
@@ -12,4 +12,49 @@ use crate::ptr::*;
 pub mod never;
 pub mod scalars;
 pub mod array;
-//pub mod option;
+pub mod option;
+
+/// Checks that a blob is either invalid in strict mode, or decodes to a value that re-encodes to
+/// exactly the same bytes. Returns whether or not the blob was valid.
+#[cfg(test)]
+pub(crate) fn check_canonical<T>(bytes: &[u8]) -> bool
+    where T: Decode<Ptr = !> + Save<!>
+{
+    use std::convert::TryFrom;
+
+    struct NeverSaver;
+
+    impl Saver for NeverSaver {
+        type SrcPtr = !;
+        type DstPtr = !;
+        type Error = !;
+
+        fn try_save_raw<R, U: ?Sized + ValidateBlob>(&self,
+            ptr: &!,
+            _: U::Metadata,
+            _: impl FnOnce(ValidBlob<U>, &()) -> R,
+        ) -> Result<Result<!, R>, !>
+        {
+            match *ptr {}
+        }
+
+        fn finish_save<U: EncodeBlob>(&mut self, _: &U) -> Result<!, !> {
+            unreachable!("types without pointers have nothing to save")
+        }
+    }
+
+    let blob = Blob::<T>::try_from(bytes).unwrap();
+    match T::validate_blob(blob, false) {
+        Ok(valid) => {
+            let value = <T as Load>::decode_blob(valid, &());
+
+            let mut poll = value.init_save();
+            poll.save_poll(&mut NeverSaver).into_ok();
+            let encoded = poll.encode_blob(vec![]).into_ok();
+
+            assert_eq!(encoded, bytes, "{} blob not canonical", std::any::type_name::<T>());
+            true
+        },
+        Err(_) => false,
+    }
+}
@@ -1,258 +1,261 @@
-use super::*;
+use std::any::type_name;
+use std::fmt;
+use std::marker::PhantomData;
 
 use thiserror::Error;
 
-impl<T: BlobSize> BlobSize for Option<T> {
-    const BLOB_LAYOUT: BlobLayout = {
-        if T::BLOB_LAYOUT.has_niche() {
-            T::BLOB_LAYOUT
-        } else {
-            BlobLayout::new(1).extend(T::BLOB_LAYOUT)
-        }
-    };
-}
+use crate::validate::{ValidateChildren, PtrValidator};
+
+use super::*;
 
 #[derive(Debug, Error, PartialEq, Eq)]
-#[error("FIXME")]
 pub enum ValidateOptionBlobError<ValueError: std::error::Error> {
+    #[error("invalid option discriminant")]
     Discriminant,
+
+    #[error("non-zero padding in none option")]
     Padding,
+
+    #[error("invalid option value: {0}")]
     Value(ValueError),
 }
 
-impl<V: Copy, T: ValidateBlob<V>> ValidateBlob<V> for Option<T> {
-    type Error = ValidateOptionBlobError<T::Error>;
+unsafe impl<T: ValidateBlob> ValidateBlob for Option<T> {
+    type BlobError = ValidateOptionBlobError<T::BlobError>;
 
-    fn validate_blob<'a>(blob: Blob<'a, Self>, padval: V) -> Result<ValidBlob<'a, Self>, Self::Error> {
-        if let Some(niche) = T::BLOB_LAYOUT.niche() {
-            let (lhs_padding, rest) = blob.as_bytes().split_at(niche.start);
-            let (niche_bytes, rhs_padding) = rest.split_at(niche.end);
+    #[inline(always)]
+    fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+        let layout = T::blob_layout();
+        if layout.has_niche() {
+            // the niche is used up by None
+            Ok(BlobLayout::new(layout.size()))
+        } else {
+            Ok(BlobLayout::new(1).extend(layout))
+        }
+    }
 
-            if niche_bytes.iter().all(|b| *b == 0) {
-                /*
-                padval.validate_padding(lhs_padding).map_err(ValidateOptionBlobError::Padding)?;
-                padval.validate_padding(rhs_padding).map_err(ValidateOptionBlobError::Padding)?;
+    fn validate_blob<'a>(blob: Blob<'a, Self>, ignore_padding: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
+        let layout = T::blob_layout();
+        let bytes = blob.as_bytes();
+        let mut fields = blob.validate_fields(ignore_padding);
 
-                unsafe { Ok(blob.assume_valid()) }
-                */ todo!()
+        if let Some(niche) = layout.niche() {
+            if bytes[niche].iter().all(|b| *b == 0) {
+                fields.validate_padding(layout.size())
+                      .map_err(|_| ValidateOptionBlobError::Padding)?;
             } else {
-                let mut fields = blob.validate_fields(padval);
-
                 fields.validate_blob::<T>().map_err(ValidateOptionBlobError::Value)?;
-                unsafe { Ok(fields.finish()) }
             }
         } else {
-            let mut fields = blob.validate_fields(padval);
-
-            match fields.validate_blob::<u8>().into_ok().as_value() {
-                1 => {
-                    fields.validate_blob::<T>().map_err(ValidateOptionBlobError::Value)?;
-                    unsafe { Ok(fields.finish()) }
+            match fields.field_bytes(1) {
+                [0] => {
+                    fields.validate_padding(layout.size())
+                          .map_err(|_| ValidateOptionBlobError::Padding)?;
                 },
-                0 => {
-                    /*
-                    let padding = fields.field_bytes(T::blob_layout().size());
-                    padval.validate_padding(padding).map_err(ValidateOptionBlobError::Padding)?;
-                    unsafe { Ok(fields.finish()) }
-                    */ todo!()
+                [1] => {
+                    fields.validate_blob::<T>().map_err(ValidateOptionBlobError::Value)?;
                 },
-                x => Err(ValidateOptionBlobError::Discriminant),
+                _ => return Err(ValidateOptionBlobError::Discriminant),
             }
         }
+        unsafe { Ok(fields.finish()) }
     }
 }
 
-impl<T: Decode> Decode for Option<T> {
-    type Zone = T::Zone;
+impl<T: Decode> Load for Option<T> {
     type Ptr = T::Ptr;
 
-    fn decode_blob(blob: ValidBlob<Self>, zone: &Self::Zone) -> Self {
-        todo!()
-    }
-}
+    fn decode_blob(blob: ValidBlob<Self>, zone: &<Self::Ptr as Ptr>::BlobZone) -> Self {
+        let layout = T::blob_layout();
+        let bytes = blob.as_bytes();
+        let mut fields = blob.decode_fields(zone);
 
-/*
-#[derive(Debug)]
-pub struct OptionSavePoll<T>(Option<T>);
+        let is_some = match layout.niche() {
+            Some(niche) => bytes[niche].iter().any(|b| *b != 0),
+            None => fields.field_bytes(1)[0] == 1,
+        };
 
-impl<Y: Zone, T: SaveIn<Y>> SaveIn<Y> for Option<T>
-where T::Saved: Sized,
-{
-    type Saved = Option<T::Saved>;
-    type SavePoll = OptionSavePoll<T::SavePoll>;
+        if is_some {
+            let value = unsafe { fields.decode_unchecked::<T>() };
+            fields.finish();
+            Some(value)
+        } else {
+            None
+        }
+    }
 
-    fn init_save(&self) -> Self::SavePoll {
-        OptionSavePoll(self.as_ref().map(T::init_save))
+    fn heap_size(owned: &Self) -> usize {
+        owned.as_ref().map_or(0, |value| T::heap_size(value))
     }
 }
 
-impl<Y: Zone, T: SavePoll<Y>> SavePoll<Y> for OptionSavePoll<T>
-where T::Target: Sized,
-{
-    type Target = Option<T::Target>;
-
-    fn save_children<D>(&mut self, dst: &mut D) -> Result<(), D::Error>
-        where D: Saver<<Self::Target as Load>::Ptr, DstZone = Y>,
+impl<Q: PersistPtr, T: ValidateChildren<Q>> ValidateChildren<Q> for Option<T> {
+    fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>
     {
-        match self.0 {
-            Some(ref mut inner) => inner.save_children(dst),
-            None => Ok(()),
+        let layout = T::blob_layout();
+        let bytes = blob.as_bytes();
+        let mut fields = blob.valid_fields();
+
+        let is_some = match layout.niche() {
+            Some(niche) => bytes[niche].iter().any(|b| *b != 0),
+            None => fields.field_bytes(1)[0] == 1,
+        };
+
+        if is_some {
+            // SAFETY: validated by Option::validate_blob()
+            let value = unsafe { fields.field_unchecked::<T>() };
+            fields.finish();
+            T::validate_children(value, validator)
+        } else {
+            Ok(())
         }
     }
-
-    fn save_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
-        todo!()
-    }
 }
-*/
-
-/*
-impl<Z, T: Decode<Z>> Decode<Z> for Option<T> {}
 
-impl<Y, T: Saved<Y>> Saved<Y> for Option<T>
-where T::Saved: Decode<Y>,
+impl<Q: Ptr, T: Saved<Q>> Saved<Q> for Option<T>
+where T::Saved: Sized,
 {
     type Saved = Option<T::Saved>;
 }
 
-#[derive(Debug)]
-pub struct OptionSavePoll<T>(Option<T>);
-
-impl<Y, Q, T: SavePoll<Y, Q>> SavePoll<Y, Q> for OptionSavePoll<T>
-where T::Target: BlobSize + Decode<Y>
+impl<Q: Ptr, T: Save<Q> + Decode> Save<Q> for Option<T>
+where T::Saved: Sized,
 {
-    type Target = Option<T::Target>;
-
-    fn target_metadata(&self) -> <Self::Target as Pointee>::Metadata {
-    }
+    type SavePoll = OptionSavePoll<Q, T>;
 
-    fn save_children<D>(&mut self, dst: &mut D) -> Result<(), D::Error>
-        where D: Saver<DstZone = Y, SrcPtr = Q>
-    {
-        match &mut self.0 {
-            Some(value) => value.save_children(dst),
-            None => Ok(()),
+    fn init_save(&self) -> Self::SavePoll {
+        OptionSavePoll {
+            marker: PhantomData,
+            state: self.as_ref().map(T::init_save),
         }
     }
+}
 
-    fn save_blob<W: WriteBlob<Y, Q>>(&self, mut dst: W) -> Result<W::Done, W::Error>
-        where Y: BlobZone
-    {
-        if let Some(niche) = T::Target::blob_layout().niche() {
-            match &self.0 {
-                None => {
-                    for _ in 0 .. T::Target::blob_layout().size() {
-                        dst = dst.write_bytes(&[0])?;
-                    }
-                    dst.done()
-                }
-                Some(value) => dst.write(value)?
-                                  .done()
-            }
-        } else {
-            match &self.0 {
-                None => {
-                    dst.write_bytes(&[0])?
-                       .write_padding(T::Target::blob_layout().size())?
-                       .done()
-                },
-                Some(value) => {
-                    dst.write_bytes(&[1])?
-                       .write(value)?
-                       .done()
-                }
-            }
-        }
-    }
+pub struct OptionSavePoll<Q: Ptr, T: Save<Q>> {
+    marker: PhantomData<fn() -> T>,
+    state: Option<T::SavePoll>,
 }
 
-impl<Y, Q, T: Save<Y, Q>> Save<Y, Q> for Option<T>
-where T::Saved: BlobSize + Decode<Y>,
+impl<Q: Ptr, T: Save<Q>> fmt::Debug for OptionSavePoll<Q, T>
+where T::SavePoll: fmt::Debug,
 {
-    type SavePoll = OptionSavePoll<T::SavePoll>;
-
-    fn init_save(&self) -> Self::SavePoll {
-        OptionSavePoll(
-            self.as_ref().map(|value| value.init_save())
-        )
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(type_name::<Self>())
+            .field("state", &self.state)
+            .finish()
     }
 }
 
-/*
-impl<Y, P, T: EncodeBlobPoll<Y, P>> EncodeBlobPoll<Y, P> for Option<T> {
-    type Target = Option<T::Target>;
-
-    fn encode_blob_poll<D>(&mut self, dst: &mut D) -> Result<(), D::Error>
-        where D: BlobSaver<DstZone = Y, SrcPtr = P>
-    {
-        match self {
-            Some(inner) => inner.encode_blob_poll(dst),
-            None => Ok(()),
-        }
-    }
-
-    fn encode_blob<W: Write>(&self, dst: &mut W) -> Result<(), W::Error> {
-        if let Some(niche) = T::Target::blob_layout().niche() {
-            match self {
-                None => {
-                    for _ in 0 .. T::Target::blob_layout().size() {
-                        dst.write(&[0])?;
-                    };
-                    Ok(())
-                },
-                Some(inner) => inner.encode_blob(dst),
-            }
-        } else {
-            match self {
-                None => {
-                    dst.write(&[0])?;
-                    dst.write_padding(T::Target::blob_layout().size())?;
-                    Ok(())
-                },
-                Some(inner) => {
-                    dst.write(&[1])?;
-                    inner.encode_blob(dst)
-                },
-            }
+impl<Q: Ptr, T: Save<Q> + Decode> EncodeBlob for OptionSavePoll<Q, T>
+where T::Saved: Sized
+{
+    type Target = Option<T::Saved>;
+
+    fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+        let layout = T::blob_layout();
+
+        match (&self.state, layout.has_niche()) {
+            (None, true) => dst.write_padding(layout.size())?.finish(),
+            (None, false) => dst.write_bytes(&[0])?
+                                .write_padding(layout.size())?
+                                .finish(),
+            (Some(value), true) => dst.write(value)?.finish(),
+            (Some(value), false) => dst.write_bytes(&[1])?
+                                       .write(value)?
+                                       .finish(),
         }
     }
 }
 
-impl<Y, P, T: EncodeBlob<Y, P>> EncodeBlob<Y, P> for Option<T> {
-    type Encoded = Option<T::Encoded>;
-    type EncodeBlobPoll = Option<T::EncodeBlobPoll>;
+// SAFETY: the value's poller is never moved, and only dropped along with the option's
+unsafe impl<Q: Ptr, T: Save<Q> + Decode> SavePoll for OptionSavePoll<Q, T>
+where T::Saved: Sized
+{
+    type SrcPtr = T::Ptr;
+
+    type DstPtr = Q;
 
-    fn init_encode_blob<D>(&self, dst: &D) -> Result<Self::EncodeBlobPoll, D::Error>
-        where D: BlobSaver<DstZone = Y, SrcPtr = P>
+    fn save_poll<S>(&mut self, saver: &mut S) -> Result<(), S::Error>
+        where S: Saver<SrcPtr = Self::SrcPtr, DstPtr = Self::DstPtr>,
     {
-        match self {
-            Some(inner) => Ok(Some(inner.init_encode_blob(dst)?)),
-            None => Ok(None),
+        match &mut self.state {
+            Some(value) => value.save_poll(saver),
+            None => Ok(()),
         }
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::convert::TryFrom;
+    use std::num::NonZeroU8;
+
+    use proptest::prelude::*;
 
-    use crate::blob::padding::*;
+    use crate::impls::check_canonical;
 
     #[test]
     fn no_niche() {
         let blob = Blob::<Option<()>>::try_from(&[0][..]).unwrap();
-        let _valid_blob = ValidateBlob::validate_blob(blob, CheckPadding).unwrap();
+        ValidateBlob::validate_blob(blob, false).unwrap();
 
         let blob = Blob::<Option<()>>::try_from(&[1][..]).unwrap();
-        let _valid_blob = ValidateBlob::validate_blob(blob, CheckPadding).unwrap();
+        ValidateBlob::validate_blob(blob, false).unwrap();
 
         let blob = Blob::<Option<()>>::try_from(&[2][..]).unwrap();
-        let err = ValidateBlob::validate_blob(blob, CheckPadding).unwrap_err();
+        let err = ValidateBlob::validate_blob(blob, false).unwrap_err();
         assert_eq!(err, ValidateOptionBlobError::Discriminant);
     }
+
+    #[test]
+    fn strict_padding() {
+        let blob = Blob::<Option<u8>>::try_from(&[0, 42][..]).unwrap();
+        let err = ValidateBlob::validate_blob(blob, false).unwrap_err();
+        assert_eq!(err, ValidateOptionBlobError::Padding);
+
+        let blob = Blob::<Option<u8>>::try_from(&[0, 42][..]).unwrap();
+        ValidateBlob::validate_blob(blob, true).unwrap();
+    }
+
+    #[test]
+    fn niche_layout() {
+        assert_eq!(<Option<NonZeroU8>>::blob_layout(), BlobLayout::new(1));
+        assert_eq!(<Option<Option<NonZeroU8>>>::blob_layout(), BlobLayout::new(2));
+    }
+
+    proptest! {
+        #[test]
+        fn option_u8_canonical(bytes in any::<[u8; 2]>()) {
+            let valid = bytes[0] == 1 || bytes == [0, 0];
+            prop_assert_eq!(check_canonical::<Option<u8>>(&bytes), valid);
+        }
+
+        #[test]
+        fn option_bool_canonical(bytes in any::<[u8; 2]>()) {
+            let valid = (bytes[0] == 1 && bytes[1] <= 1) || bytes == [0, 0];
+            prop_assert_eq!(check_canonical::<Option<bool>>(&bytes), valid);
+        }
+
+        #[test]
+        fn option_nonzero_canonical(bytes in any::<[u8; 1]>()) {
+            prop_assert!(check_canonical::<Option<NonZeroU8>>(&bytes));
+        }
+
+        #[test]
+        fn option_nonzero_array_canonical(bytes in any::<[u8; 3]>()) {
+            // the niche is the first byte; the rest is padding if it's zero
+            let valid = bytes.iter().all(|b| *b != 0) || bytes == [0, 0, 0];
+            prop_assert_eq!(check_canonical::<Option<[NonZeroU8; 3]>>(&bytes), valid);
+        }
+
+        #[test]
+        fn nested_option_canonical(bytes in any::<[u8; 2]>()) {
+            // the inner option has no niche left, so the outer one is tagged
+            let valid = bytes[0] == 1 || bytes == [0, 0];
+            prop_assert_eq!(check_canonical::<Option<Option<NonZeroU8>>>(&bytes), valid);
+        }
+    }
 }
-*/
-*/
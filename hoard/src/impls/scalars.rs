@@ -125,6 +125,70 @@ unsafe_impl_nonzero_persist! {
     num::NonZeroI8, Le<num::NonZeroI16>, Le<num::NonZeroI32>, Le<num::NonZeroI64>, Le<num::NonZeroI128>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::impls::check_canonical;
+
+    #[test]
+    fn bool_validate_blob() {
+        assert!(check_canonical::<bool>(&[0]));
+        assert!(check_canonical::<bool>(&[1]));
+        assert!(!check_canonical::<bool>(&[2]));
+    }
+
+    macro_rules! canonical_tests {
+        ($($name:ident: $t:ty => $valid:ident,)+) => {
+            proptest! {$(
+                #[test]
+                fn $name(bytes in any::<[u8; mem::size_of::<$t>()]>()) {
+                    prop_assert_eq!(check_canonical::<$t>(&bytes), $valid(&bytes));
+                }
+            )+}
+        }
+    }
+
+    fn any_bits(_: &[u8]) -> bool {
+        true
+    }
+
+    fn boolean(bytes: &[u8]) -> bool {
+        bytes[0] <= 1
+    }
+
+    fn nonzero(bytes: &[u8]) -> bool {
+        bytes.iter().any(|b| *b != 0)
+    }
+
+    canonical_tests! {
+        unit_canonical: () => any_bits,
+        bool_canonical: bool => boolean,
+        u8_canonical: u8 => any_bits,
+        u16_canonical: Le<u16> => any_bits,
+        u32_canonical: Le<u32> => any_bits,
+        u64_canonical: Le<u64> => any_bits,
+        u128_canonical: Le<u128> => any_bits,
+        i8_canonical: i8 => any_bits,
+        i16_canonical: Le<i16> => any_bits,
+        i32_canonical: Le<i32> => any_bits,
+        i64_canonical: Le<i64> => any_bits,
+        i128_canonical: Le<i128> => any_bits,
+        nonzero_u8_canonical: num::NonZeroU8 => nonzero,
+        nonzero_u16_canonical: Le<num::NonZeroU16> => nonzero,
+        nonzero_u32_canonical: Le<num::NonZeroU32> => nonzero,
+        nonzero_u64_canonical: Le<num::NonZeroU64> => nonzero,
+        nonzero_u128_canonical: Le<num::NonZeroU128> => nonzero,
+        nonzero_i8_canonical: num::NonZeroI8 => nonzero,
+        nonzero_i16_canonical: Le<num::NonZeroI16> => nonzero,
+        nonzero_i32_canonical: Le<num::NonZeroI32> => nonzero,
+        nonzero_i64_canonical: Le<num::NonZeroI64> => nonzero,
+        nonzero_i128_canonical: Le<num::NonZeroI128> => nonzero,
+    }
+}
+
 /*
 macro_rules! impl_nonzero {
     ($($t:ty,)+) => {$(
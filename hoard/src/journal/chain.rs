@@ -0,0 +1,142 @@
+//! Hash-chained commits.
+//!
+//! Every commit record includes the digest of the previous commit record, and the digest of the
+//! bytes written by the commit itself: the root blob, and everything else written since the
+//! previous mark. Rewriting any commit thus changes the digest of every record after it, so the
+//! digest of the last record commits to the entire history of the journal.
+//!
+//! Digests are SHA-256, exactly as `proofmarshal_core::commit::Digest` computes them, so a
+//! `CommitDigest` can be converted to and from one with its bytes.
+
+use std::fmt;
+
+use sha2::{Sha256, Digest as _};
+use thiserror::Error;
+
+use super::commit::CommitRecordError;
+
+/// A SHA-256 digest within a commit chain.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommitDigest([u8; 32]);
+
+impl CommitDigest {
+    /// The length of a digest, in bytes.
+    pub const LEN: usize = 32;
+
+    pub fn new(buf: [u8; 32]) -> Self {
+        Self(buf)
+    }
+
+    /// Hashes some bytes.
+    pub fn hash(bytes: &[u8]) -> Self {
+        let mut hasher = CommitHasher::new();
+        hasher.write(bytes);
+        hasher.finish()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl From<[u8; 32]> for CommitDigest {
+    fn from(buf: [u8; 32]) -> Self {
+        Self::new(buf)
+    }
+}
+
+impl From<CommitDigest> for [u8; 32] {
+    fn from(digest: CommitDigest) -> Self {
+        digest.0
+    }
+}
+
+impl fmt::Display for CommitDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CommitDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CommitDigest({})", self)
+    }
+}
+
+/// Incrementally hashes the bytes of a commit.
+#[derive(Clone, Default)]
+pub(crate) struct CommitHasher {
+    inner: Sha256,
+}
+
+impl fmt::Debug for CommitHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommitHasher").finish()
+    }
+}
+
+impl CommitHasher {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        self.inner.input(bytes)
+    }
+
+    pub(crate) fn finish(self) -> CommitDigest {
+        let mut buf = [0; 32];
+        buf.copy_from_slice(&self.inner.result());
+        CommitDigest(buf)
+    }
+}
+
+/// Returned by `Journal::verify_chain()` at the first commit that doesn't match the chain.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("commit at mark {mark} diverges from the chain: {kind}")]
+pub struct ChainError {
+    /// The mark of the first divergent commit.
+    pub mark: usize,
+    pub kind: ChainErrorKind,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ChainErrorKind {
+    #[error("{0}")]
+    Record(#[from] CommitRecordError),
+
+    #[error("previous record digest is {found}; expected {expected}")]
+    Prev {
+        expected: CommitDigest,
+        found: CommitDigest,
+    },
+
+    #[error("commit bytes digest is {found}; expected {expected}")]
+    Bytes {
+        expected: CommitDigest,
+        found: CommitDigest,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256() {
+        assert_eq!(CommitDigest::hash(b"").to_string(),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+
+        let mut hasher = CommitHasher::new();
+        hasher.write(b"a");
+        hasher.write(b"bc");
+        assert_eq!(hasher.finish(), CommitDigest::hash(b"abc"));
+    }
+}
@@ -0,0 +1,318 @@
+//! Journal commits.
+//!
+//! Every commit in a journal ends with a `CommitRecord`, written immediately prior to the mark,
+//! recording the offset of the root and the fingerprint of the type it was saved as, along with
+//! the digests chaining it to the previous commit. The record is followed by the commit's
+//! signature block, which is empty if the commit isn't signed.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+
+use thiserror::Error;
+
+use crate::blob::*;
+use crate::offset::Offset;
+use crate::scalar::Scalar;
+use crate::pile::{TryPile, GetValidBlobError};
+use crate::pile::fsck::{Fsck, FsckError, FsckStats};
+use crate::fingerprint::{Fingerprint, Schema};
+use crate::validate::ValidateChildren;
+
+use super::wordoffset::Word;
+use super::chain::CommitDigest;
+use super::sign::{self, CommitSignature, CommitVerifier};
+use super::directory::{Directory, DirectoryHeader, DirectoryError};
+
+/// The record written at the end of every commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitRecord {
+    root: Offset<'static, 'static>,
+    fingerprint: Fingerprint,
+    prev: CommitDigest,
+    bytes: CommitDigest,
+}
+
+/// Returned when a commit record can't be parsed.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CommitRecordError {
+    #[error("commit record truncated")]
+    Truncated,
+
+    #[error("invalid root offset in commit record")]
+    Offset,
+
+    #[error("invalid commit signature")]
+    Signature,
+}
+
+impl CommitRecord {
+    /// The length of an encoded commit record, in bytes.
+    pub const LEN: usize = Self::ROOT_LEN + CommitDigest::LEN * 2;
+
+    /// The length of the root offset and fingerprint alone, in bytes.
+    pub const ROOT_LEN: usize = mem::size_of::<Word>() * 2;
+
+    /// Creates a new record, with zeroed chain digests.
+    pub fn new(root: Offset, fingerprint: Fingerprint) -> Self {
+        Self {
+            root: root.to_static(),
+            fingerprint,
+            prev: CommitDigest::default(),
+            bytes: CommitDigest::default(),
+        }
+    }
+
+    /// Sets the chain digests.
+    pub fn with_chain(self, prev: CommitDigest, bytes: CommitDigest) -> Self {
+        Self { prev, bytes, ..self }
+    }
+
+    /// Gets the offset of the root.
+    pub fn root(&self) -> Offset<'static, 'static> {
+        self.root
+    }
+
+    /// Gets the fingerprint of the root's type.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// Gets the digest of the previous commit record; zero for the first commit.
+    pub fn prev(&self) -> CommitDigest {
+        self.prev
+    }
+
+    /// Gets the digest of the bytes written by the commit, prior to this record.
+    pub fn bytes_digest(&self) -> CommitDigest {
+        self.bytes
+    }
+
+    /// Computes the digest of this record, which the next commit's record chains to.
+    pub fn digest(&self) -> CommitDigest {
+        CommitDigest::hash(&self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut r = self.to_root_bytes();
+        r.extend_from_slice(self.prev.as_bytes());
+        r.extend_from_slice(self.bytes.as_bytes());
+        r
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, CommitRecordError> {
+        if buf.len() != Self::LEN {
+            return Err(CommitRecordError::Truncated);
+        }
+        let (root, digests) = buf.split_at(Self::ROOT_LEN);
+        let (prev, bytes) = digests.split_at(CommitDigest::LEN);
+
+        let mut raw = [0; 32];
+        raw.copy_from_slice(prev);
+        let prev = CommitDigest::new(raw);
+        raw.copy_from_slice(bytes);
+        let bytes = CommitDigest::new(raw);
+
+        Ok(Self::from_root_bytes(root)?.with_chain(prev, bytes))
+    }
+
+    /// Encodes only the root offset and fingerprint.
+    pub fn to_root_bytes(&self) -> Vec<u8> {
+        let mut r = Vec::with_capacity(Self::LEN);
+        r.extend_from_slice(&Scalar::encode_blob(&self.root, vec![]).into_ok());
+        r.extend_from_slice(&self.fingerprint.get().to_le_bytes());
+        r
+    }
+
+    /// Parses a root offset and fingerprint encoded by `to_root_bytes()`.
+    ///
+    /// The chain digests of the returned record are zeroed.
+    pub fn from_root_bytes(buf: &[u8]) -> Result<Self, CommitRecordError> {
+        if buf.len() != Self::ROOT_LEN {
+            return Err(CommitRecordError::Truncated);
+        }
+        let (root, fingerprint) = buf.split_at(mem::size_of::<Word>());
+
+        let root = Blob::<Offset>::try_from(root).unwrap();
+        let root = <Offset as ValidateBlob>::validate_blob(root, false)
+                       .map_err(|_| CommitRecordError::Offset)?;
+
+        let mut raw = [0; 8];
+        raw.copy_from_slice(fingerprint);
+
+        Ok(Self::new(*root.as_value(), Fingerprint::new(u64::from_le_bytes(raw))))
+    }
+}
+
+/// Returned when a root can't be loaded from a commit.
+#[derive(Debug, Error)]
+pub enum RootError<E: 'static + std::error::Error> {
+    #[error("invalid commit record: {0}")]
+    CommitRecord(#[from] CommitRecordError),
+
+    #[error("schema mismatch: root was written with fingerprint {found}, but loaded as {expected}")]
+    SchemaMismatch {
+        expected: Fingerprint,
+        found: Fingerprint,
+    },
+
+    #[error("no root named {0:?}")]
+    NotFound(String),
+
+    #[error("invalid root blob: {0}")]
+    Blob(E),
+}
+
+impl<E: 'static + std::error::Error> RootError<E> {
+    /// Maps the blob error, leaving other errors unchanged.
+    pub fn map_blob<F: 'static + std::error::Error>(self, f: impl FnOnce(E) -> F) -> RootError<F> {
+        match self {
+            RootError::CommitRecord(err) => RootError::CommitRecord(err),
+            RootError::SchemaMismatch { expected, found } => RootError::SchemaMismatch { expected, found },
+            RootError::NotFound(name) => RootError::NotFound(name),
+            RootError::Blob(err) => RootError::Blob(f(err)),
+        }
+    }
+}
+
+/// A commit within a journal.
+#[derive(Clone, Copy)]
+pub struct Commit<'p, 'v> {
+    mark: usize,
+    pile: TryPile<'p, 'v>,
+}
+
+impl fmt::Debug for Commit<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Commit")
+            .field("mark", &self.mark)
+            .field("record", &self.record())
+            .finish()
+    }
+}
+
+impl<'p, 'v> Commit<'p, 'v> {
+    /// Creates a new commit from the pile preceeding a mark.
+    ///
+    /// # Safety
+    ///
+    /// `pile` must consist of all bytes in the journal prior to the mark.
+    pub(crate) unsafe fn new_unchecked(mark: usize, pile: TryPile<'p, 'v>) -> Self {
+        Self { mark, pile }
+    }
+
+    /// Gets the word index of the mark ending this commit.
+    pub fn mark(&self) -> usize {
+        self.mark
+    }
+
+    /// Gets the pile as of this commit.
+    pub fn pile(&self) -> TryPile<'p, 'v> {
+        self.pile
+    }
+
+    /// Gets the offset of the commit record within the pile.
+    pub fn record_offset(&self) -> Result<usize, CommitRecordError> {
+        let bytes = self.pile.as_bytes();
+        let (signature_len, _) = sign::parse_signature(bytes)?;
+
+        (bytes.len() - signature_len).checked_sub(CommitRecord::LEN)
+                                     .ok_or(CommitRecordError::Truncated)
+    }
+
+    /// Gets the bytes of the commit record.
+    pub fn record_bytes(&self) -> Option<&'v [u8]> {
+        let start = self.record_offset().ok()?;
+        Some(&self.pile.as_bytes()[start .. start + CommitRecord::LEN])
+    }
+
+    /// Parses the commit record.
+    pub fn record(&self) -> Result<CommitRecord, CommitRecordError> {
+        let start = self.record_offset()?;
+        CommitRecord::from_bytes(&self.pile.as_bytes()[start .. start + CommitRecord::LEN])
+    }
+
+    /// Gets the signature of this commit, if it's signed.
+    pub fn signature(&self) -> Result<Option<CommitSignature<'v>>, CommitRecordError> {
+        sign::parse_signature(self.pile.as_bytes()).map(|(_, signature)| signature)
+    }
+
+    /// Returns `true` if this commit is signed by a key the verifier trusts.
+    pub fn verify_signature(&self, verifier: &dyn CommitVerifier) -> bool {
+        match (self.record(), self.signature()) {
+            (Ok(record), Ok(Some(signature))) => {
+                verifier.verify(signature.key_id, &record.digest(), signature.signature)
+            },
+            _ => false,
+        }
+    }
+
+    /// Gets the offset of the root, checking that it was saved as type `T`.
+    pub fn root_offset<T: ?Sized + Schema>(&self) -> Result<Offset<'p, 'v>, RootError<!>> {
+        let record = self.record()?;
+
+        let expected = T::fingerprint();
+        if record.fingerprint() == expected {
+            // SAFETY: the record was read from this commit's pile, which is branded 'p
+            Ok(unsafe { record.root().cast() })
+        } else {
+            Err(RootError::SchemaMismatch { expected, found: record.fingerprint() })
+        }
+    }
+
+    /// Validates and returns the root blob, checking that it was saved as type `T`.
+    pub fn try_root<T>(&self) -> Result<ValidBlob<'v, T>, RootError<GetValidBlobError<!, T::BlobError>>>
+        where T: ValidateBlob + Schema
+    {
+        let offset = self.root_offset::<T>().map_err(|err| err.map_blob(|never| never))?;
+
+        self.pile.get_valid_blob::<T>(offset, T::make_sized_metadata())
+                 .map_err(RootError::Blob)
+    }
+
+    /// Validates the root and everything reachable from it, checking that it was saved as type
+    /// `T`.
+    ///
+    /// Validation is subject to the limits of the pile, which commits inherit from their journal.
+    pub fn fsck_root<T>(&self) -> Result<FsckStats, RootError<FsckError>>
+        where T: Schema + ValidateChildren<Offset<'p, 'v>>
+    {
+        let offset = self.root_offset::<T>().map_err(|err| err.map_blob(|never| never))?;
+
+        Fsck::new(self.pile).validate_root::<T>(offset, T::make_sized_metadata())
+                            .map_err(RootError::Blob)
+    }
+
+    /// Loads the root directory, checking that the root was saved as one.
+    pub fn directory(&self) -> Result<Directory<'p, 'v>, RootError<DirectoryError>> {
+        let header = self.try_root::<DirectoryHeader>()
+                         .map_err(|err| err.map_blob(DirectoryError::Header))?;
+
+        Directory::load(self.pile, header.as_value())
+                  .map_err(|err| RootError::Blob(DirectoryError::Entries(err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_record_roundtrip() {
+        let record = CommitRecord::new(Offset::new(42).unwrap(), Fingerprint::new(0x1234));
+        let bytes = record.to_bytes();
+        assert_eq!(bytes.len(), CommitRecord::LEN);
+        assert_eq!(CommitRecord::from_bytes(&bytes), Ok(record));
+
+        assert_eq!(CommitRecord::from_bytes(&bytes[1..]), Err(CommitRecordError::Truncated));
+        assert_eq!(CommitRecord::from_bytes(&[0; CommitRecord::LEN]), Err(CommitRecordError::Offset));
+
+        let chained = record.with_chain(CommitDigest::new([1; 32]), CommitDigest::new([2; 32]));
+        let bytes = chained.to_bytes();
+        assert_eq!(CommitRecord::from_bytes(&bytes), Ok(chained));
+        assert_eq!(&bytes[.. CommitRecord::ROOT_LEN], &record.to_root_bytes()[..]);
+        assert_ne!(chained.digest(), record.digest());
+
+        assert_eq!(CommitRecord::from_root_bytes(&bytes[.. CommitRecord::ROOT_LEN]), Ok(record));
+    }
+}
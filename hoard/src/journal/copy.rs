@@ -0,0 +1,339 @@
+//! Copying of data from another pile into a journal.
+//!
+//! Data in one pile can't simply be pointed to from another: every `Offset` has to be rewritten
+//! to point into the destination. A `Copier` does that by loading each blob reachable from a
+//! source root, and saving it again into a journal transaction.
+//!
+//! Blobs that have already been copied are remembered by their source offset, so a copy of a
+//! new version of the source only writes what changed. What's remembered is tied to the journal
+//! the copies were written to: copies in any other journal, or in a transaction that was rolled
+//! back, are forgotten before copying again.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use super::*;
+
+use crate::pile::LoadError;
+
+/// Returned when copying fails.
+#[derive(Debug, Error)]
+pub enum CopyError {
+    #[error("source {0}")]
+    Load(#[from] LoadError),
+
+    #[error("source {0}")]
+    Root(#[from] RootError<!>),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Copies data from a source pile into journal transactions.
+///
+/// New entries are only remembered permanently once the transaction they were written in is
+/// committed. If it's rolled back or dropped instead, they're forgotten the next time the copier
+/// is used; `Copier::rollback()` forgets them right away.
+///
+/// Only copies in one journal are remembered at a time. Copying into another journal starts over.
+#[derive(Debug)]
+pub struct Copier<'s, 'sv> {
+    src: TryPile<'s, 'sv>,
+    copied: HashMap<(usize, usize), Offset<'static, 'static>>,
+
+    /// The id and generation of the journal the copies were written to.
+    dst: Option<(u64, u64)>,
+
+    /// Entries written in the transaction starting at `pending_start`, which may not be committed.
+    pending: Vec<(usize, usize)>,
+    pending_start: u64,
+}
+
+impl<'s, 'sv> Copier<'s, 'sv> {
+    pub fn new(src: TryPile<'s, 'sv>) -> Self {
+        Self {
+            src,
+            copied: HashMap::new(),
+            dst: None,
+            pending: vec![],
+            pending_start: 0,
+        }
+    }
+
+    /// Gets the source pile.
+    pub fn src(&self) -> TryPile<'s, 'sv> {
+        self.src
+    }
+
+    /// Returns the number of blobs copied so far, including uncommitted copies.
+    pub fn len(&self) -> usize {
+        self.copied.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the value at a source offset, and everything reachable from it, into a transaction.
+    ///
+    /// Returns the offset of the copy in the journal.
+    pub fn copy<T, H>(
+        &mut self,
+        tx: &mut Transaction<'_, '_, H>,
+        offset: Offset<'s, 'sv>,
+        metadata: T::Metadata,
+    ) -> Result<Offset<'static, 'static>, CopyError>
+        where T: ?Sized + SavePtr<Offset<'s, 'sv>, Offset<'static, 'static>>
+    {
+        self.sync(tx);
+
+        let mut saver = CopySaver {
+            copier: self,
+            writer: &mut tx.writer,
+            stack: RefCell::new(vec![]),
+        };
+
+        match saver.try_save::<T>(&offset, metadata)? {
+            Ok(dst) => Ok(dst),
+            Err(mut poll) => save_deep(&mut saver, &mut poll),
+        }
+    }
+
+    /// Copies a root, making it the root the transaction will commit.
+    pub fn copy_root<T, H>(
+        &mut self,
+        tx: &mut Transaction<'_, '_, H>,
+        offset: Offset<'s, 'sv>,
+        metadata: T::Metadata,
+    ) -> Result<Offset<'static, 'static>, CopyError>
+        where T: ?Sized + SavePtr<Offset<'s, 'sv>, Offset<'static, 'static>> + Schema
+    {
+        let root = self.copy::<T, H>(tx, offset, metadata)?;
+        tx.record = Some(CommitRecord::new(root, T::fingerprint()));
+        Ok(root)
+    }
+
+    /// Commits a transaction, remembering everything copied in it.
+    ///
+    /// If the commit fails, the copies are forgotten.
+    pub fn commit<H>(&mut self, tx: Transaction<'_, '_, H>) -> io::Result<Offset<'static, 'static>> {
+        self.sync(&tx);
+
+        match tx.commit() {
+            Ok(root) => {
+                self.pending.clear();
+                Ok(root)
+            },
+            Err(err) => {
+                self.rollback();
+                Err(err)
+            },
+        }
+    }
+
+    /// Forgets everything copied since the last commit.
+    pub fn rollback(&mut self) {
+        for key in self.pending.drain(..) {
+            self.copied.remove(&key);
+        }
+    }
+
+    /// Forgets any copies that may no longer be in the journal `tx` writes to.
+    fn sync<H>(&mut self, tx: &Transaction<'_, '_, H>) {
+        let journal = &*tx.writer.journal;
+        let dst = (journal.id, journal.generation);
+
+        match self.dst {
+            Some(prev) if prev == dst => {
+                if self.pending_start != tx.start_len {
+                    // The pending entries were committed by an earlier transaction: had it been
+                    // rolled back, the generation would have changed.
+                    self.pending.clear();
+                }
+            },
+            Some((id, _)) if id == journal.id => {
+                // Truncated since, so the pending entries may be gone. Committed entries are
+                // always before the start of any later transaction, so they're still there.
+                self.rollback();
+            },
+            _ => {
+                self.copied.clear();
+                self.pending.clear();
+            },
+        }
+        self.dst = Some(dst);
+        self.pending_start = tx.start_len;
+    }
+}
+
+/// The `Saver` used by `Copier`.
+///
+/// Each source blob that has to be copied is pushed onto a stack by `try_save_raw()`. Children are
+/// always finished before their parents, so the top of the stack is the source of the blob being
+/// finished by `finish_save()`.
+struct CopySaver<'c, 's, 'sv, 'w, 'a, 'p, H> {
+    copier: &'c mut Copier<'s, 'sv>,
+    writer: &'w mut JournalWriter<'a, 'p, H>,
+    stack: RefCell<Vec<(usize, usize)>>,
+}
+
+impl<'s, 'sv, H> Saver for CopySaver<'_, 's, 'sv, '_, '_, '_, H> {
+    type SrcPtr = Offset<'s, 'sv>;
+    type DstPtr = Offset<'static, 'static>;
+    type Error = CopyError;
+
+    fn try_save_raw<R, T: ?Sized + ValidateBlob>(&self,
+        ptr: &Offset<'s, 'sv>,
+        metadata: T::Metadata,
+        f: impl FnOnce(ValidBlob<T>, &TryPile<'s, 'sv>) -> R,
+    ) -> Result<Result<Offset<'static, 'static>, R>, Self::Error>
+    {
+        let r = self.copier.src.try_copy_blob(&self.copier.copied, &self.stack, *ptr, metadata, f)?;
+        Ok(r)
+    }
+
+    fn finish_save<T>(&mut self, value_poll: &T) -> Result<Offset<'static, 'static>, Self::Error>
+        where T: EncodeBlob
+    {
+        let bytes = value_poll.encode_blob(vec![]).into_ok();
+
+        let dst = Offset::new(self.writer.write_blob(&bytes).get()).expect("overflow");
+
+        if let Some(key) = self.stack.get_mut().pop() {
+            self.copier.copied.insert(key, dst);
+            self.copier.pending.push(key);
+        }
+        Ok(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempfile;
+
+    use crate::bag::Bag;
+    use crate::pile::fsck::{Fsck, FsckStats};
+    use crate::pile::LoadErrorKind;
+
+    type OffsetBag<'p, 'v, T> = Bag<T, Offset<'p, 'v>>;
+
+    #[test]
+    fn copy_root() -> Result<(), CopyError> {
+        // a u8, and a pair of bags both pointing to it
+        let buf = [42u8,
+                   1,0,0,0,0,0,0,0,
+                   1,0,0,0,0,0,0,0];
+        let src = unsafe { TryPile::new_unchecked(&buf) };
+        let src_root = Offset::new(1).unwrap();
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut copier = Copier::new(src);
+
+        let mut tx = journal.begin()?;
+        let root1 = copier.copy_root::<[OffsetBag<u8>; 2], _>(&mut tx, src_root, ())?;
+        copier.commit(tx)?;
+
+        // the shared u8 was only copied once
+        assert_eq!(copier.len(), 2);
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        let root = commit.try_root::<[OffsetBag<u8>; 2]>().unwrap();
+        let (bag1, bag2) = root.as_bytes().split_at(8);
+        assert_eq!(bag1, bag2);
+
+        let stats = Fsck::new(commit.pile())
+                         .validate_root::<[OffsetBag<u8>; 2]>(root1, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 2, bytes: 8 + 8 + 1, max_depth: 2 });
+
+        // copying again writes nothing new
+        let mut tx = journal.begin()?;
+        let root2 = copier.copy_root::<[OffsetBag<u8>; 2], _>(&mut tx, src_root, ())?;
+        copier.commit(tx)?;
+        assert_eq!(root1, root2);
+        assert_eq!(copier.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_rollback() -> Result<(), CopyError> {
+        let buf = [42u8,
+                   1,0,0,0,0,0,0,0];
+        let src = unsafe { TryPile::new_unchecked(&buf) };
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut copier = Copier::new(src);
+
+        let mut tx = journal.begin()?;
+        copier.copy::<OffsetBag<u8>, _>(&mut tx, Offset::new(1).unwrap(), ())?;
+        tx.rollback()?;
+        copier.rollback();
+        assert!(copier.is_empty());
+
+        // a bag pointing past the end of the source
+        let buf = [42u8,
+                   201,0,0,0,0,0,0,0];
+        let src = unsafe { TryPile::new_unchecked(&buf) };
+        let mut copier = Copier::new(src);
+        let mut tx = journal.begin()?;
+        match copier.copy::<OffsetBag<u8>, _>(&mut tx, Offset::new(1).unwrap(), ()) {
+            Err(CopyError::Load(LoadError { offset: 100, kind: LoadErrorKind::OutOfRange, .. })) => {},
+            r => panic!("{:?}", r),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_forgets_stale() -> Result<(), CopyError> {
+        let buf = [42u8,
+                   1,0,0,0,0,0,0,0];
+        let src = unsafe { TryPile::new_unchecked(&buf) };
+        let src_root = Offset::new(1).unwrap();
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut copier = Copier::new(src);
+
+        // dropped without telling the copier
+        let mut tx = journal.begin()?;
+        copier.copy::<OffsetBag<u8>, _>(&mut tx, src_root, ())?;
+        drop(tx);
+
+        // so the copies are written again
+        let mut tx = journal.begin()?;
+        let root = copier.copy_root::<OffsetBag<u8>, _>(&mut tx, src_root, ())?;
+        tx.commit()?;
+
+        let snapshot = journal.snapshot();
+        let stats = Fsck::new(snapshot.last_commit().unwrap().pile())
+                         .validate_root::<OffsetBag<u8>>(root, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 2, bytes: 8 + 1, max_depth: 2 });
+
+        // committed without telling the copier, and rolling back a later transaction doesn't
+        // forget them
+        let mut tx = journal.begin()?;
+        assert_eq!(copier.copy::<OffsetBag<u8>, _>(&mut tx, src_root, ())?, root);
+        copier.rollback();
+        tx.rollback()?;
+        assert_eq!(copier.len(), 2);
+
+        // another journal gets copies of its own
+        let mut other = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut tx = other.begin()?;
+        let other_root = copier.copy_root::<OffsetBag<u8>, _>(&mut tx, src_root, ())?;
+        copier.commit(tx)?;
+
+        let snapshot = other.snapshot();
+        let stats = Fsck::new(snapshot.last_commit().unwrap().pile())
+                         .validate_root::<OffsetBag<u8>>(other_root, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 2, bytes: 8 + 1, max_depth: 2 });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+//! Named roots.
+//!
+//! A commit points at exactly one root. Applications that keep several independent datasets in
+//! one journal can instead commit a root directory: a map from names to roots, each recorded
+//! along with the fingerprint of the type it was saved as. The directory is committed as a whole,
+//! so updating several named roots in one transaction is atomic.
+//!
+//! A directory is saved as two blobs. The entries blob holds every entry, sorted by name, each
+//! encoded as the length of the name, the root offset and fingerprint of a `CommitRecord`, and the
+//! UTF-8 name itself. The `DirectoryHeader` points to the entries blob, and is the root recorded
+//! by the commit.
+
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::str::{self, Utf8Error};
+
+use thiserror::Error;
+
+use leint::Le;
+
+use crate::pointee::Pointee;
+use crate::blob::*;
+use crate::offset::{Offset, ValidateOffsetBlobError};
+use crate::scalar::Scalar;
+use crate::pile::{TryPile, GetValidBlobError};
+use crate::fingerprint::{Schema, SchemaHasher};
+
+use super::commit::{CommitRecord, CommitRecordError, RootError};
+
+/// The length of an encoded entry, excluding the name.
+const ENTRY_HEADER_LEN: usize = mem::size_of::<u64>() + CommitRecord::ROOT_LEN;
+
+/// The root of a commit that holds named roots.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryHeader {
+    entries: Offset<'static, 'static>,
+    len: Le<u64>,
+}
+
+impl DirectoryHeader {
+    pub fn new(entries: Offset, len: usize) -> Self {
+        Self {
+            entries: entries.to_static(),
+            len: Le::new(len as u64),
+        }
+    }
+
+    /// Gets the offset of the entries blob.
+    pub fn entries(&self) -> Offset<'static, 'static> {
+        self.entries
+    }
+
+    /// Gets the length of the entries blob, in bytes.
+    pub fn len(&self) -> usize {
+        self.len.get() as usize
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut r = Scalar::encode_blob(&self.entries, vec![]).into_ok();
+        r.extend_from_slice(&self.len.get().to_le_bytes());
+        r
+    }
+}
+
+unsafe impl Persist for DirectoryHeader {}
+
+unsafe impl ValidateBlob for DirectoryHeader {
+    type BlobError = ValidateOffsetBlobError;
+
+    fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+        Ok(<Offset>::blob_layout().extend(<Le<u64>>::blob_layout()))
+    }
+
+    fn validate_blob<'a>(blob: Blob<'a, Self>, ignore_padding: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
+        let mut fields = blob.validate_fields(ignore_padding);
+
+        fields.validate_blob::<Offset<'static, 'static>>()?;
+        fields.validate_blob::<Le<u64>>().into_ok();
+
+        unsafe { Ok(fields.finish()) }
+    }
+}
+
+impl Schema for DirectoryHeader {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("directory")
+              .field::<Offset>("entries")
+              .field::<Le<u64>>("len");
+    }
+}
+
+/// The encoded entries of a directory.
+#[repr(transparent)]
+pub struct DirectoryBytes([u8]);
+
+impl DirectoryBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+unsafe impl Pointee for DirectoryBytes {
+    type Metadata = Le<u64>;
+    type LayoutError = !;
+
+    fn metadata(this: &Self) -> Le<u64> {
+        Le::new(this.0.len() as u64)
+    }
+
+    #[inline(always)]
+    fn make_fat_ptr(thin: *const (), len: Le<u64>) -> *const Self {
+        ptr::slice_from_raw_parts(thin as *const u8, len.get() as usize) as *const Self
+    }
+
+    #[inline(always)]
+    fn make_fat_ptr_mut(thin: *mut (), len: Le<u64>) -> *mut Self {
+        ptr::slice_from_raw_parts_mut(thin as *mut u8, len.get() as usize) as *mut Self
+    }
+}
+
+/// Returned when the entries of a directory are invalid.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ValidateDirectoryError {
+    #[error("directory entry truncated")]
+    Truncated,
+
+    #[error("invalid directory entry: {0}")]
+    Record(#[from] CommitRecordError),
+
+    #[error("directory entry name isn't valid UTF-8: {0}")]
+    Name(#[from] Utf8Error),
+
+    #[error("directory entries aren't sorted by name")]
+    Order,
+}
+
+unsafe impl ValidateBlob for DirectoryBytes {
+    type BlobError = ValidateDirectoryError;
+
+    fn try_blob_layout(len: Le<u64>) -> Result<BlobLayout, !> {
+        Ok(BlobLayout::new(len.get() as usize))
+    }
+
+    fn validate_blob<'a>(blob: Blob<'a, Self>, _: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
+        let mut prev = None;
+        for entry in Entries::new(blob.as_bytes()) {
+            let (name, _) = entry?;
+
+            // Strictly ascending, so names are also unique.
+            if prev.map_or(false, |prev| prev >= name) {
+                return Err(ValidateDirectoryError::Order);
+            }
+            prev = Some(name);
+        }
+
+        unsafe { Ok(blob.assume_valid()) }
+    }
+}
+
+/// Parses encoded directory entries.
+struct Entries<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Entries<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { rest: buf }
+    }
+
+    fn parse_entry(buf: &'a [u8]) -> Result<((&'a str, CommitRecord), &'a [u8]), ValidateDirectoryError> {
+        if buf.len() < ENTRY_HEADER_LEN {
+            return Err(ValidateDirectoryError::Truncated);
+        }
+        let (header, rest) = buf.split_at(ENTRY_HEADER_LEN);
+        let (name_len, record) = header.split_at(mem::size_of::<u64>());
+
+        let record = CommitRecord::from_root_bytes(record)?;
+
+        let name_len = u64::from_le_bytes(name_len.try_into().unwrap());
+        let name_len = usize::try_from(name_len).ok()
+                                                .filter(|len| *len <= rest.len())
+                                                .ok_or(ValidateDirectoryError::Truncated)?;
+        let (name, rest) = rest.split_at(name_len);
+
+        Ok(((str::from_utf8(name)?, record), rest))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<(&'a str, CommitRecord), ValidateDirectoryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        match Self::parse_entry(self.rest) {
+            Ok((entry, rest)) => {
+                self.rest = rest;
+                Some(Ok(entry))
+            },
+            Err(err) => {
+                self.rest = &[];
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+/// Encodes directory entries.
+pub fn encode_entries<'a>(entries: impl IntoIterator<Item = (&'a str, CommitRecord)>) -> Vec<u8> {
+    let mut r = vec![];
+    for (name, record) in entries {
+        r.extend_from_slice(&(name.len() as u64).to_le_bytes());
+        r.extend_from_slice(&record.to_root_bytes());
+        r.extend_from_slice(name.as_bytes());
+    }
+    r
+}
+
+/// Returned when a commit's root directory can't be loaded.
+#[derive(Debug, Error)]
+pub enum DirectoryError {
+    #[error("invalid directory header: {0:?}")]
+    Header(GetValidBlobError<!, ValidateOffsetBlobError>),
+
+    #[error("invalid directory entries: {0:?}")]
+    Entries(GetValidBlobError<!, ValidateDirectoryError>),
+}
+
+/// A validated root directory.
+#[derive(Clone, Copy)]
+pub struct Directory<'p, 'v> {
+    pile: TryPile<'p, 'v>,
+    entries: &'v [u8],
+}
+
+impl fmt::Debug for Directory<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter())
+            .finish()
+    }
+}
+
+impl<'p, 'v> Directory<'p, 'v> {
+    /// Loads the directory a header points to.
+    pub fn load(pile: TryPile<'p, 'v>, header: &DirectoryHeader)
+        -> Result<Self, GetValidBlobError<!, ValidateDirectoryError>>
+    {
+        // SAFETY: get_valid_blob() checks the offset against the pile anyway
+        let entries = unsafe { header.entries().cast() };
+        let blob = pile.get_valid_blob::<DirectoryBytes>(entries, header.len)?;
+        Ok(Self {
+            pile,
+            entries: blob.as_bytes(),
+        })
+    }
+
+    /// Iterates over the entries, in order of name.
+    pub fn iter(&self) -> impl Iterator<Item = (&'v str, CommitRecord)> + 'v {
+        Entries::new(self.entries).map(|entry| entry.expect("directory already validated"))
+    }
+
+    /// Gets the number of entries.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Gets the record of a named root.
+    pub fn get(&self, name: &str) -> Option<CommitRecord> {
+        self.iter().find(|(entry, _)| *entry == name)
+                   .map(|(_, record)| record)
+    }
+
+    /// Copies the entries into a map.
+    pub fn to_map(&self) -> BTreeMap<String, CommitRecord> {
+        self.iter().map(|(name, record)| (name.to_owned(), record))
+                   .collect()
+    }
+
+    /// Gets the offset of a named root, checking that it was saved as type `T`.
+    pub fn root_offset<T: ?Sized + Schema>(&self, name: &str) -> Result<Offset<'p, 'v>, RootError<!>> {
+        let record = self.get(name).ok_or_else(|| RootError::NotFound(name.to_owned()))?;
+
+        let expected = T::fingerprint();
+        if record.fingerprint() == expected {
+            // SAFETY: the directory was loaded from self.pile, and its entries point into it
+            Ok(unsafe { record.root().cast() })
+        } else {
+            Err(RootError::SchemaMismatch { expected, found: record.fingerprint() })
+        }
+    }
+
+    /// Validates and returns a named root blob, checking that it was saved as type `T`.
+    pub fn get_root<T>(&self, name: &str) -> Result<ValidBlob<'v, T>, RootError<GetValidBlobError<!, T::BlobError>>>
+        where T: ValidateBlob + Schema
+    {
+        let offset = self.root_offset::<T>(name).map_err(|err| err.map_blob(|never| never))?;
+
+        self.pile.get_valid_blob::<T>(offset, T::make_sized_metadata())
+                 .map_err(RootError::Blob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fingerprint::Fingerprint;
+
+    fn record(offset: usize) -> CommitRecord {
+        CommitRecord::new(Offset::new(offset).unwrap(), Fingerprint::new(offset as u64))
+    }
+
+    fn validate(bytes: &[u8]) -> Result<(), ValidateDirectoryError> {
+        let blob = unsafe { Blob::<DirectoryBytes>::new_unchecked(bytes, Le::new(bytes.len() as u64)) };
+        DirectoryBytes::validate_blob(blob, false).map(drop)
+    }
+
+    #[test]
+    fn entries_roundtrip() {
+        let entries = [("a", record(1)), ("bb", record(2)), ("c", record(3))];
+        let bytes = encode_entries(entries.iter().copied());
+        assert_eq!(bytes.len(), ENTRY_HEADER_LEN * 3 + 4);
+        assert_eq!(validate(&bytes), Ok(()));
+        assert_eq!(validate(&[]), Ok(()));
+
+        let header = DirectoryHeader::new(Offset::new(0).unwrap(), bytes.len());
+        let buf = [&bytes[..], &header.to_bytes()].concat();
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let blob = pile.get_valid_blob::<DirectoryHeader>(Offset::new(bytes.len()).unwrap(), ()).unwrap();
+        assert_eq!(blob.as_value(), &header);
+
+        let directory = Directory::load(pile, &header).unwrap();
+        assert_eq!(directory.len(), 3);
+        assert_eq!(directory.iter().collect::<Vec<_>>(), entries);
+        assert_eq!(directory.get("bb"), Some(record(2)));
+        assert_eq!(directory.get("b"), None);
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let bytes = encode_entries(vec![("a", record(1))]);
+        assert_eq!(validate(&bytes[.. ENTRY_HEADER_LEN - 1]), Err(ValidateDirectoryError::Truncated));
+        assert_eq!(validate(&bytes[.. ENTRY_HEADER_LEN]), Err(ValidateDirectoryError::Truncated));
+
+        let mut bad_name = bytes.clone();
+        *bad_name.last_mut().unwrap() = 0xff;
+        assert!(matches!(validate(&bad_name), Err(ValidateDirectoryError::Name(_))));
+
+        let mut bad_offset = bytes.clone();
+        bad_offset[mem::size_of::<u64>()] = 0;
+        assert_eq!(validate(&bad_offset), Err(ValidateDirectoryError::Record(CommitRecordError::Offset)));
+
+        let unsorted = encode_entries(vec![("b", record(1)), ("a", record(2))]);
+        assert_eq!(validate(&unsorted), Err(ValidateDirectoryError::Order));
+
+        let duplicate = encode_entries(vec![("a", record(1)), ("a", record(2))]);
+        assert_eq!(validate(&duplicate), Err(ValidateDirectoryError::Order));
+    }
+}
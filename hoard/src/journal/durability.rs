@@ -0,0 +1,220 @@
+//! Durability of commits.
+//!
+//! Writing a mark only hands the commit to the OS: until the file is synced, a committed root can
+//! vanish on power loss. Syncing after every commit is slow, so a journal can instead use group
+//! commit, where commits made while a sync is in progress all wait for the next sync, sharing it.
+//!
+//! Group commit only helps when commits are made concurrently, so `Transaction::commit_deferred()`
+//! returns a `PendingSync` that can be waited on after the journal has been released to other
+//! writers.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How commits are made durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Commits are never synced.
+    None,
+
+    /// Every commit is synced with `fdatasync`.
+    Sync,
+
+    /// Concurrent commits share a single `fdatasync`.
+    Group,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::None
+    }
+}
+
+/// Commit latency statistics.
+///
+/// Latency is measured from the start of the commit until it's durable, including any time spent
+/// waiting for other commits' syncs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitStats {
+    pub commits: u64,
+    pub syncs: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl CommitStats {
+    /// Gets the mean commit latency, if there have been any commits.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        match self.commits {
+            0 => None,
+            n => {
+                // Dividing the Duration itself would truncate n to a u32.
+                let mean = self.total_latency.as_nanos() / n as u128;
+                Some(Duration::new((mean / 1_000_000_000) as u64, (mean % 1_000_000_000) as u32))
+            },
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        self.commits += 1;
+        self.total_latency += latency;
+        self.max_latency = cmp::max(self.max_latency, latency);
+    }
+}
+
+/// Syncs a journal file on behalf of its commits.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    fd: File,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    /// The number of commits written.
+    written: u64,
+
+    /// The number of commits known to be durable.
+    synced: u64,
+
+    syncing: bool,
+    stats: CommitStats,
+}
+
+impl Syncer {
+    pub(crate) fn new(fd: &File) -> io::Result<Self> {
+        Ok(Self {
+            fd: fd.try_clone()?,
+            state: Default::default(),
+            synced: Condvar::new(),
+        })
+    }
+
+    /// Counts a written commit, returning its sequence number.
+    pub(crate) fn written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Waits until a commit is durable, syncing if no other commit is.
+    fn sync_through(&self, seq: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            } else if state.syncing {
+                state = self.synced.wait(state).unwrap();
+            } else {
+                // Everything written by now is covered by this sync, not just our commit.
+                let target = state.written;
+                state.syncing = true;
+                drop(state);
+
+                let r = self.fd.sync_data();
+
+                state = self.state.lock().unwrap();
+                state.syncing = false;
+                if r.is_ok() {
+                    state.synced = cmp::max(state.synced, target);
+                    state.stats.syncs += 1;
+                }
+                self.synced.notify_all();
+                r?;
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CommitStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// A commit that has been written, but may not be durable yet.
+#[derive(Debug)]
+#[must_use = "the commit may not be durable until waited on"]
+pub struct PendingSync {
+    syncer: Arc<Syncer>,
+    seq: u64,
+    durability: Durability,
+    start: Instant,
+}
+
+impl PendingSync {
+    pub(crate) fn new(syncer: Arc<Syncer>, seq: u64, durability: Durability, start: Instant) -> Self {
+        Self { syncer, seq, durability, start }
+    }
+
+    /// Waits until the commit is durable, as required by the journal's `Durability`.
+    pub fn wait(self) -> io::Result<()> {
+        match self.durability {
+            Durability::None => {},
+            // Both wait for a sync covering the commit. The difference is that with Sync, commits
+            // are made one at a time, so each gets a sync of its own.
+            Durability::Sync | Durability::Group => self.syncer.sync_through(self.seq)?,
+        }
+
+        let latency = self.start.elapsed();
+        self.syncer.state.lock().unwrap().stats.record(latency);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn group_sync() -> io::Result<()> {
+        let syncer = Arc::new(Syncer::new(&tempfile::tempfile()?)?);
+
+        let seqs: Vec<u64> = (0 .. 8).map(|_| syncer.written()).collect();
+        let threads: Vec<_> = seqs.into_iter().map(|seq| {
+            let syncer = Arc::clone(&syncer);
+            thread::spawn(move || {
+                PendingSync::new(syncer, seq, Durability::Group, Instant::now()).wait()
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        // every commit was written before the first sync, so they can all share it
+        let stats = syncer.stats();
+        assert_eq!(stats.commits, 8);
+        assert_eq!(stats.syncs, 1);
+        assert!(stats.max_latency <= stats.total_latency);
+
+        // already durable
+        PendingSync::new(Arc::clone(&syncer), 1, Durability::Group, Instant::now()).wait()?;
+        assert_eq!(syncer.stats().syncs, stats.syncs);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mean_latency() {
+        let mut stats = CommitStats::default();
+        assert_eq!(stats.mean_latency(), None);
+
+        stats.record(Duration::from_millis(10));
+        stats.record(Duration::from_millis(30));
+        assert_eq!(stats.mean_latency(), Some(Duration::from_millis(20)));
+        assert_eq!(stats.max_latency, Duration::from_millis(30));
+
+        // more commits than fit in a u32
+        let stats = CommitStats {
+            commits: 1 << 33,
+            total_latency: Duration::from_secs(3 << 33),
+            ..CommitStats::default()
+        };
+        assert_eq!(stats.mean_latency(), Some(Duration::from_secs(3)));
+    }
+}
@@ -1,6 +1,7 @@
 //! Blob storage in append-only files.
 
 use std::cmp;
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write, Seek, SeekFrom};
@@ -9,29 +10,79 @@ use std::mem::{self, MaybeUninit};
 use std::path::Path;
 use std::slice;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::Instant;
 
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
 use memmap::Mmap;
+use thiserror::Error;
 
 use crate::Le;
+use crate::bag::Bag;
 use crate::pointee::Pointee;
+use crate::blob::*;
 use crate::offset::{OffsetMut, Offset};
-use crate::pile::TryPile;
-use crate::save::{self, SavePtr, SaveBlob, Save, SavePoll};
+use crate::pile::{TryPile, GetValidBlobError};
+use crate::pile::checksum::Checksum;
+use crate::pile::encrypted::{Cipher, Key};
+use crate::ptr::Ptr;
+use crate::save::*;
+use crate::save::budget::{BudgetSaver, SaveBudget};
+use crate::fingerprint::{Fingerprint, Schema};
+use crate::progress::{Cancelled, Monitor};
+use crate::validate::ValidateLimits;
 
 mod wordoffset;
 use self::wordoffset::{Word, WordOffset};
 
+pub mod commit;
+use self::commit::*;
+
+pub mod copy;
+use self::copy::{Copier, CopyError};
+
+pub mod directory;
+
+pub mod chain;
+use self::chain::{CommitDigest, CommitHasher, ChainError, ChainErrorKind};
+
+pub mod sign;
+use self::sign::{CommitSigner, CommitVerifier};
+
+pub mod durability;
+use self::durability::{Durability, CommitStats, PendingSync, Syncer};
+use self::directory::{DirectoryHeader, encode_entries};
+
+mod watch;
+use self::watch::Watcher;
+
 #[derive(Debug)]
 pub struct Journal<'p, H = ()> {
     marker: PhantomData<fn(&'p ()) -> &'p H>,
+    fd: Arc<File>,
     mapping: Arc<Mmap>,
+
+    /// The index of the last mark in the mapping, if any.
+    ///
+    /// Marks are only looked for up to here, so the uncommitted tail of the mapping, which may be
+    /// truncated at any time, is never touched.
+    last_mark: Option<usize>,
+
+    verifier: Option<Arc<dyn CommitVerifier>>,
+    limits: ValidateLimits,
 }
 
 impl<H> Clone for Journal<'_, H> {
     fn clone(&self) -> Self {
         Self {
             marker: PhantomData,
+            fd: self.fd.clone(),
             mapping: self.mapping.clone(),
+            last_mark: self.last_mark,
+            verifier: self.verifier.clone(),
+            limits: self.limits,
         }
     }
 }
@@ -45,22 +96,105 @@ impl<'p, H> Journal<'p, H> {
     }
 
     pub fn open_fd(fd: &File) -> io::Result<Self> {
-        Ok(Self {
+        let mut this = Self {
             marker: PhantomData,
+            fd: Arc::new(fd.try_clone()?),
             mapping: Self::make_mapping(fd)?,
-        })
+            last_mark: None,
+            verifier: None,
+            limits: ValidateLimits::default(),
+        };
+        this.last_mark = this.scan_last_mark();
+
+        let (header, _) = this.mapping_parts();
+        if Checksum::from_tag(header.checksum_tag()).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown journal checksum"));
+        }
+        if header.encryption_tag() > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown journal encryption"));
+        }
+        Ok(this)
+    }
+
+    /// Only exposes commits signed by keys the verifier trusts.
+    ///
+    /// Unsigned commits, and commits signed by untrusted keys, are skipped by `commits()`,
+    /// `roots()`, and `last_commit()`.
+    pub fn with_verifier(self, verifier: impl CommitVerifier + 'static) -> Self {
+        Self {
+            verifier: Some(Arc::new(verifier)),
+            ..self
+        }
+    }
+
+    /// Returns true if the blobs in this journal are encrypted.
+    ///
+    /// The blobs in an encrypted journal have to be loaded with an `EncryptedPile`.
+    pub fn is_encrypted(&self) -> bool {
+        let (header, _) = self.mapping_parts();
+        header.encryption_tag() != 0
+    }
+
+    /// Gets the random salt mixed into the nonces of an encrypted journal.
+    ///
+    /// A `Cipher` for this journal has to be created with it.
+    pub fn salt(&self) -> u64 {
+        let (header, _) = self.mapping_parts();
+        header.salt()
+    }
+
+    /// Gets the checksum written after every blob in this journal.
+    pub fn checksum(&self) -> Checksum {
+        let (header, _) = self.mapping_parts();
+
+        // validated in open_fd()
+        Checksum::from_tag(header.checksum_tag()).unwrap()
+    }
+
+    /// Sets the limits that loads from the piles of this journal's commits are subject to.
+    ///
+    /// Journals from untrusted sources should be opened with limits, as roots and everything
+    /// reachable from them are loaded from those piles.
+    pub fn with_limits(self, limits: ValidateLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Gets the bytes of the journal after the header.
+    ///
+    /// Unlike the piles of `commits()`, this includes everything written after the last commit.
+    pub fn as_bytes(&self) -> &[u8] {
+        let (_, bytes) = self.mapping_parts();
+        bytes
     }
 
     fn make_mapping(fd: &File) -> io::Result<Arc<Mmap>> {
         let mapping = unsafe { Mmap::map(fd)? };
 
         if mapping.len() < mem::size_of::<JournalHeader<H>>() {
-            todo!()
+            Err(io::Error::new(io::ErrorKind::InvalidData, "journal shorter than its header"))
         } else {
             Ok(mapping.into())
         }
     }
 
+    /// Remaps the journal, keeping the last mark only if it's still mapped.
+    fn remap(&mut self) -> io::Result<()> {
+        self.mapping = Self::make_mapping(&self.fd)?;
+        if self.last_mark.map_or(false, |idx| idx >= self.words().len()) {
+            // Committed bytes were truncated, which writers never do.
+            self.last_mark = self.scan_last_mark();
+        }
+        Ok(())
+    }
+
+    /// Finds the last mark by scanning the mapping backwards.
+    fn scan_last_mark(&self) -> Option<usize> {
+        self.words().iter()
+            .enumerate()
+            .rev()
+            .find(|(idx, word)| word.get() == !(*idx as u64))
+            .map(|(idx, _)| idx)
+    }
 
     fn mapping_parts(&self) -> (&JournalHeader<H>, &[u8]) {
         let (header, rest) = self.mapping.split_at(mem::size_of::<JournalHeader<H>>());
@@ -79,7 +213,8 @@ impl<'p, H> Journal<'p, H> {
 
     #[must_use]
     pub fn marks(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
-        self.words().into_iter()
+        let len = self.last_mark.map_or(0, |idx| idx + 1);
+        self.words()[.. len].iter()
             .enumerate()
             .filter_map(move |(idx, word)| {
                 if word.get() == !(idx as u64) {
@@ -91,19 +226,211 @@ impl<'p, H> Journal<'p, H> {
     }
 
     pub fn roots<'v>(&'v self) -> impl DoubleEndedIterator<Item = TryPile<'p, 'v>> {
+        self.commits().map(|commit| commit.pile())
+    }
+
+    /// Returns the commits in this journal, oldest first.
+    ///
+    /// If the journal has a verifier, only commits it trusts are returned.
+    pub fn commits<'v>(&'v self) -> impl DoubleEndedIterator<Item = Commit<'p, 'v>> {
+        self.all_commits().filter(move |commit| {
+            self.verifier.as_ref().map_or(true, |verifier| commit.verify_signature(&**verifier))
+        })
+    }
+
+    /// Returns every commit in this journal, regardless of signatures.
+    fn all_commits<'v>(&'v self) -> impl DoubleEndedIterator<Item = Commit<'p, 'v>> {
         self.marks().map(move |idx| {
-            let slice = &self.mapping[mem::size_of::<JournalHeader<H>>() .. idx];
-            unsafe { TryPile::new_unchecked(slice) }
+            let (_, bytes) = self.mapping_parts();
+            let slice = &bytes[0 .. idx * mem::size_of::<Word>()];
+            let pile = unsafe { TryPile::new_unchecked(slice) }.with_checksum(self.checksum())
+                                                              .with_limits(self.limits);
+            unsafe { Commit::new_unchecked(idx, pile) }
         })
     }
+
+    /// Returns the most recent commit, if any.
+    pub fn last_commit<'v>(&'v self) -> Option<Commit<'p, 'v>> {
+        self.commits().next_back()
+    }
+
+    /// Checks for new commits, remapping the journal if there are any.
+    ///
+    /// Only the words written since the last commit are read, and the journal is only remapped if
+    /// a new mark is found among them. Returns `true` if the journal was refreshed with new
+    /// commits.
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        let header_len = mem::size_of::<JournalHeader<H>>();
+        let word_len = mem::size_of::<Word>();
+
+        let len: usize = self.fd.metadata()?.len().try_into()
+                             .map_err(|_| io::Error::new(io::ErrorKind::Other, "journal too large to map"))?;
+        if len < self.mapping.len() {
+            // Uncommitted bytes were truncated, and may have been in our mapping.
+            self.remap()?;
+            return Ok(false);
+        }
+
+        // Anything after the last mark may have been rewritten since we mapped it, so it's read
+        // rather than looked at through the mapping.
+        let start = self.last_mark.map_or(0, |idx| idx + 1);
+        let end = len.saturating_sub(header_len) / word_len;
+        if end <= start {
+            return Ok(false);
+        }
+
+        let mut buf = vec![0; (end - start) * word_len];
+        read_exact_at(&self.fd, &mut buf, (header_len + start * word_len) as u64)?;
+
+        let found = buf.chunks(word_len)
+                       .enumerate()
+                       .rev()
+                       .find(|(i, word)| u64::from_le_bytes((*word).try_into().unwrap()) == !((start + i) as u64))
+                       .map(|(i, _)| start + i);
+        match found {
+            Some(idx) => {
+                self.last_mark = Some(idx);
+                self.remap()?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Blocks until there is a commit whose mark is after `after`, returning the mark of the most
+    /// recent commit.
+    ///
+    /// Pass `0` to wait for the first commit. Readers can follow a writer by passing the mark
+    /// returned by the previous call. If the journal has a verifier, only commits it trusts are
+    /// waited for.
+    pub fn wait_for_commit(&mut self, after: usize) -> io::Result<usize> {
+        // Watch first, so a commit made while we check isn't missed.
+        let mut watcher = Watcher::new(&self.fd);
+        loop {
+            self.refresh()?;
+            if let Some(mark) = self.last_commit().map(|commit| commit.mark()).filter(|mark| *mark > after) {
+                return Ok(mark);
+            }
+            watcher.wait()?;
+        }
+    }
+
+    /// Verifies the hash chain of every commit, oldest first.
+    ///
+    /// Returns the digest of the last commit record, which commits to the entire history of the
+    /// journal, or `None` if there are no commits. Otherwise the first commit that diverges from
+    /// the chain is reported.
+    pub fn verify_chain(&self) -> Result<Option<CommitDigest>, ChainError> {
+        let word_len = mem::size_of::<Word>();
+        let (_, bytes) = self.mapping_parts();
+
+        let mut prev = None;
+        let mut start = 0;
+        for commit in self.all_commits() {
+            let mark = commit.mark();
+            let err = |kind: ChainErrorKind| ChainError { mark, kind };
+
+            let record = commit.record().map_err(|e| err(e.into()))?;
+
+            let expected = prev.unwrap_or_default();
+            if record.prev() != expected {
+                return Err(err(ChainErrorKind::Prev { expected, found: record.prev() }));
+            }
+
+            // The record can't overlap the previous mark.
+            let end = commit.record_offset().map_err(|e| err(e.into()))?;
+            if end < start {
+                return Err(err(CommitRecordError::Truncated.into()));
+            }
+            let expected = CommitDigest::hash(&bytes[start .. end]);
+            if record.bytes_digest() != expected {
+                return Err(err(ChainErrorKind::Bytes { expected, found: record.bytes_digest() }));
+            }
+
+            prev = Some(record.digest());
+            start = (mark + 1) * word_len;
+        }
+        Ok(prev)
+    }
+
+    /// Compacts the journal by copying the root of the last commit into another journal.
+    ///
+    /// Only what's reachable from that root is copied: earlier roots, spilled blobs that were
+    /// never committed, and anything else left behind are dropped. Named roots aren't copied.
+    ///
+    /// Returns the offset of the root in `dst`, or `None` if there are no commits to copy.
+    pub fn compact_into<'v, T, H2>(&'v self, dst: &mut JournalMut<'_, H2>)
+        -> Result<Option<Offset<'static, 'static>>, CopyError>
+        where T: SavePtr<Offset<'p, 'v>, Offset<'static, 'static>> + Schema
+    {
+        let commit = match self.last_commit() {
+            Some(commit) => commit,
+            None => return Ok(None),
+        };
+        let root = commit.root_offset::<T>()?;
+
+        let mut copier = Copier::new(commit.pile());
+        let mut tx = dst.begin()?;
+        copier.copy_root::<T, H2>(&mut tx, root, ())?;
+        Ok(Some(copier.commit(tx)?))
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(fd: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(fd, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(fd: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match fd.seek_read(buf, offset)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            n => {
+                buf = &mut buf[n ..];
+                offset += n as u64;
+            },
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct JournalMut<'p, H> {
     fd: File,
     journal: Journal<'p, H>,
+    cipher: Option<Cipher>,
+
+    /// Unique to this `JournalMut`, so that a `Copier` can tell which journal its copies are in.
+    id: u64,
+
+    /// The write generation mixed into the nonces of encrypted blobs.
+    ///
+    /// Bumped whenever the journal is truncated, as the offsets that were written will be reused.
+    /// `Copier` relies on that too, to notice copies that were rolled back.
+    generation: u64,
+
+    signer: Option<Arc<dyn CommitSigner>>,
+    durability: Durability,
+    syncer: Arc<Syncer>,
+    monitor: Option<Monitor>,
+
+    /// Hashes the bytes written since the last mark.
+    ///
+    /// Carried over from one writer to the next, so that spilled blobs don't have to be read back
+    /// to hash them. `None` if it has to be rebuilt from the file, as it does when the journal is
+    /// opened, or after a failed write.
+    hasher: Option<CommitHasher>,
 }
 
+/// The size of the chunks the bytes after the last mark are read back in to hash them.
+const HASH_CHUNK_LEN: usize = 64 * 1024;
+
+/// The id of the next `JournalMut` opened.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
 impl<'p, H> JournalMut<'p, H> {
     pub fn create(path: impl AsRef<Path>, header: H) -> io::Result<Self> {
         let fd = OpenOptions::new()
@@ -114,13 +441,32 @@ impl<'p, H> JournalMut<'p, H> {
         Self::create_from_fd(fd, header)
     }
 
-    pub fn create_from_fd(mut fd: File, header: H) -> io::Result<Self> {
-        let header = JournalHeader::new(header);
+    pub fn create_from_fd(fd: File, header: H) -> io::Result<Self> {
+        Self::create_from_fd_with(fd, header, Checksum::None)
+    }
+
+    /// Creates a new journal, writing a checksum after every blob.
+    ///
+    /// The checksum is recorded in the journal header, and verified whenever a blob is loaded.
+    pub fn create_from_fd_with(mut fd: File, header: H, checksum: Checksum) -> io::Result<Self> {
+        let header = JournalHeader::new(header, checksum, false);
         fd.write(header.as_bytes())?;
 
         Self::open_fd(fd)
     }
 
+    /// Creates a new journal, encrypting every blob with the specified key.
+    ///
+    /// The key should not be used for any other journal. Encrypted blobs are authenticated, so
+    /// encrypted journals don't have checksums.
+    pub fn create_encrypted_from_fd(mut fd: File, header: H, key: &Key) -> io::Result<Self> {
+        let header = JournalHeader::new(header, Checksum::None, true)
+                                   .with_salt(OsRng.next_u64());
+        fd.write(header.as_bytes())?;
+
+        Self::open_encrypted_fd(fd, key)
+    }
+
     pub fn open(path: impl AsRef<Path>, append: bool) -> io::Result<Self> {
         let fd = OpenOptions::new()
                              .read(true)
@@ -130,32 +476,441 @@ impl<'p, H> JournalMut<'p, H> {
     }
 
     pub fn open_fd(fd: File) -> io::Result<Self> {
-        let mapping = unsafe { Mmap::map(&fd)? };
+        let journal = Journal::open_fd(&fd)?;
+        if journal.is_encrypted() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "journal is encrypted"));
+        }
+
+        Ok(Self {
+            journal,
+            syncer: Arc::new(Syncer::new(&fd)?),
+            fd,
+            cipher: None,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+            signer: None,
+            durability: Durability::default(),
+            monitor: None,
+            hasher: None,
+        })
+    }
+
+    /// Opens an encrypted journal for writing.
+    pub fn open_encrypted_fd(fd: File, key: &Key) -> io::Result<Self> {
+        let journal = Journal::open_fd(&fd)?;
+        if !journal.is_encrypted() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "journal is not encrypted"));
+        }
 
         Ok(Self {
-            journal: Journal::open_fd(&fd)?,
+            cipher: Some(Cipher::new(key, journal.salt())),
+            journal,
+            syncer: Arc::new(Syncer::new(&fd)?),
             fd,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            // whoever wrote to the journal before may have used any generation
+            generation: OsRng.next_u64(),
+            signer: None,
+            durability: Durability::default(),
+            monitor: None,
+            hasher: None,
         })
     }
 
+    /// Signs every commit made from now on.
+    pub fn with_signer(self, signer: impl CommitSigner + 'static) -> Self {
+        Self {
+            signer: Some(Arc::new(signer)),
+            ..self
+        }
+    }
+
+    /// Sets how commits are made durable.
+    ///
+    /// By default commits aren't synced at all.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Sets the monitor that saves report their progress to.
+    ///
+    /// If the monitor is cancelled, a save in progress fails with `io::ErrorKind::Interrupted`,
+    /// and its transaction is rolled back, leaving the journal at its previous commit.
+    pub fn set_monitor(&mut self, monitor: Option<Monitor>) {
+        self.monitor = monitor;
+    }
+
+    /// Gets the commit latency statistics.
+    pub fn stats(&self) -> CommitStats {
+        self.syncer.stats()
+    }
+
+    /// Gets the cipher used to encrypt blobs, if the journal is encrypted.
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+
     fn reload_mapping(&mut self) -> io::Result<()> {
-        self.journal.mapping = Journal::<H>::make_mapping(&self.fd)?;
-        Ok(())
+        self.journal.remap()
+    }
+
+    /// Hashes the first `len` bytes after the header that follow the last mark, reading them back
+    /// from the file.
+    fn hash_tail(&self, len: usize) -> io::Result<CommitHasher> {
+        let header_len = mem::size_of::<JournalHeader<H>>();
+        let word_len = mem::size_of::<Word>();
+        let mut pos = self.journal.last_mark.map_or(0, |idx| (idx + 1) * word_len);
+
+        let mut hasher = CommitHasher::new();
+        let mut buf = vec![0; cmp::min(len.saturating_sub(pos), HASH_CHUNK_LEN)];
+        while pos < len {
+            let chunk = &mut buf[.. cmp::min(len - pos, HASH_CHUNK_LEN)];
+            read_exact_at(&self.fd, chunk, (header_len + pos) as u64)?;
+            hasher.write(chunk);
+            pos += chunk.len();
+        }
+        Ok(hasher)
     }
 
     pub fn snapshot(&self) -> Journal<'p, H> {
         self.journal.clone()
     }
 
-    pub fn write_root<'v, 'a: 'v, T>(&'a mut self, root: &T) -> io::Result<()>
-        where T: Save<OffsetMut<'p, 'v>, Offset<'static, 'static>>,
+    /// Gets a pile of everything written to the journal, including spilled blobs that haven't
+    /// been committed yet.
+    pub fn pile<'v>(&'v self) -> TryPile<'p, 'v> {
+        let (_, bytes) = self.journal.mapping_parts();
+        unsafe { TryPile::new_unchecked(bytes) }.with_checksum(self.journal.checksum())
+    }
+
+    /// Spills a dirty bag to the journal, freeing its value from the heap.
+    ///
+    /// The value, and everything dirty within it, is saved to the end of the journal without a
+    /// mark, and the bag is left clean, pointing to it. Spilled blobs are committed by the first
+    /// commit whose root references them; until then they can be loaded from `pile()`.
+    ///
+    /// Spilled blobs are kept if a later transaction is rolled back. If they're never committed
+    /// they're simply garbage, which `Journal::compact_into()` leaves behind, as it only copies
+    /// what's reachable from the last committed root.
+    ///
+    /// Returns the offset of the spilled value.
+    pub fn spill<'v, T>(&mut self, bag: &mut Bag<T, OffsetMut<'p, 'v>>) -> io::Result<Offset<'static, 'static>>
+        where T: ?Sized + SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>>,
     {
-        let writer = JournalWriter::new(self)?;
+        let metadata = bag.metadata();
 
-        let mut poll = root.init_save(&writer);
-        let writer = poll.save_poll(writer)?;
+        // SAFETY: a bag's pointer is always valid
+        let value = match unsafe { bag.ptr().try_get_dirty_unchecked::<T>(metadata) } {
+            Ok(value) => value,
+            Err(offset) => return Ok(offset.to_static()),
+        };
 
-        Ok(())
+        let monitor = self.monitor.clone();
+        let mut writer = JournalWriter::new(self)?;
+        let mut saver = JournalSaver::new(&mut writer);
+
+        let mut poll = value.init_save_ptr();
+        let offset = save_monitored(&mut saver, &mut poll, monitor.as_ref())?;
+
+        writer.flush()?;
+        writer.journal.reload_mapping()?;
+
+        // SAFETY: the value was just saved at offset, and we own the dirty pointer we replaced
+        unsafe {
+            let dirty = bag.replace_ptr(offset.into());
+            dirty.dealloc::<T>(metadata);
+        }
+        Ok(offset.to_static())
+    }
+
+    /// Saves a root, and commits it along with the fingerprint of its type.
+    ///
+    /// Returns the offset of the root. If anything fails, the journal is rolled back.
+    pub fn write_root<'v, T>(&mut self, root: &T) -> io::Result<Offset<'static, 'static>>
+        where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+    {
+        let mut tx = self.begin()?;
+        tx.write_root(root)?;
+        tx.commit()
+    }
+
+    /// Saves a named root, and commits it as part of the root directory.
+    ///
+    /// Returns the offset of the root. If anything fails, the journal is rolled back.
+    pub fn set_root<'v, T>(&mut self, name: &str, root: &T) -> io::Result<Offset<'static, 'static>>
+        where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+    {
+        let mut tx = self.begin()?;
+        let root_offset = tx.set_root(name, root)?;
+        tx.commit()?;
+        Ok(root_offset)
+    }
+
+    /// Begins a write transaction.
+    pub fn begin(&mut self) -> io::Result<Transaction<'_, 'p, H>> {
+        Transaction::new(self)
+    }
+
+    /// Runs a closure within a transaction, committing the transaction if the closure succeeds.
+    ///
+    /// The closure writes the new root, or named roots, through the transaction it's given. If
+    /// either the closure or the commit fails, nothing is committed and the journal is left
+    /// unchanged.
+    ///
+    /// Returns what the closure returned.
+    pub fn transact<R, E, F>(&mut self, f: F) -> Result<R, E>
+        where F: FnOnce(&mut Transaction<'_, 'p, H>) -> Result<R, E>,
+              E: From<io::Error>,
+    {
+        let mut tx = self.begin()?;
+        let r = f(&mut tx)?;
+        tx.commit()?;
+        Ok(r)
+    }
+}
+
+/// A write transaction.
+///
+/// Writes are buffered in memory, and only written to the journal by `commit()`, along with a new
+/// mark. If the transaction is rolled back - explicitly, by being dropped, or because the commit
+/// itself failed part way through - the journal is truncated back to its length at the start of
+/// the transaction.
+#[derive(Debug)]
+pub struct Transaction<'a, 'p, H> {
+    writer: JournalWriter<'a, 'p, H>,
+    start_len: u64,
+    start_hasher: CommitHasher,
+    record: Option<CommitRecord>,
+
+    /// Named roots set, or removed, in this transaction.
+    names: BTreeMap<String, Option<CommitRecord>>,
+    done: bool,
+}
+
+impl<'a, 'p, H> Transaction<'a, 'p, H> {
+    fn new(journal: &'a mut JournalMut<'p, H>) -> io::Result<Self> {
+        let start_len = journal.fd.seek(SeekFrom::End(0))?;
+        let writer = JournalWriter::new(journal)?;
+        Ok(Self {
+            start_hasher: writer.journal.hasher.clone().expect("hasher set by JournalWriter::new()"),
+            writer,
+            start_len,
+            record: None,
+            names: BTreeMap::new(),
+            done: false,
+        })
+    }
+
+    /// Gets the offset of the root of the previous commit, if any.
+    pub fn prev_root(&self) -> io::Result<Option<Offset<'static, 'static>>> {
+        match self.writer.journal.journal.last_commit() {
+            Some(commit) => {
+                let record = commit.record()
+                                   .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(Some(record.root()))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Saves a root.
+    ///
+    /// The root isn't committed until `commit()` is called. If more than one root is written, the
+    /// last one is committed.
+    pub fn write_root<'v, T>(&mut self, root: &T) -> io::Result<Offset<'static, 'static>>
+        where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+    {
+        let record = self.save(root)?;
+        self.record = Some(record);
+        Ok(record.root())
+    }
+
+    /// Saves a named root.
+    ///
+    /// The root is committed as part of the root directory by `commit()`, replacing any root of
+    /// the same name; the other named roots of the previous commit are kept. A transaction can
+    /// set named roots or write a root with `write_root()`, but not both.
+    ///
+    /// Encrypted journals don't support named roots, as the directory has to be read back to be
+    /// merged.
+    pub fn set_root<'v, T>(&mut self, name: &str, root: &T) -> io::Result<Offset<'static, 'static>>
+        where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+    {
+        if self.writer.journal.cipher.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "named roots require an unencrypted journal"));
+        }
+
+        let record = self.save(root)?;
+        self.names.insert(name.to_owned(), Some(record));
+        Ok(record.root())
+    }
+
+    /// Removes a named root from the root directory.
+    pub fn remove_root(&mut self, name: &str) {
+        self.names.insert(name.to_owned(), None);
+    }
+
+    fn save<'v, T>(&mut self, value: &T) -> io::Result<CommitRecord>
+        where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+    {
+        let monitor = self.writer.journal.monitor.clone();
+        let mut saver = JournalSaver::new(&mut self.writer);
+
+        let mut poll = value.init_save_ptr();
+        let offset = save_monitored(&mut saver, &mut poll, monitor.as_ref())?;
+
+        Ok(CommitRecord::new(offset, T::fingerprint()))
+    }
+
+    /// Writes the root directory, merging the named roots set in this transaction with those of
+    /// the previous commit.
+    ///
+    /// If the previous commit's root isn't a directory, the new directory starts out empty.
+    fn write_directory(&mut self) -> io::Result<CommitRecord> {
+        let mut entries = match self.writer.journal.journal.all_commits().next_back() {
+            Some(commit) => match commit.directory() {
+                Ok(directory) => directory.to_map(),
+                Err(RootError::SchemaMismatch { .. }) => BTreeMap::new(),
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+            },
+            None => BTreeMap::new(),
+        };
+
+        for (name, record) in mem::take(&mut self.names) {
+            match record {
+                Some(record) => entries.insert(name, record),
+                None => entries.remove(&name),
+            };
+        }
+
+        let bytes = encode_entries(entries.iter().map(|(name, record)| (name.as_str(), *record)));
+        let entries_offset = self.writer.write_blob(&bytes);
+        let header = DirectoryHeader::new(Offset::new(entries_offset.get()).expect("overflow"), bytes.len());
+
+        let header_offset = self.writer.write_blob(&header.to_bytes());
+        Ok(CommitRecord::new(Offset::new(header_offset.get()).expect("overflow"), DirectoryHeader::fingerprint()))
+    }
+
+    /// Starts saving a root incrementally, doing at most `budget` worth of work per call to
+    /// `BudgetedRoot::poll_save()`.
+    ///
+    /// `poll` must come from the root's `init_save_ptr()`. Once it's saved, the root is recorded as
+    /// if by `write_root()`. This lets a large root be saved a bit at a time, for instance from an
+    /// event loop.
+    pub fn write_root_budgeted<'t, 'v, T>(&'t mut self, poll: T::SavePtrPoll, budget: SaveBudget)
+        -> BudgetedRoot<'t, 'a, 'p, 'v, H, T>
+        where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+    {
+        BudgetedRoot {
+            record: &mut self.record,
+            saver: BudgetSaver::new(JournalSaver::new(&mut self.writer), poll, budget),
+        }
+    }
+
+    /// Commits the transaction, returning the offset of the root.
+    ///
+    /// If named roots were set, the root is the root directory. The commit is durable, as required
+    /// by the journal's `Durability`, when this returns.
+    pub fn commit(self) -> io::Result<Offset<'static, 'static>> {
+        let (root, pending) = self.commit_deferred()?;
+        pending.wait()?;
+        Ok(root)
+    }
+
+    /// Commits the transaction, without waiting for it to be durable.
+    ///
+    /// Waiting on the returned `PendingSync` after the journal has been released lets commits
+    /// from other threads share the same sync.
+    pub fn commit_deferred(mut self) -> io::Result<(Offset<'static, 'static>, PendingSync)> {
+        let start = Instant::now();
+
+        if !self.names.is_empty() {
+            if self.record.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "both a root and named roots written in transaction"));
+            }
+            self.record = Some(self.write_directory()?);
+        }
+
+        let record = self.record.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no root written in transaction")
+        })?;
+
+        let record = self.writer.write_commit_record(&record);
+        match self.writer.commit() {
+            Ok(_) => {
+                self.done = true;
+
+                let journal = &*self.writer.journal;
+                let seq = journal.syncer.written();
+                let pending = PendingSync::new(journal.syncer.clone(), seq, journal.durability, start);
+                Ok((record.root(), pending))
+            },
+            Err(err) => {
+                // the commit error is what matters; a failed truncate leaves garbage after the
+                // last mark, which is ignored anyway
+                let _ = self.truncate();
+                Err(err)
+            },
+        }
+    }
+
+    /// Rolls back the transaction.
+    pub fn rollback(mut self) -> io::Result<()> {
+        self.truncate()
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        self.done = true;
+        self.writer.buffer.clear();
+
+        let journal = &mut *self.writer.journal;
+        journal.hasher = None;
+        // the truncated offsets will be written again, possibly after the old blobs reached disk
+        journal.generation = journal.generation.wrapping_add(1);
+        journal.fd.set_len(self.start_len)?;
+        journal.hasher = Some(self.start_hasher.clone());
+        journal.reload_mapping()
+    }
+}
+
+impl<H> Drop for Transaction<'_, '_, H> {
+    fn drop(&mut self) {
+        if !self.done {
+            // nothing we can do about errors here
+            let _ = self.truncate();
+        }
+    }
+}
+
+/// A root being saved a bit at a time, created by `Transaction::write_root_budgeted()`.
+#[derive(Debug)]
+pub struct BudgetedRoot<'t, 'a, 'p, 'v, H, T>
+    where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+{
+    record: &'t mut Option<CommitRecord>,
+    saver: BudgetSaver<JournalSaver<'t, 'a, 'p, 'v, H>, T::SavePtrPoll>,
+}
+
+impl<'t, 'a, 'p, 'v, H, T> BudgetedRoot<'t, 'a, 'p, 'v, H, T>
+    where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>> + Schema,
+{
+    /// Saves the root, until either finished or out of budget.
+    ///
+    /// Once `Poll::Ready` is returned, the root is recorded in the transaction.
+    pub fn poll_save(&mut self) -> io::Result<Poll<Offset<'static, 'static>>> {
+        match self.saver.poll_save()? {
+            Poll::Ready(offset) => {
+                let record = CommitRecord::new(offset, T::fingerprint());
+                *self.record = Some(record);
+                Ok(Poll::Ready(record.root()))
+            },
+            Poll::Pending => Ok(Poll::Pending),
+        }
     }
 }
 
@@ -164,6 +919,9 @@ pub struct JournalWriter<'a, 'p: 'a, H> {
     journal: &'a mut JournalMut<'p, H>,
     buffer: Vec<u8>,
     offset: WordOffset,
+
+    /// The digest of the last commit record.
+    prev: CommitDigest,
 }
 
 impl<'a, 'p, H> JournalWriter<'a, 'p, H> {
@@ -172,19 +930,30 @@ impl<'a, 'p, H> JournalWriter<'a, 'p, H> {
 
         let pos: usize = pos.checked_sub(mem::size_of::<JournalHeader<H>>() as u64)
                             .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "journal truncated"))?
-                            .try_into().expect("FIXME");
+                            .try_into()
+                            .map_err(|_| io::Error::new(io::ErrorKind::Other, "journal too large to map"))?;
         let offset = WordOffset::align(pos);
 
         // FIXME: make sure the padding doesn't create a mark
-        let padding = [0; mem::size_of::<Word>()];
-        let padding = &padding[0 .. offset - pos];
+        //
+        // The padding is buffered like everything else, so nothing is written until flush().
+        let buffer = vec![0; offset - pos];
 
-        journal.fd.write_all(padding)?;
+        // Anything already in the journal after the last mark is part of the next commit.
+        if journal.hasher.is_none() {
+            journal.hasher = Some(journal.hash_tail(pos)?);
+        }
+
+        let prev = journal.journal.all_commits().next_back()
+                           .and_then(|commit| commit.record_bytes())
+                           .map(CommitDigest::hash)
+                           .unwrap_or_default();
 
         Ok(Self {
             journal,
             offset,
-            buffer: vec![],
+            buffer,
+            prev,
         })
     }
 
@@ -194,7 +963,15 @@ impl<'a, 'p, H> JournalWriter<'a, 'p, H> {
     }
 
     pub fn flush(&mut self) -> io::Result<()> {
-        self.journal.fd.write_all(&self.buffer)?;
+        if let Err(err) = self.journal.fd.write_all(&self.buffer) {
+            // some of the buffer may have been written anyway
+            self.journal.hasher = None;
+            return Err(err);
+        }
+
+        if let Some(hasher) = &mut self.journal.hasher {
+            hasher.write(&self.buffer);
+        }
         self.buffer.clear();
         Ok(())
     }
@@ -202,57 +979,159 @@ impl<'a, 'p, H> JournalWriter<'a, 'p, H> {
     pub fn commit(&mut self) -> io::Result<WordOffset> {
         self.flush()?;
 
+        // The commit record's bytes digest can't be trusted if an earlier flush failed.
+        if self.journal.hasher.is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other, "earlier write to journal failed"));
+        }
+
         let idx_words = self.offset.get() / mem::size_of::<Word>();
         let mark = (!idx_words).to_le_bytes();
         self.journal.fd.write(&mark)?;
         self.offset += WordOffset::WORD;
+        self.journal.hasher = Some(CommitHasher::new());
+        self.journal.journal.last_mark = Some(idx_words);
         self.journal.reload_mapping()?;
 
         // FIXME: verify mapping is correct size/file hasn't been truncated
         Ok(self.offset - WordOffset::WORD)
     }
-}
 
-impl<'p, 'v, H> SavePtr for JournalWriter<'v, 'p, H> {
-    type Source = OffsetMut<'p, 'v>;
-    type Target = Offset<'static, 'static>;
-    type Error = io::Error;
+    /// Writes a blob, followed by its checksum if the journal has checksums enabled.
+    ///
+    /// If the journal is encrypted, the blob is encrypted instead.
+    pub fn write_blob(&mut self, bytes: &[u8]) -> WordOffset {
+        if let Some(cipher) = self.journal.cipher.clone() {
+            return self.write_encrypted_blob(&cipher, bytes);
+        }
 
-    unsafe fn check_dirty<'a, T: ?Sized>(&self, ptr: &'a Self::Source, metadata: T::Metadata) -> Result<Self::Target, &'a T>
-        where T: Pointee
-    {
-        todo!()
+        let checksum = self.journal.journal.checksum();
+
+        let mut buf = Vec::with_capacity(bytes.len() + checksum.len());
+        buf.extend_from_slice(bytes);
+        checksum.append(&mut buf, 0);
+
+        let mut item = self.write_item(buf.len());
+        item.write_bytes(&buf);
+        item.finish()
+    }
+
+    /// Writes an encrypted blob.
+    ///
+    /// Part of the nonce is the offset the blob is written at. That offset depends on the encrypted
+    /// bytes themselves, as any words that could be mistaken for marks are avoided by moving the
+    /// blob forward. So we encrypt, check for conflicts, and repeat with the moved offset until
+    /// there are none.
+    fn write_encrypted_blob(&mut self, cipher: &Cipher, bytes: &[u8]) -> WordOffset {
+        let generation = self.journal.generation;
+        let (offset, ()) = self.write_unconflicted(|this| {
+            let mut buf = bytes.to_vec();
+            cipher.encrypt(this.offset.get(), generation, &mut buf, 0);
+            buf.resize(buf.len() + WordOffset::align_padding(buf.len()), 0);
+            (buf, ())
+        });
+        offset
+    }
+
+    /// Writes an item whose bytes depend on where it's written.
+    ///
+    /// `encode` is called with the writer as it is, and returns the item's bytes along with
+    /// anything else to return. If the bytes would form a mark, they're padded past, and `encode`
+    /// is called again. Zero words can never be marks, so they're safe to pad with.
+    fn write_unconflicted<R>(&mut self, mut encode: impl FnMut(&Self) -> (Vec<u8>, R)) -> (WordOffset, R) {
+        loop {
+            let (bytes, r) = encode(self);
+            let words: Vec<Word> = bytes.chunks(mem::size_of::<Word>())
+                                        .map(|chunk| Le::new(u64::from_le_bytes(chunk.try_into().unwrap())))
+                                        .collect();
+
+            match calc_conflicts(self.offset.get() / mem::size_of::<Word>(), &words) {
+                0 => {
+                    let mut item = self.write_item(bytes.len());
+                    item.write_bytes(&bytes);
+                    break (item.finish(), r);
+                },
+                n => {
+                    let padding = n * mem::size_of::<Word>();
+                    self.buffer.resize(self.buffer.len() + padding, 0);
+                    self.offset += WordOffset::try_from(padding).unwrap();
+                },
+            }
+        }
     }
 
-    fn try_save_ptr(self, saver: &impl SaveBlob) -> Result<(Self, Self::Target), Self::Error> {
-        todo!()
+    /// Writes a commit record, chained to the previous commit, followed by its signature block.
+    ///
+    /// The record must be the last item written prior to calling `commit()`. Returns the record
+    /// as written, with its chain digests set.
+    pub fn write_commit_record(&mut self, record: &CommitRecord) -> CommitRecord {
+        // The bytes digest covers any padding inserted to keep the record from forming a mark, so
+        // it's part of what's encoded again after padding.
+        let (_, record) = self.write_unconflicted(|this| {
+            // if the hasher is gone, commit() fails anyway
+            let mut hasher = this.journal.hasher.clone().unwrap_or_default();
+            hasher.write(&this.buffer);
+            let record = record.with_chain(this.prev, hasher.finish());
+
+            let mut bytes = record.to_bytes();
+            bytes.extend_from_slice(&sign::encode_signature(this.journal.signer.as_deref(), &record.digest()));
+            (bytes, record)
+        });
+        self.prev = record.digest();
+        record
     }
 }
 
-#[derive(Debug)]
-pub struct ItemAllocator<'a, 'v, 'p, H>(&'a mut JournalWriter<'v, 'p, H>);
+/// Saves a value with `save_deep()`, reporting progress to the monitor if there is one.
+fn save_monitored<S, T>(saver: &mut S, poll: &mut T, monitor: Option<&Monitor>)
+    -> Result<<S::DstPtr as Ptr>::Persist, S::Error>
+    where S: Saver,
+          S::Error: From<Cancelled>,
+          T: SavePoll<SrcPtr = S::SrcPtr, DstPtr = S::DstPtr>,
+{
+    match monitor {
+        Some(monitor) => save_deep_monitored(saver, poll, monitor),
+        None => save_deep(saver, poll),
+    }
+}
 
-impl<'a, 'p, 'v, H> save::AllocBlob for ItemAllocator<'a, 'v, 'p, H> {
-    type WriteBlob = ItemWriter<'a>;
-    type Error = io::Error;
-    type Done = WordOffset;
+/// Saves dirty `OffsetMut` data to a `JournalWriter`.
+#[derive(Debug)]
+pub struct JournalSaver<'w, 'a, 'p, 'v, H> {
+    marker: PhantomData<OffsetMut<'p, 'v>>,
+    writer: &'w mut JournalWriter<'a, 'p, H>,
+}
 
-    fn alloc_blob(mut self, size: usize) -> Result<Self::WriteBlob, Self::Error> {
-        Ok(self.0.write_item(size))
+impl<'w, 'a, 'p, 'v, H> JournalSaver<'w, 'a, 'p, 'v, H> {
+    pub fn new(writer: &'w mut JournalWriter<'a, 'p, H>) -> Self {
+        Self {
+            marker: PhantomData,
+            writer,
+        }
     }
 }
 
-impl<'a> save::WriteBlob for ItemWriter<'a> {
-    type Done = WordOffset;
+impl<'w, 'a, 'p, 'v, H> Saver for JournalSaver<'w, 'a, 'p, 'v, H> {
+    type SrcPtr = OffsetMut<'p, 'v>;
+    type DstPtr = Offset<'p, 'v>;
     type Error = io::Error;
 
-    fn write_bytes(mut self, buf: &[u8]) -> Result<Self, Self::Error> {
-        ItemWriter::write_bytes(&mut self, buf);
-        Ok(self)
+    fn try_save_raw<R, T: ?Sized + ValidateBlob>(&self,
+        ptr: &Offset<'p, 'v>,
+        _metadata: T::Metadata,
+        _f: impl FnOnce(ValidBlob<T>, &<Self::SrcPtr as Ptr>::BlobZone) -> R,
+    ) -> Result<Result<Offset<'p, 'v>, R>, Self::Error>
+    {
+        // Clean offsets already point into this journal.
+        Ok(Ok(*ptr))
     }
 
-    fn done(self) -> Result<Self::Done, Self::Error> {
-        Ok(self.finish())
+    fn finish_save<T>(&mut self, value_poll: &T) -> Result<Offset<'p, 'v>, Self::Error>
+        where T: EncodeBlob
+    {
+        let bytes = value_poll.encode_blob(vec![]).into_ok();
+        let offset = self.writer.write_blob(&bytes);
+
+        Ok(Offset::new(offset.get()).expect("overflow"))
     }
 }
 
@@ -317,8 +1196,12 @@ impl<'a> ItemWriter<'a> {
         let padding_len_words = calc_conflicts(self.offset.get() / mem::size_of::<Word>(), written_words);
         let padding_len_bytes = padding_len_words * mem::size_of::<Word>();
         if padding_len_bytes > 0 {
+            // Indexes are relative to the buffer, which doesn't necessarily start at offset zero.
             self.buffer.resize(self.buffer.len() + padding_len_bytes, 0);
-            self.buffer.copy_within(self.offset.get() .. written_bytes_len, self.offset.get() + padding_len_bytes);
+            self.buffer.copy_within(start .. start + written_bytes_len, start + padding_len_bytes);
+
+            // Zero the padding, so the moved words can't form marks where they used to be.
+            self.buffer[start .. start + padding_len_bytes].iter_mut().for_each(|b| *b = 0);
 
             *self.offset += WordOffset::try_from(padding_len_bytes).unwrap();
         }
@@ -369,12 +1252,36 @@ struct JournalHeader<H = ()> {
 }
 
 impl<H> JournalHeader<H> {
-    pub fn new(header: H) -> Self {
+    pub fn new(header: H, checksum: Checksum, encrypted: bool) -> Self {
+        let mut magic = [0; 16];
+        magic[0] = checksum.to_tag();
+        magic[1] = encrypted as u8;
         Self {
-            magic: [0; 16],
+            magic,
             header,
         }
     }
+
+    /// The first byte of the magic records the checksum type.
+    pub fn checksum_tag(&self) -> u8 {
+        self.magic[0]
+    }
+
+    /// The second byte of the magic records whether or not blobs are encrypted.
+    pub fn encryption_tag(&self) -> u8 {
+        self.magic[1]
+    }
+
+    /// The last eight bytes of the magic hold the nonce salt of an encrypted journal.
+    pub fn with_salt(mut self, salt: u64) -> Self {
+        self.magic[8 ..].copy_from_slice(&salt.to_le_bytes());
+        self
+    }
+
+    pub fn salt(&self) -> u64 {
+        u64::from_le_bytes(self.magic[8 ..].try_into().unwrap())
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         assert_eq!(mem::size_of::<H>(), 0);
         unsafe {
@@ -451,6 +1358,26 @@ mod tests {
         assert_eq!(writer.finish(), 16);
     }
 
+    #[test]
+    fn test_conflicts_after_buffered() {
+        let mut dst = vec![];
+        let mut offset = WordOffset::try_from(0).unwrap();
+
+        let mut writer = ItemWriter::new(&mut dst, &mut offset, 8);
+        writer.write_bytes(&[0x1b; 8]);
+        assert_eq!(writer.finish(), 0);
+
+        // Conflicting on the second word of the buffer, so the padding isn't at the start of it
+        let word = [0xfe,0xff,0xff,0xff,0xff,0xff,0xff,0xff];
+        let mut writer = ItemWriter::new(&mut dst, &mut offset, 8);
+        writer.write_bytes(&word);
+        assert_eq!(writer.finish(), 16);
+
+        assert_eq!(&dst[.. 8], &[0x1b; 8]);
+        assert_eq!(&dst[8 .. 16], &[0; 8]);
+        assert_eq!(&dst[16 ..], &word);
+    }
+
     #[test]
     fn journal_create() -> io::Result<()> {
         let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
@@ -491,4 +1418,768 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn write_root_records_fingerprint() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+
+        let root_offset = journal.write_root(&42u8)?;
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        let record = commit.record().unwrap();
+        assert_eq!(record.root(), root_offset);
+        assert_eq!(record.fingerprint(), Fingerprint::of::<u8>());
+
+        let root = commit.try_root::<u8>().unwrap();
+        assert_eq!(root.as_value(), &42);
+
+        match commit.try_root::<bool>() {
+            Err(RootError::SchemaMismatch { expected, found }) => {
+                assert_eq!(expected, Fingerprint::of::<bool>());
+                assert_eq!(found, Fingerprint::of::<u8>());
+            },
+            r => panic!("expected schema mismatch; got {:?}", r),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_limits() -> io::Result<()> {
+        use crate::pile::{GetBlobError, fsck::{FsckError, FsckStats}};
+        use crate::validate::LimitError;
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.write_root(&Le::new(42u64))?;
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        assert_eq!(commit.try_root::<Le<u64>>().unwrap().as_value(), &Le::new(42));
+        assert_eq!(commit.fsck_root::<Le<u64>>().unwrap(),
+                   FsckStats { blobs: 1, bytes: 8, max_depth: 1 });
+
+        let limits = ValidateLimits { max_blob_size: 4, ..ValidateLimits::UNLIMITED };
+        let snapshot = journal.snapshot().with_limits(limits);
+        let commit = snapshot.last_commit().unwrap();
+        match commit.try_root::<Le<u64>>() {
+            Err(RootError::Blob(GetValidBlobError::Blob(GetBlobError::Limit(
+                LimitError::BlobSize { size: 8, limit: 4 })))) => {},
+            r => panic!("expected limit error; got {:?}", r),
+        }
+        match commit.fsck_root::<Le<u64>>() {
+            Err(RootError::Blob(FsckError::Limit { err: LimitError::BlobSize { size: 8, limit: 4 }, .. })) => {},
+            r => panic!("expected limit error; got {:?}", r),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_root_checksummed() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd_with(tempfile()?, (), Checksum::Crc32c)?;
+        let root_offset = journal.write_root(&Le::new(0x1234_5678u32))?;
+
+        let snapshot = journal.snapshot();
+        assert_eq!(snapshot.checksum(), Checksum::Crc32c);
+
+        let commit = snapshot.last_commit().unwrap();
+        assert_eq!(commit.pile().checksum(), Checksum::Crc32c);
+        assert_eq!(commit.try_root::<Le<u32>>().unwrap().as_value(), &Le::new(0x1234_5678));
+
+        let bytes = commit.pile().as_bytes();
+        let start = root_offset.get();
+        assert_eq!(&bytes[start + 4 .. start + 8],
+                   &crate::pile::checksum::crc32c(&bytes[start .. start + 4]).to_le_bytes());
+
+        // reopening picks up the checksum from the header
+        let reopened = Journal::<()>::open_fd(&journal.fd)?;
+        assert_eq!(reopened.checksum(), Checksum::Crc32c);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_root_encrypted() -> io::Result<()> {
+        use crate::pile::encrypted::{EncryptedPile, EncryptedLoadError};
+
+        let key = Key::new([0x42; 32]);
+        let mut journal = JournalMut::create_encrypted_from_fd(tempfile()?, (), &key)?;
+        journal.write_root(&Le::new(0x1234_5678u32))?;
+        journal.write_root(&Le::new(0x9abc_def0u32))?;
+
+        let snapshot = journal.snapshot();
+        assert!(snapshot.is_encrypted());
+
+        for (commit, expected) in snapshot.commits().zip(&[0x1234_5678u32, 0x9abc_def0]) {
+            let offset = commit.root_offset::<Le<u32>>().unwrap();
+            assert!(!commit.pile().as_bytes().windows(4).any(|w| w == &expected.to_le_bytes()));
+
+            let pile = EncryptedPile::new(commit.pile(), journal.cipher().unwrap());
+            assert_eq!(pile.try_take::<Le<u32>>(offset, ()).unwrap(), Le::new(*expected));
+
+            let wrong_key = Cipher::new(&Key::new([0; 32]), snapshot.salt());
+            let pile = EncryptedPile::new(commit.pile(), &wrong_key);
+            match pile.try_take::<Le<u32>>(offset, ()) {
+                Err(EncryptedLoadError::Decrypt(err)) => assert_eq!(err.offset, offset.get()),
+                r => panic!("{:?}", r),
+            }
+        }
+
+        // writing requires the key
+        let fd = journal.fd.try_clone()?;
+        assert!(JournalMut::<()>::open_fd(fd.try_clone()?).is_err());
+        JournalMut::<()>::open_encrypted_fd(fd, &key)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_rollback_changes_nonces() -> io::Result<()> {
+        use std::io::Read;
+        use crate::pile::encrypted::EncryptedPile;
+
+        fn contents(fd: &File) -> io::Result<Vec<u8>> {
+            let mut fd = fd.try_clone()?;
+            let mut buf = vec![];
+            fd.seek(SeekFrom::Start(0))?;
+            fd.read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+
+        let key = Key::new([0x42; 32]);
+        let mut journal = JournalMut::create_encrypted_from_fd(tempfile()?, (), &key)?;
+        let fd = journal.fd.try_clone()?;
+
+        // a failed write that reached the disk before being rolled back
+        let mut tx = journal.begin()?;
+        tx.write_root(&Le::new(0x1234_5678u32))?;
+        tx.writer.flush()?;
+        let failed = contents(&fd)?;
+        tx.rollback()?;
+
+        journal.write_root(&Le::new(0x1234_5678u32))?;
+        let written = contents(&fd)?;
+
+        // the same bytes at the same offset, encrypted with a different nonce
+        let header_len = mem::size_of::<JournalHeader>();
+        assert_ne!(&failed[header_len ..], &written[header_len .. failed.len()]);
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        let pile = EncryptedPile::new(commit.pile(), journal.cipher().unwrap());
+        let offset = commit.root_offset::<Le<u32>>().unwrap();
+        assert_eq!(pile.try_take::<Le<u32>>(offset, ()).unwrap(), Le::new(0x1234_5678));
+
+        // every journal gets its own salt
+        let other = JournalMut::create_encrypted_from_fd(tempfile()?, (), &key)?;
+        assert_ne!(other.snapshot().salt(), snapshot.salt());
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_rollback() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.write_root(&1u8)?;
+        let len = journal.fd.metadata()?.len();
+
+        // dropped without commit
+        let mut tx = journal.begin()?;
+        tx.write_root(&Le::new(2u64))?;
+        drop(tx);
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        // explicitly rolled back
+        let mut tx = journal.begin()?;
+        tx.write_root(&3u8)?;
+        tx.rollback()?;
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        // nothing to commit
+        assert!(journal.begin()?.commit().is_err());
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        let snapshot = journal.snapshot();
+        assert_eq!(snapshot.commits().count(), 1);
+        assert_eq!(snapshot.last_commit().unwrap().try_root::<u8>().unwrap().as_value(), &1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transact() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+
+        let root1 = journal.transact(|tx| {
+            assert_eq!(tx.prev_root()?, None);
+            tx.write_root(&1u8)
+        })?;
+
+        let root2 = journal.transact(|tx| {
+            assert_eq!(tx.prev_root()?, Some(root1));
+            tx.write_root(&2u8)
+        })?;
+        assert_ne!(root1, root2);
+
+        // writes within the closure are rolled back with it
+        let len = journal.fd.metadata()?.len();
+        let r = journal.transact(|tx| -> io::Result<()> {
+            tx.write_root(&3u8)?;
+            Err(io::Error::new(io::ErrorKind::Other, "aborted"))
+        });
+        assert_eq!(r.unwrap_err().to_string(), "aborted");
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        // as are failed commits, keeping the original error
+        let r = journal.transact(|_| Ok::<_, io::Error>(()));
+        assert_eq!(r.unwrap_err().to_string(), "no root written in transaction");
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        let snapshot = journal.snapshot();
+        assert_eq!(snapshot.commits().count(), 2);
+        assert_eq!(snapshot.last_commit().unwrap().record().unwrap().root(), root2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_root_budgeted() -> io::Result<()> {
+        use crate::pile::fsck::{Fsck, FsckStats};
+
+        type Root<'p, 'v> = Bag<Bag<u8, OffsetMut<'p, 'v>>, OffsetMut<'p, 'v>>;
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let value = Bag::new(Bag::new(42u8));
+
+        let mut tx = journal.begin()?;
+        let poll = <Root as SavePtr<OffsetMut, Offset>>::init_save_ptr(&value);
+        let budget = SaveBudget { bytes: usize::MAX, blobs: 1 };
+        let mut root_saver = tx.write_root_budgeted::<Root>(poll, budget);
+
+        let mut calls = 0;
+        let root = loop {
+            calls += 1;
+            if let Poll::Ready(root) = root_saver.poll_save()? {
+                break root;
+            }
+        };
+        assert_eq!(calls, 3);
+        assert_eq!(tx.commit()?, root);
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        assert_eq!(commit.record().unwrap().fingerprint(), Fingerprint::of::<Root>());
+        let stats = Fsck::new(commit.pile())
+                         .validate_root::<Bag<Bag<u8, Offset>, Offset>>(root, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 3, bytes: 8 + 8 + 1, max_depth: 3 });
+
+        Ok(())
+    }
+
+    #[test]
+    fn named_roots() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.write_root(&1u8)?;
+
+        let mut tx = journal.begin()?;
+        let a_offset = tx.set_root("a", &2u8)?;
+        tx.set_root("b", &Le::new(3u32))?;
+        tx.commit()?;
+
+        // previous named roots are kept
+        journal.set_root("c", &true)?;
+
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        assert_eq!(commit.record().unwrap().fingerprint(), Fingerprint::of::<DirectoryHeader>());
+
+        let directory = commit.directory().unwrap();
+        assert_eq!(directory.iter().map(|(name, _)| name).collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(directory.get("a").unwrap().root(), a_offset);
+        assert_eq!(directory.get("b").unwrap().fingerprint(), Fingerprint::of::<Le<u32>>());
+
+        assert_eq!(directory.get_root::<u8>("a").unwrap().as_value(), &2);
+        assert_eq!(directory.get_root::<Le<u32>>("b").unwrap().as_value(), &Le::new(3));
+        assert_eq!(directory.get_root::<bool>("c").unwrap().as_value(), &true);
+
+        match directory.get_root::<bool>("a") {
+            Err(RootError::SchemaMismatch { expected, found }) => {
+                assert_eq!(expected, Fingerprint::of::<bool>());
+                assert_eq!(found, Fingerprint::of::<u8>());
+            },
+            r => panic!("expected schema mismatch; got {:?}", r),
+        }
+
+        match directory.get_root::<u8>("d") {
+            Err(RootError::NotFound(name)) => assert_eq!(name, "d"),
+            r => panic!("expected not found; got {:?}", r),
+        }
+
+        // a plain root isn't a directory
+        let first = snapshot.commits().next().unwrap();
+        assert!(matches!(first.directory(), Err(RootError::SchemaMismatch { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn named_roots_replace_and_remove() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.set_root("a", &1u8)?;
+        journal.set_root("b", &2u8)?;
+
+        let mut tx = journal.begin()?;
+        tx.set_root("a", &Le::new(3u16))?;
+        tx.remove_root("b");
+        tx.commit()?;
+
+        let snapshot = journal.snapshot();
+        let directory = snapshot.last_commit().unwrap().directory().unwrap();
+        assert_eq!(directory.len(), 1);
+        assert_eq!(directory.get_root::<Le<u16>>("a").unwrap().as_value(), &Le::new(3));
+        assert!(directory.get("b").is_none());
+
+        // earlier commits are unchanged
+        let prev = snapshot.commits().rev().nth(1).unwrap().directory().unwrap();
+        assert_eq!(prev.get_root::<u8>("b").unwrap().as_value(), &2);
+
+        // a transaction can't commit both kinds of root
+        let len = journal.fd.metadata()?.len();
+        let mut tx = journal.begin()?;
+        tx.set_root("a", &4u8)?;
+        tx.write_root(&5u8)?;
+        assert_eq!(tx.commit().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        Ok(())
+    }
+
+    #[test]
+    fn named_roots_checksummed() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd_with(tempfile()?, (), Checksum::Crc32c)?;
+        journal.set_root("a", &1u8)?;
+        journal.set_root("b", &2u8)?;
+
+        let snapshot = journal.snapshot();
+        let directory = snapshot.last_commit().unwrap().directory().unwrap();
+        assert_eq!(directory.get_root::<u8>("a").unwrap().as_value(), &1);
+        assert_eq!(directory.get_root::<u8>("b").unwrap().as_value(), &2);
+
+        Ok(())
+    }
+
+    /// Copies a journal file, overwriting some bytes of the copy.
+    fn tampered_copy(fd: &File, offset: usize, bytes: &[u8]) -> io::Result<File> {
+        let mut buf = vec![0; fd.metadata()?.len() as usize];
+        read_exact_at(fd, &mut buf, 0)?;
+        buf[offset .. offset + bytes.len()].copy_from_slice(bytes);
+
+        let mut copy = tempfile()?;
+        copy.write_all(&buf)?;
+        Ok(copy)
+    }
+
+    #[test]
+    fn verify_chain() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        assert_eq!(journal.snapshot().verify_chain(), Ok(None));
+
+        journal.write_root(&1u8)?;
+        journal.write_root(&Le::new(2u32))?;
+        journal.set_root("a", &3u8)?;
+
+        let snapshot = journal.snapshot();
+        let records: Vec<CommitRecord> = snapshot.commits().map(|commit| commit.record().unwrap()).collect();
+        assert_eq!(records[0].prev(), CommitDigest::default());
+        assert_eq!(records[1].prev(), records[0].digest());
+        assert_eq!(records[2].prev(), records[1].digest());
+        assert_eq!(snapshot.verify_chain(), Ok(Some(records[2].digest())));
+
+        let marks: Vec<usize> = snapshot.marks().collect();
+        let header_len = mem::size_of::<JournalHeader<()>>();
+        let word_len = mem::size_of::<Word>();
+
+        // rewriting the root of the second commit breaks its bytes digest
+        let root = records[1].root().get();
+        let tampered = tampered_copy(&journal.fd, header_len + root, &[0xff])?;
+        match Journal::<()>::open_fd(&tampered)?.verify_chain() {
+            Err(ChainError { mark, kind: ChainErrorKind::Bytes { found, .. } }) => {
+                assert_eq!(mark, marks[1]);
+                assert_eq!(found, records[1].bytes_digest());
+            },
+            r => panic!("{:?}", r),
+        }
+
+        // as does rewriting the first record, but it's detected by the next commit
+        let fingerprint = snapshot.commits().next().unwrap().record_offset().unwrap() + word_len;
+        let tampered = tampered_copy(&journal.fd, header_len + fingerprint, &[0xff; 8])?;
+        match Journal::<()>::open_fd(&tampered)?.verify_chain() {
+            Err(ChainError { mark, kind: ChainErrorKind::Prev { expected, found } }) => {
+                assert_eq!(mark, marks[1]);
+                assert_eq!(found, records[0].digest());
+                assert_ne!(expected, found);
+            },
+            r => panic!("{:?}", r),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_chain_uncommitted_bytes() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd_with(tempfile()?, (), Checksum::Crc32c)?;
+        journal.write_root(&1u8)?;
+
+        // bytes left behind by a writer that never committed become part of the next commit
+        let mut writer = JournalWriter::new(&mut journal)?;
+        writer.write_blob(&[0x42; 5]);
+        writer.flush()?;
+        drop(writer);
+
+        journal.write_root(&2u8)?;
+        assert!(journal.snapshot().verify_chain().unwrap().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn signed_commits() -> io::Result<()> {
+        use self::sign::{HmacSigner, HmacVerifier, Ed25519Signer, Ed25519Verifier};
+        use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+
+        let secret = SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let ed25519 = Ed25519Signer::new("service-b", Keypair { secret, public });
+
+        let fd = tempfile()?;
+        let journal = JournalMut::create_from_fd(fd.try_clone()?, ())?;
+        journal.with_signer(HmacSigner::new("service-a", &b"secret"[..])).write_root(&1u8)?;
+
+        let mut journal = JournalMut::<()>::open_fd(fd.try_clone()?)?;
+        journal.write_root(&2u8)?;
+
+        let mut journal = JournalMut::<()>::open_fd(fd.try_clone()?)?.with_signer(ed25519);
+        journal.write_root(&3u8)?;
+
+        let snapshot = journal.snapshot();
+        let signers: Vec<Option<&str>> = snapshot.commits()
+            .map(|commit| commit.signature().unwrap().map(|signature| signature.key_id))
+            .collect();
+        assert_eq!(signers, [Some("service-a"), None, Some("service-b")]);
+
+        // signatures don't break the chain
+        assert!(snapshot.verify_chain().unwrap().is_some());
+
+        let roots = |journal: &Journal<()>| -> Vec<u8> {
+            journal.commits().map(|commit| *commit.try_root::<u8>().unwrap().as_value()).collect()
+        };
+        assert_eq!(roots(&snapshot), [1, 2, 3]);
+
+        let hmac = snapshot.clone().with_verifier(HmacVerifier::new().trust("service-a", &b"secret"[..]));
+        assert_eq!(roots(&hmac), [1]);
+        assert_eq!(hmac.roots().count(), 1);
+
+        let wrong_key = snapshot.clone().with_verifier(HmacVerifier::new().trust("service-a", &b"wrong"[..]));
+        assert_eq!(roots(&wrong_key), Vec::<u8>::new());
+        assert!(wrong_key.last_commit().is_none());
+
+        let verifier = Ed25519Verifier::new().trust("service-b", public);
+        let ed25519 = snapshot.with_verifier(verifier);
+        assert_eq!(roots(&ed25519), [3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn durability() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        assert_eq!(journal.durability(), Durability::None);
+
+        journal.write_root(&1u8)?;
+        assert_eq!(journal.stats().commits, 1);
+        assert_eq!(journal.stats().syncs, 0);
+
+        journal.set_durability(Durability::Sync);
+        journal.write_root(&2u8)?;
+        journal.write_root(&3u8)?;
+        let stats = journal.stats();
+        assert_eq!(stats.commits, 3);
+        assert_eq!(stats.syncs, 2);
+        assert!(stats.mean_latency().unwrap() <= stats.max_latency);
+
+        // rolled back commits aren't counted
+        journal.begin()?.rollback()?;
+        assert_eq!(journal.stats(), stats);
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_commit() -> io::Result<()> {
+        use std::sync::Mutex;
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.set_durability(Durability::Group);
+        let journal = Arc::new(Mutex::new(journal));
+
+        let threads: Vec<_> = (0 .. 8u8).map(|i| {
+            let journal = Arc::clone(&journal);
+            std::thread::spawn(move || -> io::Result<()> {
+                let pending = {
+                    let mut journal = journal.lock().unwrap();
+                    let mut tx = journal.begin()?;
+                    tx.write_root(&i)?;
+                    tx.commit_deferred()?.1
+                };
+                // the journal is unlocked, so other commits can join our sync
+                pending.wait()
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        let journal = journal.lock().unwrap();
+        let stats = journal.stats();
+        assert_eq!(stats.commits, 8);
+        assert!(stats.syncs >= 1 && stats.syncs <= 8);
+        assert_eq!(journal.snapshot().commits().count(), 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spill() -> io::Result<()> {
+        use crate::pile::fsck::{Fsck, FsckStats};
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+
+        let mut bag = Bag::new(Bag::new(42u8));
+        let offset = journal.spill(&mut bag)?;
+        assert_eq!(bag.ptr().get_offset().map(|offset| offset.get()), Some(offset.get()));
+
+        // spilling a clean bag writes nothing
+        let len = journal.fd.metadata()?.len();
+        assert_eq!(journal.spill(&mut bag)?, offset);
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        // spilled, but not committed
+        assert!(journal.snapshot().last_commit().is_none());
+        let stats = Fsck::new(journal.pile())
+                         .validate_root::<Bag<u8, Offset>>(offset, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 2, bytes: 8 + 1, max_depth: 2 });
+
+        // rolling back a transaction keeps the spilled blobs
+        let mut tx = journal.begin()?;
+        tx.write_root(&1u8)?;
+        tx.rollback()?;
+        assert_eq!(journal.fd.metadata()?.len(), len);
+
+        let root = journal.write_root(&bag)?;
+        let snapshot = journal.snapshot();
+        let commit = snapshot.last_commit().unwrap();
+        let stats = Fsck::new(commit.pile())
+                         .validate_root::<Bag<Bag<u8, Offset>, Offset>>(root, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 3, bytes: 8 + 8 + 1, max_depth: 3 });
+        assert!(snapshot.verify_chain().unwrap().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn spill_reopen() -> io::Result<()> {
+        let fd = tempfile()?;
+        let mut journal = JournalMut::create_from_fd(fd.try_clone()?, ())?;
+        let mut bag = Bag::new(42u8);
+        journal.spill(&mut bag)?;
+        drop(journal);
+
+        // the spilled blob has to be read back to hash it
+        let mut journal = JournalMut::open_fd(fd)?;
+        journal.write_root(&bag)?;
+        assert!(journal.snapshot().verify_chain().unwrap().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn spill_compaction() -> Result<(), copy::CopyError> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.write_root(&1u8)?;
+        journal.spill(&mut Bag::new(Le::new(0x42u64)))?;
+
+        // the spilled blob is never committed, so it isn't copied
+        let mut compacted = JournalMut::create_from_fd(tempfile()?, ())?;
+        let root = journal.snapshot().compact_into::<u8, _>(&mut compacted)?.unwrap();
+
+        let mut expected = JournalMut::create_from_fd(tempfile()?, ())?;
+        assert_eq!(expected.write_root(&1u8)?, root);
+        assert_eq!(compacted.fd.metadata()?.len(), expected.fd.metadata()?.len());
+        assert!(compacted.fd.metadata()?.len() < journal.fd.metadata()?.len());
+
+        // nothing to compact
+        let empty = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut dst = JournalMut::create_from_fd(tempfile()?, ())?;
+        assert_eq!(empty.snapshot().compact_into::<u8, _>(&mut dst)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn monitor() -> io::Result<()> {
+        use std::sync::Mutex;
+
+        use crate::progress::{CancelToken, Progress};
+
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let root = journal.write_root(&1u8)?;
+        let len = journal.fd.metadata()?.len();
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let observer = {
+            let reports = Arc::clone(&reports);
+            move |progress: &Progress| reports.lock().unwrap().push(*progress)
+        };
+
+        // cancelled part way through the save
+        let cancel = CancelToken::new();
+        let monitor = {
+            let cancel = cancel.clone();
+            let observer = observer.clone();
+            Monitor::new().with_observer(move |progress: &Progress| {
+                observer(progress);
+                if progress.nodes == 2 {
+                    cancel.cancel();
+                }
+            })
+        };
+        journal.set_monitor(Some(monitor.with_cancel_token(cancel)));
+
+        let value = Bag::new(Bag::new(42u8));
+        let err = journal.write_root(&value).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(*reports.lock().unwrap(),
+                   [Progress { bytes: 1, nodes: 1, depth: 2 },
+                    Progress { bytes: 1 + 8, nodes: 2, depth: 1 }]);
+
+        // the journal is left at its previous commit
+        assert_eq!(journal.fd.metadata()?.len(), len);
+        let snapshot = journal.snapshot();
+        assert_eq!(snapshot.last_commit().unwrap().record().unwrap().root().get(), root.get());
+
+        reports.lock().unwrap().clear();
+        journal.set_monitor(Some(Monitor::new().with_observer(observer)));
+        journal.write_root(&value)?;
+        assert_eq!(*reports.lock().unwrap(),
+                   [Progress { bytes: 1, nodes: 1, depth: 2 },
+                    Progress { bytes: 1 + 8, nodes: 2, depth: 1 },
+                    Progress { bytes: 1 + 8 + 8, nodes: 3, depth: 0 }]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refresh() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut reader = journal.snapshot();
+        assert!(!reader.refresh()?);
+
+        journal.write_root(&1u8)?;
+        assert_eq!(reader.commits().count(), 0);
+        assert!(reader.refresh()?);
+        assert_eq!(reader.commits().count(), 1);
+        assert!(!reader.refresh()?);
+
+        // uncommitted writes don't cause a remap
+        let mut tx = journal.begin()?;
+        tx.write_root(&2u8)?;
+        tx.writer.flush()?;
+        let mapping = reader.mapping.clone();
+        assert!(!reader.refresh()?);
+        assert!(Arc::ptr_eq(&mapping, &reader.mapping));
+
+        // and once rolled back, the truncated bytes aren't mapped
+        tx.rollback()?;
+        assert!(!reader.refresh()?);
+        assert_eq!(reader.mapping.len() as u64, journal.fd.metadata()?.len());
+
+        journal.write_root(&3u8)?;
+        assert!(reader.refresh()?);
+        assert_eq!(reader.last_commit().unwrap().try_root::<u8>().unwrap().as_value(), &3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_short_file() -> io::Result<()> {
+        let mut fd = tempfile()?;
+        fd.write_all(&[0; 3])?;
+        match Journal::<()>::open_fd(&fd) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            Ok(journal) => panic!("{:?}", journal),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_tracks_last_mark() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        journal.write_root(&1u8)?;
+        let mut reader = journal.snapshot();
+        let first = reader.last_commit().unwrap().mark();
+
+        // an uncommitted tail is never scanned for marks, even if it's mapped
+        let mut tx = journal.begin()?;
+        tx.write_root(&2u8)?;
+        tx.writer.flush()?;
+        assert!(!reader.refresh()?);
+        assert_eq!(reader.marks().collect::<Vec<_>>(), &[first]);
+        tx.commit()?;
+
+        assert!(reader.refresh()?);
+        let second = reader.last_commit().unwrap().mark();
+        assert!(second > first);
+        assert_eq!(reader.marks().collect::<Vec<_>>(), &[first, second]);
+
+        // reopening finds the same last mark
+        let reopened = Journal::<()>::open_fd(&journal.fd)?;
+        assert_eq!(reopened.marks().collect::<Vec<_>>(), &[first, second]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_commit() -> io::Result<()> {
+        let mut journal = JournalMut::create_from_fd(tempfile()?, ())?;
+        let mut reader = journal.snapshot();
+
+        let writer = std::thread::spawn(move || -> io::Result<()> {
+            for i in 0 .. 3u8 {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                journal.write_root(&i)?;
+            }
+            Ok(())
+        });
+
+        let mut mark = 0;
+        let mut seen = vec![];
+        while seen.last() != Some(&2) {
+            mark = reader.wait_for_commit(mark)?;
+
+            let commit = reader.last_commit().unwrap();
+            assert_eq!(commit.mark(), mark);
+            seen.push(*commit.try_root::<u8>().unwrap().as_value());
+        }
+        writer.join().unwrap()?;
+
+        // commits may be coalesced, but are seen in order
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,308 @@
+//! Signed commits.
+//!
+//! A commit can carry a signature over the digest of its commit record, which chains to every
+//! previous commit. The signature is written between the record and the mark, along with the id
+//! of the key that produced it:
+//!
+//! ```text
+//! [record][key id][signature][zero padding][trailer]
+//! ```
+//!
+//! The trailer word holds the length of the key id in its low 32 bits, and the length of the
+//! signature in its high 32 bits. Unsigned commits still have a trailer, of zero. As the top bit
+//! of a trailer is always clear, it can never be mistaken for a mark.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::mem;
+use std::str;
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::chain::CommitDigest;
+use super::commit::CommitRecordError;
+use super::wordoffset::{Word, WordOffset};
+
+/// Signs commits.
+pub trait CommitSigner : fmt::Debug + Send + Sync {
+    /// Gets the id of the signing key, which tells verifiers who produced a commit.
+    fn key_id(&self) -> &str;
+
+    /// Signs the digest of a commit record.
+    fn sign(&self, digest: &CommitDigest) -> Vec<u8>;
+}
+
+/// Verifies commit signatures.
+pub trait CommitVerifier : fmt::Debug + Send + Sync {
+    /// Returns `true` if the key is trusted, and the signature is valid.
+    fn verify(&self, key_id: &str, digest: &CommitDigest, signature: &[u8]) -> bool;
+}
+
+/// The signature of a commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitSignature<'a> {
+    pub key_id: &'a str,
+    pub signature: &'a [u8],
+}
+
+/// Encodes the signature block and trailer written after a commit record.
+pub(crate) fn encode_signature(signer: Option<&dyn CommitSigner>, digest: &CommitDigest) -> Vec<u8> {
+    let mut r = vec![];
+    let trailer = match signer {
+        None => 0,
+        Some(signer) => {
+            let key_id = signer.key_id();
+            let signature = signer.sign(digest);
+
+            r.extend_from_slice(key_id.as_bytes());
+            r.extend_from_slice(&signature);
+            r.resize(r.len() + WordOffset::align_padding(r.len()), 0);
+
+            let key_id_len = u32::try_from(key_id.len()).expect("key id too long");
+            let signature_len = u32::try_from(signature.len()).expect("signature too long");
+            assert!(signature_len < 1 << 31, "signature too long");
+            (key_id_len as u64) | ((signature_len as u64) << 32)
+        },
+    };
+    r.extend_from_slice(&trailer.to_le_bytes());
+    r
+}
+
+/// Parses the signature block at the end of the bytes of a commit.
+///
+/// Returns the length of the block, including the trailer, and the signature if any.
+pub(crate) fn parse_signature(buf: &[u8]) -> Result<(usize, Option<CommitSignature>), CommitRecordError> {
+    let word_len = mem::size_of::<Word>();
+    let trailer_start = buf.len().checked_sub(word_len).ok_or(CommitRecordError::Truncated)?;
+    let trailer = u64::from_le_bytes(buf[trailer_start ..].try_into().unwrap());
+    if trailer == 0 {
+        return Ok((word_len, None));
+    }
+
+    let key_id_len = (trailer & 0xffff_ffff) as usize;
+    let signature_len = (trailer >> 32) as usize;
+    let unpadded = key_id_len + signature_len;
+    let block_len = unpadded + WordOffset::align_padding(unpadded);
+
+    let start = trailer_start.checked_sub(block_len).ok_or(CommitRecordError::Truncated)?;
+    let block = &buf[start .. trailer_start];
+
+    let (key_id, rest) = block.split_at(key_id_len);
+    let key_id = str::from_utf8(key_id).map_err(|_| CommitRecordError::Signature)?;
+    let signature = &rest[.. signature_len];
+
+    Ok((block_len + word_len, Some(CommitSignature { key_id, signature })))
+}
+
+/// Signs commits with HMAC-SHA256.
+pub struct HmacSigner {
+    key_id: String,
+    key: Vec<u8>,
+}
+
+impl fmt::Debug for HmacSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HmacSigner")
+            .field("key_id", &self.key_id)
+            .finish()
+    }
+}
+
+fn hmac_sha256(key: &[u8], digest: &CommitDigest) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.input(digest.as_bytes());
+    mac
+}
+
+impl HmacSigner {
+    pub fn new(key_id: impl Into<String>, key: impl Into<Vec<u8>>) -> Self {
+        Self {
+            key_id: key_id.into(),
+            key: key.into(),
+        }
+    }
+}
+
+impl CommitSigner for HmacSigner {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn sign(&self, digest: &CommitDigest) -> Vec<u8> {
+        hmac_sha256(&self.key, digest).result().code().to_vec()
+    }
+}
+
+/// Verifies HMAC-SHA256 signatures from a set of trusted keys.
+#[derive(Default)]
+pub struct HmacVerifier {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl fmt::Debug for HmacVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HmacVerifier")
+            .field("key_ids", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl HmacVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts a key.
+    pub fn trust(mut self, key_id: impl Into<String>, key: impl Into<Vec<u8>>) -> Self {
+        self.keys.insert(key_id.into(), key.into());
+        self
+    }
+}
+
+impl CommitVerifier for HmacVerifier {
+    fn verify(&self, key_id: &str, digest: &CommitDigest, signature: &[u8]) -> bool {
+        match self.keys.get(key_id) {
+            // constant time comparison
+            Some(key) => hmac_sha256(key, digest).verify(signature).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Signs commits with ed25519.
+pub struct Ed25519Signer {
+    key_id: String,
+    keypair: Keypair,
+}
+
+impl fmt::Debug for Ed25519Signer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Ed25519Signer")
+            .field("key_id", &self.key_id)
+            .field("public", &self.keypair.public)
+            .finish()
+    }
+}
+
+impl Ed25519Signer {
+    pub fn new(key_id: impl Into<String>, keypair: Keypair) -> Self {
+        Self {
+            key_id: key_id.into(),
+            keypair,
+        }
+    }
+
+    /// Gets the public key, for verifiers to trust.
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public
+    }
+}
+
+impl CommitSigner for Ed25519Signer {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn sign(&self, digest: &CommitDigest) -> Vec<u8> {
+        self.keypair.sign(digest.as_bytes()).to_bytes().to_vec()
+    }
+}
+
+/// Verifies ed25519 signatures from a set of trusted public keys.
+#[derive(Debug, Default)]
+pub struct Ed25519Verifier {
+    keys: HashMap<String, PublicKey>,
+}
+
+impl Ed25519Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts a public key.
+    pub fn trust(mut self, key_id: impl Into<String>, key: PublicKey) -> Self {
+        self.keys.insert(key_id.into(), key);
+        self
+    }
+}
+
+impl CommitVerifier for Ed25519Verifier {
+    fn verify(&self, key_id: &str, digest: &CommitDigest, signature: &[u8]) -> bool {
+        let signature = match Signature::try_from(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        match self.keys.get(key_id) {
+            Some(key) => key.verify(digest.as_bytes(), &signature).is_ok(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ed25519_dalek::SecretKey;
+
+    fn ed25519_keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    #[test]
+    fn signature_block() {
+        let digest = CommitDigest::hash(b"record");
+
+        let unsigned = encode_signature(None, &digest);
+        assert_eq!(unsigned, [0; 8]);
+        assert_eq!(parse_signature(&unsigned), Ok((8, None)));
+
+        let signer = HmacSigner::new("abc", &b"secret"[..]);
+        let block = encode_signature(Some(&signer), &digest);
+        assert_eq!(block.len(), 3 + 32 + 5 + 8);
+
+        let buf = [&[0xaa; 16][..], &block].concat();
+        let (len, signature) = parse_signature(&buf).unwrap();
+        assert_eq!(len, block.len());
+        assert_eq!(signature, Some(CommitSignature { key_id: "abc", signature: &signer.sign(&digest) }));
+
+        assert_eq!(parse_signature(&block[1 ..]), Err(CommitRecordError::Truncated));
+        assert_eq!(parse_signature(&[]), Err(CommitRecordError::Truncated));
+    }
+
+    #[test]
+    fn hmac() {
+        let digest = CommitDigest::hash(b"record");
+        let signer = HmacSigner::new("a", &b"secret"[..]);
+        let signature = signer.sign(&digest);
+
+        let verifier = HmacVerifier::new().trust("a", &b"secret"[..])
+                                          .trust("b", &b"other"[..]);
+        assert!(verifier.verify("a", &digest, &signature));
+        assert!(!verifier.verify("b", &digest, &signature));
+        assert!(!verifier.verify("c", &digest, &signature));
+        assert!(!verifier.verify("a", &CommitDigest::hash(b"other"), &signature));
+        assert!(!verifier.verify("a", &digest, &signature[1 ..]));
+    }
+
+    #[test]
+    fn ed25519() {
+        let digest = CommitDigest::hash(b"record");
+        let signer = Ed25519Signer::new("a", ed25519_keypair(1));
+        let signature = signer.sign(&digest);
+        assert_eq!(signature.len(), 64);
+
+        let verifier = Ed25519Verifier::new().trust("a", signer.public_key())
+                                             .trust("b", ed25519_keypair(2).public);
+        assert!(verifier.verify("a", &digest, &signature));
+        assert!(!verifier.verify("b", &digest, &signature));
+        assert!(!verifier.verify("a", &CommitDigest::hash(b"other"), &signature));
+        assert!(!verifier.verify("a", &digest, &signature[1 ..]));
+    }
+}
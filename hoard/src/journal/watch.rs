@@ -0,0 +1,61 @@
+//! Waiting for changes to a journal file.
+//!
+//! On Linux, changes are watched for with inotify. Elsewhere, or if inotify isn't available, the
+//! file is simply polled.
+
+use std::fs::File;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+/// How often the file is polled when it can't be watched.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits for a file to change.
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    #[cfg(target_os = "linux")]
+    inotify: Option<inotify::Inotify>,
+}
+
+impl Watcher {
+    /// Starts watching a file.
+    ///
+    /// Changes made after this returns will wake up `wait()`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new(fd: &File) -> Self {
+        use std::os::unix::io::AsRawFd;
+        use inotify::{Inotify, WatchMask};
+
+        // We only have the fd, not the path; inotify follows the /proc symlink to the file.
+        let inotify = Inotify::init().and_then(|mut inotify| {
+            let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+            inotify.add_watch(path, WatchMask::MODIFY)?;
+            Ok(inotify)
+        });
+
+        Self { inotify: inotify.ok() }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn new(_fd: &File) -> Self {
+        Self {}
+    }
+
+    /// Blocks until the file might have changed.
+    ///
+    /// Spurious wakeups are possible.
+    pub(crate) fn wait(&mut self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(inotify) = &mut self.inotify {
+                let mut buf = [0; 1024];
+                inotify.read_events_blocking(&mut buf)?;
+                return Ok(());
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+        Ok(())
+    }
+}
@@ -44,6 +44,8 @@ compile_error!("little endian required");
 
 use thiserror::Error;
 
+pub use leint::Le;
+
 pub mod refs;
 pub mod pointee;
 pub mod scalar;
@@ -53,15 +55,24 @@ pub mod ptr;
 
 pub mod load;
 pub mod save;
+pub mod validate;
+pub mod usage;
+pub mod progress;
 
 pub mod impls;
+pub mod coerce;
 
 pub mod heap;
 pub mod bag;
+pub mod compressed;
 
 pub mod offset;
+pub mod reloffset;
 pub mod pile;
 
+pub mod fingerprint;
+pub mod journal;
+
 /*
 pub mod zone;
 pub mod load;
@@ -25,6 +25,13 @@ pub trait Load : IntoOwned + ValidateBlob {
     fn try_deref_blob<'a>(blob: ValidBlob<'a, Self>, zone: &<Self::Ptr as Ptr>::BlobZone) -> Result<&'a Self, ValidBlob<'a, Self>> {
         Err(blob)
     }
+
+    /// Heap memory owned by a decoded value, not counting the value itself.
+    ///
+    /// Used to account for decoded values held by a `DecodeCache`.
+    fn heap_size(_owned: &Self::Owned) -> usize {
+        0
+    }
 }
 
 /// Automatically implemented for `Sized` types that implement `Load`.
@@ -45,7 +52,14 @@ pub trait LoadPtr<P: Ptr> : IntoOwned + ValidateBlob {
 
     fn deref_blob<'a>(blob: ValidBlob<'a, Self>, zone: &P::BlobZone) -> Ref<'a, Self>
     {
-        Ref::Owned(Self::decode_blob(blob, zone))
+        match Self::try_deref_blob(blob, zone) {
+            Ok(r) => Ref::Ref(r),
+            Err(blob) => Ref::Owned(Self::decode_blob(blob, zone)),
+        }
+    }
+
+    fn heap_size(_owned: &Self::Owned) -> usize {
+        0
     }
 }
 
@@ -62,4 +76,12 @@ where P::BlobZone: AsZone<<T::Ptr as Ptr>::BlobZone>
     fn decode_blob(blob: ValidBlob<Self>, zone: &P::BlobZone) -> Self::Owned {
         T::decode_blob(blob, zone.as_zone())
     }
+
+    fn try_deref_blob<'a>(blob: ValidBlob<'a, Self>, zone: &P::BlobZone) -> Result<&'a Self, ValidBlob<'a, Self>> {
+        T::try_deref_blob(blob, zone.as_zone())
+    }
+
+    fn heap_size(owned: &Self::Owned) -> usize {
+        T::heap_size(owned)
+    }
 }
@@ -1,10 +0,0 @@
-pub trait PtrValidator<Z> {
-    type Error;
-}
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn test() {
-    }
-}
@@ -23,8 +23,10 @@ use crate::scalar::*;
 use crate::ptr::*;
 use crate::pile::*;
 use crate::heap::*;
+use crate::fingerprint::{Schema, SchemaHasher};
+use crate::coerce::TryCoerce;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Offset<'pile, 'version> {
     marker: PhantomData<(
@@ -71,7 +73,22 @@ impl Scalar for Offset<'_, '_> {
     }
 
     fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
-        todo!()
+        dst.write_bytes(&self.raw.get().get().to_le_bytes())?
+           .finish()
+    }
+}
+
+impl Schema for Offset<'_, '_> {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("offset")
+              .write_layout(Self::BLOB_LAYOUT);
+    }
+}
+
+/// `OffsetMut` is persisted as an `Offset`, so the two share a schema.
+impl Schema for OffsetMut<'_, '_> {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        Offset::describe_schema(hasher)
     }
 }
 
@@ -216,11 +233,14 @@ impl<'p, 'v> Offset<'p, 'v> {
         }
     }
 
-    /// Casts the `Offset` to a different lifetime.
+    /// Casts the `Offset` to different lifetimes.
+    ///
+    /// # Safety
     ///
-    /// This is *safe* because an offset by itself has no guarantees associated with it.
+    /// The `'p` lifetime brands an offset as belonging to a particular pile. The offset must be
+    /// in range of the pile branded `'p2`, and valid for `'v2`.
     #[inline(always)]
-    pub fn cast<'p2, 'v2>(&self) -> Offset<'p2, 'v2> {
+    pub unsafe fn cast<'p2, 'v2>(&self) -> Offset<'p2, 'v2> {
         Offset {
             marker: PhantomData,
             raw: self.raw,
@@ -275,6 +295,16 @@ pub enum Kind<'p, 'v> {
     Ptr(HeapPtr),
 }
 
+impl<'p, 'v> OffsetMut<'p, 'v> {
+    /// Moves a value to the heap, returning a dirty `OffsetMut` pointing to it.
+    ///
+    /// The value is owned by the returned pointer, and must be deallocated with `Ptr::dealloc()`.
+    pub fn alloc<T>(value: T) -> Self {
+        // SAFETY: heap allocations are at least 2-byte aligned
+        unsafe { Self::from_ptr_unchecked(heap_alloc_value(value)) }
+    }
+}
+
 impl<'p, 'v, A> OffsetMut<'p, 'v, A> {
     /// Create an `OffsetMut` from a pointer.
     ///
@@ -283,7 +313,8 @@ impl<'p, 'v, A> OffsetMut<'p, 'v, A> {
     pub fn from_ptr(ptr: NonNull<u16>) -> Option<Self> {
         let raw = ptr.as_ptr() as usize as u64;
 
-        if raw & 1 == 1 {
+        // offsets have the low bit set, so pointers must not
+        if raw & 1 == 0 {
             unsafe { Some(mem::transmute(ptr.as_ptr() as usize as u64)) }
         } else {
             None
@@ -359,9 +390,7 @@ impl<'p, 'v> Ptr for OffsetMut<'p, 'v> {
 
     unsafe fn try_get_dirty_unchecked<T: ?Sized + Pointee>(&self, metadata: T::Metadata) -> Result<&T, Self::Persist> {
         match self.kind() {
-            Kind::Ptr(ptr) => {
-                todo!()
-            },
+            Kind::Ptr(ptr) => Ok(ptr.try_get_dirty_unchecked::<T>(metadata).into_ok()),
             Kind::Offset(offset) => Err(offset),
         }
     }
@@ -431,17 +460,69 @@ impl<'p, 'v> ShallowDumper<'p, 'v> {
         where T: SavePtr<OffsetMut<'p, 'v>, Offset<'p, 'v>>
     {
         let mut encoder = value.init_save_ptr();
-        encoder.save_poll(&mut self).into_ok();
-        let offset = self.finish_save(&encoder).into_ok();
+        let offset = save_deep(&mut self, &mut encoder).into_ok();
         (self.written, offset)
     }
 }
 
+unsafe impl<'p, 'v1: 'v2, 'v2> TryCoerce<Offset<'p, 'v2>> for Offset<'p, 'v1> {
+    type Error = !;
+}
+
+unsafe impl<'p, 'v1: 'v2, 'v2> TryCoerce<OffsetMut<'p, 'v2>> for OffsetMut<'p, 'v1> {
+    type Error = !;
+}
+
+/// Error returned when trying to coerce a dirty `OffsetMut` to an `Offset`.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("offset is dirty")]
+pub struct TryCoerceDirtyError;
+
+unsafe impl<'p, 'v1: 'v2, 'v2> TryCoerce<Offset<'p, 'v2>> for OffsetMut<'p, 'v1> {
+    type Error = TryCoerceDirtyError;
+
+    fn try_coerce_ptr(this: &Self) -> Result<*const Offset<'p, 'v2>, TryCoerceDirtyError> {
+        match this.kind() {
+            // SAFETY: #[repr(transparent)]
+            Kind::Offset(_) => Ok(this as *const Self as *const _),
+            Kind::Ptr(_) => Err(TryCoerceDirtyError),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::bag::Bag;
 
+    use proptest::prelude::*;
+
+    use crate::impls::check_canonical;
+
+    proptest! {
+        #[test]
+        fn offset_canonical(bytes in any::<[u8; 8]>()) {
+            check_canonical::<Offset<'static, 'static>>(&bytes);
+        }
+
+        #[test]
+        fn offset_valid_roundtrip(n in 0 .. Offset::MAX) {
+            let raw = ((n as u64) << 1 | 1).to_le_bytes();
+            prop_assert!(check_canonical::<Offset<'static, 'static>>(&raw));
+        }
+    }
+
+    #[test]
+    fn try_coerce_offset_mut() {
+        let clean: OffsetMut = Offset::new(42).unwrap().into();
+        let offset: Offset = clean.try_coerce().unwrap();
+        assert_eq!(offset, 42);
+
+        let dirty: OffsetMut = OffsetMut::from_ptr(NonNull::dangling()).unwrap();
+        assert_eq!(TryCoerce::<Offset>::try_coerce(dirty).unwrap_err(),
+                   TryCoerceDirtyError);
+    }
+
     #[test]
     fn test_shallow_dumper() {
         let (buf, offset) = ShallowDumper::new(0).save(&42u8);
@@ -0,0 +1,458 @@
+//! Caching of decoded values.
+//!
+//! Types that can't be dereferenced directly from a pile have to be decoded on every load. A
+//! `DecodeCache` keeps recently decoded values around, keyed by offset and type, so that repeated
+//! loads of the same blob return a reference to the already decoded value.
+//!
+//! The cache is used by loading through a `CachedPile` zone rather than the pile itself.
+//!
+//! # Memory use
+//!
+//! A cached value can't be dropped while a reference to it may be outstanding, which is for as
+//! long as the cache is borrowed. Evicted values are retired instead, and only dropped by
+//! `DecodeCache::purge()`, or when a new `CachedPile` is created, as both borrow the cache
+//! mutably. Retired values have a budget of their own, as large as the cache's, so memory use can
+//! reach twice the budget. Once the retired values have used up theirs, nothing more is cached
+//! until they're dropped.
+
+use std::any::TypeId;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::NonNull;
+
+use owned::IntoOwned;
+
+use crate::fingerprint::erased_type_id;
+use crate::load::LoadPtr;
+use crate::offset::Offset;
+use crate::ptr::{Ptr, TryGetPtr};
+use crate::refs::Ref;
+
+use super::{TryPile, LoadError};
+
+/// Cache hit and miss statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// A bounded, least-recently-used, cache of values decoded from a pile.
+///
+/// The cache is branded with the pile's `'p` lifetime, so it can only be used with that pile (or
+/// successive versions of it). Since values are decoded from the pile's bytes, the cache is also
+/// tied to the `'v` lifetime of those bytes, which must outlive it.
+///
+/// The memory budget covers both the values themselves, and the heap memory they own as reported
+/// by `LoadPtr::heap_size()`.
+///
+/// References returned by the cache remain valid for as long as the cache is borrowed, so evicted
+/// values can't be dropped immediately. Instead they are retired, and dropped by `purge()`, or by
+/// creating a new `CachedPile`. Retired values get a budget of the same size: **memory use can
+/// reach twice the budget**, and once retired values have used theirs up, nothing more is evicted,
+/// so further loads return owned values without caching them. Long-lived users should create a
+/// new `CachedPile` for each batch of loads, so that retired values are dropped regularly.
+pub struct DecodeCache<'p, 'v> {
+    marker: PhantomData<fn(&'p (), &'v ()) -> (&'p (), &'v ())>,
+    budget: usize,
+    state: RefCell<CacheState>,
+}
+
+/// Offset, blob size, and (lifetime erased) type.
+type Key = (usize, usize, TypeId);
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<Key, Entry>,
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    used: usize,
+    retired: Vec<ErasedBox>,
+    retired_size: usize,
+    stats: CacheStats,
+}
+
+struct Entry {
+    value: ErasedBox,
+    size: usize,
+    tick: u64,
+}
+
+/// A boxed value of an erased, possibly non-`'static`, type.
+struct ErasedBox {
+    ptr: NonNull<()>,
+    drop: unsafe fn(NonNull<()>),
+}
+
+impl ErasedBox {
+    fn new<T>(value: T) -> Self {
+        unsafe fn drop_box<T>(ptr: NonNull<()>) {
+            drop(Box::from_raw(ptr.cast::<T>().as_ptr()))
+        }
+
+        Self {
+            ptr: NonNull::from(Box::leak(Box::new(value))).cast(),
+            drop: drop_box::<T>,
+        }
+    }
+}
+
+impl Drop for ErasedBox {
+    fn drop(&mut self) {
+        // SAFETY: ptr and drop were created together from a Box<T> in new()
+        unsafe { (self.drop)(self.ptr) }
+    }
+}
+
+impl fmt::Debug for DecodeCache<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.borrow();
+        f.debug_struct("DecodeCache")
+            .field("budget", &self.budget)
+            .field("used", &state.used)
+            .field("len", &state.entries.len())
+            .field("retired", &state.retired.len())
+            .field("retired_size", &state.retired_size)
+            .field("stats", &state.stats)
+            .finish()
+    }
+}
+
+/// Not `#[may_dangle]`: cached values may borrow from the pile, so `'v` must still be alive when
+/// they're dropped.
+impl Drop for DecodeCache<'_, '_> {
+    fn drop(&mut self) {
+    }
+}
+
+impl<'p, 'v> DecodeCache<'p, 'v> {
+    /// Creates a new cache with the specified memory budget, in bytes.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            marker: PhantomData,
+            budget,
+            state: Default::default(),
+        }
+    }
+
+    /// Gets the memory budget.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Gets the memory used by cached values.
+    pub fn used(&self) -> usize {
+        self.state.borrow().used
+    }
+
+    /// Gets the memory used by retired values, waiting to be purged.
+    pub fn retired(&self) -> usize {
+        self.state.borrow().retired_size
+    }
+
+    /// Gets the number of cached values.
+    pub fn len(&self) -> usize {
+        self.state.borrow().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the hit and miss statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.state.borrow().stats
+    }
+
+    /// Gets a cached value, marking it as recently used.
+    ///
+    /// # Safety
+    ///
+    /// Types are compared with their lifetimes erased, so the value at `offset` must have been
+    /// inserted as exactly `T`, lifetimes included. Values decoded from a `TryPile<'p, 'v>` only
+    /// borrow from `'p` and `'v`, which is why `CachedPile` upholds this.
+    pub(crate) unsafe fn get<T: ?Sized + IntoOwned>(&self, offset: Offset<'p, 'v>, size: usize) -> Option<&T> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let key = (offset.get(), size, erased_type_id::<T>());
+
+        if let Some(entry) = state.entries.get_mut(&key) {
+            state.stats.hits += 1;
+
+            state.tick += 1;
+            state.lru.remove(&entry.tick);
+            state.lru.insert(state.tick, key);
+            entry.tick = state.tick;
+
+            // SAFETY: keyed by type, and boxes are only dropped by methods taking &mut self
+            let owned = &*entry.value.ptr.cast::<T::Owned>().as_ptr();
+            Some(owned.borrow())
+        } else {
+            state.stats.misses += 1;
+            None
+        }
+    }
+
+    /// Inserts a value, evicting least-recently-used values as needed to stay within budget.
+    ///
+    /// The value is given back if it can't be cached: either because it's larger than the entire
+    /// budget, or because the retired values waiting to be purged have used up the budget.
+    ///
+    /// # Safety
+    ///
+    /// Same as `get()`.
+    pub(crate) unsafe fn insert<T: ?Sized + IntoOwned>(
+        &self,
+        offset: Offset<'p, 'v>,
+        size: usize,
+        value: T::Owned,
+        heap_size: usize,
+    ) -> Result<&T, T::Owned>
+    {
+        let mut state = self.state.borrow_mut();
+        let key = (offset.get(), size, erased_type_id::<T>());
+
+        if let Some(entry) = state.entries.get(&key) {
+            // outstanding references may point to the existing value, so keep it
+            let owned = &*entry.value.ptr.cast::<T::Owned>().as_ptr();
+            return Ok(owned.borrow());
+        }
+
+        let cost = mem::size_of::<T::Owned>().saturating_add(heap_size);
+        if cost > self.budget {
+            return Err(value);
+        }
+
+        while state.used + cost > self.budget {
+            if !state.try_evict_lru(self.budget) {
+                return Err(value);
+            }
+        }
+
+        let value = ErasedBox::new(value);
+        let ptr = value.ptr;
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.lru.insert(tick, key);
+        state.used += cost;
+        state.entries.insert(key, Entry { value, size: cost, tick });
+
+        // SAFETY: boxes are only dropped by methods taking &mut self
+        Ok((&*ptr.cast::<T::Owned>().as_ptr()).borrow())
+    }
+
+    /// Drops retired values.
+    pub fn purge(&mut self) {
+        let state = self.state.get_mut();
+        state.retired.clear();
+        state.retired_size = 0;
+    }
+
+    /// Drops all values.
+    pub fn clear(&mut self) {
+        let stats = self.stats();
+        *self.state.get_mut() = CacheState {
+            stats,
+            ..Default::default()
+        };
+    }
+}
+
+impl CacheState {
+    /// Retires the least-recently-used value, if that would keep retired values within budget.
+    fn try_evict_lru(&mut self, budget: usize) -> bool {
+        let (&tick, &key) = self.lru.iter().next().expect("used > 0 implies entries");
+        let size = self.entries[&key].size;
+        if self.retired_size + size > budget {
+            return false;
+        }
+
+        self.lru.remove(&tick);
+        let entry = self.entries.remove(&key).expect("lru and entries in sync");
+        self.used -= size;
+        self.retired_size += size;
+        self.retired.push(entry.value);
+        self.stats.evictions += 1;
+        true
+    }
+}
+
+/// A pile zone that loads through a `DecodeCache`.
+///
+/// Loading a clean pointer through a `CachedPile` returns a reference to a cached value when the
+/// same blob has been decoded before. Types that can be dereferenced directly from the pile bypass
+/// the cache, as do zero-sized blobs, whose metadata can't be told apart by size.
+///
+/// Creating a `CachedPile` borrows the cache mutably, and purges it: no references into it can be
+/// left from loads through earlier zones.
+#[derive(Debug, Clone, Copy)]
+pub struct CachedPile<'c, 'p, 'v> {
+    pile: TryPile<'p, 'v>,
+    cache: &'c DecodeCache<'p, 'v>,
+}
+
+impl<'c, 'p, 'v> CachedPile<'c, 'p, 'v> {
+    /// Creates a new zone, dropping the values retired from the cache.
+    pub fn new(pile: TryPile<'p, 'v>, cache: &'c mut DecodeCache<'p, 'v>) -> Self {
+        cache.purge();
+        Self { pile, cache }
+    }
+
+    /// Gets the underlying pile.
+    pub fn pile(&self) -> TryPile<'p, 'v> {
+        self.pile
+    }
+
+    /// Gets the cache.
+    pub fn cache(&self) -> &'c DecodeCache<'p, 'v> {
+        self.cache
+    }
+}
+
+impl<'c, 'p, 'v, P> TryGetPtr<P> for CachedPile<'c, 'p, 'v>
+    where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = TryPile<'p, 'v>>
+{
+    type Error = LoadError;
+
+    unsafe fn try_get_ptr_unchecked<'a, 'z: 'a, T: ?Sized + LoadPtr<P>>(&'z self, ptr: &'a P, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        let offset = match ptr.try_get_dirty_unchecked::<T>(metadata) {
+            Ok(value) => return Ok(Ref::Ref(value)),
+            Err(offset) => offset,
+        };
+
+        let size = T::try_blob_layout(metadata).ok().map(|layout| layout.size());
+        if let Some(size) = size.filter(|size| *size > 0) {
+            if let Some(r) = self.cache.get::<T>(offset, size) {
+                return Ok(Ref::Ref(r));
+            }
+        }
+
+        let blob = self.pile.get_valid_blob::<T>(offset, metadata)
+                            .map_err(|err| LoadError::from_blob::<T>(offset.get(), err))?;
+        let size = blob.as_bytes().len();
+
+        match T::try_deref_blob(blob, &self.pile) {
+            Ok(r) => Ok(Ref::Ref(r)),
+            Err(blob) if size == 0 => Ok(Ref::Owned(T::decode_blob(blob, &self.pile))),
+            Err(blob) => {
+                let value = T::decode_blob(blob, &self.pile);
+                let heap_size = T::heap_size(&value);
+                match self.cache.insert::<T>(offset, size, value, heap_size) {
+                    Ok(r) => Ok(Ref::Ref(r)),
+                    Err(value) => Ok(Ref::Owned(value)),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::offset::OffsetMut;
+
+    #[test]
+    fn lru_eviction() {
+        let offset = |n| Offset::new(n).unwrap();
+        let mut cache = DecodeCache::new(16);
+
+        unsafe {
+            let a: *const u64 = cache.insert::<u64>(offset(0), 8, 1, 0).unwrap();
+            assert_eq!(cache.insert::<u64>(offset(8), 8, 2, 0), Ok(&2));
+            assert_eq!(cache.used(), 16);
+
+            // same offset, different type, is a different entry
+            assert_eq!(cache.get::<u32>(offset(0), 8), None);
+
+            // touch 0, so 8 is evicted
+            assert_eq!(cache.get::<u64>(offset(0), 8), Some(&1));
+            assert_eq!(cache.insert::<u64>(offset(16), 8, 3, 0), Ok(&3));
+            assert_eq!(cache.get::<u64>(offset(8), 8), None);
+            assert_eq!(cache.get::<u64>(offset(0), 8).map(|r| r as *const u64), Some(a));
+            assert_eq!(cache.len(), 2);
+            assert_eq!(cache.retired(), 8);
+
+            assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 2, evictions: 1 });
+
+            // retired values use up the budget, so nothing more is cached until they're purged
+            assert_eq!(cache.insert::<u64>(offset(24), 8, 4, 0), Ok(&4));
+            assert_eq!(cache.retired(), 16);
+            assert_eq!(cache.insert::<u64>(offset(32), 8, 5, 0), Err(5));
+
+            cache.purge();
+            assert_eq!(cache.retired(), 0);
+            assert_eq!(cache.get::<u64>(offset(24), 8), Some(&4));
+            assert_eq!(cache.insert::<u64>(offset(32), 8, 5, 0), Ok(&5));
+
+            // too big to cache, counting heap memory
+            assert_eq!(cache.insert::<[u64; 4]>(offset(40), 32, [0; 4], 0), Err([0; 4]));
+            assert_eq!(cache.insert::<u64>(offset(48), 8, 6, 100), Err(6));
+            assert_eq!(cache.len(), 2);
+        }
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.used(), 0);
+    }
+
+    #[test]
+    fn cached_pile() {
+        let buf = [1u8, 42];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        let mut cache = DecodeCache::new(1024);
+        let zone = CachedPile::new(pile, &mut cache);
+        let ptr = OffsetMut::from(Offset::new(0).unwrap());
+
+        let r1 = unsafe { zone.try_get_ptr_unchecked::<Option<u8>>(&ptr, ()) }.unwrap();
+        let r2 = unsafe { zone.try_get_ptr_unchecked::<Option<u8>>(&ptr, ()) }.unwrap();
+        match (r1, r2) {
+            (Ref::Ref(r1), Ref::Ref(r2)) => {
+                assert_eq!(r1, &Some(42));
+                assert!(std::ptr::eq(r1, r2));
+            },
+            _ => panic!(),
+        }
+        assert_eq!(zone.cache().stats().hits, 1);
+
+        // zero-copy types bypass the cache
+        let ptr = OffsetMut::from(Offset::new(1).unwrap());
+        let r = unsafe { zone.try_get_ptr_unchecked::<u8>(&ptr, ()) }.unwrap();
+        assert_eq!(*r, 42);
+        assert_eq!(zone.cache().len(), 1);
+
+        // errors are the pile's
+        let ptr = OffsetMut::from(Offset::new(2).unwrap());
+        match unsafe { zone.try_get_ptr_unchecked::<Option<u8>>(&ptr, ()) } {
+            Err(LoadError { offset: 2, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn cached_pile_purges() {
+        let buf = [1u8, 42, 1, 43];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        let mut cache = DecodeCache::new(mem::size_of::<Option<u8>>());
+
+        let zone = CachedPile::new(pile, &mut cache);
+        for offset in [0, 2].iter() {
+            let ptr = OffsetMut::from(Offset::new(*offset).unwrap());
+            unsafe { zone.try_get_ptr_unchecked::<Option<u8>>(&ptr, ()) }.unwrap();
+        }
+        assert_eq!(zone.cache().retired(), mem::size_of::<Option<u8>>());
+
+        // nothing loaded through the old zone can still be borrowed
+        let zone = CachedPile::new(pile, &mut cache);
+        assert_eq!(zone.cache().retired(), 0);
+        assert_eq!(zone.cache().len(), 1);
+    }
+}
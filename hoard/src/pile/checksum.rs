@@ -0,0 +1,129 @@
+//! Optional per-blob checksums.
+//!
+//! Validation only catches corruption that happens to produce an invalid value; a flipped bit in
+//! an integer goes unnoticed. When checksums are enabled, every blob is followed by a checksum of
+//! its bytes, which is verified before the blob is handed out for validation.
+
+use std::convert::TryInto;
+
+use thiserror::Error;
+
+/// The checksum stored after each blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Checksum {
+    /// No checksum.
+    None,
+
+    /// A little-endian CRC-32C (Castagnoli).
+    Crc32c,
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Checksum::None
+    }
+}
+
+/// Returned when a blob's checksum doesn't match.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("checksum mismatch for blob at offset {offset}: expected {expected:#010x}, got {actual:#010x}")]
+pub struct ChecksumError {
+    pub offset: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl Checksum {
+    /// Gets the checksum corresponding to a tag byte.
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Checksum::None),
+            1 => Some(Checksum::Crc32c),
+            _ => None,
+        }
+    }
+
+    /// Gets the tag byte used to record the checksum type.
+    pub fn to_tag(self) -> u8 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc32c => 1,
+        }
+    }
+
+    /// Returns the number of bytes the checksum takes up after each blob.
+    pub fn len(self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc32c => 4,
+        }
+    }
+
+    /// Appends the checksum of `buf[start ..]` to `buf`.
+    pub fn append(self, buf: &mut Vec<u8>, start: usize) {
+        match self {
+            Checksum::None => {},
+            Checksum::Crc32c => {
+                let crc = crc32c(&buf[start ..]);
+                buf.extend_from_slice(&crc.to_le_bytes());
+            },
+        }
+    }
+
+    /// Verifies the checksum of a blob.
+    ///
+    /// `stored` must be exactly `self.len()` bytes.
+    pub fn verify(self, offset: usize, blob: &[u8], stored: &[u8]) -> Result<(), ChecksumError> {
+        assert_eq!(stored.len(), self.len());
+        match self {
+            Checksum::None => Ok(()),
+            Checksum::Crc32c => {
+                let expected = u32::from_le_bytes(stored.try_into().unwrap());
+                let actual = crc32c(blob);
+                if expected == actual {
+                    Ok(())
+                } else {
+                    Err(ChecksumError { offset, expected, actual })
+                }
+            },
+        }
+    }
+}
+
+/// Computes the CRC-32C of some bytes.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    const POLY: u32 = 0x82f6_3b78;
+
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0 .. 8 {
+            crc = (crc >> 1) ^ (POLY & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn append_verify() {
+        let mut buf = vec![0xff, 1, 2, 3];
+        Checksum::Crc32c.append(&mut buf, 1);
+        assert_eq!(buf.len(), 8);
+        Checksum::Crc32c.verify(1, &buf[1 .. 4], &buf[4 ..]).unwrap();
+
+        buf[2] ^= 0x10;
+        let err = Checksum::Crc32c.verify(1, &buf[1 .. 4], &buf[4 ..]).unwrap_err();
+        assert_eq!(err.offset, 1);
+        assert_ne!(err.expected, err.actual);
+    }
+}
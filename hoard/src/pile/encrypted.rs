@@ -0,0 +1,523 @@
+//! Encryption at rest.
+//!
+//! In an encrypted journal every blob is encrypted with XChaCha20-Poly1305, using a key supplied
+//! by the application. The ciphertext is preceded by the blob's write generation, and followed by
+//! the 16 byte authentication tag, so corruption and tampering are detected as well.
+//!
+//! Encrypted blobs can't be used in place, so an `EncryptedPile` decrypts each blob into a
+//! temporary buffer and decodes an owned copy of the value from it.
+//!
+//! An `EncryptedPile` loads pointers such as `OffsetMut` through `TryGetPtr`. Their values are
+//! decoded with an empty `TryPile` rather than the pile of ciphertext, so the children of a loaded
+//! value have to be loaded through the `EncryptedPile` too; they can never be mistaken for
+//! plaintext. Pointers that load through a zone of their own should be `EncryptedPilePtr`s, whose
+//! zone decrypts as well.
+//!
+//! # Nonces
+//!
+//! The 24 byte nonce of a blob is made up of a random salt chosen when the journal is created, the
+//! write generation, and the blob's offset. A failed commit truncates the journal, and the offsets
+//! it used will be reused by the next commit; the generation is bumped on every truncate, so even
+//! if the failed write had already reached the disk no nonce is ever used twice. The generation
+//! starts from a random value whenever a journal is opened for writing, as the generations used by
+//! earlier writers aren't known.
+//!
+//! The salt keeps nonces apart if a key is accidentally used for more than one journal, but keys
+//! should still be unique to a journal.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Tag, KeyInit, AeadInPlace};
+use thiserror::Error;
+
+use crate::pointee::Pointee;
+use crate::refs::Ref;
+use crate::blob::*;
+use crate::load::*;
+use crate::ptr::*;
+use crate::offset::Offset;
+
+use super::{TryPile, LoadError, LoadErrorKind};
+
+/// The length of the write generation preceding each encrypted blob.
+pub const GENERATION_LEN: usize = 8;
+
+/// The length of the authentication tag following each encrypted blob.
+pub const TAG_LEN: usize = 16;
+
+/// A 256-bit encryption key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Encrypts and decrypts blobs.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    salt: u64,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+/// Returned when a blob fails to decrypt.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("blob at offset {offset} failed to decrypt")]
+pub struct DecryptError {
+    pub offset: usize,
+}
+
+impl Cipher {
+    /// Creates a cipher for the journal with the specified salt.
+    pub fn new(key: &Key, salt: u64) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(&key.0.into()),
+            salt,
+        }
+    }
+
+    pub fn salt(&self) -> u64 {
+        self.salt
+    }
+
+    fn nonce(&self, generation: u64, offset: usize) -> XNonce {
+        let mut nonce = [0; 24];
+        nonce[.. 8].copy_from_slice(&self.salt.to_le_bytes());
+        nonce[8 .. 16].copy_from_slice(&generation.to_le_bytes());
+        nonce[16 ..].copy_from_slice(&(offset as u64).to_le_bytes());
+        nonce.into()
+    }
+
+    /// Encrypts the blob at `buf[start ..]` in place, inserting the generation before it and
+    /// appending the tag.
+    ///
+    /// `offset` is the offset the blob will be written at, and `generation` the current write
+    /// generation of the journal. The same pair must never be used twice.
+    pub fn encrypt(&self, offset: usize, generation: u64, buf: &mut Vec<u8>, start: usize) {
+        let tag = self.aead.encrypt_in_place_detached(&self.nonce(generation, offset), b"", &mut buf[start ..])
+                           .expect("blob too large to encrypt");
+        buf.splice(start .. start, generation.to_le_bytes().iter().copied());
+        buf.extend_from_slice(&tag);
+    }
+
+    /// Decrypts a blob, preceded by its generation and followed by its tag.
+    pub fn decrypt(&self, offset: usize, encrypted: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if encrypted.len() < GENERATION_LEN + TAG_LEN {
+            return Err(DecryptError { offset });
+        }
+        let (generation, rest) = encrypted.split_at(GENERATION_LEN);
+        let generation = u64::from_le_bytes(generation.try_into().unwrap());
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let tag: [u8; TAG_LEN] = tag.try_into().unwrap();
+
+        let mut buf = ciphertext.to_vec();
+        self.aead.decrypt_in_place_detached(&self.nonce(generation, offset), b"", &mut buf, &Tag::from(tag))
+                 .map_err(|_| DecryptError { offset })?;
+        Ok(buf)
+    }
+}
+
+/// Returned when a value can't be loaded from an `EncryptedPile`.
+#[derive(Debug, Error)]
+pub enum EncryptedLoadError<LayoutError: fmt::Debug, ValidateError: fmt::Debug> {
+    #[error("blob at offset {offset} has an invalid layout: {err:?}")]
+    Layout {
+        offset: usize,
+        err: LayoutError,
+    },
+
+    #[error("blob at offset {offset} is out of range")]
+    OutOfRange {
+        offset: usize,
+    },
+
+    #[error("{0}")]
+    Decrypt(#[from] DecryptError),
+
+    #[error("blob at offset {offset} is invalid: {err:?}")]
+    Validate {
+        offset: usize,
+        err: ValidateError,
+    },
+}
+
+impl LoadError {
+    /// Converts the error from loading a value out of an `EncryptedPile`.
+    pub fn from_encrypted<T: ?Sized + ValidateBlob>(err: EncryptedLoadError<T::LayoutError, T::BlobError>) -> Self {
+        let (offset, kind) = match err {
+            EncryptedLoadError::Layout { offset, err } => (offset, LoadErrorKind::Layout(err.into())),
+            EncryptedLoadError::OutOfRange { offset } => (offset, LoadErrorKind::OutOfRange),
+            EncryptedLoadError::Decrypt(err) => (err.offset, LoadErrorKind::Decrypt(err)),
+            EncryptedLoadError::Validate { offset, err } => (offset, LoadErrorKind::Validate(err.into())),
+        };
+        Self::new::<T>(offset, kind)
+    }
+}
+
+/// A pile whose blobs are encrypted.
+#[derive(Debug, Clone, Copy)]
+pub struct EncryptedPile<'p, 'v, 'c> {
+    pile: TryPile<'p, 'v>,
+    cipher: &'c Cipher,
+}
+
+impl<'p, 'v, 'c> EncryptedPile<'p, 'v, 'c> {
+    pub fn new(pile: TryPile<'p, 'v>, cipher: &'c Cipher) -> Self {
+        Self { pile, cipher }
+    }
+
+    /// Gets the underlying pile of encrypted blobs.
+    pub fn pile(&self) -> TryPile<'p, 'v> {
+        self.pile
+    }
+
+    /// Decrypts the blob at an offset.
+    pub fn decrypt_blob<T: ?Sized + ValidateBlob>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata)
+        -> Result<Vec<u8>, EncryptedLoadError<T::LayoutError, T::BlobError>>
+    {
+        let start = offset.get();
+        let size = T::try_blob_layout(metadata)
+                     .map_err(|err| EncryptedLoadError::Layout { offset: start, err })?
+                     .size();
+
+        let encrypted = start.checked_add(size)
+                             .and_then(|end| end.checked_add(GENERATION_LEN + TAG_LEN))
+                             .and_then(|end| self.pile.as_bytes().get(start .. end))
+                             .ok_or(EncryptedLoadError::OutOfRange { offset: start })?;
+
+        Ok(self.cipher.decrypt(start, encrypted)?)
+    }
+
+    /// Gets the zone values loaded with `EncryptedPilePtr`s use.
+    pub fn zone(&self) -> EncryptedZone<'p, 'v, 'c> {
+        EncryptedZone(*self)
+    }
+
+    /// Decrypts, validates, and decodes the value at an offset, decoding it with `zone`.
+    fn decode<T, P>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata, zone: &P::BlobZone)
+        -> Result<T::Owned, EncryptedLoadError<T::LayoutError, T::BlobError>>
+        where T: ?Sized + LoadPtr<P>,
+              P: Ptr,
+    {
+        let buf = self.decrypt_blob::<T>(offset, metadata)?;
+
+        // SAFETY: decrypt_blob() returned exactly the blob's size
+        let blob = unsafe { Blob::<T>::new_unchecked(&buf, metadata) };
+        let blob = T::validate_blob(blob, false)
+                     .map_err(|err| EncryptedLoadError::Validate { offset: offset.get(), err })?;
+
+        Ok(T::decode_blob(blob, zone))
+    }
+
+    /// Decrypts, validates, and decodes the value at an offset.
+    ///
+    /// The value is decoded with an empty `TryPile`, so its children have to be loaded through
+    /// this `EncryptedPile` as well.
+    pub fn try_take<T>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata)
+        -> Result<T::Owned, EncryptedLoadError<T::LayoutError, T::BlobError>>
+        where T: ?Sized + LoadPtr<Offset<'p, 'v>>
+    {
+        self.decode::<T, Offset<'p, 'v>>(offset, metadata, &TryPile::default())
+    }
+
+    /// Gets the value behind a pointer.
+    ///
+    /// Dirty values are returned by reference, as usual; clean values are decrypted into
+    /// `Ref::Owned`, decoded with an empty `TryPile` like `try_take()` does.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be valid for a `T` with the specified metadata.
+    pub unsafe fn try_get_unchecked<'a, T, P>(&self, ptr: &'a P, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, EncryptedLoadError<T::LayoutError, T::BlobError>>
+        where T: ?Sized + LoadPtr<P>,
+              P: Ptr<Persist = Offset<'p, 'v>, BlobZone = TryPile<'p, 'v>>,
+    {
+        match ptr.try_get_dirty_unchecked::<T>(metadata) {
+            Ok(value) => Ok(Ref::Ref(value)),
+            Err(offset) => self.decode::<T, P>(offset, metadata, &TryPile::default()).map(Ref::Owned),
+        }
+    }
+}
+
+/// Loads the targets of pointers such as `OffsetMut`, whose zone is a `TryPile`.
+///
+/// Values are decoded with an empty `TryPile`, as such pointers are loaded through an explicit
+/// zone: the children of a loaded value are decrypted by this `EncryptedPile` too.
+impl<'p, 'v, 'c, P> TryGetPtr<P> for EncryptedPile<'p, 'v, 'c>
+    where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = TryPile<'p, 'v>>
+{
+    type Error = LoadError;
+
+    unsafe fn try_get_ptr_unchecked<'a, 'z: 'a, T: ?Sized + LoadPtr<P>>(&'z self, ptr: &'a P, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        self.try_get_unchecked::<T, P>(ptr, metadata)
+            .map_err(LoadError::from_encrypted::<T>)
+    }
+}
+
+/// The zone of an `EncryptedPile`, which values decoded from it keep.
+#[derive(Debug, Clone, Copy)]
+pub struct EncryptedZone<'p, 'v, 'c>(EncryptedPile<'p, 'v, 'c>);
+
+impl AsZone<Self> for EncryptedZone<'_, '_, '_> {
+    fn as_zone(&self) -> &Self {
+        self
+    }
+}
+
+impl AsZone<()> for EncryptedZone<'_, '_, '_> {
+    fn as_zone(&self) -> &() {
+        &()
+    }
+}
+
+/// Decrypts the targets of pointers, decoding them with this zone.
+impl<'p, 'v, 'c, P> TryGetPtr<P> for EncryptedZone<'p, 'v, 'c>
+    where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = Self>
+{
+    type Error = LoadError;
+
+    unsafe fn try_get_ptr_unchecked<'a, 'z: 'a, T: ?Sized + LoadPtr<P>>(&'z self, ptr: &'a P, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        match ptr.try_get_dirty_unchecked::<T>(metadata) {
+            Ok(value) => Ok(Ref::Ref(value)),
+            Err(offset) => self.0.decode::<T, P>(offset, metadata, self)
+                                 .map(Ref::Owned)
+                                 .map_err(LoadError::from_encrypted::<T>),
+        }
+    }
+}
+
+/// A pointer into an `EncryptedPile`, whose target is decrypted when loaded.
+#[derive(Debug)]
+pub struct EncryptedPilePtr<'p, 'v, 'c> {
+    offset: Offset<'p, 'v>,
+    zone: EncryptedZone<'p, 'v, 'c>,
+}
+
+impl<'p, 'v, 'c> EncryptedPilePtr<'p, 'v, 'c> {
+    /// Creates a pointer to an offset within a pile.
+    pub fn new(offset: Offset<'p, 'v>, zone: EncryptedZone<'p, 'v, 'c>) -> Self {
+        Self { offset, zone }
+    }
+}
+
+unsafe impl<'p, 'v> ValidateBlob for EncryptedPilePtr<'p, 'v, '_> {
+    type BlobError = <Offset<'p, 'v> as ValidateBlob>::BlobError;
+
+    fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+        Ok(<Offset<'p, 'v> as ValidateBlob>::blob_layout())
+    }
+
+    fn validate_blob(blob: Blob<Self>, ignore_padding: bool) -> Result<ValidBlob<Self>, Self::BlobError> {
+        let mut fields = blob.validate_fields(ignore_padding);
+        fields.validate_blob::<Offset<'p, 'v>>()?;
+        unsafe { Ok(fields.finish()) }
+    }
+}
+
+impl<'p, 'v, 'c> Load for EncryptedPilePtr<'p, 'v, 'c> {
+    type Ptr = Self;
+
+    fn decode_blob(blob: ValidBlob<Self>, zone: &EncryptedZone<'p, 'v, 'c>) -> Self {
+        let mut fields = blob.decode_fields(zone);
+        let offset = unsafe { fields.decode_unchecked() };
+        fields.finish();
+
+        Self {
+            zone: *zone,
+            offset,
+        }
+    }
+}
+
+impl AsPtrImpl<Self> for EncryptedPilePtr<'_, '_, '_> {
+    fn as_ptr_impl(this: &Self) -> &Self {
+        this
+    }
+}
+
+impl<'p, 'v, 'c> Ptr for EncryptedPilePtr<'p, 'v, 'c> {
+    type Zone = EncryptedZone<'p, 'v, 'c>;
+    type BlobZone = EncryptedZone<'p, 'v, 'c>;
+    type Persist = Offset<'p, 'v>;
+
+    unsafe fn dealloc<T: ?Sized + Pointee>(&self, metadata: T::Metadata) {
+        self.offset.dealloc::<T>(metadata)
+    }
+
+    unsafe fn try_get_dirty_unchecked<T: ?Sized + Pointee>(&self, metadata: T::Metadata) -> Result<&T, Self::Persist> {
+        self.offset.try_get_dirty_unchecked::<T>(metadata)
+    }
+}
+
+impl<'p, 'v, 'c> TryGet for EncryptedPilePtr<'p, 'v, 'c> {
+    type Error = LoadError;
+
+    unsafe fn try_get_unchecked<'a, T: ?Sized + LoadPtr<Self>>(&'a self, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        self.zone.try_get_ptr_unchecked::<T>(self, metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::offset::OffsetMut;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let cipher = Cipher::new(&Key::new([1; 32]), 7);
+
+        let mut buf = vec![0xaa, 42, 43];
+        cipher.encrypt(1, 3, &mut buf, 1);
+        assert_eq!(buf.len(), 3 + GENERATION_LEN + TAG_LEN);
+        assert_eq!(buf[0], 0xaa);
+        assert_eq!(&buf[1 .. 1 + GENERATION_LEN], &3u64.to_le_bytes());
+        assert_ne!(&buf[1 + GENERATION_LEN .. 3 + GENERATION_LEN], &[42, 43]);
+
+        assert_eq!(cipher.decrypt(1, &buf[1 ..]).unwrap(), vec![42, 43]);
+
+        // the nonce depends on the offset
+        assert_eq!(cipher.decrypt(2, &buf[1 ..]), Err(DecryptError { offset: 2 }));
+
+        // the generation
+        let mut other_generation = buf[1 ..].to_vec();
+        other_generation[0] ^= 1;
+        assert_eq!(cipher.decrypt(1, &other_generation), Err(DecryptError { offset: 1 }));
+
+        // the salt
+        let other = Cipher::new(&Key::new([1; 32]), 8);
+        assert_eq!(other.decrypt(1, &buf[1 ..]), Err(DecryptError { offset: 1 }));
+
+        // and the key matters
+        let other = Cipher::new(&Key::new([2; 32]), 7);
+        assert_eq!(other.decrypt(1, &buf[1 ..]), Err(DecryptError { offset: 1 }));
+
+        // truncated blobs don't panic
+        assert_eq!(cipher.decrypt(1, &buf[1 .. 10]), Err(DecryptError { offset: 1 }));
+    }
+
+    #[test]
+    fn same_offset_different_generation() {
+        let cipher = Cipher::new(&Key::new([1; 32]), 7);
+
+        let mut a = vec![0; 32];
+        cipher.encrypt(8, 1, &mut a, 0);
+        let mut b = vec![0; 32];
+        cipher.encrypt(8, 2, &mut b, 0);
+
+        // a rewritten offset doesn't reuse the keystream
+        assert_ne!(&a[GENERATION_LEN .. GENERATION_LEN + 32], &b[GENERATION_LEN .. GENERATION_LEN + 32]);
+        assert_eq!(cipher.decrypt(8, &a).unwrap(), vec![0; 32]);
+        assert_eq!(cipher.decrypt(8, &b).unwrap(), vec![0; 32]);
+    }
+
+    #[test]
+    fn encrypted_pile() {
+        let cipher = Cipher::new(&Key::new([1; 32]), 7);
+
+        let mut buf = vec![0xff];
+        cipher.encrypt(1, 0, &mut buf, 1);
+        buf.push(42);
+
+        let pile = EncryptedPile::new(unsafe { TryPile::new_unchecked(&buf) }, &cipher);
+        assert_eq!(pile.try_take::<u8>(Offset::new(1).unwrap(), ()).unwrap(), 0xff);
+
+        // clean pointers are decrypted, dirty pointers aren't touched
+        let clean = OffsetMut::from(Offset::new(1).unwrap());
+        match unsafe { pile.try_get_unchecked::<u8, _>(&clean, ()) } {
+            Ok(Ref::Owned(0xff)) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let dirty = OffsetMut::alloc(7u8);
+        match unsafe { pile.try_get_unchecked::<u8, _>(&dirty, ()) } {
+            Ok(Ref::Ref(&7)) => {},
+            r => panic!("{:?}", r),
+        }
+        unsafe { dirty.dealloc::<u8>(()) };
+
+        // tampering is detected
+        buf[1 + GENERATION_LEN] ^= 1;
+        let pile = EncryptedPile::new(unsafe { TryPile::new_unchecked(&buf) }, &cipher);
+        match pile.try_take::<u8>(Offset::new(1).unwrap(), ()) {
+            Err(EncryptedLoadError::Decrypt(DecryptError { offset: 1 })) => {},
+            r => panic!("{:?}", r),
+        }
+
+        match pile.try_take::<u8>(Offset::new(10).unwrap(), ()) {
+            Err(EncryptedLoadError::OutOfRange { offset: 10 }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn try_get_ptr() {
+        let cipher = Cipher::new(&Key::new([1; 32]), 7);
+
+        let mut buf = vec![0xff];
+        cipher.encrypt(1, 0, &mut buf, 1);
+
+        let pile = EncryptedPile::new(unsafe { TryPile::new_unchecked(&buf) }, &cipher);
+        let clean = OffsetMut::from(Offset::new(1).unwrap());
+        match unsafe { pile.try_get_ptr_unchecked::<u8>(&clean, ()) } {
+            Ok(Ref::Owned(0xff)) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let wrong_key = Cipher::new(&Key::new([2; 32]), 7);
+        let pile = EncryptedPile::new(unsafe { TryPile::new_unchecked(&buf) }, &wrong_key);
+        match unsafe { pile.try_get_ptr_unchecked::<u8>(&clean, ()) } {
+            Err(LoadError { offset: 1, kind: LoadErrorKind::Decrypt(DecryptError { offset: 1 }), .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn encrypted_pile_ptr() {
+        let cipher = Cipher::new(&Key::new([1; 32]), 7);
+
+        // an offset pointing to the blob after it, tagged as an offset
+        let child = 1 + GENERATION_LEN + 8 + TAG_LEN;
+        let mut buf = vec![0];
+        buf.extend_from_slice(&(((child as u64) << 1) | 1).to_le_bytes());
+        cipher.encrypt(1, 0, &mut buf, 1);
+        assert_eq!(buf.len(), child);
+        buf.push(42);
+        cipher.encrypt(child, 0, &mut buf, child);
+
+        let pile = EncryptedPile::new(unsafe { TryPile::new_unchecked(&buf) }, &cipher);
+        let root = EncryptedPilePtr::new(Offset::new(1).unwrap(), pile.zone());
+
+        // the loaded pointer keeps the zone, so its target is decrypted too
+        let child = match unsafe { root.try_get_unchecked::<EncryptedPilePtr>(()) } {
+            Ok(Ref::Owned(child)) => child,
+            r => panic!("{:?}", r),
+        };
+        match unsafe { child.try_get_unchecked::<u8>(()) } {
+            Ok(Ref::Owned(42)) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+}
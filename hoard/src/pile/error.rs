@@ -1,23 +1,40 @@
+use std::any::type_name;
 use std::error::Error;
 use std::fmt::Debug;
+use std::io;
 
 use thiserror::Error;
 
 use crate::pointee::Pointee;
+use crate::blob::ValidateBlob;
+use crate::validate::LimitError;
+
+use super::checksum::ChecksumError;
+use super::encrypted::DecryptError;
 
 use super::*;
 
 #[derive(Debug, Error)]
-#[error("fixme")]
 pub enum GetBlobError<LayoutError: Debug> {
+    #[error("blob out of range")]
     OutOfRange,
+
+    #[error("invalid blob layout: {0:?}")]
     Layout(LayoutError),
+
+    #[error("{0}")]
+    Limit(LimitError),
+
+    #[error("{0}")]
+    Checksum(ChecksumError),
 }
 
 #[derive(Debug, Error)]
-#[error("fixme")]
 pub enum GetValidBlobError<LayoutError: Debug, ValidateError: Debug> {
+    #[error("{0}")]
     Blob(GetBlobError<LayoutError>),
+
+    #[error("invalid blob: {0:?}")]
     Validate(ValidateError),
 }
 
@@ -27,6 +44,68 @@ impl<L: Error, V: Error> From<GetBlobError<L>> for GetValidBlobError<L, V> {
     }
 }
 
+/// Returned when the target of a pointer can't be loaded.
+///
+/// Unlike `GetValidBlobError`, this doesn't depend on the type being loaded, so it can be the
+/// error of a zone.
+#[derive(Debug, Error)]
+#[error("can't load {type_name} at offset {offset}: {kind}")]
+pub struct LoadError {
+    pub offset: usize,
+    pub type_name: &'static str,
+    pub kind: LoadErrorKind,
+}
+
+/// Why a `LoadError` happened.
+#[derive(Debug, Error)]
+pub enum LoadErrorKind {
+    #[error("out of range")]
+    OutOfRange,
+
+    #[error("invalid layout: {0}")]
+    Layout(Box<dyn Error + 'static + Send + Sync>),
+
+    #[error("{0}")]
+    Limit(LimitError),
+
+    #[error("{0}")]
+    Checksum(ChecksumError),
+
+    #[error("{0}")]
+    Decrypt(DecryptError),
+
+    #[error("read failed: {0}")]
+    Io(io::Error),
+
+    #[error("invalid blob: {0}")]
+    Validate(Box<dyn Error + 'static + Send + Sync>),
+}
+
+impl LoadError {
+    pub fn new<T: ?Sized + ValidateBlob>(offset: usize, kind: LoadErrorKind) -> Self {
+        Self { offset, type_name: type_name::<T>(), kind }
+    }
+
+    /// Converts the error from loading a valid blob.
+    pub fn from_blob<T: ?Sized + ValidateBlob>(offset: usize, err: GetValidBlobError<T::LayoutError, T::BlobError>) -> Self {
+        let kind = match err {
+            GetValidBlobError::Blob(GetBlobError::OutOfRange) => LoadErrorKind::OutOfRange,
+            GetValidBlobError::Blob(GetBlobError::Layout(err)) => LoadErrorKind::Layout(err.into()),
+            GetValidBlobError::Blob(GetBlobError::Limit(err)) => LoadErrorKind::Limit(err),
+            GetValidBlobError::Blob(GetBlobError::Checksum(err)) => LoadErrorKind::Checksum(err),
+            GetValidBlobError::Validate(err) => LoadErrorKind::Validate(err.into()),
+        };
+        Self::new::<T>(offset, kind)
+    }
+}
+
+/// Returned when a pile is extended with a buffer that doesn't start with the existing bytes.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("new buffer doesn't start with the existing {len} bytes of the pile")]
+pub struct ExtendError {
+    pub len: usize,
+}
+
 /*
 #[derive(Debug, Error)]
 #[error("FIXME")]
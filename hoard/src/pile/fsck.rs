@@ -0,0 +1,493 @@
+//! Deep validation of everything reachable from a root.
+
+use std::any::type_name;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::blob::*;
+use crate::offset::Offset;
+use crate::validate::{PtrValidator, ValidateChildren, ValidateContext, ValidateLimits, LimitError};
+use crate::progress::{Cancelled, Monitor};
+
+use super::*;
+use super::checksum::ChecksumError;
+
+/// Returned when deep validation fails.
+#[derive(Debug, Error)]
+pub enum FsckError {
+    #[error("{type_name} at offset {offset} has an invalid layout: {err}")]
+    Layout {
+        offset: usize,
+        type_name: &'static str,
+        err: Box<dyn Error + 'static + Send + Sync>,
+    },
+
+    #[error("{type_name} at offset {offset} with size {size} is out of range")]
+    OutOfRange {
+        offset: usize,
+        size: usize,
+        type_name: &'static str,
+    },
+
+    #[error("{type_name} at offset {offset} is invalid: {err}")]
+    Validate {
+        offset: usize,
+        type_name: &'static str,
+        err: Box<dyn Error + 'static + Send + Sync>,
+    },
+
+    #[error("{type_name} at offset {offset} is corrupt: {err}")]
+    Checksum {
+        offset: usize,
+        type_name: &'static str,
+        err: ChecksumError,
+    },
+
+    #[error("pointer to offset {offset} from parent at {parent} points forward")]
+    ForwardPointer {
+        offset: usize,
+        parent: usize,
+    },
+
+    #[error("{type_name} at offset {offset} overlaps {other_type_name} at offset {other_offset}")]
+    Overlap {
+        offset: usize,
+        type_name: &'static str,
+        other_offset: usize,
+        other_type_name: &'static str,
+    },
+
+    #[error("{type_name} at offset {offset}: {err}")]
+    Limit {
+        offset: usize,
+        type_name: &'static str,
+        err: LimitError,
+    },
+
+    #[error("validation cancelled at offset {offset}")]
+    Cancelled {
+        offset: usize,
+    },
+}
+
+/// Statistics from a successful `Fsck` run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FsckStats {
+    /// Number of distinct blobs validated.
+    pub blobs: usize,
+
+    /// Total bytes in distinct blobs.
+    pub bytes: usize,
+
+    /// The deepest pointer followed.
+    pub max_depth: usize,
+}
+
+
+/// Validates every blob reachable from a root.
+///
+/// In addition to validating each blob, `Fsck` checks the pointer graph itself:
+///
+/// * Every child must end at or before the start of its parent. Piles are append-only, so
+///   children are always written first; a forward pointer could only have been created
+///   maliciously, and would allow cycles.
+/// * No two distinct blobs may overlap, including a blob nested inside a larger one. Blobs that
+///   are reachable more than once are only validated once. Zero-sized blobs only overlap blobs
+///   they're strictly inside of.
+///
+/// Children are validated from a work stack rather than by recursion, so arbitrarily deep data
+/// can be validated without overflowing the stack.
+pub struct Fsck<'p, 'v> {
+    pile: TryPile<'p, 'v>,
+    ctx: ValidateContext,
+    parent: usize,
+    stats: FsckStats,
+    visited: BTreeMap<(usize, usize), &'static str>,
+    pending: Vec<Pending<'p, 'v>>,
+}
+
+/// A valid blob whose children have yet to be validated.
+struct Pending<'p, 'v> {
+    offset: usize,
+    depth: usize,
+    validate_children: Box<dyn FnOnce(&mut Fsck<'p, 'v>) -> Result<(), FsckError>>,
+}
+
+impl fmt::Debug for Fsck<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Fsck")
+            .field("pile", &self.pile)
+            .field("ctx", &self.ctx)
+            .field("parent", &self.parent)
+            .field("stats", &self.stats)
+            .field("visited", &self.visited)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl<'p, 'v> Fsck<'p, 'v> {
+    /// Creates a new `Fsck`, subject to the pile's limits.
+    pub fn new(pile: TryPile<'p, 'v>) -> Self {
+        Self::with_limits(pile, pile.limits())
+    }
+
+    /// Creates a new `Fsck`, overriding the pile's limits.
+    pub fn with_limits(pile: TryPile<'p, 'v>, limits: ValidateLimits) -> Self {
+        Self {
+            pile: pile.with_limits(limits),
+            ctx: ValidateContext::new(limits),
+            parent: pile.as_bytes().len(),
+            stats: FsckStats::default(),
+            visited: BTreeMap::new(),
+            pending: vec![],
+        }
+    }
+
+    /// Reports progress to a `Monitor` as each blob is validated.
+    ///
+    /// If the monitor is cancelled, validation stops with `FsckError::Cancelled`.
+    pub fn with_monitor(self, monitor: Monitor) -> Self {
+        Self {
+            ctx: self.ctx.with_monitor(monitor),
+            ..self
+        }
+    }
+
+    /// Validates a root, and everything reachable from it.
+    pub fn validate_root<T>(mut self, offset: Offset<'p, 'v>, metadata: T::Metadata) -> Result<FsckStats, FsckError>
+        where T: ?Sized + ValidateChildren<Offset<'p, 'v>>
+    {
+        self.validate_ptr::<T>(&offset, metadata)?;
+
+        while let Some(pending) = self.pending.pop() {
+            self.parent = pending.offset;
+            self.ctx.set_depth(pending.depth);
+            (pending.validate_children)(&mut self)?;
+        }
+        Ok(self.stats)
+    }
+
+    /// Checks a blob against the visited blobs, returning `true` if it has already been visited.
+    ///
+    /// Visited blobs are keyed by their `(start, end)` range. Since every blob is checked against
+    /// all the others before it's visited, no two visited blobs overlap. Scanning backwards from
+    /// `end`, the first blob ending at or before `start` thus proves that every earlier blob does
+    /// too, and the scan stops there.
+    fn check_overlap(&self, start: usize, end: usize, type_name: &'static str) -> Result<bool, FsckError> {
+        for (&(other_start, other_end), &other_type_name) in self.visited.range(..= (end, end)).rev() {
+            if other_end <= start && (other_start, other_end) != (start, end) {
+                break;
+            } else if (other_start, other_end, other_type_name) == (start, end, type_name) {
+                return Ok(true);
+            } else if start < other_end && other_start < end {
+                return Err(FsckError::Overlap {
+                    offset: start,
+                    type_name,
+                    other_offset: other_start,
+                    other_type_name,
+                });
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<'p, 'v> PtrValidator<Offset<'p, 'v>> for Fsck<'p, 'v> {
+    type Error = FsckError;
+
+    fn validate_ptr<T>(&mut self, ptr: &Offset<'p, 'v>, metadata: T::Metadata) -> Result<(), FsckError>
+        where T: ?Sized + ValidateChildren<Offset<'p, 'v>>
+    {
+        let type_name = type_name::<T>();
+        let start = ptr.get();
+
+        let size = T::try_blob_layout(metadata)
+                     .map_err(|err| FsckError::Layout { offset: start, type_name, err: err.into() })?
+                     .size();
+
+        let limit_err = |err| FsckError::Limit { offset: start, type_name, err };
+        self.ctx.check_blob_size(size).map_err(limit_err)?;
+
+        let end = start.checked_add(size)
+                       .filter(|end| *end <= self.pile.as_bytes().len())
+                       .ok_or(FsckError::OutOfRange { offset: start, size, type_name })?;
+
+        if end > self.parent {
+            return Err(FsckError::ForwardPointer { offset: start, parent: self.parent });
+        }
+
+        if self.check_overlap(start, end, type_name)? {
+            return Ok(());
+        }
+
+        self.ctx.consume_bytes(size).map_err(limit_err)?;
+
+        self.pile.get_valid_blob::<T>(*ptr, metadata)
+                 .map_err(|err| match err {
+                     GetValidBlobError::Validate(err) => FsckError::Validate {
+                         offset: start, type_name, err: err.into(),
+                     },
+                     GetValidBlobError::Blob(GetBlobError::Checksum(err)) => FsckError::Checksum {
+                         offset: start, type_name, err,
+                     },
+                     GetValidBlobError::Blob(GetBlobError::Limit(err)) => FsckError::Limit {
+                         offset: start, type_name, err,
+                     },
+                     GetValidBlobError::Blob(_) => FsckError::OutOfRange {
+                         offset: start, size, type_name,
+                     },
+                 })?;
+
+        self.visited.insert((start, end), type_name);
+        self.stats.blobs += 1;
+        self.stats.bytes += size;
+
+        self.ctx.enter().map_err(limit_err)?;
+        self.stats.max_depth = self.ctx.max_depth();
+        self.ctx.report_blob().map_err(|Cancelled| FsckError::Cancelled { offset: start })?;
+        let depth = self.ctx.depth();
+        self.ctx.exit();
+
+        // Capturing the blob would tie the closure to the lifetime of T, so the blob is fetched
+        // again when its children are validated.
+        let ptr = ptr.to_static();
+        self.pending.push(Pending {
+            offset: start,
+            depth,
+            validate_children: Box::new(move |fsck| {
+                let pile = fsck.pile;
+                // SAFETY: the offset was validated against this same pile by validate_ptr()
+                let blob = pile.get_blob::<T>(unsafe { ptr.cast() }, metadata)
+                               .expect("blob already validated");
+
+                // SAFETY: validated by validate_ptr()
+                let blob = unsafe { blob.assume_valid() };
+                T::validate_children(blob, fsck)
+            }),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use leint::Le;
+
+    use crate::bag::Bag;
+
+    type OffsetBag<'p, 'v, T> = Bag<T, Offset<'p, 'v>>;
+
+    #[test]
+    fn fsck_scalar() {
+        let buf = [42u8];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        let stats = Fsck::new(pile).validate_root::<u8>(Offset::new(0).unwrap(), ()).unwrap();
+        assert_eq!(stats, FsckStats { blobs: 1, bytes: 1, max_depth: 1 });
+
+        match Fsck::new(pile).validate_root::<u8>(Offset::new(1).unwrap(), ()) {
+            Err(FsckError::OutOfRange { offset: 1, size: 1, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn fsck_bag() {
+        // a bool, followed by a bag pointing to it
+        let buf = [1u8,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let stats = Fsck::new(pile)
+                         .validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 2, bytes: 9, max_depth: 2 });
+
+        // an invalid bool
+        let buf = [2u8,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        match Fsck::new(pile).validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ()) {
+            Err(FsckError::Validate { offset: 0, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn fsck_forward_pointer() {
+        // a bag pointing to itself
+        let buf = [0u8,
+                   3,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        match Fsck::new(pile).validate_root::<OffsetBag<u8>>(Offset::new(1).unwrap(), ()) {
+            Err(FsckError::ForwardPointer { offset: 1, parent: 1 }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn fsck_overlap() {
+        // a pair of bags pointing to overlapping u16's
+        let buf = [1u8, 1, 1,
+                   1,0,0,0,0,0,0,0,
+                   3,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        match Fsck::new(pile).validate_root::<[OffsetBag<Le<u16>>; 2]>(Offset::new(3).unwrap(), ()) {
+            Err(FsckError::Overlap { offset: 1, other_offset: 0, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        // the same u16 reachable twice is fine
+        let buf = [1u8, 1,
+                   1,0,0,0,0,0,0,0,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        let stats = Fsck::new(pile)
+                         .validate_root::<[OffsetBag<Le<u16>>; 2]>(Offset::new(2).unwrap(), ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 2, bytes: 18, max_depth: 2 });
+    }
+
+    /// Two bags pointing to different types.
+    struct Pair<'p, 'v, A, B>(OffsetBag<'p, 'v, A>, OffsetBag<'p, 'v, B>);
+
+    unsafe impl<'p, 'v, A: ValidateBlob, B: ValidateBlob> ValidateBlob for Pair<'p, 'v, A, B> {
+        type BlobError = <OffsetBag<'p, 'v, A> as ValidateBlob>::BlobError;
+
+        fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+            Ok(<OffsetBag<A>>::blob_layout().extend(<OffsetBag<B>>::blob_layout()))
+        }
+
+        fn validate_blob<'a>(blob: Blob<'a, Self>, ignore_padding: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
+            let mut fields = blob.validate_fields(ignore_padding);
+            fields.validate_blob::<OffsetBag<A>>()?;
+            fields.validate_blob::<OffsetBag<B>>()?;
+            unsafe { Ok(fields.finish()) }
+        }
+    }
+
+    impl<'p, 'v, A, B> ValidateChildren<Offset<'p, 'v>> for Pair<'p, 'v, A, B>
+        where A: ValidateChildren<Offset<'p, 'v>>,
+              B: ValidateChildren<Offset<'p, 'v>>,
+    {
+        fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+            where V: PtrValidator<Offset<'p, 'v>>
+        {
+            let mut fields = blob.valid_fields();
+
+            // SAFETY: validated by Pair::validate_blob()
+            let a = unsafe { fields.field_unchecked::<OffsetBag<A>>() };
+            let b = unsafe { fields.field_unchecked::<OffsetBag<B>>() };
+            fields.finish();
+
+            <OffsetBag<A>>::validate_children(a, validator)?;
+            <OffsetBag<B>>::validate_children(b, validator)
+        }
+    }
+
+    #[test]
+    fn fsck_nested_overlap() {
+        // a u8 inside of a u32
+        let buf = [1u8, 1, 1, 1,
+                   1,0,0,0,0,0,0,0,
+                   3,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        match Fsck::new(pile).validate_root::<Pair<Le<u32>, u8>>(Offset::new(4).unwrap(), ()) {
+            Err(FsckError::Overlap { offset: 1, other_offset: 0, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        // the other way around
+        let buf = [1u8, 1, 1, 1,
+                   3,0,0,0,0,0,0,0,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        match Fsck::new(pile).validate_root::<Pair<u8, Le<u32>>>(Offset::new(4).unwrap(), ()) {
+            Err(FsckError::Overlap { offset: 0, other_offset: 1, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        // a zero-sized blob at the start of a u8 doesn't overlap it
+        let buf = [1u8,
+                   1,0,0,0,0,0,0,0,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        let stats = Fsck::new(pile)
+                         .validate_root::<Pair<(), u8>>(Offset::new(1).unwrap(), ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: 3, bytes: 17, max_depth: 2 });
+
+        // but one inside a u32 does
+        let buf = [1u8, 1, 1, 1,
+                   1,0,0,0,0,0,0,0,
+                   5,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        match Fsck::new(pile).validate_root::<Pair<Le<u32>, ()>>(Offset::new(4).unwrap(), ()) {
+            Err(FsckError::Overlap { offset: 2, other_offset: 0, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn fsck_monitor() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::progress::{CancelToken, Progress};
+
+        // a bool, followed by a bag pointing to it
+        let buf = [1u8,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let monitor = {
+            let reports = Arc::clone(&reports);
+            Monitor::new().with_observer(move |progress: &Progress| reports.lock().unwrap().push(*progress))
+        };
+        Fsck::new(pile).with_monitor(monitor)
+                       .validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ())
+                       .unwrap();
+        assert_eq!(*reports.lock().unwrap(),
+                   [Progress { bytes: 8, nodes: 1, depth: 1 },
+                    Progress { bytes: 9, nodes: 2, depth: 2 }]);
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        match Fsck::new(pile).with_monitor(Monitor::new().with_cancel_token(cancel))
+                             .validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ())
+        {
+            Err(FsckError::Cancelled { offset: 1 }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn fsck_limits() {
+        let buf = [1u8,
+                   1,0,0,0,0,0,0,0];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let limits = ValidateLimits { max_depth: 1, ..ValidateLimits::UNLIMITED };
+        match Fsck::with_limits(pile, limits).validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ()) {
+            Err(FsckError::Limit { offset: 0, err: LimitError::Depth { limit: 1 }, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let limits = ValidateLimits { max_bytes: 8, ..ValidateLimits::UNLIMITED };
+        match Fsck::with_limits(pile, limits).validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ()) {
+            Err(FsckError::Limit { offset: 0, err: LimitError::Bytes { limit: 8 }, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let limits = ValidateLimits { max_blob_size: 4, ..ValidateLimits::UNLIMITED };
+        match Fsck::with_limits(pile, limits).validate_root::<OffsetBag<bool>>(Offset::new(1).unwrap(), ()) {
+            Err(FsckError::Limit { offset: 1, err: LimitError::BlobSize { size: 8, limit: 4 }, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+}
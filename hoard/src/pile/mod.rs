@@ -13,6 +13,8 @@
 
 use std::marker::PhantomData;
 use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::mem::ManuallyDrop;
 use std::ops::Deref;
 
@@ -27,11 +29,30 @@ use crate::offset::*;
 use crate::refs::Ref;
 use crate::load::*;
 use crate::blob::*;
+use crate::validate::{ValidateContext, ValidateLimits};
+use crate::coerce::TryCoerce;
+
+pub mod error;
+pub use self::error::*;
+
+pub mod fsck;
+
+pub mod checksum;
+use self::checksum::Checksum;
+
+pub mod encrypted;
+
+#[cfg(unix)]
+pub mod pread;
+
+pub mod cache;
 
 #[derive(Debug, Clone, Copy)]
 pub struct TryPile<'p, 'v> {
     marker: PhantomData<fn(&'p ()) -> &'p ()>,
     buf: &'v [u8],
+    checksum: Checksum,
+    limits: ValidateLimits,
 }
 
 impl<'p> Default for TryPile<'p, 'static> {
@@ -40,9 +61,228 @@ impl<'p> Default for TryPile<'p, 'static> {
     }
 }
 
+/// Piles can be re-branded with a shorter version lifetime, even when nested in a type that is
+/// invariant over it.
+unsafe impl<'p, 'v1: 'v2, 'v2> TryCoerce<TryPile<'p, 'v2>> for TryPile<'p, 'v1> {
+    type Error = !;
+}
+
+/// A `Unique` slice brands the pile: no other pile can share its `'p` lifetime.
+impl<'p, 'v> From<Unique<'p, &'v [u8]>> for TryPile<'p, 'v> {
+    fn from(buf: Unique<'p, &'v [u8]>) -> Self {
+        // SAFETY: the slice is unique for 'p
+        unsafe { Self::new_unchecked(Unique::into_inner(buf)) }
+    }
+}
+
+impl<'v> TryPile<'_, 'v> {
+    /// Creates a new `TryPile` from a slice, with a fresh `'p` brand.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use hoard::pile::TryPile;
+    /// # use hoard::offset::Offset;
+    /// TryPile::new(&[42], |pile| {
+    ///     let blob = pile.get_valid_blob::<u8>(Offset::new(0).unwrap(), ()).unwrap();
+    ///     assert_eq!(blob.as_value(), &42);
+    /// })
+    /// ```
+    ///
+    /// Offsets from one pile can't be used with another:
+    ///
+    /// ```compile_fail
+    /// # use hoard::pile::TryPile;
+    /// # use hoard::offset::Offset;
+    /// TryPile::new(&[1], |pile1| {
+    ///     TryPile::new(&[2], |pile2| {
+    ///         let offset = Offset::new(0).unwrap();
+    ///         pile1.get_blob::<u8>(offset, ()).unwrap();
+    ///         pile2.get_blob::<u8>(offset, ()).unwrap();
+    ///     })
+    /// })
+    /// ```
+    pub fn new<R>(buf: &'v [u8], f: impl for<'p> FnOnce(TryPile<'p, 'v>) -> R) -> R {
+        Unique::new(buf, |buf| f(TryPile::from(buf)))
+    }
+}
+
 impl<'p, 'v> TryPile<'p, 'v> {
     pub unsafe fn new_unchecked(buf: &'v [u8]) -> Self {
-        Self { marker: PhantomData, buf, }
+        Self { marker: PhantomData, buf, checksum: Checksum::None, limits: ValidateLimits::default() }
+    }
+
+    /// Extends the pile to a new buffer, without checking that it starts with the old one.
+    ///
+    /// # Safety
+    ///
+    /// `new_buf` must start with the bytes of this pile.
+    pub unsafe fn extend_unchecked<'v2>(&self, new_buf: &'v2 [u8]) -> TryPile<'p, 'v2>
+        where 'v: 'v2
+    {
+        debug_assert!(new_buf.starts_with(self.buf));
+        TryPile { marker: PhantomData, buf: new_buf, checksum: self.checksum, limits: self.limits }
+    }
+
+    /// Extends the pile to a new buffer that starts with the old one.
+    ///
+    /// The new pile keeps the `'p` brand, so offsets from this version remain usable with it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use hoard::pile::TryPile;
+    /// # use hoard::offset::Offset;
+    /// let buf = [1, 2];
+    /// TryPile::new(&buf[.. 1], |pile| {
+    ///     let old = Offset::new(0).unwrap();
+    ///
+    ///     let pile = pile.extend(&buf).unwrap();
+    ///     assert_eq!(pile.get_valid_blob::<u8>(old, ()).unwrap().as_value(), &1);
+    ///     assert!(pile.extend(&[3]).is_err());
+    /// })
+    /// ```
+    pub fn extend<'v2>(&self, new_buf: &'v2 [u8]) -> Result<TryPile<'p, 'v2>, ExtendError>
+        where 'v: 'v2
+    {
+        if new_buf.starts_with(self.buf) {
+            // SAFETY: checked above
+            Ok(unsafe { self.extend_unchecked(new_buf) })
+        } else {
+            Err(ExtendError { len: self.buf.len() })
+        }
+    }
+
+    /// Sets the checksum expected after every blob.
+    pub fn with_checksum(self, checksum: Checksum) -> Self {
+        Self { checksum, ..self }
+    }
+
+    /// Gets the checksum expected after every blob.
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// Sets the limits that loads from this pile are subject to.
+    ///
+    /// Every blob loaded from the pile is checked against the single blob limit before it's
+    /// touched. The remaining limits bound whole traversals, and are used by `Fsck`.
+    pub fn with_limits(self, limits: ValidateLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Gets the limits that loads from this pile are subject to.
+    pub fn limits(&self) -> ValidateLimits {
+        self.limits
+    }
+
+    /// Gets the bytes of the pile.
+    pub fn as_bytes(&self) -> &'v [u8] {
+        self.buf
+    }
+
+    /// Gets the (unvalidated) blob at an offset.
+    pub fn get_blob<T: ?Sized + ValidateBlob>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata)
+        -> Result<Blob<'v, T>, GetBlobError<T::LayoutError>>
+    {
+        let size = T::try_blob_layout(metadata)
+                     .map_err(GetBlobError::Layout)?
+                     .size();
+
+        self.blob_at(offset, metadata, size)
+    }
+
+    /// Gets the (unvalidated) blob at an offset, subject to the limits of a `ValidateContext`.
+    ///
+    /// The size of the blob is checked *before* the blob is touched, and the size is consumed
+    /// from the context's byte budget.
+    pub fn get_blob_limited<T: ?Sized + ValidateBlob>(
+        &self,
+        ctx: &mut ValidateContext,
+        offset: Offset<'p, 'v>,
+        metadata: T::Metadata
+    ) -> Result<Blob<'v, T>, GetBlobError<T::LayoutError>>
+    {
+        let size = T::try_blob_layout(metadata)
+                     .map_err(GetBlobError::Layout)?
+                     .size();
+
+        ctx.check_blob(size).map_err(GetBlobError::Limit)?;
+        self.blob_at(offset, metadata, size)
+    }
+
+    fn blob_at<T: ?Sized + ValidateBlob>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata, size: usize)
+        -> Result<Blob<'v, T>, GetBlobError<T::LayoutError>>
+    {
+        self.limits.check_blob_size(size).map_err(GetBlobError::Limit)?;
+
+        let start = offset.get();
+        let end = start.checked_add(size).ok_or(GetBlobError::OutOfRange)?;
+        let slice = self.buf.get(start .. end).ok_or(GetBlobError::OutOfRange)?;
+
+        if self.checksum != Checksum::None {
+            let stored = end.checked_add(self.checksum.len())
+                            .and_then(|checksum_end| self.buf.get(end .. checksum_end))
+                            .ok_or(GetBlobError::OutOfRange)?;
+            self.checksum.verify(start, slice, stored).map_err(GetBlobError::Checksum)?;
+        }
+
+        Ok(unsafe { Blob::new_unchecked(slice, metadata) })
+    }
+
+    /// Gets and validates the blob at an offset.
+    pub fn get_valid_blob<T: ?Sized + ValidateBlob>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata)
+        -> Result<ValidBlob<'v, T>, GetValidBlobError<T::LayoutError, T::BlobError>>
+    {
+        let blob = self.get_blob::<T>(offset, metadata)?;
+
+        T::validate_blob(blob, false)
+          .map_err(GetValidBlobError::Validate)
+    }
+
+    /// Gets and validates the blob at an offset, subject to the limits of a `ValidateContext`.
+    pub fn get_valid_blob_limited<T: ?Sized + ValidateBlob>(
+        &self,
+        ctx: &mut ValidateContext,
+        offset: Offset<'p, 'v>,
+        metadata: T::Metadata
+    ) -> Result<ValidBlob<'v, T>, GetValidBlobError<T::LayoutError, T::BlobError>>
+    {
+        let blob = self.get_blob_limited::<T>(ctx, offset, metadata)?;
+
+        T::validate_blob(blob, false)
+          .map_err(GetValidBlobError::Validate)
+    }
+
+    /// Looks up, or validates, a blob that a `Saver` is copying out of the pile.
+    ///
+    /// Copies are keyed by source offset and size, so a blob is never substituted for a
+    /// differently sized one. If the blob was already copied, its destination is returned.
+    /// Otherwise the blob is validated, its key is pushed onto `stack` for `finish_save()` to pop,
+    /// and `f` is called with it.
+    pub(crate) fn try_copy_blob<D: Copy, R, T: ?Sized + ValidateBlob>(
+        &self,
+        copied: &HashMap<(usize, usize), D>,
+        stack: &RefCell<Vec<(usize, usize)>>,
+        offset: Offset<'p, 'v>,
+        metadata: T::Metadata,
+        f: impl FnOnce(ValidBlob<T>, &Self) -> R,
+    ) -> Result<Result<D, R>, LoadError>
+    {
+        let size = T::try_blob_layout(metadata)
+                     .map_err(|err| LoadError::new::<T>(offset.get(), LoadErrorKind::Layout(err.into())))?
+                     .size();
+
+        let key = (offset.get(), size);
+        if let Some(dst) = copied.get(&key) {
+            return Ok(Ok(*dst));
+        }
+
+        let blob = self.get_valid_blob::<T>(offset, metadata)
+                       .map_err(|err| LoadError::from_blob::<T>(offset.get(), err))?;
+
+        stack.borrow_mut().push(key);
+        Ok(Err(f(blob, self)))
     }
 }
 
@@ -92,6 +332,29 @@ impl AsZone<()> for TryPile<'_, '_> {
     }
 }
 
+/// Loads the targets of pointers from the pile, subject to the pile's limits.
+///
+/// Dirty pointers are dereferenced directly; blobs are dereferenced in place when possible, and
+/// decoded into `Ref::Owned` otherwise.
+impl<'p, 'v, P> TryGetPtr<P> for TryPile<'p, 'v>
+    where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = Self>
+{
+    type Error = LoadError;
+
+    unsafe fn try_get_ptr_unchecked<'a, 'z: 'a, T: ?Sized + LoadPtr<P>>(&'z self, ptr: &'a P, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        match ptr.try_get_dirty_unchecked::<T>(metadata) {
+            Ok(value) => Ok(Ref::Ref(value)),
+            Err(offset) => {
+                let blob = self.get_valid_blob::<T>(offset, metadata)
+                               .map_err(|err| LoadError::from_blob::<T>(offset.get(), err))?;
+                Ok(T::deref_blob(blob, self))
+            },
+        }
+    }
+}
+
 impl AsPtrImpl<Self> for TryPilePtr<'_, '_> {
     fn as_ptr_impl(this: &Self) -> &Self {
         this
@@ -112,6 +375,16 @@ impl<'p, 'v> Ptr for TryPilePtr<'p, 'v> {
     }
 }
 
+impl<'p, 'v> TryGet for TryPilePtr<'p, 'v> {
+    type Error = LoadError;
+
+    unsafe fn try_get_unchecked<'a, T: ?Sized + LoadPtr<Self>>(&'a self, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        self.pile.try_get_ptr_unchecked::<T>(self, metadata)
+    }
+}
+
 pub struct TryPilePtrMut<'p, 'v> {
     offset: OffsetMut<'p, 'v>,
     pile: TryPile<'p, 'v>,
@@ -167,6 +440,111 @@ impl<'p, 'v> Ptr for TryPilePtrMut<'p, 'v> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_blob_checksummed() {
+        let mut buf = vec![42];
+        Checksum::Crc32c.append(&mut buf, 0);
+        buf.push(43);
+
+        let pile = unsafe { TryPile::new_unchecked(&buf) }.with_checksum(Checksum::Crc32c);
+        let blob = pile.get_valid_blob::<u8>(Offset::new(0).unwrap(), ()).unwrap();
+        assert_eq!(blob.as_value(), &42);
+
+        // no room for the checksum
+        match pile.get_blob::<u8>(Offset::new(5).unwrap(), ()) {
+            Err(GetBlobError::OutOfRange) => {},
+            r => panic!("{:?}", r),
+        }
+
+        buf[0] ^= 1;
+        let pile = unsafe { TryPile::new_unchecked(&buf) }.with_checksum(Checksum::Crc32c);
+        match pile.get_blob::<u8>(Offset::new(0).unwrap(), ()) {
+            Err(GetBlobError::Checksum(err)) => assert_eq!(err.offset, 0),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn extend() {
+        let mut buf = vec![42];
+        Checksum::Crc32c.append(&mut buf, 0);
+        let old_len = buf.len();
+        buf.push(43);
+        Checksum::Crc32c.append(&mut buf, old_len);
+
+        TryPile::new(&buf[.. old_len], |pile| {
+            let pile = pile.with_checksum(Checksum::Crc32c);
+            let old = Offset::new(0).unwrap();
+            let new = Offset::new(old_len).unwrap();
+            assert!(pile.get_blob::<u8>(new, ()).is_err());
+
+            let extended = pile.extend(&buf).unwrap();
+            assert_eq!(extended.checksum(), Checksum::Crc32c);
+            assert_eq!(extended.get_valid_blob::<u8>(old, ()).unwrap().as_value(), &42);
+            assert_eq!(extended.get_valid_blob::<u8>(new, ()).unwrap().as_value(), &43);
+
+            assert_eq!(pile.extend(&[43]).unwrap_err(), ExtendError { len: old_len });
+        })
+    }
+
+    #[test]
+    fn limits() {
+        use leint::Le;
+
+        use crate::validate::LimitError;
+
+        let buf = [1, 2, 3, 4];
+        let limits = ValidateLimits { max_blob_size: 2, ..ValidateLimits::default() };
+        let pile = unsafe { TryPile::new_unchecked(&buf) }.with_limits(limits);
+
+        assert_eq!(pile.get_valid_blob::<Le<u16>>(Offset::new(0).unwrap(), ()).unwrap().as_value().get(), 0x0201);
+        match pile.get_valid_blob::<Le<u32>>(Offset::new(0).unwrap(), ()) {
+            Err(GetValidBlobError::Blob(GetBlobError::Limit(LimitError::BlobSize { size: 4, limit: 2 }))) => {},
+            r => panic!("{:?}", r),
+        }
+
+        // extending keeps the limits
+        let extended = unsafe { pile.extend_unchecked(&buf) };
+        assert_eq!(extended.limits(), limits);
+    }
+
+    #[test]
+    fn try_get_ptr() {
+        let buf = [42, 43];
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+        let clean = OffsetMut::from(Offset::new(1).unwrap());
+        match unsafe { pile.try_get_ptr_unchecked::<u8>(&clean, ()) } {
+            Ok(Ref::Ref(&43)) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let dirty = OffsetMut::alloc(7u8);
+        match unsafe { pile.try_get_ptr_unchecked::<u8>(&dirty, ()) } {
+            Ok(Ref::Ref(&7)) => {},
+            r => panic!("{:?}", r),
+        }
+        unsafe { dirty.dealloc::<u8>(()) };
+
+        let out_of_range = OffsetMut::from(Offset::new(2).unwrap());
+        match unsafe { pile.try_get_ptr_unchecked::<u8>(&out_of_range, ()) } {
+            Err(LoadError { offset: 2, kind: LoadErrorKind::OutOfRange, .. }) => {},
+            r => panic!("{:?}", r),
+        }
+
+        let limits = ValidateLimits { max_blob_size: 0, ..ValidateLimits::default() };
+        let pile = pile.with_limits(limits);
+        match unsafe { pile.try_get_ptr_unchecked::<u8>(&clean, ()) } {
+            Err(LoadError { offset: 1, kind: LoadErrorKind::Limit(_), .. }) => {},
+            r => panic!("{:?}", r),
+        }
+    }
+}
+
 /*
 impl<'p, 'v> Ptr for TryPilePtr<'p, 'v> {
     type Zone = TryPile<'p, 'v>;
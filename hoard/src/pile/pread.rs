@@ -0,0 +1,534 @@
+//! Piles read with `pread` rather than mapped into memory.
+//!
+//! Mapping a file requires address space for the whole file, and if the file is truncated while
+//! mapped, touching the missing pages raises `SIGBUS`. A `PreadPile` instead reads each blob into
+//! an owned buffer with positioned reads, so a short read is simply an error.
+//!
+//! Reads go through a small page cache, as blobs are usually small and clustered: loading a tree
+//! will read many neighbouring blobs.
+//!
+//! A `PreadPile` loads pointers through `TryGetPtr`, just like a `TryPile`. As blobs aren't in
+//! memory, values are always returned as `Ref::Owned`. Pointers that load through a zone of their
+//! own should be `PreadPilePtr`s, whose zone reads through the same page cache.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
+
+use singlelife::Unique;
+
+use crate::pointee::Pointee;
+use crate::refs::Ref;
+use crate::blob::*;
+use crate::load::*;
+use crate::ptr::*;
+use crate::offset::Offset;
+use crate::validate::ValidateLimits;
+
+use super::{TryPile, LoadError, LoadErrorKind};
+use super::cache::CacheStats;
+use super::checksum::Checksum;
+
+/// The size of the pages cached by a `PreadPile`.
+pub const PAGE_SIZE: usize = 4096;
+
+/// A pile read from a file with positioned reads.
+pub struct PreadPile<'p, 'v> {
+    marker: PhantomData<fn(&'p ()) -> &'p ()>,
+    file: &'v File,
+    len: usize,
+    checksum: Checksum,
+    limits: ValidateLimits,
+    cache: PageCache,
+}
+
+impl fmt::Debug for PreadPile<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PreadPile")
+            .field("file", &self.file)
+            .field("len", &self.len)
+            .field("checksum", &self.checksum)
+            .field("limits", &self.limits)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+impl<'v> PreadPile<'_, 'v> {
+    /// Creates a new `PreadPile` from a file, with a fresh `'p` brand.
+    ///
+    /// The length of the pile is the length of the file at the time of the call; bytes appended
+    /// later aren't part of the pile. Up to `cache_pages` pages are cached.
+    pub fn new<R>(file: &'v File, cache_pages: usize, f: impl for<'p> FnOnce(PreadPile<'p, 'v>) -> R)
+        -> io::Result<R>
+    {
+        let len = file.metadata()?.len() as usize;
+        Ok(Unique::new(file, |file| {
+            // SAFETY: the file is unique for 'p
+            f(unsafe { PreadPile::new_unchecked(Unique::into_inner(file), len, cache_pages) })
+        }))
+    }
+}
+
+impl<'p, 'v> PreadPile<'p, 'v> {
+    /// Creates a new `PreadPile` of the first `len` bytes of a file.
+    ///
+    /// # Safety
+    ///
+    /// As with `TryPile::new_unchecked()`, no other pile may share the `'p` brand.
+    pub unsafe fn new_unchecked(file: &'v File, len: usize, cache_pages: usize) -> Self {
+        Self {
+            marker: PhantomData,
+            file,
+            len,
+            checksum: Checksum::None,
+            limits: ValidateLimits::default(),
+            cache: PageCache::new(cache_pages),
+        }
+    }
+
+    /// Sets the checksum expected after every blob.
+    pub fn with_checksum(self, checksum: Checksum) -> Self {
+        Self { checksum, ..self }
+    }
+
+    /// Gets the checksum expected after every blob.
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// Sets the limits that loads from this pile are subject to.
+    pub fn with_limits(self, limits: ValidateLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Gets the limits that loads from this pile are subject to.
+    pub fn limits(&self) -> ValidateLimits {
+        self.limits
+    }
+
+    /// Gets the length of the pile, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Gets the page cache statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.state.borrow().stats
+    }
+
+    /// Gets the zone values loaded with `PreadPilePtr`s use.
+    pub fn zone(&self) -> PreadZone<'_, 'p, 'v> {
+        PreadZone(self)
+    }
+
+    /// Reads the bytes of the blob at an offset, verifying its checksum if necessary.
+    ///
+    /// Exactly as many bytes as the blob layout calls for are returned.
+    fn read_blob<T: ?Sized + ValidateBlob>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata)
+        -> Result<Vec<u8>, LoadError>
+    {
+        let start = offset.get();
+        let err = |kind| LoadError::new::<T>(start, kind);
+
+        let size = T::try_blob_layout(metadata)
+                     .map_err(|e| err(LoadErrorKind::Layout(e.into())))?
+                     .size();
+        self.limits.check_blob_size(size).map_err(|e| err(LoadErrorKind::Limit(e)))?;
+
+        let end = start.checked_add(size)
+                       .and_then(|end| end.checked_add(self.checksum.len()))
+                       .filter(|end| *end <= self.len)
+                       .ok_or_else(|| err(LoadErrorKind::OutOfRange))?;
+
+        let mut buf = vec![0; end - start];
+        self.cache.read(self.file, self.len, start, &mut buf)
+                  .map_err(|e| err(LoadErrorKind::Io(e)))?;
+
+        let stored = buf.split_off(size);
+        self.checksum.verify(start, &buf, &stored)
+                     .map_err(|e| err(LoadErrorKind::Checksum(e)))?;
+
+        Ok(buf)
+    }
+
+    /// Reads, validates, and decodes the value at an offset.
+    fn decode<T, P>(&self, offset: Offset<'p, 'v>, metadata: T::Metadata, zone: &P::BlobZone)
+        -> Result<T::Owned, LoadError>
+        where T: ?Sized + LoadPtr<P>,
+              P: Ptr,
+    {
+        let buf = self.read_blob::<T>(offset, metadata)?;
+
+        // SAFETY: read_blob() returned exactly the blob's size
+        let blob = unsafe { Blob::<T>::new_unchecked(&buf, metadata) };
+        let blob = T::validate_blob(blob, false)
+                     .map_err(|err| LoadError::new::<T>(offset.get(), LoadErrorKind::Validate(err.into())))?;
+
+        Ok(T::decode_blob(blob, zone))
+    }
+}
+
+/// Loads the targets of pointers such as `OffsetMut`, whose zone is a `TryPile`.
+///
+/// Values are decoded with an empty `TryPile`, as such pointers are loaded through an explicit
+/// zone: the children of a loaded value are loaded through this `PreadPile` too.
+impl<'p, 'v, P> TryGetPtr<P> for PreadPile<'p, 'v>
+    where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = TryPile<'p, 'v>>
+{
+    type Error = LoadError;
+
+    unsafe fn try_get_ptr_unchecked<'a, 'z: 'a, T: ?Sized + LoadPtr<P>>(&'z self, ptr: &'a P, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, LoadError>
+    {
+        match ptr.try_get_dirty_unchecked::<T>(metadata) {
+            Ok(value) => Ok(Ref::Ref(value)),
+            Err(offset) => self.decode::<T, P>(offset, metadata, &TryPile::default()).map(Ref::Owned),
+        }
+    }
+}
+
+/// The zone of a `PreadPile`, which values decoded from it keep.
+#[derive(Debug, Clone, Copy)]
+pub struct PreadZone<'a, 'p, 'v>(&'a PreadPile<'p, 'v>);
+
+impl AsZone<Self> for PreadZone<'_, '_, '_> {
+    fn as_zone(&self) -> &Self {
+        self
+    }
+}
+
+impl AsZone<()> for PreadZone<'_, '_, '_> {
+    fn as_zone(&self) -> &() {
+        &()
+    }
+}
+
+/// Loads the targets of pointers through the page cache, decoding them with this zone.
+impl<'a, 'p, 'v, P> TryGetPtr<P> for PreadZone<'a, 'p, 'v>
+    where P: Ptr<Persist = Offset<'p, 'v>, BlobZone = Self>
+{
+    type Error = LoadError;
+
+    unsafe fn try_get_ptr_unchecked<'b, 'z: 'b, T: ?Sized + LoadPtr<P>>(&'z self, ptr: &'b P, metadata: T::Metadata)
+        -> Result<Ref<'b, T>, LoadError>
+    {
+        match ptr.try_get_dirty_unchecked::<T>(metadata) {
+            Ok(value) => Ok(Ref::Ref(value)),
+            Err(offset) => self.0.decode::<T, P>(offset, metadata, self).map(Ref::Owned),
+        }
+    }
+}
+
+/// A pointer into a `PreadPile`, which loads its target through the pile's page cache.
+#[derive(Debug)]
+pub struct PreadPilePtr<'a, 'p, 'v> {
+    offset: Offset<'p, 'v>,
+    zone: PreadZone<'a, 'p, 'v>,
+}
+
+impl<'a, 'p, 'v> PreadPilePtr<'a, 'p, 'v> {
+    /// Creates a pointer to an offset within a pile.
+    pub fn new(offset: Offset<'p, 'v>, zone: PreadZone<'a, 'p, 'v>) -> Self {
+        Self { offset, zone }
+    }
+}
+
+unsafe impl<'p, 'v> ValidateBlob for PreadPilePtr<'_, 'p, 'v> {
+    type BlobError = <Offset<'p, 'v> as ValidateBlob>::BlobError;
+
+    fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+        Ok(<Offset<'p, 'v> as ValidateBlob>::blob_layout())
+    }
+
+    fn validate_blob(blob: Blob<Self>, ignore_padding: bool) -> Result<ValidBlob<Self>, Self::BlobError> {
+        let mut fields = blob.validate_fields(ignore_padding);
+        fields.validate_blob::<Offset<'p, 'v>>()?;
+        unsafe { Ok(fields.finish()) }
+    }
+}
+
+impl<'a, 'p, 'v> Load for PreadPilePtr<'a, 'p, 'v> {
+    type Ptr = Self;
+
+    fn decode_blob(blob: ValidBlob<Self>, zone: &PreadZone<'a, 'p, 'v>) -> Self {
+        let mut fields = blob.decode_fields(zone);
+        let offset = unsafe { fields.decode_unchecked() };
+        fields.finish();
+
+        Self {
+            zone: *zone,
+            offset,
+        }
+    }
+}
+
+impl AsPtrImpl<Self> for PreadPilePtr<'_, '_, '_> {
+    fn as_ptr_impl(this: &Self) -> &Self {
+        this
+    }
+}
+
+impl<'a, 'p, 'v> Ptr for PreadPilePtr<'a, 'p, 'v> {
+    type Zone = PreadZone<'a, 'p, 'v>;
+    type BlobZone = PreadZone<'a, 'p, 'v>;
+    type Persist = Offset<'p, 'v>;
+
+    unsafe fn dealloc<T: ?Sized + Pointee>(&self, metadata: T::Metadata) {
+        self.offset.dealloc::<T>(metadata)
+    }
+
+    unsafe fn try_get_dirty_unchecked<T: ?Sized + Pointee>(&self, metadata: T::Metadata) -> Result<&T, Self::Persist> {
+        self.offset.try_get_dirty_unchecked::<T>(metadata)
+    }
+}
+
+impl<'a, 'p, 'v> TryGet for PreadPilePtr<'a, 'p, 'v> {
+    type Error = LoadError;
+
+    unsafe fn try_get_unchecked<'b, T: ?Sized + LoadPtr<Self>>(&'b self, metadata: T::Metadata)
+        -> Result<Ref<'b, T>, LoadError>
+    {
+        self.zone.try_get_ptr_unchecked::<T>(self, metadata)
+    }
+}
+
+/// A bounded, least-recently-used, cache of file pages.
+struct PageCache {
+    capacity: usize,
+    state: RefCell<PageCacheState>,
+}
+
+#[derive(Default)]
+struct PageCacheState {
+    pages: HashMap<usize, (Box<[u8]>, u64)>,
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.borrow();
+        f.debug_struct("PageCache")
+            .field("capacity", &self.capacity)
+            .field("len", &state.pages.len())
+            .field("stats", &state.stats)
+            .finish()
+    }
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Default::default(),
+        }
+    }
+
+    /// Reads `dst.len()` bytes at `start` from the first `len` bytes of `file`.
+    fn read(&self, file: &File, len: usize, start: usize, dst: &mut [u8]) -> io::Result<()> {
+        if self.capacity == 0 {
+            return file.read_exact_at(dst, start as u64);
+        }
+
+        let mut state = self.state.borrow_mut();
+        let end = start + dst.len();
+        let mut pos = start;
+        while pos < end {
+            let page_idx = pos / PAGE_SIZE;
+            let page_start = page_idx * PAGE_SIZE;
+
+            let page = state.get_or_load(self.capacity, page_idx, || {
+                let mut page = vec![0; cmp::min(PAGE_SIZE, len - page_start)];
+                file.read_exact_at(&mut page, page_start as u64)?;
+                Ok(page.into())
+            })?;
+
+            let n = cmp::min(end, page_start + page.len()) - pos;
+            dst[pos - start .. pos - start + n].copy_from_slice(&page[pos - page_start .. pos - page_start + n]);
+            pos += n;
+        }
+        Ok(())
+    }
+}
+
+impl PageCacheState {
+    fn get_or_load(&mut self, capacity: usize, idx: usize, load: impl FnOnce() -> io::Result<Box<[u8]>>)
+        -> io::Result<&[u8]>
+    {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some((_, old_tick)) = self.pages.get_mut(&idx) {
+            self.lru.remove(old_tick);
+            *old_tick = tick;
+            self.stats.hits += 1;
+        } else {
+            let page = load()?;
+            self.stats.misses += 1;
+
+            while self.pages.len() >= capacity {
+                let (&old_tick, &evicted) = self.lru.iter().next().expect("capacity > 0 implies pages");
+                self.lru.remove(&old_tick);
+                self.pages.remove(&evicted).expect("lru and pages in sync");
+                self.stats.evictions += 1;
+            }
+            self.pages.insert(idx, (page, tick));
+        }
+        self.lru.insert(tick, idx);
+
+        Ok(&self.pages[&idx].0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::offset::OffsetMut;
+
+    fn file_with(bytes: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(bytes).unwrap();
+        file
+    }
+
+    /// Loads a value through the `TryGetPtr` impl of a `PreadPile`.
+    fn take<'p, 'v, T>(pile: &PreadPile<'p, 'v>, offset: usize) -> Result<T, LoadError>
+        where T: Pointee<Metadata = ()> + LoadPtr<OffsetMut<'p, 'v>, Owned = T>
+    {
+        let ptr = OffsetMut::from(Offset::new(offset).unwrap());
+        match unsafe { pile.try_get_ptr_unchecked::<T>(&ptr, ()) }? {
+            Ref::Owned(value) => Ok(value),
+            Ref::Ref(_) => panic!("clean values are always owned"),
+        }
+    }
+
+    #[test]
+    fn read_blobs() {
+        let mut bytes = vec![0; PAGE_SIZE * 3];
+        bytes[1] = 42;
+        bytes[PAGE_SIZE - 2 .. PAGE_SIZE + 2].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        let file = file_with(&bytes);
+
+        PreadPile::new(&file, 2, |pile| {
+            assert_eq!(pile.len(), PAGE_SIZE * 3);
+            assert_eq!(take::<u8>(&pile, 1).unwrap(), 42);
+
+            // spans two pages
+            assert_eq!(take::<[u8; 4]>(&pile, PAGE_SIZE - 2).unwrap(),
+                       0x1234_5678u32.to_le_bytes());
+            assert_eq!(pile.stats(), CacheStats { hits: 1, misses: 2, evictions: 0 });
+
+            // the first page is now the most recently used, so the second is evicted
+            take::<u8>(&pile, 0).unwrap();
+            take::<u8>(&pile, PAGE_SIZE * 2).unwrap();
+            take::<u8>(&pile, 1).unwrap();
+            assert_eq!(pile.stats(), CacheStats { hits: 3, misses: 3, evictions: 1 });
+
+            let dirty = OffsetMut::alloc(7u8);
+            match unsafe { pile.try_get_ptr_unchecked::<u8>(&dirty, ()) } {
+                Ok(Ref::Ref(&7)) => {},
+                r => panic!("{:?}", r),
+            }
+            unsafe { dirty.dealloc::<u8>(()) };
+
+            match take::<u8>(&pile, PAGE_SIZE * 3) {
+                Err(LoadError { offset, kind: LoadErrorKind::OutOfRange, .. }) => assert_eq!(offset, PAGE_SIZE * 3),
+                r => panic!("{:?}", r),
+            }
+
+            match take::<bool>(&pile, 1) {
+                Err(LoadError { offset: 1, kind: LoadErrorKind::Validate(_), .. }) => {},
+                r => panic!("{:?}", r),
+            }
+        }).unwrap()
+    }
+
+    #[test]
+    fn pread_pile_ptr() {
+        // an offset pointing to the byte after it, tagged as an offset
+        let mut bytes = ((8u64 << 1) | 1).to_le_bytes().to_vec();
+        bytes.push(42);
+        let file = file_with(&bytes);
+
+        PreadPile::new(&file, 1, |pile| {
+            let root = PreadPilePtr::new(Offset::new(0).unwrap(), pile.zone());
+
+            // the loaded pointer keeps the zone, so its target is read through the page cache too
+            let child = match unsafe { root.try_get_unchecked::<PreadPilePtr>(()) } {
+                Ok(Ref::Owned(child)) => child,
+                r => panic!("{:?}", r),
+            };
+            match unsafe { child.try_get_unchecked::<u8>(()) } {
+                Ok(Ref::Owned(42)) => {},
+                r => panic!("{:?}", r),
+            }
+            assert_eq!(pile.stats(), CacheStats { hits: 1, misses: 1, evictions: 0 });
+        }).unwrap()
+    }
+
+    #[test]
+    fn read_limited() {
+        let file = file_with(&[1, 2, 3, 4]);
+
+        PreadPile::new(&file, 0, |pile| {
+            let pile = pile.with_limits(ValidateLimits { max_blob_size: 2, ..ValidateLimits::default() });
+            assert_eq!(take::<[u8; 2]>(&pile, 0).unwrap(), [1, 2]);
+            match take::<[u8; 4]>(&pile, 0) {
+                Err(LoadError { offset: 0, kind: LoadErrorKind::Limit(_), .. }) => {},
+                r => panic!("{:?}", r),
+            }
+        }).unwrap()
+    }
+
+    #[test]
+    fn read_checksummed() {
+        let mut bytes = vec![42];
+        Checksum::Crc32c.append(&mut bytes, 0);
+        let file = file_with(&bytes);
+
+        PreadPile::new(&file, 0, |pile| {
+            let pile = pile.with_checksum(Checksum::Crc32c);
+            assert_eq!(take::<u8>(&pile, 0).unwrap(), 42);
+
+            match take::<u8>(&pile, 1) {
+                Err(LoadError { offset: 1, kind: LoadErrorKind::OutOfRange, .. }) => {},
+                r => panic!("{:?}", r),
+            }
+        }).unwrap();
+
+        file.write_at(&[43], 0).unwrap();
+        PreadPile::new(&file, 0, |pile| {
+            let pile = pile.with_checksum(Checksum::Crc32c);
+            match take::<u8>(&pile, 0) {
+                Err(LoadError { kind: LoadErrorKind::Checksum(err), .. }) => assert_eq!(err.offset, 0),
+                r => panic!("{:?}", r),
+            }
+        }).unwrap();
+    }
+
+    #[test]
+    fn truncated_file() {
+        let file = file_with(&[1, 2, 3, 4]);
+
+        PreadPile::new(&file, 1, |pile| {
+            file.set_len(2).unwrap();
+
+            match take::<u8>(&pile, 3) {
+                Err(LoadError { offset: 3, kind: LoadErrorKind::Io(err), .. }) => {
+                    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+                },
+                r => panic!("{:?}", r),
+            }
+        }).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! Progress reporting and cancellation of long-running saves and validation.
+//!
+//! A `Monitor` is handed to an operation, which reports its `Progress` to the monitor's
+//! `Observer` as each blob is saved or validated. The same report is where the monitor's
+//! `CancelToken` is checked: once cancelled, the operation stops with a `Cancelled` error.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use thiserror::Error;
+
+/// The progress of a save or validation.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Blob bytes saved or validated so far.
+    pub bytes: usize,
+
+    /// Blobs saved or validated so far.
+    pub nodes: usize,
+
+    /// The pointer depth of the most recent blob.
+    pub depth: usize,
+}
+
+/// Observes the progress of saves and validation.
+pub trait Observer : Send + Sync {
+    /// Called after every blob is saved or validated.
+    fn progress(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress) + Send + Sync> Observer for F {
+    fn progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Cancels an operation, possibly from another thread.
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels every operation using this token, or a clone of it.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Returned when an operation is cancelled.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("operation cancelled")]
+pub struct Cancelled;
+
+impl From<Cancelled> for io::Error {
+    fn from(err: Cancelled) -> Self {
+        io::Error::new(io::ErrorKind::Interrupted, err)
+    }
+}
+
+/// The observer and cancellation token of an operation.
+///
+/// Cloning a `Monitor` is cheap, and the clone reports to the same observer.
+#[derive(Default, Clone)]
+pub struct Monitor {
+    observer: Option<Arc<dyn Observer>>,
+    cancel: Option<CancelToken>,
+}
+
+impl fmt::Debug for Monitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Monitor")
+            .field("observer", &self.observer.is_some())
+            .field("cancel", &self.cancel)
+            .finish()
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports progress to an observer.
+    pub fn with_observer(self, observer: impl Observer + 'static) -> Self {
+        Self {
+            observer: Some(Arc::new(observer)),
+            ..self
+        }
+    }
+
+    /// Stops the operation once the token is cancelled.
+    pub fn with_cancel_token(self, cancel: CancelToken) -> Self {
+        Self {
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    /// Reports progress, returning an error if the operation has been cancelled.
+    ///
+    /// Progress is reported even if the operation has been cancelled, so the observer sees how far
+    /// it got.
+    pub fn report(&self, progress: &Progress) -> Result<(), Cancelled> {
+        if let Some(observer) = &self.observer {
+            observer.progress(progress);
+        }
+
+        match &self.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn report() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let cancel = CancelToken::new();
+
+        let monitor = {
+            let reports = Arc::clone(&reports);
+            Monitor::new().with_observer(move |progress: &Progress| reports.lock().unwrap().push(*progress))
+                          .with_cancel_token(cancel.clone())
+        };
+
+        let progress = Progress { bytes: 8, nodes: 1, depth: 1 };
+        assert_eq!(monitor.report(&progress), Ok(()));
+
+        cancel.cancel();
+        assert_eq!(monitor.clone().report(&progress), Err(Cancelled));
+        assert_eq!(*reports.lock().unwrap(), [progress, progress]);
+
+        assert_eq!(Monitor::new().report(&progress), Ok(()));
+    }
+}
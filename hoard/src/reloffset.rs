@@ -0,0 +1,405 @@
+//! Self-relative offsets, for relocatable bundles.
+//!
+//! An `Offset` is relative to the start of the pile, so data saved with offsets can only be used
+//! at the position it was saved at. A `RelOffset` is instead relative to its own position. Data
+//! saved with `RelOffset`s by a `BundleSaver` forms a self-contained bundle, which can be
+//! concatenated with other bundles, appended to a journal, or embedded in a message, and loaded in
+//! place wherever it ends up.
+//!
+//! While saving, the position of the blob containing a pointer isn't known yet. The saver
+//! therefore hands out *pending* `RelOffset`s, which record the position of the target within the
+//! bundle, and are converted to deltas as they're written. Pending offsets are distinguished from
+//! deltas by the least-significant-bit, and never validate.
+//!
+//! A bundle is loaded in place through its pile: `TryPile` implements `TryGetPtr<RelOffset>`,
+//! resolving each `RelOffset` relative to where it is within the pile's bytes. Values that can be
+//! used in place, such as a `Bag<T, RelOffset>`, are returned by reference, so the `RelOffset`s
+//! within them can be resolved in turn.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+
+use thiserror::Error;
+use leint::Le;
+
+use crate::refs::Ref;
+use crate::blob::*;
+use crate::load::*;
+use crate::save::*;
+use crate::scalar::*;
+use crate::ptr::*;
+use crate::offset::{Offset, OffsetMut};
+use crate::pile::{TryPile, LoadError};
+use crate::fingerprint::{Schema, SchemaHasher};
+
+/// An offset relative to its own position.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct RelOffset {
+    raw: Le<u64>,
+}
+
+unsafe impl Persist for RelOffset {}
+
+impl fmt::Debug for RelOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.delta() {
+            Some(delta) => f.debug_tuple("RelOffset").field(&delta).finish(),
+            None => f.debug_tuple("Pending").field(&(self.raw.get() >> 1)).finish(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid relative offset")]
+#[non_exhaustive]
+pub struct ValidateRelOffsetBlobError;
+
+impl RelOffset {
+    /// The largest delta.
+    pub const MAX: i64 = (1 << 62) - 1;
+
+    /// The smallest delta.
+    pub const MIN: i64 = -(1 << 62);
+
+    /// Creates a new `RelOffset` from a delta.
+    ///
+    /// Returns `None` if the delta is out of range.
+    pub fn new(delta: i64) -> Option<Self> {
+        if Self::MIN <= delta && delta <= Self::MAX {
+            Some(Self { raw: (((delta as u64) << 1) | 1).into() })
+        } else {
+            None
+        }
+    }
+
+    /// Creates a `RelOffset`, located at `at`, pointing to `target`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hoard::reloffset::RelOffset;
+    ///
+    /// let rel = RelOffset::from_positions(10, 3).unwrap();
+    /// assert_eq!(rel.delta(), Some(-7));
+    ///
+    /// // only the distance matters
+    /// assert_eq!(rel.resolve(110), Some(103));
+    /// ```
+    pub fn from_positions(at: usize, target: usize) -> Option<Self> {
+        let at: i64 = at.try_into().ok()?;
+        let target: i64 = target.try_into().ok()?;
+        Self::new(target - at)
+    }
+
+    fn pending(target: usize) -> Self {
+        assert!(target <= Offset::MAX, "overflow");
+        Self { raw: ((target as u64) << 1).into() }
+    }
+
+    /// Gets the delta, or `None` if the offset is pending.
+    pub fn delta(&self) -> Option<i64> {
+        let raw = self.raw.get();
+        if raw & 1 == 1 {
+            Some((raw as i64) >> 1)
+        } else {
+            None
+        }
+    }
+
+    /// Resolves the offset, given its own position.
+    ///
+    /// Returns `None` if the offset is pending, or the target would be out of range.
+    pub fn resolve(&self, at: usize) -> Option<usize> {
+        let at: i64 = at.try_into().ok()?;
+        at.checked_add(self.delta()?)?
+          .try_into().ok()
+    }
+
+    /// Resolves an offset that is located within a pile.
+    ///
+    /// Returns `None` if `self` isn't a reference into the pile's bytes, such as a copy of a
+    /// decoded value.
+    pub fn resolve_in<'p, 'v>(&self, pile: &TryPile<'p, 'v>) -> Option<Offset<'p, 'v>> {
+        let base = pile.as_bytes().as_ptr() as usize;
+        let at = (self as *const Self as usize).checked_sub(base)?;
+
+        if at.checked_add(mem::size_of::<Self>())? <= pile.as_bytes().len() {
+            self.resolve(at).and_then(Offset::new)
+        } else {
+            None
+        }
+    }
+}
+
+impl Scalar for RelOffset {
+    const BLOB_LAYOUT: BlobLayout = BlobLayout::new_nonzero(mem::size_of::<Self>());
+
+    type ScalarBlobError = ValidateRelOffsetBlobError;
+
+    fn validate_blob<'a>(blob: Blob<'a, Self>) -> Result<ValidBlob<'a, Self>, Self::ScalarBlobError> {
+        let raw = u64::from_le_bytes(blob.as_bytes().try_into().unwrap());
+
+        // pending offsets are never written
+        if raw & 0b1 == 0b1 {
+            unsafe { Ok(blob.assume_valid()) }
+        } else {
+            Err(ValidateRelOffsetBlobError)
+        }
+    }
+
+    fn decode_blob<'a>(blob: ValidBlob<'a, Self>) -> Self {
+        blob.as_value().clone()
+    }
+
+    fn try_deref_blob<'a>(blob: ValidBlob<'a, Self>) -> Result<&'a Self, ValidBlob<'a, Self>> {
+        Ok(blob.as_value())
+    }
+
+    /// Pending offsets are converted to deltas if the writer knows its position.
+    ///
+    /// Otherwise, as when a blob is measured or compressed on its own, there's nothing to be
+    /// relative to, and the pending offset is written as-is. Pending offsets never validate, so
+    /// loading such a blob fails instead of silently pointing somewhere else.
+    fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+        let target = (self.raw.get() >> 1) as usize;
+        let rel = match (self.delta(), dst.position()) {
+            (None, Some(at)) => Self::from_positions(at, target).expect("overflow"),
+            _ => *self,
+        };
+
+        dst.write_bytes(&rel.raw.get().to_le_bytes())?
+           .finish()
+    }
+}
+
+impl Schema for RelOffset {
+    fn describe_schema(hasher: &mut SchemaHasher) {
+        hasher.write_str("reloffset")
+              .write_layout(Self::BLOB_LAYOUT);
+    }
+}
+
+impl AsPtrImpl<Self> for RelOffset {
+    fn as_ptr_impl(this: &Self) -> &Self {
+        this
+    }
+}
+
+impl PersistPtr for RelOffset {
+    type Zone = !;
+    type BlobZone = ();
+}
+
+/// Returned when the target of a `RelOffset` can't be loaded.
+#[derive(Debug, Error)]
+pub enum RelLoadError {
+    /// The `RelOffset` isn't within the pile's bytes, eg because it's a copy of a decoded value, or
+    /// its target is before the start of the pile.
+    #[error("relative offset can't be resolved within the pile")]
+    Unresolved,
+
+    #[error("{0}")]
+    Load(#[from] LoadError),
+}
+
+/// Loads the targets of `RelOffset`s located within the pile.
+///
+/// Values are dereferenced in place when possible, and decoded into `Ref::Owned` otherwise. Only
+/// values used in place can have their own `RelOffset`s resolved.
+impl<'p, 'v> TryGetPtr<RelOffset> for TryPile<'p, 'v> {
+    type Error = RelLoadError;
+
+    unsafe fn try_get_ptr_unchecked<'a, 'z: 'a, T: ?Sized + LoadPtr<RelOffset>>(&'z self, ptr: &'a RelOffset, metadata: T::Metadata)
+        -> Result<Ref<'a, T>, RelLoadError>
+    {
+        let offset = ptr.resolve_in(self).ok_or(RelLoadError::Unresolved)?;
+        let blob = self.get_valid_blob::<T>(offset, metadata)
+                       .map_err(|err| LoadError::from_blob::<T>(offset.get(), err))?;
+        Ok(T::deref_blob(blob, &()))
+    }
+}
+
+/// Saves a value, and everything reachable from it, into a relocatable bundle.
+///
+/// Dirty values are saved as usual. Clean values are copied from the source pile, as the bundle
+/// has to be self-contained; values reachable more than once are only copied once.
+#[derive(Debug)]
+pub struct BundleSaver<'p, 'v> {
+    src: TryPile<'p, 'v>,
+    written: Vec<u8>,
+    copied: HashMap<(usize, usize), usize>,
+    stack: RefCell<Vec<(usize, usize)>>,
+}
+
+/// Writes blobs to a bundle, keeping track of the position.
+struct BundleWriter(Vec<u8>);
+
+impl WriteBlob for BundleWriter {
+    type Ok = Self;
+    type Error = !;
+
+    fn write_bytes(mut self, buf: &[u8]) -> Result<Self, !> {
+        self.0.extend_from_slice(buf);
+        Ok(self)
+    }
+
+    fn write_padding(mut self, len: usize) -> Result<Self, !> {
+        self.0.resize(self.0.len() + len, 0);
+        Ok(self)
+    }
+
+    fn finish(self) -> Result<Self, !> {
+        Ok(self)
+    }
+
+    fn position(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+impl<'p, 'v> BundleSaver<'p, 'v> {
+    /// Creates a new saver, copying clean values from `src`.
+    pub fn new(src: TryPile<'p, 'v>) -> Self {
+        Self {
+            src,
+            written: vec![],
+            copied: HashMap::new(),
+            stack: RefCell::new(vec![]),
+        }
+    }
+
+    /// Saves a value, returning the bundle and the position of the value within it.
+    pub fn save<T: ?Sized>(mut self, value: &T) -> Result<(Vec<u8>, usize), LoadError>
+        where T: SavePtr<OffsetMut<'p, 'v>, RelOffset>
+    {
+        let mut poll = value.init_save_ptr();
+        poll.save_poll(&mut self)?;
+
+        let root = self.written.len();
+        self.finish_save(&poll)?;
+        Ok((self.written, root))
+    }
+}
+
+impl<'p, 'v> Saver for BundleSaver<'p, 'v> {
+    type SrcPtr = OffsetMut<'p, 'v>;
+    type DstPtr = RelOffset;
+    type Error = LoadError;
+
+    fn try_save_raw<R, T: ?Sized + ValidateBlob>(&self,
+        ptr: &Offset<'p, 'v>,
+        metadata: T::Metadata,
+        f: impl FnOnce(ValidBlob<T>, &TryPile<'p, 'v>) -> R,
+    ) -> Result<Result<RelOffset, R>, Self::Error>
+    {
+        let r = self.src.try_copy_blob(&self.copied, &self.stack, *ptr, metadata, f)?;
+        Ok(r.map(RelOffset::pending))
+    }
+
+    fn finish_save<T>(&mut self, value_poll: &T) -> Result<RelOffset, Self::Error>
+        where T: EncodeBlob
+    {
+        let dst = self.written.len();
+
+        let written = mem::replace(&mut self.written, vec![]);
+        let BundleWriter(written) = value_poll.encode_blob(BundleWriter(written)).into_ok();
+        self.written = written;
+
+        if let Some(key) = self.stack.get_mut().pop() {
+            self.copied.insert(key, dst);
+        }
+        Ok(RelOffset::pending(dst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    use crate::bag::Bag;
+
+    #[test]
+    fn delta_roundtrip() {
+        for &delta in &[0, 1, -1, RelOffset::MAX, RelOffset::MIN] {
+            assert_eq!(RelOffset::new(delta).unwrap().delta(), Some(delta));
+        }
+        assert!(RelOffset::new(RelOffset::MAX + 1).is_none());
+        assert!(RelOffset::new(RelOffset::MIN - 1).is_none());
+
+        assert_eq!(RelOffset::new(-2).unwrap().resolve(1), None);
+        assert_eq!(RelOffset::pending(5).delta(), None);
+        assert_eq!(RelOffset::pending(5).resolve(0), None);
+    }
+
+    #[test]
+    fn bundle_relocation() {
+        let buf = [0xff, 0xff, 42];
+        let src = unsafe { TryPile::new_unchecked(&buf) };
+
+        // a dirty bag, pointing to a clean u8 in the source pile
+        let clean = unsafe { Bag::<u8, _>::from_raw_parts(OffsetMut::from(Offset::new(2).unwrap()), ()) };
+        let bag = Bag::new(clean);
+
+        let (bundle, root) = BundleSaver::new(src).save(&bag).unwrap();
+
+        // the u8, then the inner bag, then the outer bag
+        assert_eq!(bundle.len(), 17);
+        assert_eq!(root, 9);
+        assert_eq!(&bundle[1 .. 9], &RelOffset::new(-1).unwrap().raw.get().to_le_bytes());
+        assert_eq!(&bundle[9 .. 17], &RelOffset::new(-8).unwrap().raw.get().to_le_bytes());
+
+        // the bundle can be loaded in place wherever it's embedded
+        for prefix in 0 .. 3 {
+            let mut buf = vec![0xee; prefix];
+            buf.extend_from_slice(&bundle);
+            let pile = unsafe { TryPile::new_unchecked(&buf) };
+
+            let outer = pile.get_valid_blob::<RelOffset>(Offset::new(prefix + root).unwrap(), ()).unwrap();
+            let inner_offset = outer.as_value().resolve_in(&pile).unwrap();
+            assert_eq!(inner_offset, prefix + 1);
+
+            let inner = pile.get_valid_blob::<RelOffset>(inner_offset, ()).unwrap();
+            let value_offset = inner.as_value().resolve_in(&pile).unwrap();
+            let value = pile.get_valid_blob::<u8>(value_offset, ()).unwrap();
+            assert_eq!(value.as_value(), &42);
+
+            // copies no longer know where they are
+            let copy = *inner.as_value();
+            assert_eq!(copy.resolve_in(&pile), None);
+            assert_eq!(copy.resolve(prefix + 1), Some(prefix));
+
+            // bags are loaded in place, so the pointers within them resolve too
+            let outer = pile.get_valid_blob::<Bag<Bag<u8, RelOffset>, RelOffset>>(Offset::new(prefix + root).unwrap(), ())
+                            .unwrap();
+            let inner = match outer.as_value().try_get_in(&pile).unwrap() {
+                Ref::Ref(inner) => inner,
+                Ref::Owned(_) => panic!("bag decoded instead of used in place"),
+            };
+            assert_eq!(*inner.try_get_in(&pile).unwrap(), 42);
+
+            let inner_blob = pile.get_valid_blob::<Bag<u8, RelOffset>>(Offset::new(prefix + 1).unwrap(), ()).unwrap();
+            let decoded = <Bag<u8, RelOffset> as Load>::decode_blob(inner_blob, &());
+            match decoded.try_get_in(&pile) {
+                Err(RelLoadError::Unresolved) => {},
+                r => panic!("{:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn pending_invalid() {
+        let bytes = RelOffset::pending(4).raw.get().to_le_bytes();
+        let blob = Blob::<RelOffset>::try_from(&bytes[..]).unwrap();
+        assert!(<RelOffset as Scalar>::validate_blob(blob).is_err());
+
+        // writers that don't know their position write pending offsets as-is
+        let written = <RelOffset as Scalar>::encode_blob(&RelOffset::pending(4), vec![]).into_ok();
+        assert_eq!(written, bytes);
+    }
+}
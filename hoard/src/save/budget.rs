@@ -0,0 +1,220 @@
+//! Incremental saving with a work budget.
+//!
+//! Saving is a poll-based state machine: every `SavePoll` implementation keeps track of which
+//! children have already been saved, and picks up where it left off if `save_poll()` is called
+//! again after an error. `BudgetSaver` takes advantage of that by returning `Poll::Pending` once
+//! its budget is used up, allowing a large save to be spread out over many calls.
+//!
+//! Saving goes through the same work stack as `save_deep()`, so deep values don't overflow the
+//! stack. The work stack is kept between calls, along with the value being saved, so each call
+//! resumes from the nearest deferred value rather than walking down again from the root: at most
+//! `MAX_DEPTH` ancestors are polled again.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::NonNull;
+use std::task::Poll;
+
+use super::*;
+use super::stack::{Deferred, StackSaver};
+
+/// The amount of work a `BudgetSaver` may do per call to `poll_save()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveBudget {
+    /// Maximum number of blob bytes saved.
+    pub bytes: usize,
+
+    /// Maximum number of blobs saved.
+    pub blobs: usize,
+}
+
+impl SaveBudget {
+    /// An unlimited budget.
+    pub const UNLIMITED: Self = Self {
+        bytes: usize::MAX,
+        blobs: usize::MAX,
+    };
+}
+
+/// Saves a value a bit at a time, doing a limited amount of work per call to `poll_save()`.
+///
+/// The budget is checked before each blob is saved, so a single large blob can overshoot it.
+/// Otherwise a save could never make progress.
+///
+/// The saver owns the value's poller, so that the work stack can keep pointing into it between
+/// calls. It's kept on the heap, and only ever accessed through a raw pointer, so moving the saver
+/// doesn't invalidate the work stack.
+pub struct BudgetSaver<S: Saver, T> {
+    marker: PhantomData<T>,
+    saver: StackSaver<'static, S>,
+    poll: NonNull<T>,
+}
+
+impl<S: Saver, T> Drop for BudgetSaver<S, T> {
+    fn drop(&mut self) {
+        // SAFETY: allocated by new(), and only freed here
+        unsafe { drop(Box::from_raw(self.poll.as_ptr())) }
+    }
+}
+
+impl<S: Saver, T> fmt::Debug for BudgetSaver<S, T>
+where S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BudgetSaver")
+            .field("inner", self.saver.inner())
+            .field("budget", &self.saver.budget())
+            .field("deferred", &self.saver.deferred())
+            .finish()
+    }
+}
+
+impl<S: Saver, T> BudgetSaver<S, T>
+where T: SavePoll<SrcPtr = S::SrcPtr, DstPtr = S::DstPtr>
+{
+    /// Creates a new `BudgetSaver` to save the value `poll` was created from.
+    pub fn new(inner: S, poll: T, budget: SaveBudget) -> Self {
+        let mut saver = StackSaver::new(inner);
+        saver.set_budget(budget);
+        Self {
+            marker: PhantomData,
+            saver,
+            poll: NonNull::from(Box::leak(Box::new(poll))),
+        }
+    }
+
+    /// Gets the per-call budget.
+    pub fn budget(&self) -> SaveBudget {
+        self.saver.budget()
+    }
+
+    /// Sets the per-call budget.
+    pub fn set_budget(&mut self, budget: SaveBudget) {
+        self.saver.set_budget(budget)
+    }
+
+    /// Gets a reference to the inner saver.
+    pub fn inner(&self) -> &S {
+        self.saver.inner()
+    }
+
+    /// Unwraps the inner saver, and the poller of the value being saved.
+    pub fn into_parts(self) -> (S, T) {
+        let this = mem::ManuallyDrop::new(self);
+
+        // SAFETY: this isn't dropped, so each field is read exactly once
+        unsafe {
+            let saver = std::ptr::read(&this.saver);
+            let poll = Box::from_raw(this.poll.as_ptr());
+            (saver.into_inner(), *poll)
+        }
+    }
+
+    /// Saves the value and its children, until either finished or out of budget.
+    ///
+    /// The budget is refilled at the start of each call. Once `Poll::Ready` has been returned with
+    /// the saved pointer, the saver is done with. If saving fails, the next call starts over from
+    /// the root; anything already saved is kept.
+    pub fn poll_save(&mut self) -> Result<Poll<<S::DstPtr as Ptr>::Persist>, S::Error> {
+        self.saver.refill();
+
+        // SAFETY: The poller is only ever accessed by save(), or after the saver is done with it by
+        // into_parts() and drop(). As it's on the heap, moving the saver doesn't move it.
+        match unsafe { self.saver.save(self.poll.as_ptr()) } {
+            Ok(ptr) => Ok(Poll::Ready(ptr)),
+            Err(Deferred::Budget) => Ok(Poll::Pending),
+            Err(Deferred::Err(err)) => Err(err),
+            Err(Deferred::Pending | Deferred::Cancelled) => unreachable!(),
+        }
+    }
+}
+
+/// Counts the bytes an `EncodeBlob` would write.
+pub(crate) struct CountBytes(pub(crate) usize);
+
+impl WriteBlob for CountBytes {
+    type Ok = usize;
+    type Error = !;
+
+    fn write_bytes(self, buf: &[u8]) -> Result<Self, !> {
+        Ok(CountBytes(self.0 + buf.len()))
+    }
+
+    fn write_padding(self, len: usize) -> Result<Self, !> {
+        Ok(CountBytes(self.0 + len))
+    }
+
+    fn finish(self) -> Result<usize, !> {
+        Ok(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::offset::{Offset, OffsetMut, ShallowDumper};
+
+    /// Saves `n` u8's as children, one at a time.
+    struct Chain {
+        n: u8,
+        saved: Vec<usize>,
+    }
+
+    impl EncodeBlob for Chain {
+        type Target = ();
+
+        fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+            dst.finish()
+        }
+    }
+
+    unsafe impl SavePoll for Chain {
+        type SrcPtr = OffsetMut<'static, 'static>;
+        type DstPtr = Offset<'static, 'static>;
+
+        fn save_poll<S>(&mut self, saver: &mut S) -> Result<(), S::Error>
+            where S: Saver<SrcPtr = Self::SrcPtr, DstPtr = Self::DstPtr>,
+        {
+            while self.saved.len() < self.n as usize {
+                let value = self.saved.len() as u8;
+                let offset = saver.finish_save(&<u8 as Save<Self::DstPtr>>::init_save(&value))?;
+                self.saved.push(offset.get());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn budgeted_save() {
+        let budget = SaveBudget { bytes: usize::MAX, blobs: 3 };
+        let chain = Chain { n: 10, saved: vec![] };
+        let mut saver = BudgetSaver::new(ShallowDumper::new(0), chain, budget);
+
+        let mut calls = 0;
+        let root = loop {
+            calls += 1;
+            if let Poll::Ready(root) = saver.poll_save().into_ok() {
+                break root;
+            }
+        };
+
+        // 10 children plus the root, three at a time
+        assert_eq!(calls, 4);
+        let (_, chain) = saver.into_parts();
+        assert_eq!(chain.saved, (0 .. 10).collect::<Vec<usize>>());
+        assert_eq!(root.get(), 10);
+    }
+
+    #[test]
+    fn byte_budget() {
+        let budget = SaveBudget { bytes: 4, blobs: usize::MAX };
+        let chain = Chain { n: 10, saved: vec![] };
+        let mut saver = BudgetSaver::new(ShallowDumper::new(0), chain, budget);
+
+        assert_eq!(saver.poll_save().into_ok(), Poll::Pending);
+        let (_, chain) = saver.into_parts();
+        assert_eq!(chain.saved.len(), 4);
+    }
+}
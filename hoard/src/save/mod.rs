@@ -15,6 +15,11 @@ use crate::scalar::Scalar;
 
 use super::*;
 
+pub mod budget;
+
+pub mod stack;
+pub use self::stack::{save_deep, save_deep_monitored};
+
 /// Provides the projection of a type saved with a specific type of pointer.
 pub trait Saved<DstPtr> : Pointee {
     /// The projected type, with all internal pointers replaced by `DstPtr`.
@@ -51,7 +56,13 @@ pub trait EncodeBlob {
 }
 
 /// The interface to the state machine that saves a value and its children.
-pub trait SavePoll : EncodeBlob {
+///
+/// # Safety
+///
+/// Once polling a child's poller has returned an error, the child's poller must be neither moved
+/// nor dropped until polling it again succeeds. Pollers passed to `Saver::save_dirty()` rely on
+/// this, as `save_deep()` keeps pointers to the pollers it defers, and resumes them directly.
+pub unsafe trait SavePoll : EncodeBlob {
     /// The type of pointer that children of this value will be behind.
     type SrcPtr : Ptr;
 
@@ -108,7 +119,8 @@ impl<Q: Ptr, R: Ptr, T: Save<R>> EncodeBlob for SavePtrPoll<Q, R, T> {
     }
 }
 
-impl<Q: Ptr, R: Ptr, T: Save<R>> SavePoll for SavePtrPoll<Q, R, T>
+// SAFETY: just a wrapper
+unsafe impl<Q: Ptr, R: Ptr, T: Save<R>> SavePoll for SavePtrPoll<Q, R, T>
 where T::Ptr: AsPtr<Q>,
       Q::BlobZone: AsZone<<T::Ptr as Ptr>::BlobZone>,
 {
@@ -161,6 +173,33 @@ where Q: AsPtr<S::SrcPtr>,
     {
         self.inner.finish_save(value_poll)
     }
+
+    unsafe fn save_dirty<T>(&mut self, poll: &mut T) -> Result<(), Self::Error>
+        where T: PollDirty<Self>
+    {
+        // SAFETY: #[repr(transparent)], and the adapter is kept in place along with poll
+        let poll = &mut *(poll as *mut T as *mut PollDirtyAdapter<Q, T>);
+        self.inner.save_dirty(poll)
+    }
+}
+
+/// Adapts a `PollDirty` for a `SaverAdapter` back to the inner saver.
+#[repr(transparent)]
+struct PollDirtyAdapter<Q, T> {
+    marker: PhantomData<Q>,
+    inner: T,
+}
+
+impl<Q: Ptr, S: Saver, T> PollDirty<S> for PollDirtyAdapter<Q, T>
+where T: PollDirty<SaverAdapter<Q, S>>,
+      Q: AsPtr<S::SrcPtr>,
+      <S::SrcPtr as Ptr>::BlobZone: AsZone<Q::BlobZone>,
+{
+    fn poll_dirty(&mut self, saver: &mut S) -> Result<(), S::Error> {
+        // SAFETY: #[repr(transparent)]
+        let saver = unsafe { &mut *(saver as *mut S as *mut SaverAdapter<Q, S>) };
+        self.inner.poll_dirty(saver)
+    }
 }
 
 /// Saves data in one zone to another zone.
@@ -200,6 +239,82 @@ pub trait Saver {
     /// Saves a value whose children have been saved.
     fn finish_save<T>(&mut self, value_poll: &T) -> Result<<Self::DstPtr as Ptr>::Persist, Self::Error>
         where T: EncodeBlob;
+
+    /// Saves the dirty value behind a pointer.
+    ///
+    /// The default implementation saves it immediately, recursing into its children. Savers that
+    /// have to handle arbitrarily deep data can instead defer it to a work stack, as `save_deep()`
+    /// does.
+    ///
+    /// # Safety
+    ///
+    /// If this returns an error, `poll` must be neither moved nor dropped until calling this again
+    /// succeeds, as it may be resumed through a pointer in the meantime.
+    unsafe fn save_dirty<T>(&mut self, poll: &mut T) -> Result<(), Self::Error>
+        where T: PollDirty<Self>,
+              Self: Sized,
+    {
+        poll.poll_dirty(self)
+    }
+}
+
+/// Saves the dirty value behind a pointer.
+///
+/// Implemented by the `SavePoll` of pointer types, such as `Bag`, so that the `Saver` decides when
+/// the value is saved with `Saver::save_dirty()`.
+pub trait PollDirty<S: Saver> {
+    /// Saves the value and its children, remembering where the value was saved.
+    fn poll_dirty(&mut self, saver: &mut S) -> Result<(), S::Error>;
+}
+
+/// Lets savers that wrap another saver, like the one used by `save_deep()`, borrow it.
+impl<'s, S: Saver> Saver for &'s mut S {
+    type SrcPtr = S::SrcPtr;
+    type DstPtr = S::DstPtr;
+    type Error = S::Error;
+
+    fn try_save_raw<R, T: ?Sized + ValidateBlob>(
+        &self,
+        ptr: &<Self::SrcPtr as Ptr>::Persist,
+        metadata: T::Metadata,
+        f: impl FnOnce(ValidBlob<T>, &<Self::SrcPtr as Ptr>::BlobZone) -> R,
+    ) -> Result<Result<<Self::DstPtr as Ptr>::Persist, R>,
+                Self::Error>
+    {
+        (**self).try_save_raw(ptr, metadata, f)
+    }
+
+    fn finish_save<T>(&mut self, value_poll: &T) -> Result<<Self::DstPtr as Ptr>::Persist, Self::Error>
+        where T: EncodeBlob
+    {
+        (**self).finish_save(value_poll)
+    }
+
+    unsafe fn save_dirty<T>(&mut self, poll: &mut T) -> Result<(), Self::Error>
+        where T: PollDirty<Self>
+    {
+        // SAFETY: #[repr(transparent)], and the adapter is kept in place along with poll
+        let poll = &mut *(poll as *mut T as *mut PollDirtyRef<'s, S, T>);
+        (**self).save_dirty(poll)
+    }
+}
+
+/// Adapts a `PollDirty` for a `&mut S` back to `S`.
+#[repr(transparent)]
+struct PollDirtyRef<'s, S, T> {
+    marker: PhantomData<&'s mut S>,
+    inner: T,
+}
+
+impl<'s, S: Saver, T> PollDirty<S> for PollDirtyRef<'s, S, T>
+where T: PollDirty<&'s mut S>,
+{
+    fn poll_dirty(&mut self, saver: &mut S) -> Result<(), S::Error> {
+        // SAFETY: The reference is only lent to inner for the duration of the call, behind a
+        // reference to a local, so it can't outlive the borrow it was made from.
+        let mut saver: &'s mut S = unsafe { &mut *(saver as *mut S) };
+        self.inner.poll_dirty(&mut saver)
+    }
 }
 
 pub trait WriteBlob : Sized {
@@ -215,8 +330,53 @@ pub trait WriteBlob : Sized {
         Ok(self)
     }
 
-    //fn write<Y: Zone, T: SavePoll<Y>>(self, value: &T) -> Result<Self, Self::Error>;
+    /// Writes a field, encoded by another `EncodeBlob` implementation.
+    fn write<T: EncodeBlob>(self, value: &T) -> Result<Self, Self::Error> {
+        value.encode_blob(FieldWriter(self))
+    }
+
+    /// Writes a scalar field.
+    fn write_scalar<T: Scalar>(self, value: &T) -> Result<Self, Self::Error> {
+        value.encode_blob(FieldWriter(self))
+    }
+
     fn finish(self) -> Result<Self::Ok, Self::Error>;
+
+    /// Gets the position the next byte will be written at, if the writer knows it.
+    ///
+    /// Self-relative pointers need this to encode themselves.
+    fn position(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Adaptor used by `WriteBlob::write()` and `write_scalar()` to hand back the parent writer.
+#[derive(Debug)]
+struct FieldWriter<W>(W);
+
+impl<W: WriteBlob> WriteBlob for FieldWriter<W> {
+    type Ok = W;
+    type Error = W::Error;
+
+    #[inline(always)]
+    fn write_bytes(self, buf: &[u8]) -> Result<Self, Self::Error> {
+        self.0.write_bytes(buf).map(FieldWriter)
+    }
+
+    #[inline(always)]
+    fn write_padding(self, len: usize) -> Result<Self, Self::Error> {
+        self.0.write_padding(len).map(FieldWriter)
+    }
+
+    #[inline(always)]
+    fn finish(self) -> Result<W, Self::Error> {
+        Ok(self.0)
+    }
+
+    #[inline(always)]
+    fn position(&self) -> Option<usize> {
+        self.0.position()
+    }
 }
 
 impl WriteBlob for Vec<u8> {
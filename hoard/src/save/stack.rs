@@ -0,0 +1,463 @@
+//! Saving of arbitrarily deep data.
+//!
+//! Saving is naturally recursive: the `SavePoll` of a pointer saves the value it points to, which
+//! in turn saves the values behind its own pointers. A long enough chain of pointers would
+//! overflow the stack. `save_deep()` bounds the recursion instead: past `MAX_DEPTH` dirty pointers
+//! deep, the next dirty value is pushed onto a heap-allocated work stack, and an error unwinds
+//! back to `save_deep()`, which saves the values on the work stack from the top down.
+//!
+//! As every `SavePoll` implementation picks up where it left off after an error, the values that
+//! were unwound through simply carry on once they're polled again.
+//!
+//! The work stack lives in the `StackSaver`, so a save that stops early can be resumed from the
+//! top of the stack rather than from the root. `BudgetSaver` relies on that.
+
+use std::mem;
+
+use super::*;
+use super::budget::{CountBytes, SaveBudget};
+
+use crate::progress::{Cancelled, Monitor, Progress};
+
+/// The number of dirty pointers saving recurses through before deferring to the work stack.
+const MAX_DEPTH: usize = 128;
+
+/// Saves a value, and everything dirty within it, without unbounded recursion.
+///
+/// Returns the saved pointer, as `Saver::finish_save()` does.
+pub fn save_deep<S, T>(saver: &mut S, poll: &mut T) -> Result<<S::DstPtr as Ptr>::Persist, S::Error>
+    where S: Saver,
+          T: SavePoll<SrcPtr = S::SrcPtr, DstPtr = S::DstPtr>,
+{
+    let mut saver = StackSaver::new(saver);
+
+    // SAFETY: poll is borrowed mutably for the whole save, and the stack starts out empty
+    unsafe { saver.save(poll) }.map_err(|err| match err {
+        Deferred::Err(err) => err,
+        Deferred::Pending | Deferred::Budget | Deferred::Cancelled => unreachable!(),
+    })
+}
+
+/// Saves a value like `save_deep()`, reporting progress to a `Monitor` as each blob is saved.
+///
+/// If the monitor is cancelled, saving stops with a `Cancelled` error. Blobs that have already
+/// been saved are left as-is; it's up to the caller to discard them.
+pub fn save_deep_monitored<S, T>(saver: &mut S, poll: &mut T, monitor: &Monitor)
+    -> Result<<S::DstPtr as Ptr>::Persist, S::Error>
+    where S: Saver,
+          S::Error: From<Cancelled>,
+          T: SavePoll<SrcPtr = S::SrcPtr, DstPtr = S::DstPtr>,
+{
+    let mut saver = StackSaver::new(saver);
+    saver.monitor = Some(monitor);
+
+    // SAFETY: poll is borrowed mutably for the whole save, and the stack starts out empty
+    unsafe { saver.save(poll) }.map_err(|err| match err {
+        Deferred::Err(err) => err,
+        Deferred::Cancelled => Cancelled.into(),
+        Deferred::Pending | Deferred::Budget => unreachable!(),
+    })
+}
+
+/// The error returned by `StackSaver`.
+pub(crate) enum Deferred<E> {
+    /// A dirty value was pushed onto the work stack.
+    Pending,
+
+    /// The budget ran out.
+    Budget,
+
+    /// The monitor was cancelled.
+    Cancelled,
+
+    /// The inner saver failed.
+    Err(E),
+}
+
+/// The `Saver` used by `save_deep()` and `BudgetSaver`.
+pub(crate) struct StackSaver<'m, S: Saver> {
+    inner: S,
+
+    /// The depth of the value being saved from the top of the work stack.
+    base: usize,
+
+    /// The depth recursed to from `base`.
+    depth: usize,
+
+    /// Deferred values, along with their depths.
+    ///
+    /// Each entry points into the state of the entry below it, or of the value being saved for
+    /// the bottom entry. Their real lifetimes are erased, so they must only be dereferenced while
+    /// that value is borrowed by `save()`.
+    stack: Vec<(*mut dyn PollDirty<StackSaver<'m, S>>, usize)>,
+
+    monitor: Option<&'m Monitor>,
+    progress: Progress,
+
+    budget: SaveBudget,
+    bytes: usize,
+    blobs: usize,
+}
+
+impl<'m, S: Saver> StackSaver<'m, S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            base: 0,
+            depth: 0,
+            stack: vec![],
+            monitor: None,
+            progress: Progress::default(),
+            budget: SaveBudget::UNLIMITED,
+            bytes: 0,
+            blobs: 0,
+        }
+    }
+
+    pub(crate) fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+
+    pub(crate) fn budget(&self) -> SaveBudget {
+        self.budget
+    }
+
+    pub(crate) fn set_budget(&mut self, budget: SaveBudget) {
+        self.budget = budget;
+    }
+
+    /// Restores the full budget.
+    pub(crate) fn refill(&mut self) {
+        self.bytes = 0;
+        self.blobs = 0;
+    }
+
+    /// The number of values deferred to the work stack.
+    pub(crate) fn deferred(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn exhausted(&self) -> bool {
+        self.bytes >= self.budget.bytes || self.blobs >= self.budget.blobs
+    }
+
+    /// Saves a value, starting from the top of the work stack.
+    ///
+    /// If this fails with `Deferred::Budget`, the work stack is kept and the next call resumes
+    /// from where this one stopped. On any other error it's cleared.
+    ///
+    /// `poll` is taken as a raw pointer, as the work stack may point into it: it's only
+    /// dereferenced once the stack is empty.
+    ///
+    /// # Safety
+    ///
+    /// `poll` must be valid for writes for the duration of the call. If the work stack isn't
+    /// empty, `poll` must be the value the last call failed with `Deferred::Budget` on, and must
+    /// have been neither moved nor accessed since.
+    pub(crate) unsafe fn save<T>(&mut self, poll: *mut T) -> Result<<S::DstPtr as Ptr>::Persist, Deferred<S::Error>>
+        where T: SavePoll<SrcPtr = S::SrcPtr, DstPtr = S::DstPtr>,
+    {
+        loop {
+            let r = match self.stack.last() {
+                Some(&(top, depth)) => {
+                    self.base = depth;
+
+                    // SAFETY: top was pushed by save_dirty(), whose caller keeps it in place until
+                    // it's done. It lives within poll, which our caller keeps in place, and
+                    // untouched, while the stack isn't empty. Pushing it unwound every borrow of it
+                    // back to here, and only the top of the stack is ever polled, so this is the
+                    // only live reference to it.
+                    (*top).poll_dirty(self)
+                },
+                None => {
+                    self.base = 0;
+                    (*poll).save_poll(self)
+                },
+            };
+
+            match r {
+                Ok(()) => {
+                    if self.stack.pop().is_none() {
+                        return self.finish_save(&*poll);
+                    }
+                },
+                Err(Deferred::Pending) => {},
+                Err(Deferred::Budget) => break Err(Deferred::Budget),
+                Err(err) => {
+                    self.stack.clear();
+                    break Err(err)
+                },
+            }
+        }
+    }
+}
+
+impl<S: Saver> Saver for StackSaver<'_, S> {
+    type SrcPtr = S::SrcPtr;
+    type DstPtr = S::DstPtr;
+    type Error = Deferred<S::Error>;
+
+    fn try_save_raw<R, T: ?Sized + ValidateBlob>(
+        &self,
+        ptr: &<Self::SrcPtr as Ptr>::Persist,
+        metadata: T::Metadata,
+        f: impl FnOnce(ValidBlob<T>, &<Self::SrcPtr as Ptr>::BlobZone) -> R,
+    ) -> Result<Result<<Self::DstPtr as Ptr>::Persist, R>,
+                Self::Error>
+    {
+        self.inner.try_save_raw(ptr, metadata, f)
+                  .map_err(Deferred::Err)
+    }
+
+    fn finish_save<T>(&mut self, value_poll: &T) -> Result<<Self::DstPtr as Ptr>::Persist, Self::Error>
+        where T: EncodeBlob
+    {
+        // Checked before saving, so that a single large blob can't stop a save from ever making
+        // progress.
+        if self.exhausted() {
+            return Err(Deferred::Budget);
+        }
+
+        let size = if self.monitor.is_some() || self.budget.bytes < usize::MAX {
+            value_poll.encode_blob(CountBytes(0)).into_ok()
+        } else {
+            0
+        };
+        let r = self.inner.finish_save(value_poll)
+                          .map_err(Deferred::Err)?;
+
+        self.bytes = self.bytes.saturating_add(size);
+        self.blobs += 1;
+
+        if let Some(monitor) = self.monitor {
+            self.progress.bytes += size;
+            self.progress.nodes += 1;
+            self.progress.depth = self.base + self.depth;
+            monitor.report(&self.progress).map_err(|Cancelled| Deferred::Cancelled)?;
+        }
+        Ok(r)
+    }
+
+    unsafe fn save_dirty<T>(&mut self, poll: &mut T) -> Result<(), Self::Error>
+        where T: PollDirty<Self>
+    {
+        if self.depth < MAX_DEPTH {
+            self.depth += 1;
+            let r = poll.poll_dirty(self);
+            self.depth -= 1;
+            r
+        } else {
+            // SAFETY: Only the lifetime of the trait object is erased. As we return an error, our
+            // caller keeps the poll in place until a later call succeeds, which only happens
+            // once save() has polled this entry to completion and popped it. The poll lives
+            // within the value save() is saving, which its caller keeps in place and untouched for
+            // as long as the entry is on the stack.
+            let poll: *mut (dyn PollDirty<Self> + '_) = poll;
+            self.stack.push((mem::transmute(poll), self.base + self.depth + 1));
+            Err(Deferred::Pending)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::task::Poll;
+
+    use crate::bag::Bag;
+    use crate::save::budget::BudgetSaver;
+    use crate::offset::{Offset, OffsetMut, ShallowDumper};
+    use crate::pile::TryPile;
+    use crate::pile::fsck::{Fsck, FsckStats};
+    use crate::usage::{measure, MeasureUsage, Usage, UsageCounter};
+    use crate::validate::{ValidateChildren, ValidateLimits, PtrValidator};
+
+    /// A linked list, as deep as it is long.
+    struct Link<P: Ptr>(Option<Bag<Link<P>, P>>);
+
+    fn chain<'p, 'v>(len: usize) -> Link<OffsetMut<'p, 'v>> {
+        let mut link = Link(None);
+        for _ in 0 .. len {
+            link = Link(Some(Bag::new(link)));
+        }
+        link
+    }
+
+    unsafe impl<P: Ptr> ValidateBlob for Link<P> {
+        type BlobError = <Option<Bag<Self, P>> as ValidateBlob>::BlobError;
+
+        fn try_blob_layout(_: ()) -> Result<BlobLayout, !> {
+            Ok(<Option<Bag<Self, P>>>::blob_layout())
+        }
+
+        fn validate_blob<'a>(blob: Blob<'a, Self>, ignore_padding: bool) -> Result<ValidBlob<'a, Self>, Self::BlobError> {
+            let mut fields = blob.validate_fields(ignore_padding);
+            fields.validate_blob::<Option<Bag<Self, P>>>()?;
+            unsafe { Ok(fields.finish()) }
+        }
+    }
+
+    impl<P: Ptr> Load for Link<P> {
+        type Ptr = P;
+
+        fn decode_blob(blob: ValidBlob<Self>, zone: &P::BlobZone) -> Self {
+            let mut fields = blob.decode_fields(zone);
+            let next = unsafe { fields.decode_unchecked() };
+            fields.finish();
+            Self(next)
+        }
+    }
+
+    impl<'p, 'v> ValidateChildren<Offset<'p, 'v>> for Link<Offset<'p, 'v>> {
+        fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+            where V: PtrValidator<Offset<'p, 'v>>
+        {
+            let mut fields = blob.valid_fields();
+
+            // SAFETY: validated by Link::validate_blob()
+            let next = unsafe { fields.field_unchecked::<Option<Bag<Self, Offset<'p, 'v>>>>() };
+            fields.finish();
+            <Option<Bag<Self, Offset<'p, 'v>>>>::validate_children(next, validator)
+        }
+    }
+
+    impl<P: Ptr> MeasureUsage for Link<P> {
+        fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>) {
+            self.0.measure_usage(counter)
+        }
+    }
+
+    impl<P: Ptr, Q: Ptr> Saved<Q> for Link<P> {
+        type Saved = Link<Q>;
+    }
+
+    struct LinkSavePoll<'p, 'v>(
+        <Option<Bag<Link<OffsetMut<'p, 'v>>, OffsetMut<'p, 'v>>> as Save<Offset<'p, 'v>>>::SavePoll
+    );
+
+    impl<'p, 'v> Save<Offset<'p, 'v>> for Link<OffsetMut<'p, 'v>> {
+        type SavePoll = LinkSavePoll<'p, 'v>;
+
+        fn init_save(&self) -> Self::SavePoll {
+            LinkSavePoll(Save::<Offset<'p, 'v>>::init_save(&self.0))
+        }
+    }
+
+    impl<'p, 'v> EncodeBlob for LinkSavePoll<'p, 'v> {
+        type Target = Link<Offset<'p, 'v>>;
+
+        fn encode_blob<W: WriteBlob>(&self, dst: W) -> Result<W::Ok, W::Error> {
+            dst.write(&self.0)?
+               .finish()
+        }
+    }
+
+    unsafe impl<'p, 'v> SavePoll for LinkSavePoll<'p, 'v> {
+        type SrcPtr = OffsetMut<'p, 'v>;
+        type DstPtr = Offset<'p, 'v>;
+
+        fn save_poll<S>(&mut self, saver: &mut S) -> Result<(), S::Error>
+            where S: Saver<SrcPtr = Self::SrcPtr, DstPtr = Self::DstPtr>,
+        {
+            self.0.save_poll(saver)
+        }
+    }
+
+    #[test]
+    fn save_deep_nested() {
+        let value = Bag::<_, OffsetMut>::new(Bag::new(Bag::new(42u8)));
+
+        let mut dumper = ShallowDumper::new(0);
+        let mut poll = value.init_save_ptr();
+        let offset: Offset = save_deep(&mut dumper, &mut poll).into_ok();
+        assert_eq!(offset, 1 + 8 + 8);
+
+        let (buf, expected) = ShallowDumper::new(0).save(&value);
+        assert_eq!(offset, expected);
+        assert_eq!(buf.len(), 1 + 8 + 8 + 8);
+    }
+
+    #[test]
+    fn poller_outlives_value() {
+        // the poller doesn't refer back to the value it was created from
+        let mut poll = {
+            let value = Bag::<_, OffsetMut>::new(Bag::new(42u8));
+            value.init_save_ptr()
+        };
+
+        let mut dumper = ShallowDumper::new(0);
+        let offset: Offset = save_deep(&mut dumper, &mut poll).into_ok();
+        assert_eq!(offset, 1 + 8);
+    }
+
+    #[test]
+    fn budgeted_deep_chain() {
+        const LEN: usize = 1000;
+
+        // deep enough that values are deferred to the work stack, which has to be kept between
+        // calls for the save to make progress one blob at a time
+        let link = chain(LEN);
+        let budget = SaveBudget { bytes: usize::MAX, blobs: 1 };
+        let poll = <Link<OffsetMut> as SavePtr<OffsetMut, Offset>>::init_save_ptr(&link);
+        let mut saver = BudgetSaver::new(ShallowDumper::new(0), poll, budget);
+
+        let mut calls = 0;
+        let offset: Offset = loop {
+            calls += 1;
+            if let Poll::Ready(offset) = saver.poll_save().into_ok() {
+                break offset;
+            }
+        };
+        assert_eq!(calls, LEN + 1);
+
+        let (_, expected) = ShallowDumper::new(0).save(&link);
+        assert_eq!(offset, expected);
+    }
+
+    #[test]
+    fn save_and_drop_deep_chain() {
+        const LEN: usize = 10_000_000;
+
+        let link = chain(LEN);
+        let (buf, offset) = ShallowDumper::new(0).save(&link);
+        assert_eq!(buf.len(), (LEN + 1) * 8);
+        assert_eq!(offset, LEN * 8);
+
+        // the last link is the only one that's empty
+        assert_eq!(&buf[.. 8], &[0; 8]);
+        drop(link);
+    }
+
+    #[test]
+    fn fsck_deep_chain() {
+        const LEN: usize = 10_000_000;
+
+        let link = chain(LEN);
+        let (buf, offset) = ShallowDumper::new(0).save(&link);
+
+        let pile = unsafe { TryPile::new_unchecked(&buf) };
+        let stats = Fsck::with_limits(pile, ValidateLimits::UNLIMITED)
+                         .validate_root::<Link<Offset>>(offset, ())
+                         .unwrap();
+        assert_eq!(stats, FsckStats { blobs: LEN + 1, bytes: (LEN + 1) * 8, max_depth: LEN + 1 });
+    }
+
+    #[test]
+    fn measure_deep_chain() {
+        const LEN: usize = 10_000_000;
+
+        let link = chain(LEN);
+        assert_eq!(measure(&link),
+                   Usage {
+                       dirty_nodes: LEN,
+                       shallow_dirty_bytes: LEN * mem::size_of::<Link<OffsetMut>>(),
+                       shallow_clean_bytes: 0,
+                       max_depth: LEN,
+                   });
+    }
+}
@@ -82,7 +82,8 @@ impl<Q: Ptr, T: Scalar> EncodeBlob for ScalarSavePoll<Q, T> {
     }
 }
 
-impl<Q: Ptr, T: Scalar> SavePoll for ScalarSavePoll<Q, T> {
+// SAFETY: scalars have no children
+unsafe impl<Q: Ptr, T: Scalar> SavePoll for ScalarSavePoll<Q, T> {
     type SrcPtr = !;
 
     type DstPtr = Q;
@@ -0,0 +1,218 @@
+//! Memory and dirtiness accounting.
+//!
+//! Dirty data lives on the heap until it's saved. `MeasureUsage` walks the dirty part of a tree,
+//! using `Ptr::try_get_dirty_unchecked()` to tell dirty pointers from clean ones, so that
+//! applications can decide when to commit based on memory pressure.
+//!
+//! Both byte counts are shallow. Dirty values are counted by their own size, not whatever heap
+//! memory they own indirectly. Clean pointers aren't followed, as nothing is loaded from the zone;
+//! only the size of the blob each one points to directly is counted.
+//!
+//! Like saving, measuring is naturally recursive. `UsageCounter` bounds the recursion: past
+//! `MAX_DEPTH` dirty pointers deep, values are deferred to a heap-allocated work stack instead.
+
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::ops::AddAssign;
+
+use crate::blob::*;
+use crate::scalar::Scalar;
+
+/// The number of dirty pointers measuring recurses through before deferring to the work stack.
+const MAX_DEPTH: usize = 128;
+
+/// Totals reported by a `UsageCounter`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of dirty values behind pointers.
+    pub dirty_nodes: usize,
+
+    /// The sizes of the dirty values themselves.
+    ///
+    /// A shallow count: heap memory the values own indirectly, such as a `Vec`'s buffer, isn't
+    /// included.
+    pub shallow_dirty_bytes: usize,
+
+    /// The sizes of the blobs clean pointers within the dirty part of the tree point to.
+    ///
+    /// A shallow count: clean data isn't loaded, so whatever those blobs point to in turn isn't
+    /// included. This is a lower bound on the clean bytes reachable.
+    pub shallow_clean_bytes: usize,
+
+    /// The deepest dirty value, counting pointers followed.
+    pub max_depth: usize,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.dirty_nodes += other.dirty_nodes;
+        self.shallow_dirty_bytes += other.shallow_dirty_bytes;
+        self.shallow_clean_bytes += other.shallow_clean_bytes;
+        self.max_depth = self.max_depth.max(other.max_depth);
+    }
+}
+
+/// A dirty value whose children have yet to be measured.
+type Deferred<'a> = Box<dyn FnOnce(&mut UsageCounter<'a>) + 'a>;
+
+/// Accumulates `Usage` during a traversal of values borrowed for `'a`.
+#[derive(Default)]
+pub struct UsageCounter<'a> {
+    usage: Usage,
+    depth: usize,
+
+    /// The depth recursed to since the last value was taken from the work stack.
+    recursion: usize,
+
+    /// Deferred values, along with their depths.
+    stack: Vec<(Deferred<'a>, usize)>,
+    draining: bool,
+}
+
+impl fmt::Debug for UsageCounter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UsageCounter")
+            .field("usage", &self.usage)
+            .field("depth", &self.depth)
+            .field("deferred", &self.stack.len())
+            .finish()
+    }
+}
+
+impl<'a> UsageCounter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the totals so far.
+    pub fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    /// Counts a dirty value, and everything reachable from it.
+    pub fn dirty<T: ?Sized + MeasureUsage>(&mut self, value: &'a T) {
+        self.usage.dirty_nodes += 1;
+        self.usage.shallow_dirty_bytes += mem::size_of_val(value);
+
+        let depth = self.depth + 1;
+        self.usage.max_depth = cmp::max(self.usage.max_depth, depth);
+
+        if self.recursion < MAX_DEPTH {
+            self.recursion += 1;
+            self.depth = depth;
+            value.measure_usage(self);
+            self.depth -= 1;
+            self.recursion -= 1;
+
+            if self.recursion == 0 && !self.draining {
+                self.drain();
+            }
+        } else {
+            self.stack.push((Box::new(move |counter: &mut Self| value.measure_usage(counter)), depth));
+        }
+    }
+
+    /// Measures deferred values, until the work stack is empty.
+    fn drain(&mut self) {
+        let depth = self.depth;
+        self.draining = true;
+        while let Some((deferred, deferred_depth)) = self.stack.pop() {
+            self.depth = deferred_depth;
+            deferred(self);
+        }
+        self.draining = false;
+        self.depth = depth;
+    }
+
+    /// Counts a clean pointer to a blob of the specified size.
+    pub fn clean(&mut self, blob_size: usize) {
+        self.usage.shallow_clean_bytes += blob_size;
+    }
+
+    /// Counts a clean pointer to a value.
+    pub fn clean_ptr<T: ?Sized + ValidateBlob>(&mut self, metadata: T::Metadata) {
+        // metadata of a clean pointer was validated when it was loaded
+        if let Ok(layout) = T::try_blob_layout(metadata) {
+            self.clean(layout.size());
+        }
+    }
+}
+
+/// Measures the memory used by the pointers within a value.
+pub trait MeasureUsage {
+    /// Adds the usage of every pointer within this value to a counter.
+    ///
+    /// The value itself isn't counted.
+    fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>);
+}
+
+/// Measures the memory used by everything reachable from a value.
+pub fn measure<T: ?Sized + MeasureUsage>(value: &T) -> Usage {
+    let mut counter = UsageCounter::new();
+    value.measure_usage(&mut counter);
+    counter.usage()
+}
+
+impl<T: Scalar> MeasureUsage for T {
+    #[inline(always)]
+    fn measure_usage<'a>(&'a self, _: &mut UsageCounter<'a>) {
+    }
+}
+
+impl<T: MeasureUsage, const N: usize> MeasureUsage for [T; N] {
+    fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>) {
+        for item in self.iter() {
+            item.measure_usage(counter);
+        }
+    }
+}
+
+impl<T: MeasureUsage> MeasureUsage for [T] {
+    fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>) {
+        for item in self.iter() {
+            item.measure_usage(counter);
+        }
+    }
+}
+
+impl<T: MeasureUsage> MeasureUsage for Option<T> {
+    fn measure_usage<'a>(&'a self, counter: &mut UsageCounter<'a>) {
+        if let Some(value) = self {
+            value.measure_usage(counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use leint::Le;
+
+    use crate::bag::Bag;
+    use crate::offset::{Offset, OffsetMut};
+
+    type OffsetBag<'p, 'v, T> = Bag<T, OffsetMut<'p, 'v>>;
+
+    #[test]
+    fn measure_bags() {
+        assert_eq!(measure(&42u8), Usage::default());
+
+        let clean: OffsetBag<Le<u64>> = unsafe {
+            Bag::from_raw_parts(Offset::new(0).unwrap().into(), ())
+        };
+        assert_eq!(measure(&clean),
+                   Usage { dirty_nodes: 0, shallow_dirty_bytes: 0, shallow_clean_bytes: 8, max_depth: 0 });
+
+        let tree = Bag::new([Some(Bag::new(Le::new(1u64))), Some(clean), None]);
+        let inner: OffsetBag<_> = Bag::new(tree);
+        assert_eq!(measure(&inner),
+                   Usage {
+                       dirty_nodes: 3,
+                       shallow_dirty_bytes: 8 + mem::size_of::<[Option<OffsetBag<Le<u64>>>; 3]>() + 8,
+                       shallow_clean_bytes: 8,
+                       max_depth: 3,
+                   });
+    }
+}
@@ -0,0 +1,270 @@
+//! Validation of everything reachable from a blob.
+//!
+//! `ValidateBlob` only checks the bytes of a single blob. `ValidateChildren` extends that to the
+//! pointers *within* a valid blob, handing each one to a `PtrValidator`, which is responsible for
+//! fetching, validating, and recursing into the target.
+//!
+//! Data from untrusted sources should be validated with a `ValidateContext`, which bounds the
+//! total bytes touched, the pointer depth, and the size of any single blob. Piles carry
+//! `ValidateLimits` too, so the single blob limit also applies to ordinary loads.
+
+use thiserror::Error;
+
+use crate::pointee::Pointee;
+use crate::blob::*;
+use crate::ptr::*;
+use crate::scalar::Scalar;
+use crate::progress::{Cancelled, Monitor, Progress};
+
+/// Limits on the resources consumed by validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidateLimits {
+    /// The maximum total number of blob bytes validated.
+    pub max_bytes: usize,
+
+    /// The maximum pointer depth.
+    pub max_depth: usize,
+
+    /// The maximum size of any single blob.
+    pub max_blob_size: usize,
+}
+
+impl ValidateLimits {
+    /// No limits at all.
+    pub const UNLIMITED: Self = Self {
+        max_bytes: usize::MAX,
+        max_depth: usize::MAX,
+        max_blob_size: usize::MAX,
+    };
+
+    /// Checks a blob size against the single blob limit.
+    pub fn check_blob_size(&self, size: usize) -> Result<(), LimitError> {
+        if size <= self.max_blob_size {
+            Ok(())
+        } else {
+            Err(LimitError::BlobSize { size, limit: self.max_blob_size })
+        }
+    }
+}
+
+impl Default for ValidateLimits {
+    /// Unlimited, other than a depth limit of 1024.
+    fn default() -> Self {
+        Self {
+            max_depth: 1024,
+            ..Self::UNLIMITED
+        }
+    }
+}
+
+/// Returned when a `ValidateLimits` limit is exceeded.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("blob size {size} exceeds limit of {limit} bytes")]
+    BlobSize {
+        size: usize,
+        limit: usize,
+    },
+
+    #[error("byte budget of {limit} exceeded")]
+    Bytes {
+        limit: usize,
+    },
+
+    #[error("depth limit of {limit} exceeded")]
+    Depth {
+        limit: usize,
+    },
+}
+
+/// Tracks resource usage against `ValidateLimits`.
+#[derive(Debug, Clone, Default)]
+pub struct ValidateContext {
+    limits: ValidateLimits,
+    bytes: usize,
+    depth: usize,
+    max_depth: usize,
+    blobs: usize,
+    monitor: Option<Monitor>,
+}
+
+impl ValidateContext {
+    pub fn new(limits: ValidateLimits) -> Self {
+        Self {
+            limits,
+            bytes: 0,
+            depth: 0,
+            max_depth: 0,
+            blobs: 0,
+            monitor: None,
+        }
+    }
+
+    /// Reports progress to a `Monitor`, which can also cancel validation.
+    pub fn with_monitor(self, monitor: Monitor) -> Self {
+        Self {
+            monitor: Some(monitor),
+            ..self
+        }
+    }
+
+    /// Gets the limits.
+    pub fn limits(&self) -> &ValidateLimits {
+        &self.limits
+    }
+
+    /// Gets the total number of bytes consumed so far.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Gets the current depth.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Gets the greatest depth reached so far.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Checks a blob size against the single blob limit, without consuming anything.
+    ///
+    /// This should be done as soon as the size is known: the size comes from untrusted metadata,
+    /// and can be absurdly large.
+    pub fn check_blob_size(&self, size: usize) -> Result<(), LimitError> {
+        self.limits.check_blob_size(size)
+    }
+
+    /// Consumes bytes from the byte budget.
+    pub fn consume_bytes(&mut self, size: usize) -> Result<(), LimitError> {
+        self.bytes = self.bytes.checked_add(size)
+                               .filter(|bytes| *bytes <= self.limits.max_bytes)
+                               .ok_or(LimitError::Bytes { limit: self.limits.max_bytes })?;
+        Ok(())
+    }
+
+    /// Checks the size of a blob, and consumes it from the byte budget.
+    pub fn check_blob(&mut self, size: usize) -> Result<(), LimitError> {
+        self.check_blob_size(size)?;
+        self.consume_bytes(size)
+    }
+
+    /// Enters a blob, increasing the depth.
+    ///
+    /// Every successful call must be paired with a call to `exit()`.
+    pub fn enter(&mut self) -> Result<(), LimitError> {
+        if self.depth < self.limits.max_depth {
+            self.depth += 1;
+            self.max_depth = self.max_depth.max(self.depth);
+            Ok(())
+        } else {
+            Err(LimitError::Depth { limit: self.limits.max_depth })
+        }
+    }
+
+    /// Exits a blob, decreasing the depth.
+    pub fn exit(&mut self) {
+        self.depth = self.depth.checked_sub(1).expect("exit() called without enter()");
+    }
+
+    /// Counts a validated blob, reporting progress to the monitor, if any.
+    ///
+    /// Should be called once the blob's bytes have been consumed, and the blob entered. Returns an
+    /// error if validation has been cancelled.
+    pub fn report_blob(&mut self) -> Result<(), Cancelled> {
+        self.blobs += 1;
+        match &self.monitor {
+            Some(monitor) => monitor.report(&Progress {
+                bytes: self.bytes,
+                nodes: self.blobs,
+                depth: self.depth,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the current depth.
+    ///
+    /// Validators that defer children to a work stack, rather than recursing, use this to return
+    /// to the depth of the blob whose children are being validated.
+    pub fn set_depth(&mut self, depth: usize) {
+        assert!(depth <= self.max_depth, "depth never reached with enter()");
+        self.depth = depth;
+    }
+}
+
+/// Validates the targets of persistent pointers.
+pub trait PtrValidator<Q: PersistPtr> {
+    /// The error returned when validation fails.
+    type Error;
+
+    /// Validates the target of a pointer, and everything reachable from it.
+    fn validate_ptr<T>(&mut self, ptr: &Q, metadata: T::Metadata) -> Result<(), Self::Error>
+        where T: ?Sized + ValidateChildren<Q>;
+}
+
+/// Validation of the children of a valid blob.
+pub trait ValidateChildren<Q: PersistPtr> : ValidateBlob {
+    /// Validates the children of a blob, using the provided validator.
+    fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>;
+}
+
+impl<Q: PersistPtr, T: Scalar> ValidateChildren<Q> for T {
+    #[inline(always)]
+    fn validate_children<V>(_: ValidBlob<Self>, _: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>
+    {
+        Ok(())
+    }
+}
+
+impl<Q: PersistPtr, T: ValidateChildren<Q>, const N: usize> ValidateChildren<Q> for [T; N] {
+    fn validate_children<V>(blob: ValidBlob<Self>, validator: &mut V) -> Result<(), V::Error>
+        where V: PtrValidator<Q>
+    {
+        let mut fields = blob.valid_fields();
+        for _ in 0 .. N {
+            // SAFETY: arrays are validated item by item
+            let item = unsafe { fields.field_unchecked::<T>() };
+            T::validate_children(item, validator)?;
+        }
+        fields.finish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_context_limits() {
+        let mut ctx = ValidateContext::new(ValidateLimits {
+            max_bytes: 10,
+            max_depth: 2,
+            max_blob_size: 8,
+        });
+
+        assert_eq!(ctx.check_blob(9), Err(LimitError::BlobSize { size: 9, limit: 8 }));
+        assert_eq!(ctx.bytes(), 0);
+
+        ctx.check_blob(8).unwrap();
+        assert_eq!(ctx.check_blob(3), Err(LimitError::Bytes { limit: 10 }));
+        ctx.check_blob(2).unwrap();
+        assert_eq!(ctx.bytes(), 10);
+
+        ctx.enter().unwrap();
+        ctx.enter().unwrap();
+        assert_eq!(ctx.enter(), Err(LimitError::Depth { limit: 2 }));
+        ctx.exit();
+        ctx.exit();
+        assert_eq!(ctx.depth(), 0);
+        assert_eq!(ctx.max_depth(), 2);
+
+        ctx.set_depth(1);
+        ctx.enter().unwrap();
+        assert_eq!(ctx.enter(), Err(LimitError::Depth { limit: 2 }));
+    }
+}